@@ -0,0 +1,295 @@
+//! Batched search that shares tree traversals among nearby queries.
+//!
+//! The queries are clustered into a small query tree, and each group of nearby
+//! queries is represented by the center and radius of a `Cluster` in that tree.
+//! The upper levels of each search tree are traversed once per group, pruning
+//! or confirming `Cluster`s against the whole group, before the remaining
+//! `Cluster`s are refined separately for each query in the group.
+
+use rayon::prelude::*;
+
+use distances::Number;
+
+use crate::{Cluster, Dataset, Instance, PartitionCriteria, Tree, VecDataset};
+
+use super::{knn, rnn};
+
+/// A group of nearby queries that share a traversal of the search trees.
+struct Group<'a, I: Instance, U: Number> {
+    /// The query at the center of the group.
+    center: &'a I,
+    /// The largest distance from the center to any query in the group.
+    radius: U,
+    /// The indices of the queries in the group in the input batch, along
+    /// with their distances to the center.
+    members: Vec<(usize, U)>,
+}
+
+/// Builds a tree over the queries so that nearby queries can be grouped.
+///
+/// # Arguments
+///
+/// * `queries` - The queries to group.
+/// * `metric` - The distance function of the searched dataset.
+/// * `is_expensive` - Whether the metric is expensive to compute.
+fn query_tree<I: Instance, U: Number>(
+    queries: &[&I],
+    metric: fn(&I, &I) -> U,
+    is_expensive: bool,
+) -> Tree<I, U, VecDataset<I, U, usize>> {
+    let data = queries.iter().map(|&q| q.clone()).collect();
+    let data = VecDataset::new("queries".to_string(), data, metric, is_expensive);
+    Tree::new(data, Some(42)).partition(&PartitionCriteria::default())
+}
+
+/// Creates a `Group` from a `Cluster` of the query tree.
+fn make_group<'a, I: Instance, U: Number>(
+    query_tree: &'a Tree<I, U, VecDataset<I, U, usize>>,
+    cluster: &Cluster<U>,
+) -> Group<'a, I, U> {
+    let data = query_tree.data();
+    let center = &data[cluster.arg_center()];
+    let indices = cluster.indices().collect::<Vec<_>>();
+    let members = data
+        .query_to_many(center, &indices)
+        .into_iter()
+        .zip(indices)
+        .map(|(d, i)| (data.original_index(i), d))
+        .collect();
+    Group {
+        center,
+        radius: cluster.radius(),
+        members,
+    }
+}
+
+/// Searches one tree for a group of queries, each with its own radius.
+///
+/// `Cluster`s are pruned, confirmed or descended into using only the distance
+/// from the group center to the `Cluster` center. Once a `Cluster` is a leaf
+/// or is no larger than the group, it is handed over to a per-query clustered
+/// RNN search rooted at that `Cluster`.
+///
+/// # Arguments
+///
+/// * `tree` - The tree to search.
+/// * `group` - The group of queries.
+/// * `queries` - The queries in the input batch.
+/// * `radii` - The search radius for each member of the group.
+///
+/// # Returns
+///
+/// The hits for each member of the group, in the same order as the members.
+fn group_search<I, U, D>(tree: &Tree<I, U, D>, group: &Group<I, U>, queries: &[&I], radii: &[U]) -> Vec<Vec<(usize, U)>>
+where
+    I: Instance,
+    U: Number,
+    D: Dataset<I, U>,
+{
+    let data = tree.data();
+    let (min_radius, max_radius) = radii.iter().fold((radii[0], radii[0]), |(lo, hi), &r| {
+        (if r < lo { r } else { lo }, if r > hi { r } else { hi })
+    });
+
+    let mut confirmed = Vec::new();
+    let mut refine = Vec::new();
+    let mut candidates = vec![&tree.root];
+    while let Some(c) = candidates.pop() {
+        let d = c.distance_to_instance(data, group.center);
+        if d > c.radius() + group.radius + max_radius {
+            continue;
+        }
+
+        if d + c.radius() + group.radius <= min_radius {
+            confirmed.push(c);
+        } else if c.is_leaf() || c.radius() <= group.radius {
+            refine.push(c);
+        } else if let Some([left, right]) = c.children() {
+            candidates.push(left);
+            candidates.push(right);
+        } else {
+            unreachable!("Non-leaf clusters have children.")
+        }
+    }
+
+    let confirmed = confirmed.into_iter().flat_map(Cluster::indices).collect::<Vec<_>>();
+
+    group
+        .members
+        .par_iter()
+        .zip(radii.par_iter())
+        .map(|(&(q, _), &radius)| {
+            let query = queries[q];
+            let mut hits = confirmed
+                .iter()
+                .copied()
+                .zip(data.query_to_many(query, &confirmed))
+                .collect::<Vec<_>>();
+            for &root in &refine {
+                let [c, s] = rnn::clustered::tree_search(data, root, query, radius);
+                hits.extend(rnn::clustered::leaf_search(data, c, s, query, radius));
+            }
+            hits
+        })
+        .collect()
+}
+
+/// Searches all trees for a group of queries and adds the shard offsets.
+fn sharded_group_search<I, U, D>(
+    trees: &[(&Tree<I, U, D>, usize)],
+    group: &Group<I, U>,
+    queries: &[&I],
+    radii: &[U],
+) -> Vec<Vec<(usize, U)>>
+where
+    I: Instance,
+    U: Number,
+    D: Dataset<I, U>,
+{
+    let mut hits = vec![Vec::new(); group.members.len()];
+    for &(tree, offset) in trees {
+        for (all, shard_hits) in hits.iter_mut().zip(group_search(tree, group, queries, radii)) {
+            all.extend(shard_hits.into_iter().map(|(i, d)| (offset + i, d)));
+        }
+    }
+    hits
+}
+
+/// Collects the groups of queries for RNN search.
+///
+/// A `Cluster` of the query tree becomes a group once it is a leaf or its
+/// radius is no larger than the search radius.
+fn rnn_groups<I: Instance, U: Number>(
+    query_tree: &Tree<I, U, VecDataset<I, U, usize>>,
+    radius: U,
+) -> Vec<Group<'_, I, U>> {
+    let mut groups = Vec::new();
+    let mut clusters = vec![query_tree.root()];
+    while let Some(c) = clusters.pop() {
+        match c.children() {
+            Some([left, right]) if c.radius() > radius => {
+                clusters.push(left);
+                clusters.push(right);
+            }
+            _ => groups.push(make_group(query_tree, c)),
+        }
+    }
+    groups
+}
+
+/// Batched RNN search with shared tree traversals.
+///
+/// # Arguments
+///
+/// * `trees` - The trees to search, along with the offsets of their indices.
+/// * `queries` - The queries to search around.
+/// * `radius` - The radius to search within.
+///
+/// # Returns
+///
+/// The hits for each query, in the same order as `queries`.
+pub fn rnn_search<I, U, D>(trees: &[(&Tree<I, U, D>, usize)], queries: &[&I], radius: U) -> Vec<Vec<(usize, U)>>
+where
+    I: Instance,
+    U: Number,
+    D: Dataset<I, U>,
+{
+    let Some(&(tree, _)) = trees.first() else {
+        return vec![Vec::new(); queries.len()];
+    };
+    if queries.is_empty() {
+        return Vec::new();
+    }
+
+    let query_tree = query_tree(queries, tree.data().metric(), tree.data().is_metric_expensive());
+    let groups = rnn_groups(&query_tree, radius);
+
+    let mut results = vec![Vec::new(); queries.len()];
+    let group_hits = groups
+        .par_iter()
+        .map(|group| {
+            let radii = vec![radius; group.members.len()];
+            sharded_group_search(trees, group, queries, &radii)
+        })
+        .collect::<Vec<_>>();
+    for (group, hits) in groups.iter().zip(group_hits) {
+        for (&(q, _), hits) in group.members.iter().zip(hits) {
+            results[q] = hits;
+        }
+    }
+    results
+}
+
+/// Batched KNN search with shared tree traversals.
+///
+/// For each group, the `k` nearest neighbors of the group center are found
+/// first. If `r` is the distance to the farthest of those, then the `k`
+/// nearest neighbors of a query at distance `d` from the center all lie within
+/// `r + d` of the query, so a group RNN search with those radii, followed by
+/// keeping the `k` nearest hits for each query, gives exact results.
+///
+/// A `Cluster` of the query tree becomes a group once it is a leaf or its
+/// radius is no larger than `r`.
+///
+/// # Arguments
+///
+/// * `trees` - The trees to search, along with the offsets of their indices.
+/// * `queries` - The queries to search around.
+/// * `k` - The number of neighbors to search for.
+///
+/// # Returns
+///
+/// The hits for each query, in the same order as `queries`.
+pub fn knn_search<I, U, D>(trees: &[(&Tree<I, U, D>, usize)], queries: &[&I], k: usize) -> Vec<Vec<(usize, U)>>
+where
+    I: Instance,
+    U: Number,
+    D: Dataset<I, U>,
+{
+    let Some(&(tree, _)) = trees.first() else {
+        return vec![Vec::new(); queries.len()];
+    };
+    if queries.is_empty() {
+        return Vec::new();
+    }
+
+    let query_tree = query_tree(queries, tree.data().metric(), tree.data().is_metric_expensive());
+
+    let mut groups = Vec::new();
+    let mut clusters = vec![query_tree.root()];
+    while let Some(c) = clusters.pop() {
+        let center = &query_tree.data()[c.arg_center()];
+        let center_hits = trees
+            .iter()
+            .flat_map(|&(tree, offset)| {
+                knn::greedy_sieve::search(tree, center, k)
+                    .into_iter()
+                    .map(move |(i, d)| (offset + i, d))
+            })
+            .collect::<Vec<_>>();
+        let knn_radius = knn::Hits::from_vec(k, center_hits).peek();
+
+        match c.children() {
+            Some([left, right]) if c.radius() > knn_radius => {
+                clusters.push(left);
+                clusters.push(right);
+            }
+            _ => groups.push((make_group(&query_tree, c), knn_radius)),
+        }
+    }
+
+    let mut results = vec![Vec::new(); queries.len()];
+    let group_hits = groups
+        .par_iter()
+        .map(|(group, knn_radius)| {
+            let radii = group.members.iter().map(|&(_, d)| *knn_radius + d).collect::<Vec<_>>();
+            sharded_group_search(trees, group, queries, &radii)
+        })
+        .collect::<Vec<_>>();
+    for ((group, _), hits) in groups.iter().zip(group_hits) {
+        for (&(q, _), hits) in group.members.iter().zip(hits) {
+            results[q] = knn::Hits::from_vec(k, hits).extract();
+        }
+    }
+    results
+}
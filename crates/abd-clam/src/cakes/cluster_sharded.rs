@@ -0,0 +1,504 @@
+//! CAKES search with datasets sharded by clusters.
+
+use core::cmp::Ordering;
+
+use std::path::Path;
+
+use distances::Number;
+use rayon::prelude::*;
+
+use super::{Search, SingleShard};
+use crate::{knn, rnn, utils, Cluster, Dataset, Instance, PartitionCriteria, VecDataset};
+
+/// Cakes search with datasets sharded by clusters.
+///
+/// The dataset is first partitioned into top-level `Cluster`s, and each shard
+/// holds the instances of one of those `Cluster`s. The center and radius of the
+/// root of each shard's tree bound the shard, so that a query only visits the
+/// shards whose ball could contain hits. The results are the same as when
+/// visiting every shard.
+///
+/// # Type parameters
+///
+/// - `I`: The type of the dataset elements.
+/// - `U`: The type of the distance values.
+/// - `D`: The type of the dataset.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct ClusterSharded<I: Instance, U: Number, D: Dataset<I, U>> {
+    /// The shards, in the order of their `Cluster`s in the full dataset.
+    shards: Vec<SingleShard<I, U, D>>,
+    /// The offsets of the indices of the shards.
+    offsets: Vec<usize>,
+    /// The original index of each instance in the concatenation of the shards.
+    permutation: Vec<usize>,
+    /// The inverse of `permutation`.
+    inverse: Vec<usize>,
+}
+
+impl<I: Instance, U: Number, M: Instance> ClusterSharded<I, U, VecDataset<I, U, M>> {
+    /// Creates a new `ClusterSharded` instance by partitioning the dataset.
+    ///
+    /// The dataset is partitioned until every `Cluster` has a cardinality of
+    /// at most `max_cardinality`, and each leaf of that partition becomes a
+    /// shard. Shards may only be larger than `max_cardinality` when they hold
+    /// duplicate instances.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The dataset to shard.
+    /// * `max_cardinality` - The maximum cardinality of each shard.
+    /// * `seed` - The seed to use for the random number generator.
+    /// * `criteria` - The criteria to use for partitioning the tree of each shard.
+    #[must_use]
+    pub fn from_dataset(
+        mut data: VecDataset<I, U, M>,
+        max_cardinality: usize,
+        seed: Option<u64>,
+        criteria: &PartitionCriteria<U>,
+    ) -> Self {
+        let shard_criteria = PartitionCriteria::new(true).with_min_cardinality(max_cardinality);
+        let root = Cluster::new_root(&data, seed).partition(&mut data, &shard_criteria);
+
+        let mut leaves = root
+            .subtree()
+            .into_iter()
+            .filter(|c| c.is_leaf())
+            .map(Cluster::indices)
+            .collect::<Vec<_>>();
+        leaves.sort_by_key(|r| r.start);
+
+        let permutation = data
+            .permuted_indices()
+            .map_or_else(|| (0..data.cardinality()).collect(), <[usize]>::to_vec);
+
+        let name = data.name().to_string();
+        let (metric, is_expensive) = (data.metric(), data.is_metric_expensive());
+        let mut metadata = data.metadata().to_vec();
+        let mut instances = data.data_owned();
+
+        let mut shards = leaves
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, r)| {
+                VecDataset::new(
+                    format!("{name}-shard-{i}"),
+                    instances.split_off(r.start),
+                    metric,
+                    is_expensive,
+                )
+                .assign_metadata(metadata.split_off(r.start))
+                .unwrap_or_else(|_| unreachable!("We just split this dataset at the same indices."))
+            })
+            .collect::<Vec<_>>();
+        shards.reverse();
+
+        let shards = shards
+            .into_par_iter()
+            .map(|d| SingleShard::new(d, seed, criteria))
+            .collect();
+
+        Self::new(shards, permutation)
+    }
+}
+
+impl<I: Instance, U: Number, D: Dataset<I, U>> ClusterSharded<I, U, D> {
+    /// Creates a new `ClusterSharded` instance from shards that were already
+    /// built.
+    ///
+    /// # Arguments
+    ///
+    /// * `shards` - The shards to use.
+    /// * `permutation` - The original index of each instance in the
+    ///   concatenation of the shards, each in its order before its tree was built.
+    #[must_use]
+    pub fn new(shards: Vec<SingleShard<I, U, D>>, permutation: Vec<usize>) -> Self {
+        let offsets = shards
+            .iter()
+            .scan(0, |o, s| {
+                let offset = *o;
+                *o += s.data().cardinality();
+                Some(offset)
+            })
+            .collect();
+
+        Self {
+            shards,
+            offsets,
+            inverse: utils::inverse_permutation(&permutation),
+            permutation,
+        }
+    }
+
+    /// Returns the shards.
+    pub fn shards(&self) -> Vec<&SingleShard<I, U, D>> {
+        self.shards.iter().collect()
+    }
+
+    /// Returns the offsets of the indices of all shards.
+    pub fn shard_offsets(&self) -> Vec<usize> {
+        self.offsets.clone()
+    }
+
+    /// Finds the shard that contains the given index.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - An index across all shards.
+    ///
+    /// # Returns
+    ///
+    /// The position of the shard in `shards` and the index within that shard.
+    ///
+    /// # Errors
+    ///
+    /// * If `index` is not smaller than the total cardinality of the shards.
+    pub fn locate(&self, index: usize) -> Result<(usize, usize), String> {
+        let cardinality = self.permutation.len();
+        if index >= cardinality {
+            return Err(format!(
+                "Index {index} is out of bounds for sharded data with cardinality {cardinality}."
+            ));
+        }
+
+        let shard = self.offsets.iter().take_while(|&&o| o <= index).count() - 1;
+        Ok((shard, index - self.offsets[shard]))
+    }
+
+    /// Returns the index of an instance in the dataset from before it was
+    /// sharded.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - An index across all shards, as returned by search.
+    ///
+    /// # Errors
+    ///
+    /// * If `index` is not smaller than the total cardinality of the shards.
+    pub fn original_index(&self, index: usize) -> Result<usize, String> {
+        let (s, local_index) = self.locate(index)?;
+        Ok(self.shard_original_index(s, local_index))
+    }
+
+    /// Returns the index of an instance in the dataset from before it was
+    /// sharded, given the position of its shard and its index in that shard.
+    pub(crate) fn shard_original_index(&self, shard: usize, local_index: usize) -> usize {
+        let local_original = self.shards[shard].data().original_index(local_index);
+        self.permutation[self.offsets[shard] + local_original]
+    }
+
+    /// Returns the index across all shards, as used by search, of an instance
+    /// in the dataset from before it was sharded.
+    ///
+    /// # Arguments
+    ///
+    /// * `original_index` - The index of the instance before sharding.
+    ///
+    /// # Errors
+    ///
+    /// * If `original_index` is not a valid index in the dataset.
+    pub fn permuted_index(&self, original_index: usize) -> Result<usize, String> {
+        let position =
+            utils::permuted_index(Some(&self.inverse), original_index, self.permutation.len()).ok_or_else(|| {
+                format!(
+                    "Index {original_index} is out of bounds for sharded data with cardinality {}.",
+                    self.permutation.len()
+                )
+            })?;
+        let (s, local_index) = self.locate(position)?;
+        self.shards[s].permuted_index(local_index).map(|i| i + self.offsets[s])
+    }
+
+    /// Returns the distances from the query to the centers of the shards.
+    fn center_distances(&self, query: &I) -> Vec<U> {
+        self.shards
+            .par_iter()
+            .map(|s| s.tree().root().distance_to_instance(s.data(), query))
+            .collect()
+    }
+
+    /// Returns the shards whose ball overlaps with the query ball.
+    ///
+    /// Only these shards can contain hits for RNN search with the given
+    /// `radius`.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radius` - The search radius.
+    pub fn route(&self, query: &I, radius: U) -> Vec<usize> {
+        self.center_distances(query)
+            .into_iter()
+            .zip(self.shards.iter())
+            .enumerate()
+            .filter(|(_, (d, s))| *d <= s.tree().radius() + radius)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Returns the largest shard, on which the choice of algorithm is tuned.
+    pub fn tuning_shard(&self) -> &SingleShard<I, U, D> {
+        &self.shards[self.largest_shard()]
+    }
+
+    /// Returns the largest shard, on which the choice of algorithm is tuned.
+    pub fn tuning_shard_mut(&mut self) -> &mut SingleShard<I, U, D> {
+        let s = self.largest_shard();
+        &mut self.shards[s]
+    }
+
+    /// Returns the position of the largest shard, which is used for tuning.
+    fn largest_shard(&self) -> usize {
+        self.shards
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, s)| s.data().cardinality())
+            .map_or(0, |(i, _)| i)
+    }
+
+    /// Performs KNN search by visiting the shards in increasing order of the
+    /// smallest possible distance from the query to any of their instances.
+    ///
+    /// The first shard is searched for the `k` nearest neighbors. Every
+    /// following shard is searched with RNN search, using the distance to the
+    /// current `k`-th nearest neighbor as the radius, and the visits stop once
+    /// no remaining shard can contain a closer instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `k` - The number of neighbors to search for.
+    /// * `search` - Searches the shard at the given position for the `k` nearest
+    ///   neighbors if the radius is `None`, or for the neighbors within the
+    ///   radius otherwise.
+    fn routed_knn<F>(&self, query: &I, k: usize, search: F) -> Vec<(usize, U)>
+    where
+        F: Fn(usize, Option<U>) -> Vec<(usize, U)>,
+    {
+        let mut order = self
+            .center_distances(query)
+            .into_iter()
+            .zip(self.shards.iter())
+            .map(|(d, s)| {
+                let r = s.tree().radius();
+                if d < r {
+                    U::zero()
+                } else {
+                    d - r
+                }
+            })
+            .enumerate()
+            .collect::<Vec<_>>();
+        order.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Greater));
+
+        let mut hits = knn::Hits::new(k);
+        for (s, d_min) in order {
+            let radius = if hits.len() < k {
+                None
+            } else if d_min <= hits.peek() {
+                Some(hits.peek())
+            } else {
+                break;
+            };
+            let o = self.offsets[s];
+            hits.push_batch(search(s, radius).into_iter().map(|(i, d)| (i + o, d)));
+        }
+
+        hits.extract()
+    }
+}
+
+impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for ClusterSharded<I, U, D> {
+    #[allow(clippy::similar_names)]
+    fn save(&self, path: &Path) -> Result<(), String> {
+        if !path.exists() {
+            return Err(format!("Path does not exist: {}", path.display()));
+        }
+
+        if !path.is_dir() {
+            return Err(format!("Path is not a directory: {}", path.display()));
+        }
+
+        let shards_dir = path.join("cluster_shards");
+        if !shards_dir.exists() {
+            std::fs::create_dir(&shards_dir).map_err(|e| format!("Failed to create directory: {e:?}"))?;
+        }
+        for (i, shard) in self.shards.iter().enumerate() {
+            let shard_dir = shards_dir.join(format!("shard_{i}"));
+            if !shard_dir.exists() {
+                std::fs::create_dir(&shard_dir).map_err(|e| format!("Failed to create directory: {e:?}"))?;
+            }
+            shard.save(&shard_dir)?;
+        }
+
+        // Remove any shards left over from a previous save with more shards.
+        for i in self.shards.len().. {
+            let shard_dir = shards_dir.join(format!("shard_{i}"));
+            if !shard_dir.exists() {
+                break;
+            }
+            std::fs::remove_dir_all(&shard_dir).map_err(|e| format!("Failed to remove directory: {e:?}"))?;
+        }
+
+        utils::write_indices(&path.join("permutation"), &self.permutation)?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::similar_names)]
+    fn load(path: &Path, metric: fn(&I, &I) -> U, is_expensive: bool) -> Result<Self, String>
+    where
+        Self: Sized,
+    {
+        if !path.exists() {
+            return Err(format!("Path does not exist: {}", path.display()));
+        }
+
+        if !path.is_dir() {
+            return Err(format!("Path is not a directory: {}", path.display()));
+        }
+
+        let shards_dir = path.join("cluster_shards");
+        let mut shards = Vec::<SingleShard<I, U, D>>::new();
+        for i in 0.. {
+            let shard_dir = shards_dir.join(format!("shard_{i}"));
+            if !shard_dir.exists() {
+                break;
+            }
+            shards.push(SingleShard::load(&shard_dir, metric, is_expensive)?);
+        }
+        if shards.is_empty() {
+            return Err(format!("No shards found in {}", shards_dir.display()));
+        }
+
+        let permutation = utils::read_indices(&path.join("permutation"))?;
+
+        let cardinality = shards.iter().map(|s| s.data().cardinality()).sum::<usize>();
+        if permutation.len() != cardinality {
+            return Err(format!(
+                "The permutation has {} indices but the shards have {cardinality} instances.",
+                permutation.len()
+            ));
+        }
+
+        Ok(Self::new(shards, permutation))
+    }
+
+    fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_cardinalities(&self) -> Vec<usize> {
+        self.shards.iter().map(|s| s.data().cardinality()).collect()
+    }
+
+    fn tuned_rnn_algorithm(&self) -> rnn::Algorithm {
+        self.shards[self.largest_shard()].tuned_rnn_algorithm()
+    }
+
+    fn rnn_search(&self, query: &I, radius: U, algo: rnn::Algorithm) -> Vec<(usize, U)> {
+        self.route(query, radius)
+            .into_par_iter()
+            .flat_map(|s| {
+                let o = self.offsets[s];
+                self.shards[s]
+                    .rnn_search(query, radius, algo)
+                    .into_par_iter()
+                    .map(move |(i, d)| (i + o, d))
+            })
+            .collect()
+    }
+
+    fn linear_rnn_search(&self, query: &I, radius: U) -> Vec<(usize, U)> {
+        self.shards
+            .par_iter()
+            .zip(self.offsets.par_iter())
+            .flat_map(|(shard, &o)| {
+                shard
+                    .linear_rnn_search(query, radius)
+                    .into_par_iter()
+                    .map(move |(i, d)| (i + o, d))
+            })
+            .collect()
+    }
+
+    fn rnn_by_index(
+        &self,
+        original_index: usize,
+        radius: U,
+        exclude_duplicates: bool,
+    ) -> Result<Vec<(usize, U)>, String> {
+        let (owner, index) = self.locate(self.permuted_index(original_index)?)?;
+        let query = &self.shards[owner].data()[index];
+
+        Ok(self
+            .route(query, radius)
+            .into_par_iter()
+            .flat_map(|s| {
+                let self_index = if s == owner { Some(index) } else { None };
+                let o = self.offsets[s];
+                self.shards[s]
+                    .rnn_excluding(query, radius, self_index, exclude_duplicates)
+                    .into_par_iter()
+                    .map(move |(i, d)| (i + o, d))
+            })
+            .collect())
+    }
+
+    fn tuned_knn_algorithm(&self) -> knn::Algorithm {
+        self.shards[self.largest_shard()].tuned_knn_algorithm()
+    }
+
+    fn knn_search(&self, query: &I, k: usize, algo: knn::Algorithm) -> Vec<(usize, U)> {
+        self.routed_knn(query, k, |s, radius| {
+            radius.map_or_else(
+                || self.shards[s].knn_search(query, k, algo),
+                |radius| self.shards[s].rnn_search(query, radius, rnn::Algorithm::Clustered),
+            )
+        })
+    }
+
+    fn auto_tune_rnn(&mut self, radius: U, tuning_depth: usize) {
+        let s = self.largest_shard();
+        self.shards[s].auto_tune_rnn(radius, tuning_depth);
+    }
+
+    fn auto_tune_knn(&mut self, k: usize, tuning_depth: usize) {
+        let s = self.largest_shard();
+        self.shards[s].auto_tune_knn(k, tuning_depth);
+    }
+
+    fn linear_knn_search(&self, query: &I, k: usize) -> Vec<(usize, U)> {
+        let hits = self
+            .shards
+            .par_iter()
+            .zip(self.offsets.par_iter())
+            .flat_map(|(shard, &o)| {
+                shard
+                    .linear_knn_search(query, k)
+                    .into_par_iter()
+                    .map(move |(i, d)| (i + o, d))
+            })
+            .collect();
+        knn::Hits::from_vec(k, hits).extract()
+    }
+
+    fn knn_by_index(
+        &self,
+        original_index: usize,
+        k: usize,
+        exclude_duplicates: bool,
+    ) -> Result<Vec<(usize, U)>, String> {
+        let (owner, index) = self.locate(self.permuted_index(original_index)?)?;
+        let query = &self.shards[owner].data()[index];
+
+        Ok(self.routed_knn(query, k, |s, radius| {
+            let self_index = if s == owner { Some(index) } else { None };
+            radius.map_or_else(
+                || self.shards[s].knn_excluding(query, k, self_index, exclude_duplicates),
+                |radius| self.shards[s].rnn_excluding(query, radius, self_index, exclude_duplicates),
+            )
+        }))
+    }
+}
@@ -0,0 +1,67 @@
+//! Summaries of filters for filtered search.
+
+use core::ops::Range;
+
+/// A summary of which instances pass a filter in filtered search.
+///
+/// The instances in every `Cluster` occupy a contiguous range of indices in
+/// the reordered dataset. The summary stores, for each shard, the number of
+/// passing instances before each index, so that the number of passing
+/// instances in any `Cluster` is found in constant time. Filtered search uses
+/// this to skip the `Cluster`s that have no passing instances.
+///
+/// Building the summary evaluates the filter once for every instance, so it
+/// pays off when the same filter is used for many queries.
+#[derive(Debug, Clone)]
+#[allow(clippy::module_name_repetitions)]
+pub struct FilterSummary {
+    /// For each shard, the prefix sums of the number of passing instances.
+    counts: Vec<Vec<usize>>,
+}
+
+impl FilterSummary {
+    /// Creates a new `FilterSummary`.
+    ///
+    /// # Arguments
+    ///
+    /// * `passes` - For each shard, whether the instance at each index passes the filter.
+    pub(crate) fn new(passes: &[Vec<bool>]) -> Self {
+        let counts = passes
+            .iter()
+            .map(|shard| {
+                core::iter::once(0)
+                    .chain(shard.iter().scan(0, |count, &p| {
+                        if p {
+                            *count += 1;
+                        }
+                        Some(*count)
+                    }))
+                    .collect()
+            })
+            .collect();
+        Self { counts }
+    }
+
+    /// The number of shards that were summarized.
+    #[must_use]
+    pub fn num_shards(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// The total number of instances that pass the filter.
+    #[must_use]
+    pub fn num_passing(&self) -> usize {
+        self.counts.iter().map(|c| c.last().copied().unwrap_or_default()).sum()
+    }
+
+    /// Whether the instance at the given index in the given shard passes the filter.
+    pub(crate) fn passes(&self, shard: usize, index: usize) -> bool {
+        self.counts[shard][index + 1] > self.counts[shard][index]
+    }
+
+    /// The number of instances in the given range of indices in the given
+    /// shard that pass the filter.
+    pub(crate) fn count_in(&self, shard: usize, indices: Range<usize>) -> usize {
+        self.counts[shard][indices.end] - self.counts[shard][indices.start]
+    }
+}
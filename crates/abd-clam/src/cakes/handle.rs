@@ -0,0 +1,192 @@
+//! A thread-safe handle to a `Cakes` instance that can be swapped while serving.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+    thread::JoinHandle,
+};
+
+use distances::Number;
+
+use super::Cakes;
+use crate::{knn, rnn, Dataset, Instance};
+
+/// A thread-safe handle to a `Cakes` instance whose index can be replaced
+/// while searches are being served.
+///
+/// Each search takes a snapshot of the current index, which is an `Arc` to it,
+/// and runs against that snapshot. Replacing the index swaps the `Arc` under a
+/// write lock that is held only for the swap itself, so searches never wait on
+/// a rebuild. Searches that started before a swap finish against the old
+/// index, which is dropped once the last of them is done.
+///
+/// The handle is cheap to share: wrap it in an `Arc` or hand out references
+/// to it from many threads.
+#[allow(clippy::module_name_repetitions)]
+pub struct CakesHandle<I: Instance, U: Number, D: Dataset<I, U>> {
+    /// The current index.
+    current: RwLock<Arc<Cakes<I, U, D>>>,
+    /// The number of times the index has been replaced.
+    generation: AtomicU64,
+}
+
+/// A background replacement of the index, which yields the previous index once
+/// the swap is done.
+type Replacement<I, U, D> = JoinHandle<Result<Arc<Cakes<I, U, D>>, String>>;
+
+impl<I: Instance, U: Number, D: Dataset<I, U>> CakesHandle<I, U, D> {
+    /// Creates a new handle serving the given index.
+    pub fn new(cakes: Cakes<I, U, D>) -> Self {
+        Self::from_arc(Arc::new(cakes))
+    }
+
+    /// Creates a new handle serving an index that is already shared.
+    pub const fn from_arc(cakes: Arc<Cakes<I, U, D>>) -> Self {
+        Self {
+            current: RwLock::new(cakes),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns a snapshot of the current index.
+    ///
+    /// The snapshot stays valid, and keeps the index alive, even if the index
+    /// is replaced afterwards. Use it to run several searches against the same
+    /// index.
+    pub fn snapshot(&self) -> Arc<Cakes<I, U, D>> {
+        Arc::clone(&self.current.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Returns a snapshot of the current index along with its generation.
+    fn versioned_snapshot(&self) -> (Arc<Cakes<I, U, D>>, u64) {
+        let current = self.current.read().unwrap_or_else(PoisonError::into_inner);
+        // The generation only changes under the write lock.
+        (Arc::clone(&current), self.generation.load(Ordering::Acquire))
+    }
+
+    /// Returns the number of times the index has been replaced.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Atomically replaces the index.
+    ///
+    /// # Returns
+    ///
+    /// The previous index. Searches that are still running against it keep it
+    /// alive until they finish.
+    pub fn replace(&self, cakes: Cakes<I, U, D>) -> Arc<Cakes<I, U, D>> {
+        self.replace_arc(Arc::new(cakes))
+    }
+
+    /// Atomically replaces the index with one that is already shared.
+    ///
+    /// # Returns
+    ///
+    /// The previous index.
+    pub fn replace_arc(&self, cakes: Arc<Cakes<I, U, D>>) -> Arc<Cakes<I, U, D>> {
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        let old = core::mem::replace(&mut *current, cakes);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        drop(current);
+        old
+    }
+
+    /// Replaces the index only if it has not been replaced since the given
+    /// generation.
+    ///
+    /// # Returns
+    ///
+    /// The previous index.
+    ///
+    /// # Errors
+    ///
+    /// * If the index has been replaced since `generation`.
+    fn replace_if_current(&self, cakes: Cakes<I, U, D>, generation: u64) -> Result<Arc<Cakes<I, U, D>>, String> {
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        let latest = self.generation.load(Ordering::Acquire);
+        if latest != generation {
+            return Err(format!(
+                "The index was replaced while its replacement was being made, from generation {generation} to {latest}."
+            ));
+        }
+        let old = core::mem::replace(&mut *current, Arc::new(cakes));
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        drop(current);
+        Ok(old)
+    }
+
+    /// Performs an RNN search against the current index.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radius` - The search radius.
+    /// * `algo` - The algorithm to use.
+    pub fn rnn_search(&self, query: &I, radius: U, algo: rnn::Algorithm) -> Vec<(usize, U)> {
+        self.snapshot().rnn_search(query, radius, algo)
+    }
+
+    /// Performs an RNN search against the current index with its tuned algorithm.
+    pub fn tuned_rnn_search(&self, query: &I, radius: U) -> Vec<(usize, U)> {
+        self.snapshot().tuned_rnn_search(query, radius)
+    }
+
+    /// Performs a KNN search against the current index.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `k` - The number of nearest neighbors to return.
+    /// * `algo` - The algorithm to use.
+    pub fn knn_search(&self, query: &I, k: usize, algo: knn::Algorithm) -> Vec<(usize, U)> {
+        self.snapshot().knn_search(query, k, algo)
+    }
+
+    /// Performs a KNN search against the current index with its tuned algorithm.
+    pub fn tuned_knn_search(&self, query: &I, k: usize) -> Vec<(usize, U)> {
+        self.snapshot().tuned_knn_search(query, k)
+    }
+}
+
+impl<I, U, D> CakesHandle<I, U, D>
+where
+    I: Instance + 'static,
+    U: Number + 'static,
+    D: Dataset<I, U> + 'static,
+{
+    /// Builds or loads a replacement index on a background thread and swaps
+    /// it in once it is ready.
+    ///
+    /// The current index keeps serving searches while the replacement is
+    /// being made. If `make` fails, the current index is left in place.
+    ///
+    /// The replacement is made from a snapshot of the index at some generation.
+    /// If the index is replaced again before the replacement is ready, e.g. by
+    /// another background replacement that finished first, the now stale
+    /// replacement is discarded rather than overwriting the newer index.
+    ///
+    /// # Arguments
+    ///
+    /// * `make` - Builds or loads the replacement index. It is given a
+    ///   snapshot of the current index, e.g. to read its data or tuning.
+    ///
+    /// # Returns
+    ///
+    /// A handle to the background thread, which yields the previous index
+    /// once the swap is done, the error from `make`, or an error if the
+    /// replacement was stale.
+    pub fn replace_in_background<F>(self: &Arc<Self>, make: F) -> Replacement<I, U, D>
+    where
+        F: FnOnce(&Cakes<I, U, D>) -> Result<Cakes<I, U, D>, String> + Send + 'static,
+    {
+        let handle = Arc::clone(self);
+        std::thread::spawn(move || {
+            let (snapshot, generation) = handle.versioned_snapshot();
+            let replacement = make(&snapshot)?;
+            handle.replace_if_current(replacement, generation)
+        })
+    }
+}
@@ -4,7 +4,7 @@ use distances::Number;
 
 use crate::{Cluster, Dataset, Instance, Tree};
 
-use super::{OrdNumber, RevNumber};
+use super::{OrdNumber, RevNumber, SharedBound};
 
 /// K-Nearest Neighbor search with expanding threshold.
 ///
@@ -51,6 +51,287 @@ where
     hits.into_iter().map(|(i, OrdNumber(d))| (i, d)).collect()
 }
 
+/// K-Nearest Neighbor search with expanding threshold, leaving out the query
+/// itself and, optionally, its exact duplicates.
+///
+/// When the query is an instance in the tree, `self_index` should be its index
+/// in the (permuted) dataset. The query is then known to lie inside every
+/// `Cluster` on the path from the root to its leaf, so the distances to the
+/// centers of those `Cluster`s are not needed to compute their `d_min`.
+///
+/// # Arguments
+///
+/// * `tree` - The tree to search.
+/// * `query` - The query to search around.
+/// * `k` - The number of neighbors to search for.
+/// * `self_index` - The index of the query in the dataset, if it is in the dataset.
+/// * `exclude_duplicates` - Whether to leave out instances at distance zero from the query.
+///
+/// # Returns
+///
+/// A vector of 2-tuples, where the first element is the index of the instance
+/// and the second element is the distance from the query to the instance.
+/// There may be fewer than `k` hits if the tree does not have enough instances
+/// left after the exclusions.
+pub fn search_excluding<I, U, D>(
+    tree: &Tree<I, U, D>,
+    query: &I,
+    k: usize,
+    self_index: Option<usize>,
+    exclude_duplicates: bool,
+) -> Vec<(usize, U)>
+where
+    I: Instance,
+    U: Number,
+    D: Dataset<I, U>,
+{
+    search_filtered(tree, query, k, self_index, exclude_duplicates, |_| true, |_| true)
+}
+
+/// K-Nearest Neighbor search with expanding threshold, keeping only those
+/// instances that pass a filter.
+///
+/// The search keeps traversing the tree until `k` instances that pass the
+/// filter have been found, or until the tree is exhausted. Instances that do
+/// not pass the filter are dropped before computing their distances to the
+/// query, and `Cluster`s for which `has_any` is `false` are never expanded.
+///
+/// # Arguments
+///
+/// * `tree` - The tree to search.
+/// * `query` - The query to search around.
+/// * `k` - The number of neighbors to search for.
+/// * `self_index` - The index of the query in the dataset, if it is in the
+///   dataset. The query is left out of the hits.
+/// * `exclude_duplicates` - Whether to leave out instances at distance zero from the query.
+/// * `keep` - Whether the instance at the given index passes the filter.
+/// * `has_any` - Whether the given `Cluster` may contain any instance that passes the filter.
+///
+/// # Returns
+///
+/// A vector of 2-tuples, where the first element is the index of the instance
+/// and the second element is the distance from the query to the instance.
+/// There may be fewer than `k` hits if the tree does not have enough instances
+/// that pass the filter.
+pub fn search_filtered<I, U, D, P, C>(
+    tree: &Tree<I, U, D>,
+    query: &I,
+    k: usize,
+    self_index: Option<usize>,
+    exclude_duplicates: bool,
+    keep: P,
+    has_any: C,
+) -> Vec<(usize, U)>
+where
+    I: Instance,
+    U: Number,
+    D: Dataset<I, U>,
+    P: Fn(usize) -> bool,
+    C: Fn(&Cluster<U>) -> bool,
+{
+    let mut candidates = priority_queue::PriorityQueue::<&Cluster<U>, RevNumber<U>>::new();
+    let mut hits = priority_queue::PriorityQueue::<usize, OrdNumber<U>>::new();
+
+    let (data, root) = (tree.data(), &tree.root);
+
+    let d_min_of = |c: &Cluster<U>| {
+        if self_index.is_some_and(|i| c.indices().contains(&i)) {
+            U::zero()
+        } else {
+            d_min(c, c.distance_to_instance(data, query))
+        }
+    };
+
+    if has_any(root) {
+        candidates.push(root, RevNumber(d_min_of(root)));
+    }
+
+    while let Some((&c, &RevNumber(d))) = candidates.peek() {
+        if hits.len() == k && hits.peek().is_some_and(|(_, &OrdNumber(h))| h < d) {
+            break;
+        }
+        candidates.pop();
+
+        if let Some(children) = c.children() {
+            for child in children.into_iter().filter(|&child| has_any(child)) {
+                candidates.push(child, RevNumber(d_min_of(child)));
+            }
+        } else {
+            let indices = c
+                .indices()
+                .filter(|&i| self_index != Some(i) && keep(i))
+                .collect::<Vec<_>>();
+            let distances = if c.is_singleton() {
+                vec![d; indices.len()]
+            } else {
+                data.query_to_many(query, &indices)
+            };
+            indices
+                .into_iter()
+                .zip(distances)
+                .filter(|&(_, d)| !(exclude_duplicates && d == U::zero()))
+                .for_each(|(i, d)| {
+                    hits.push(i, OrdNumber(d));
+                });
+            trim_hits(k, &mut hits);
+        }
+    }
+
+    hits.into_iter().map(|(i, OrdNumber(d))| (i, d)).collect()
+}
+
+/// K-Nearest Neighbor search with expanding threshold on one of several trees
+/// that are searched concurrently.
+///
+/// `Cluster`s whose `d_min` exceeds the shared `bound` are never expanded, and
+/// whenever this search has `k` hits, the distance to the farthest of them is
+/// published to the `bound`. Merging the hits from all trees and keeping the
+/// `k` nearest gives the exact `k` nearest neighbors over all trees.
+///
+/// # Arguments
+///
+/// * `tree` - The tree to search.
+/// * `query` - The query to search around.
+/// * `k` - The number of neighbors to search for.
+/// * `bound` - The bound shared with the searches on the other trees.
+///
+/// # Returns
+///
+/// A vector of 2-tuples, where the first element is the index of the instance
+/// and the second element is the distance from the query to the instance.
+pub fn search_bounded<I, U, D>(tree: &Tree<I, U, D>, query: &I, k: usize, bound: &SharedBound) -> Vec<(usize, U)>
+where
+    I: Instance,
+    U: Number,
+    D: Dataset<I, U>,
+{
+    let mut candidates = priority_queue::PriorityQueue::<&Cluster<U>, RevNumber<U>>::new();
+    let mut hits = priority_queue::PriorityQueue::<usize, OrdNumber<U>>::new();
+
+    let (data, root) = (tree.data(), &tree.root);
+    candidates.push(root, RevNumber(d_min(root, root.distance_to_instance(data, query))));
+
+    while let Some((&c, &RevNumber(d))) = candidates.peek() {
+        if bound.excludes(d) || (hits.len() == k && hits.peek().is_some_and(|(_, &OrdNumber(h))| h < d)) {
+            break;
+        }
+        candidates.pop();
+
+        if let Some(children) = c.children() {
+            for child in children {
+                candidates.push(child, RevNumber(d_min(child, child.distance_to_instance(data, query))));
+            }
+        } else {
+            let indices = c.indices().collect::<Vec<_>>();
+            let distances = if c.is_singleton() {
+                vec![d; indices.len()]
+            } else {
+                data.query_to_many(query, &indices)
+            };
+            indices.into_iter().zip(distances).for_each(|(i, d)| {
+                hits.push(i, OrdNumber(d));
+            });
+            trim_hits(k, &mut hits);
+            if hits.len() == k {
+                if let Some((_, &OrdNumber(h))) = hits.peek() {
+                    bound.tighten(h);
+                }
+            }
+        }
+    }
+
+    hits.into_iter().map(|(i, OrdNumber(d))| (i, d)).collect()
+}
+
+/// An iterator over the nearest neighbors of a query, in non-decreasing order
+/// of distance.
+///
+/// This uses the same pair of priority queues as `search`, but `Cluster`s are
+/// only expanded when more neighbors are pulled from the iterator. A hit is
+/// yielded once its distance is no larger than the `d_min` of every `Cluster`
+/// that has not yet been expanded, so no closer instance can appear later.
+///
+/// The iterator may search over several trees at once, e.g. for the shards of
+/// a dataset, in which case the indices of each tree are shifted by its offset.
+pub struct NearestIter<'a, I: Instance, U: Number, D: Dataset<I, U>> {
+    /// The trees to search, with the offsets of their indices.
+    trees: Vec<(&'a Tree<I, U, D>, usize)>,
+    /// The query to search around.
+    query: &'a I,
+    /// The `Cluster`s yet to be expanded, keyed by the position of their tree.
+    candidates: priority_queue::PriorityQueue<(usize, &'a Cluster<U>), RevNumber<U>>,
+    /// The instances whose distances are known but which have not been yielded.
+    hits: priority_queue::PriorityQueue<usize, RevNumber<U>>,
+}
+
+impl<'a, I: Instance, U: Number, D: Dataset<I, U>> NearestIter<'a, I, U, D> {
+    /// Creates a new iterator over the nearest neighbors of `query`.
+    ///
+    /// # Arguments
+    ///
+    /// * `trees` - The trees to search, with the offsets of their indices.
+    /// * `query` - The query to search around.
+    pub(crate) fn new(trees: Vec<(&'a Tree<I, U, D>, usize)>, query: &'a I) -> Self {
+        let mut candidates = priority_queue::PriorityQueue::new();
+        for (t, &(tree, _)) in trees.iter().enumerate() {
+            let d = tree.root.distance_to_instance(tree.data(), query);
+            candidates.push((t, &tree.root), RevNumber(d_min(&tree.root, d)));
+        }
+
+        Self {
+            trees,
+            query,
+            candidates,
+            hits: priority_queue::PriorityQueue::new(),
+        }
+    }
+
+    /// Expands the closest candidate `Cluster`, either into its children or,
+    /// for a leaf, into hits.
+    fn expand(&mut self) {
+        let ((t, c), RevNumber(d)) = self
+            .candidates
+            .pop()
+            .unwrap_or_else(|| unreachable!("`candidates` is non-empty"));
+        let (tree, offset) = self.trees[t];
+
+        if let Some(children) = c.children() {
+            for child in children {
+                let d = child.distance_to_instance(tree.data(), self.query);
+                self.candidates.push((t, child), RevNumber(d_min(child, d)));
+            }
+        } else {
+            let indices = c.indices().collect::<Vec<_>>();
+            let distances = if c.is_singleton() {
+                vec![d; indices.len()]
+            } else {
+                tree.data().query_to_many(self.query, &indices)
+            };
+            for (i, d) in indices.into_iter().zip(distances) {
+                self.hits.push(offset + i, RevNumber(d));
+            }
+        }
+    }
+}
+
+impl<I: Instance, U: Number, D: Dataset<I, U>> Iterator for NearestIter<'_, I, U, D> {
+    type Item = (usize, U);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let closest_hit = self.hits.peek().map(|(_, &RevNumber(d))| d);
+            let closest_candidate = self.candidates.peek().map(|(_, &RevNumber(d))| d);
+
+            match (closest_hit, closest_candidate) {
+                (None, None) => return None,
+                (Some(h), Some(c)) if h > c => self.expand(),
+                (Some(_), _) => return self.hits.pop().map(|(i, RevNumber(d))| (i, d)),
+                (None, Some(_)) => self.expand(),
+            }
+        }
+    }
+}
+
 /// Calculates the theoretical best case distance for a point in a cluster, i.e.,
 /// the closest a point in a given cluster could possibly be to the query.
 fn d_min<U: Number>(c: &Cluster<U>, d: U) -> U {
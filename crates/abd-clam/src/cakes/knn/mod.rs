@@ -8,7 +8,11 @@
 //! to this enum as they are being implemented. They should not be considered
 //! stable until they are documented as such.
 
-use core::{cmp::Ordering, hash::Hash};
+use core::{
+    cmp::Ordering,
+    hash::Hash,
+    sync::atomic::{self, AtomicU64},
+};
 
 use distances::Number;
 use priority_queue::PriorityQueue;
@@ -270,6 +274,39 @@ impl<I: Hash + Eq + Copy, U: Number> Hits<I, U> {
     }
 }
 
+/// An upper bound on the distance to the `k`-th nearest neighbor of a query,
+/// shared by searches that run concurrently over different shards.
+///
+/// Each shard's `k`-th nearest neighbor is at least as far as the overall
+/// `k`-th nearest neighbor, so any shard may tighten the bound, and every shard
+/// may prune `Cluster`s that lie beyond it. Distances are non-negative, so the
+/// bits of their `f64` representations are ordered like the distances
+/// themselves and the bound can be tightened with a single atomic `fetch_min`.
+pub(crate) struct SharedBound(AtomicU64);
+
+impl SharedBound {
+    /// Creates a new bound, which starts out at infinity.
+    pub fn new() -> Self {
+        Self(AtomicU64::new(f64::INFINITY.to_bits()))
+    }
+
+    /// Returns the current value of the bound.
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(atomic::Ordering::Relaxed))
+    }
+
+    /// Lowers the bound to `d` if `d` is smaller than the current value.
+    pub fn tighten<U: Number>(&self, d: U) {
+        self.0.fetch_min(d.as_f64().to_bits(), atomic::Ordering::Relaxed);
+    }
+
+    /// Whether an instance at distance `d` from the query cannot be among the
+    /// `k` nearest neighbors.
+    pub fn excludes<U: Number>(&self, d: U) -> bool {
+        d.as_f64() > self.get()
+    }
+}
+
 /// Field by which we rank elements in priority queue of hits.
 #[derive(Debug)]
 pub struct OrdNumber<U: Number>(U);
@@ -0,0 +1,165 @@
+//! Choosing between a single shard and sharding from a memory budget.
+
+use std::path::Path;
+
+use distances::Number;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+
+use crate::{Cluster, Dataset, Instance, PartitionCriteria, Tree, VecDataset};
+
+/// The number of instances sampled to estimate the memory cost.
+const SAMPLE_SIZE: usize = 1024;
+
+/// The name of the file in which the layout is saved.
+const FILE_NAME: &str = "auto-layout.json";
+
+/// The layout chosen for a `Cakes` from a memory budget, along with the
+/// estimates the choice was based on.
+///
+/// The memory cost of an instance is estimated from a random sample of the
+/// dataset. It counts the instance and its metadata, its entry in the
+/// permutation of the dataset, and its share of the `Cluster`s in a tree built
+/// on the sample with the same partition criteria.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AutoLayout {
+    /// The memory budget, in bytes, for the tree and dataset of any one shard.
+    pub memory_budget_bytes: usize,
+    /// The cardinality of the full dataset.
+    pub cardinality: usize,
+    /// The number of instances sampled for the estimates.
+    pub sample_size: usize,
+    /// The estimated memory, in bytes, of an instance with its metadata.
+    pub instance_bytes: usize,
+    /// The memory, in bytes, of one `Cluster`.
+    pub cluster_bytes: usize,
+    /// The number of `Cluster`s per instance in the tree of the sample.
+    pub clusters_per_instance: f64,
+    /// The estimated memory, in bytes, of an instance together with its share
+    /// of the tree.
+    pub bytes_per_instance: usize,
+    /// The estimated memory, in bytes, of the tree and dataset as one shard.
+    pub estimated_total_bytes: usize,
+    /// The maximum cardinality of each shard, or `None` if the dataset fits in
+    /// a single shard.
+    pub max_shard_cardinality: Option<usize>,
+}
+
+impl AutoLayout {
+    /// Estimates the memory cost of the dataset and chooses the layout.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The dataset to search.
+    /// * `memory_budget_bytes` - The memory budget for any one shard.
+    /// * `seed` - The seed to use for the random number generator.
+    /// * `criteria` - The criteria to use for partitioning the tree.
+    pub(crate) fn estimate<I: Instance, U: Number, M: Instance>(
+        data: &VecDataset<I, U, M>,
+        memory_budget_bytes: usize,
+        seed: Option<u64>,
+        criteria: &PartitionCriteria<U>,
+    ) -> Self {
+        let cardinality = data.cardinality();
+        let sample_size = cardinality.min(SAMPLE_SIZE);
+        let sample = seed
+            .map_or_else(
+                || rand::seq::index::sample(&mut rand::thread_rng(), cardinality, sample_size),
+                |seed| rand::seq::index::sample(&mut rand::rngs::StdRng::seed_from_u64(seed), cardinality, sample_size),
+            )
+            .into_vec();
+
+        // An empty dataset has an empty sample, and nothing to divide among.
+        let per_instance = |total: usize| {
+            let n = sample_size.max(1);
+            total / n + <usize as From<bool>>::from(total % n != 0)
+        };
+
+        let instance_bytes = per_instance(
+            sample
+                .iter()
+                .map(|&i| {
+                    core::mem::size_of::<I>()
+                        + data[i].to_bytes().len()
+                        + core::mem::size_of::<M>()
+                        + data.metadata_of(i).to_bytes().len()
+                        + core::mem::size_of::<usize>()
+                })
+                .sum(),
+        );
+
+        let cluster_bytes = core::mem::size_of::<Cluster<U>>();
+        let num_clusters = if sample_size == 0 {
+            0
+        } else {
+            let sample = sample.iter().map(|&i| data[i].clone()).collect();
+            let sample = VecDataset::new("sample".to_string(), sample, data.metric(), data.is_metric_expensive());
+            Tree::new(sample, seed).partition(criteria).root().subtree().len()
+        };
+        let clusters_per_instance = num_clusters.as_f64() / sample_size.max(1).as_f64();
+
+        let bytes_per_instance = instance_bytes + per_instance(num_clusters * cluster_bytes);
+        let estimated_total_bytes = bytes_per_instance * cardinality;
+
+        let max_shard_cardinality = if estimated_total_bytes <= memory_budget_bytes {
+            None
+        } else {
+            Some((memory_budget_bytes / bytes_per_instance.max(1)).max(1))
+        };
+
+        Self {
+            memory_budget_bytes,
+            cardinality,
+            sample_size,
+            instance_bytes,
+            cluster_bytes,
+            clusters_per_instance,
+            bytes_per_instance,
+            estimated_total_bytes,
+            max_shard_cardinality,
+        }
+    }
+
+    /// Whether the dataset is split into several shards.
+    #[must_use]
+    pub const fn is_sharded(&self) -> bool {
+        self.max_shard_cardinality.is_some()
+    }
+
+    /// Saves the layout as JSON in the given directory.
+    ///
+    /// # Errors
+    ///
+    /// * If the file cannot be written.
+    pub(crate) fn save(&self, dir: &Path) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(dir.join(FILE_NAME), contents).map_err(|e| e.to_string())
+    }
+
+    /// Loads the layout from the given directory, if it was saved there.
+    ///
+    /// # Errors
+    ///
+    /// * If the file exists but cannot be read or parsed.
+    pub(crate) fn load(dir: &Path) -> Result<Option<Self>, String> {
+        let path = dir.join(FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&contents).map(Some).map_err(|e| e.to_string())
+    }
+
+    /// Removes a previously saved layout from the given directory.
+    ///
+    /// # Errors
+    ///
+    /// * If the file exists but cannot be removed.
+    pub(crate) fn remove(dir: &Path) -> Result<(), String> {
+        let path = dir.join(FILE_NAME);
+        if path.exists() {
+            std::fs::remove_file(path).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
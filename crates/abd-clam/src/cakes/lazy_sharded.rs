@@ -0,0 +1,568 @@
+//! CAKES search with randomly sharded datasets that are loaded from disk on demand.
+
+use core::{cell::Cell, ops::Deref};
+
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+};
+
+use distances::Number;
+
+use super::{
+    sharded::{load_issued_ids, CARDINALITIES_FILE},
+    Search, SingleShard,
+};
+use crate::{knn, rnn, CacheStats, Dataset, Instance};
+
+thread_local! {
+    /// The number of shards that the current thread is using or loading.
+    static BUSY: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Cakes search with randomly sharded datasets whose shards stay on disk.
+///
+/// This reads the directory layout written by saving a randomly sharded
+/// `Cakes`. Only the sample shard is kept in memory. The other shards are
+/// loaded when a search needs them and are kept in a least-recently-used
+/// cache of bounded size, so that an index larger than the available memory
+/// can still be searched.
+///
+/// At most `max_resident` shards, besides the sample shard, are in memory at
+/// once, including the shards that searches are using and those being loaded.
+/// A search waits while every slot is in use by other searches, and a shard
+/// that several searches need at once is loaded only once. The one exception
+/// is a thread that already holds a shard, e.g. when rayon runs a second
+/// search on a thread whose first search is waiting for its own parallel work.
+/// Such a thread loads a shard without caching it instead of waiting, so that
+/// searches cannot deadlock.
+///
+/// Indices in search results are the same as for the fully loaded `Cakes`.
+///
+/// # Type parameters
+///
+/// - `I`: The type of the dataset elements.
+/// - `U`: The type of the distance values.
+/// - `D`: The type of the dataset.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct LazyRandomlySharded<I: Instance, U: Number, D: Dataset<I, U>> {
+    /// The random sample of the full dataset, which is always resident.
+    sample_shard: SingleShard<I, U, D>,
+    /// The directories of the other shards.
+    shard_dirs: Vec<PathBuf>,
+    /// The cardinalities of the other shards.
+    cardinalities: Vec<usize>,
+    /// The offsets of the indices of the other shards.
+    offsets: Vec<usize>,
+    /// The metric for the shards.
+    metric: fn(&I, &I) -> U,
+    /// Whether the metric is expensive to compute.
+    is_expensive: bool,
+    /// The maximum number of shards, besides the sample shard, kept in memory.
+    max_resident: usize,
+    /// The resident shards and the shards being loaded.
+    cache: Mutex<ShardCache<LoadedShard<I, U, D>>>,
+    /// Signalled when a shard is loaded or a search stops using a shard.
+    released: Condvar,
+}
+
+/// A shard loaded from disk, along with the indices issued for its instances
+/// before it was merged with other shards, if it was.
+#[derive(Debug)]
+struct LoadedShard<I: Instance, U: Number, D: Dataset<I, U>> {
+    /// The shard.
+    shard: SingleShard<I, U, D>,
+    /// The issued index, relative to the offset of the shard, of the instance
+    /// at each index in the shard.
+    issued: Option<Vec<usize>>,
+}
+
+/// A shard in memory, along with the number of searches using it.
+#[derive(Debug)]
+struct Resident<S> {
+    /// The position of the shard among the non-sample shards.
+    position: usize,
+    /// The shard.
+    shard: Arc<S>,
+    /// The number of searches using the shard.
+    users: usize,
+}
+
+/// The resident shards, the shards being loaded, and statistics on the cache.
+#[derive(Debug)]
+struct ShardCache<S> {
+    /// The resident shards, from most to least recently used.
+    resident: VecDeque<Resident<S>>,
+    /// The positions of the shards being loaded.
+    loading: Vec<usize>,
+    /// The number of times a shard was already resident.
+    hits: usize,
+    /// The number of times a shard had to be loaded.
+    misses: usize,
+    /// The number of shards that were evicted.
+    evictions: usize,
+}
+
+impl<S> ShardCache<S> {
+    /// Creates an empty cache.
+    fn new(max_resident: usize) -> Self {
+        Self {
+            resident: VecDeque::with_capacity(max_resident),
+            loading: Vec::new(),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Marks the resident shard at the given position as used by one more
+    /// search and as the most recently used, and returns it.
+    fn acquire(&mut self, position: usize) -> Option<Arc<S>> {
+        let pos = self.resident.iter().position(|r| r.position == position)?;
+        let mut entry = self
+            .resident
+            .remove(pos)
+            .unwrap_or_else(|| unreachable!("We just found this entry."));
+        entry.users += 1;
+        let shard = Arc::clone(&entry.shard);
+        self.resident.push_front(entry);
+        self.hits += 1;
+        Some(shard)
+    }
+
+    /// Marks the resident shard at the given position as used by one fewer
+    /// search.
+    fn release(&mut self, position: usize) {
+        if let Some(entry) = self.resident.iter_mut().find(|r| r.position == position) {
+            entry.users -= 1;
+        }
+    }
+
+    /// Evicts the least recently used shards that no search is using until
+    /// there is room to load one more shard. Returns whether there is room.
+    fn make_room(&mut self, max_resident: usize) -> bool {
+        while self.resident.len() + self.loading.len() >= max_resident {
+            let Some(idle) = self.resident.iter().rposition(|r| r.users == 0) else {
+                return false;
+            };
+            self.resident.remove(idle);
+            self.evictions += 1;
+        }
+        true
+    }
+}
+
+/// A shard in use by a search. A resident shard is not evicted while it is
+/// in use.
+struct ShardGuard<'a, I: Instance, U: Number, D: Dataset<I, U>> {
+    /// The sharded index that owns the shard.
+    owner: &'a LazyRandomlySharded<I, U, D>,
+    /// The position of the shard among the non-sample shards.
+    position: usize,
+    /// The shard.
+    shard: Arc<LoadedShard<I, U, D>>,
+    /// Whether the shard is in the cache.
+    cached: bool,
+}
+
+impl<I: Instance, U: Number, D: Dataset<I, U>> Deref for ShardGuard<'_, I, U, D> {
+    type Target = SingleShard<I, U, D>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.shard.shard
+    }
+}
+
+impl<I: Instance, U: Number, D: Dataset<I, U>> ShardGuard<'_, I, U, D> {
+    /// Returns the index returned by search for the instance at the given
+    /// index in the shard.
+    fn global_index(&self, index: usize) -> usize {
+        self.owner.offsets[self.position] + self.shard.issued.as_ref().map_or(index, |ids| ids[index])
+    }
+}
+
+impl<I: Instance, U: Number, D: Dataset<I, U>> Drop for ShardGuard<'_, I, U, D> {
+    fn drop(&mut self) {
+        BUSY.with(|b| b.set(b.get() - 1));
+        if self.cached {
+            self.owner.lock_cache().release(self.position);
+            self.owner.released.notify_all();
+        }
+    }
+}
+
+impl<I: Instance, U: Number, D: Dataset<I, U>> LazyRandomlySharded<I, U, D> {
+    /// Opens a saved randomly sharded `Cakes` without loading its shards.
+    ///
+    /// The cardinalities of the shards are read from the file written along
+    /// with the shards. For an index saved without that file, each shard is
+    /// read once, one at a time, to find its cardinality.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The directory to which the sharded `Cakes` was saved.
+    /// * `metric` - The metric to use for the search.
+    /// * `is_expensive` - Whether the metric is expensive to compute.
+    /// * `max_resident` - The maximum number of shards, besides the sample
+    ///   shard, to keep in memory at once.
+    ///
+    /// # Errors
+    ///
+    /// * If the `path` does not exist.
+    /// * If the `path` is not a valid directory.
+    /// * If the `path` does not contain a valid sharded Cakes structure.
+    /// * If `max_resident` is zero.
+    #[allow(clippy::similar_names)]
+    pub fn load(path: &Path, metric: fn(&I, &I) -> U, is_expensive: bool, max_resident: usize) -> Result<Self, String> {
+        if !path.exists() {
+            return Err(format!("Path does not exist: {}", path.display()));
+        }
+
+        if !path.is_dir() {
+            return Err(format!("Path is not a directory: {}", path.display()));
+        }
+
+        if max_resident == 0 {
+            return Err("At least one shard must be allowed to be resident.".to_string());
+        }
+
+        let sample_shard = SingleShard::<I, U, D>::load(&path.join("sample_shard"), metric, is_expensive)?;
+
+        let shards_dir = path.join("shards");
+        let mut shard_dirs = Vec::new();
+        for i in 0.. {
+            let shard_dir = shards_dir.join(format!("shard_{i}"));
+            if !shard_dir.exists() {
+                break;
+            }
+            shard_dirs.push(shard_dir);
+        }
+
+        let cardinalities_file = path.join(CARDINALITIES_FILE);
+        let cardinalities = if cardinalities_file.exists() {
+            let contents = std::fs::read_to_string(cardinalities_file).map_err(|e| e.to_string())?;
+            serde_json::from_str::<Vec<usize>>(&contents).map_err(|e| e.to_string())?
+        } else {
+            shard_dirs
+                .iter()
+                .map(|d| SingleShard::<I, U, D>::load(d, metric, is_expensive).map(|s| s.data().cardinality()))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        if cardinalities.len() != shard_dirs.len() {
+            return Err(format!(
+                "Found {} shards but {} shard cardinalities.",
+                shard_dirs.len(),
+                cardinalities.len()
+            ));
+        }
+
+        let offsets = cardinalities
+            .iter()
+            .scan(sample_shard.data().cardinality(), |o, &c| {
+                let offset = *o;
+                *o += c;
+                Some(offset)
+            })
+            .collect();
+
+        Ok(Self {
+            sample_shard,
+            shard_dirs,
+            cardinalities,
+            offsets,
+            metric,
+            is_expensive,
+            max_resident,
+            cache: Mutex::new(ShardCache::new(max_resident)),
+            released: Condvar::new(),
+        })
+    }
+
+    /// Returns the number of shards, including the sample shard.
+    pub fn num_shards(&self) -> usize {
+        1 + self.shard_dirs.len()
+    }
+
+    /// Returns the cardinalities of the shards, including the sample shard.
+    pub fn shard_cardinalities(&self) -> Vec<usize> {
+        core::iter::once(self.sample_shard.data().cardinality())
+            .chain(self.cardinalities.iter().copied())
+            .collect()
+    }
+
+    /// Returns the total cardinality of the dataset.
+    pub fn total_cardinality(&self) -> usize {
+        self.shard_cardinalities().iter().sum()
+    }
+
+    /// Returns the number of shards, besides the sample shard, that are
+    /// currently in memory.
+    pub fn num_resident(&self) -> usize {
+        self.lock_cache().resident.len()
+    }
+
+    /// Returns statistics on the cache of shards.
+    ///
+    /// A hit is a search finding a shard already in memory, and a miss is a
+    /// search loading a shard from disk.
+    pub fn cache_stats(&self) -> CacheStats {
+        let cache = self.lock_cache();
+        CacheStats {
+            hits: cache.hits,
+            misses: cache.misses,
+            evictions: cache.evictions,
+            len: cache.resident.len(),
+        }
+    }
+
+    /// Locks the cache, ignoring poisoning since the cache is always left
+    /// consistent.
+    fn lock_cache(&self) -> MutexGuard<'_, ShardCache<LoadedShard<I, U, D>>> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the positions of the non-sample shards in the order in which a
+    /// search visits them.
+    ///
+    /// The resident shards come first, from most to least recently used, so
+    /// that a search uses them before loading the other shards evicts them.
+    /// Visiting every shard in a fixed order would instead evict each shard
+    /// just before the next search needs it whenever there are more shards
+    /// than `max_resident`.
+    fn visit_order(&self) -> Vec<usize> {
+        let mut order = self
+            .lock_cache()
+            .resident
+            .iter()
+            .map(|r| r.position)
+            .collect::<Vec<_>>();
+        let mut is_resident = vec![false; self.shard_dirs.len()];
+        for &i in &order {
+            is_resident[i] = true;
+        }
+        order.extend((0..self.shard_dirs.len()).filter(|&i| !is_resident[i]));
+        order
+    }
+
+    /// Returns the shard at the given position among the non-sample shards,
+    /// loading it from disk if it is not resident.
+    ///
+    /// Loading happens without holding the lock on the cache, so searches that
+    /// only need resident shards are not blocked. A search that needs a shard
+    /// that another search is loading waits for that load to finish.
+    ///
+    /// # Errors
+    ///
+    /// * If the shard cannot be loaded from disk.
+    fn shard(&self, i: usize) -> Result<ShardGuard<'_, I, U, D>, String> {
+        let nested = BUSY.with(Cell::get) > 0;
+        BUSY.with(|b| b.set(b.get() + 1));
+
+        let mut cache = self.lock_cache();
+        let cached = loop {
+            if let Some(shard) = cache.acquire(i) {
+                drop(cache);
+                return Ok(ShardGuard {
+                    owner: self,
+                    position: i,
+                    shard,
+                    cached: true,
+                });
+            }
+            if !cache.loading.contains(&i) && cache.make_room(self.max_resident) {
+                cache.loading.push(i);
+                break true;
+            }
+            if nested {
+                break false;
+            }
+            cache = self.released.wait(cache).unwrap_or_else(PoisonError::into_inner);
+        };
+        cache.misses += 1;
+        drop(cache);
+
+        let shard = self.load_shard(i).map(Arc::new);
+
+        if cached {
+            let mut cache = self.lock_cache();
+            cache.loading.retain(|&j| j != i);
+            if let Ok(shard) = &shard {
+                cache.resident.push_front(Resident {
+                    position: i,
+                    shard: Arc::clone(shard),
+                    users: 1,
+                });
+            }
+            drop(cache);
+            self.released.notify_all();
+        }
+
+        match shard {
+            Ok(shard) => Ok(ShardGuard {
+                owner: self,
+                position: i,
+                shard,
+                cached,
+            }),
+            Err(e) => {
+                BUSY.with(|b| b.set(b.get() - 1));
+                Err(e)
+            }
+        }
+    }
+
+    /// Loads the shard at the given position among the non-sample shards,
+    /// along with the indices issued for its instances before it was merged.
+    ///
+    /// # Errors
+    ///
+    /// * If the shard cannot be loaded from disk.
+    fn load_shard(&self, i: usize) -> Result<LoadedShard<I, U, D>, String> {
+        let shard_dir = &self.shard_dirs[i];
+        Ok(LoadedShard {
+            shard: SingleShard::load(shard_dir, self.metric, self.is_expensive)?,
+            issued: load_issued_ids(shard_dir)?,
+        })
+    }
+
+    /// Returns the tuned RNN algorithm.
+    pub fn tuned_rnn_algorithm(&self) -> rnn::Algorithm {
+        self.sample_shard.tuned_rnn_algorithm()
+    }
+
+    /// Returns the tuned KNN algorithm.
+    pub fn tuned_knn_algorithm(&self) -> knn::Algorithm {
+        self.sample_shard.tuned_knn_algorithm()
+    }
+
+    /// Automatically finds the best RNN algorithm to use, using the sample shard.
+    ///
+    /// # Arguments
+    ///
+    /// * `radius` - The search radius.
+    /// * `tuning_depth` - The number of instances to use for tuning.
+    pub fn auto_tune_rnn(&mut self, radius: U, tuning_depth: usize) {
+        self.sample_shard.auto_tune_rnn(radius, tuning_depth);
+    }
+
+    /// Automatically finds the best KNN algorithm to use, using the sample shard.
+    ///
+    /// # Arguments
+    ///
+    /// * `k` - The number of nearest neighbors to return.
+    /// * `tuning_depth` - The number of instances to use for tuning.
+    pub fn auto_tune_knn(&mut self, k: usize, tuning_depth: usize) {
+        self.sample_shard.auto_tune_knn(k, tuning_depth);
+    }
+
+    /// Performs an RNN search with the given algorithm.
+    ///
+    /// The shards are visited one at a time, starting with the resident ones,
+    /// so that no more than `max_resident` of them need to be in memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radius` - The search radius.
+    /// * `algo` - The algorithm to use.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the index of the instance and the distance
+    /// to the query.
+    ///
+    /// # Errors
+    ///
+    /// * If a shard cannot be loaded from disk.
+    pub fn rnn_search(&self, query: &I, radius: U, algo: rnn::Algorithm) -> Result<Vec<(usize, U)>, String> {
+        let mut hits = self.sample_shard.rnn_search(query, radius, algo);
+        for i in self.visit_order() {
+            let shard = self.shard(i)?;
+            hits.extend(
+                shard
+                    .rnn_search(query, radius, algo)
+                    .into_iter()
+                    .map(|(j, d)| (shard.global_index(j), d)),
+            );
+        }
+        Ok(hits)
+    }
+
+    /// Performs an RNN search with the tuned algorithm.
+    ///
+    /// # Errors
+    ///
+    /// * If a shard cannot be loaded from disk.
+    pub fn tuned_rnn_search(&self, query: &I, radius: U) -> Result<Vec<(usize, U)>, String> {
+        self.rnn_search(query, radius, self.tuned_rnn_algorithm())
+    }
+
+    /// Performs a linear RNN search.
+    ///
+    /// # Errors
+    ///
+    /// * If a shard cannot be loaded from disk.
+    pub fn linear_rnn_search(&self, query: &I, radius: U) -> Result<Vec<(usize, U)>, String> {
+        self.rnn_search(query, radius, rnn::Algorithm::Linear)
+    }
+
+    /// Performs a KNN search with the given algorithm.
+    ///
+    /// The sample shard is searched for the `k` nearest neighbors, and every
+    /// other shard is searched with RNN search, using the distance to the
+    /// current `k`-th nearest neighbor as the radius.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `k` - The number of nearest neighbors to return.
+    /// * `algo` - The algorithm to use.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the index of the instance and the distance
+    /// to the query.
+    ///
+    /// # Errors
+    ///
+    /// * If a shard cannot be loaded from disk.
+    pub fn knn_search(&self, query: &I, k: usize, algo: knn::Algorithm) -> Result<Vec<(usize, U)>, String> {
+        let mut hits = knn::Hits::from_vec(k, self.sample_shard.knn_search(query, k, algo));
+        for i in self.visit_order() {
+            let shard = self.shard(i)?;
+            let new_hits = if hits.len() < k {
+                shard.knn_search(query, k, algo)
+            } else {
+                shard.rnn_search(query, hits.peek(), rnn::Algorithm::Clustered)
+            };
+            hits.push_batch(new_hits.into_iter().map(|(j, d)| (shard.global_index(j), d)));
+        }
+        Ok(hits.extract())
+    }
+
+    /// Performs a KNN search with the tuned algorithm.
+    ///
+    /// # Errors
+    ///
+    /// * If a shard cannot be loaded from disk.
+    pub fn tuned_knn_search(&self, query: &I, k: usize) -> Result<Vec<(usize, U)>, String> {
+        self.knn_search(query, k, self.tuned_knn_algorithm())
+    }
+
+    /// Performs a linear KNN search.
+    ///
+    /// # Errors
+    ///
+    /// * If a shard cannot be loaded from disk.
+    pub fn linear_knn_search(&self, query: &I, k: usize) -> Result<Vec<(usize, U)>, String> {
+        let mut hits = knn::Hits::from_vec(k, self.sample_shard.linear_knn_search(query, k));
+        for i in self.visit_order() {
+            let shard = self.shard(i)?;
+            let new_hits = shard.linear_knn_search(query, k);
+            hits.push_batch(new_hits.into_iter().map(|(j, d)| (shard.global_index(j), d)));
+        }
+        Ok(hits.extract())
+    }
+}
@@ -1,22 +1,35 @@
 //! CLAM-Accelerated K-nearest-neighbor Entropy-scaling Search.
 
-use core::ops::Index;
+use core::{cmp::Ordering, ops::Index};
 
 use std::path::Path;
 
+mod batch;
+mod cluster_sharded;
+mod filter;
+mod handle;
 pub mod knn;
+mod layout;
+mod lazy_sharded;
 pub mod rnn;
 mod search;
 mod sharded;
 mod singular;
+mod tuning;
 
+use cluster_sharded::ClusterSharded;
 use distances::Number;
+pub use filter::FilterSummary;
+pub use handle::CakesHandle;
+pub use layout::AutoLayout;
+pub use lazy_sharded::LazyRandomlySharded;
 use rayon::prelude::*;
 use search::Search;
 use sharded::RandomlySharded;
 use singular::SingleShard;
+pub use tuning::{AlgorithmReport, Timings, TuningReport};
 
-use crate::{Dataset, Instance, PartitionCriteria, Tree};
+use crate::{Cluster, Dataset, Instance, PartitionCriteria, Tree, VecDataset};
 
 /// CAKES search.
 pub enum Cakes<I: Instance, U: Number, D: Dataset<I, U>> {
@@ -24,6 +37,9 @@ pub enum Cakes<I: Instance, U: Number, D: Dataset<I, U>> {
     SingleShard(SingleShard<I, U, D>),
     /// Search with multiple shards.
     RandomlySharded(RandomlySharded<I, U, D>),
+    /// Search with shards made from top-level clusters, routing each query to
+    /// only the shards that can contain hits.
+    ClusterSharded(ClusterSharded<I, U, D>),
 }
 
 impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
@@ -52,6 +68,7 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         match self {
             Self::SingleShard(ss) => ss.save(path),
             Self::RandomlySharded(rs) => rs.save(path),
+            Self::ClusterSharded(cs) => cs.save(path),
         }
     }
 
@@ -81,9 +98,12 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
             return Err(format!("Path '{}' is not a directory.", path.display()));
         }
 
-        // Check if there is a subdirectory for `sample_shard`.
+        // Check if there is a subdirectory for `sample_shard` or `cluster_shards`.
         let sample_shard_path = path.join("sample_shard");
-        if sample_shard_path.exists() {
+        if path.join("cluster_shards").exists() {
+            let cs = ClusterSharded::load(path, metric, is_expensive)?;
+            Ok(Self::ClusterSharded(cs))
+        } else if sample_shard_path.exists() {
             let rs = RandomlySharded::load(path, metric, is_expensive)?;
             Ok(Self::RandomlySharded(rs))
         } else {
@@ -97,6 +117,7 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         match self {
             Self::SingleShard(ss) => vec![ss.tree()],
             Self::RandomlySharded(rs) => rs.shards().into_iter().map(SingleShard::tree).collect(),
+            Self::ClusterSharded(cs) => cs.shards().into_iter().map(SingleShard::tree).collect(),
         }
     }
 
@@ -105,6 +126,7 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         match self {
             Self::SingleShard(ss) => vec![ss.data()],
             Self::RandomlySharded(rs) => rs.shards().into_iter().map(SingleShard::data).collect(),
+            Self::ClusterSharded(cs) => cs.shards().into_iter().map(SingleShard::data).collect(),
         }
     }
 
@@ -124,11 +146,103 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         Self::RandomlySharded(RandomlySharded::new(shards))
     }
 
+    /// Adds a new shard to a randomly sharded CAKES instance.
+    ///
+    /// The tree for the new shard is built from `data`. The indices, and the
+    /// original indices, of the instances already in the dataset do not change,
+    /// and the new instances get the indices that follow them, in the order
+    /// of `data`.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The new data.
+    /// * `seed` - The seed to use for the random number generator.
+    /// * `criteria` - The criteria to use for partitioning the tree.
+    /// * `save_dir` - The directory to which this `Cakes` was saved, if the
+    ///   new shard should also be saved there.
+    ///
+    /// # Returns
+    ///
+    /// The offset of the indices of the new shard.
+    ///
+    /// # Errors
+    ///
+    /// * If this `Cakes` is not randomly sharded.
+    /// * If `save_dir` does not contain a saved randomly sharded `Cakes`.
+    /// * If the new shard cannot be saved.
+    pub fn append_shard(
+        &mut self,
+        data: D,
+        seed: Option<u64>,
+        criteria: &PartitionCriteria<U>,
+        save_dir: Option<&Path>,
+    ) -> Result<usize, String> {
+        match self {
+            Self::RandomlySharded(rs) => rs.append_shard(SingleShard::new(data, seed, criteria), save_dir),
+            _ => Err("Shards can only be appended to a randomly sharded Cakes.".to_string()),
+        }
+    }
+
+    /// Returns the shards along with the offsets of their indices.
+    fn shards_with_offsets(&self) -> Vec<(&SingleShard<I, U, D>, usize)> {
+        match self {
+            Self::SingleShard(ss) => vec![(ss, 0)],
+            Self::RandomlySharded(rs) => rs.shards().into_iter().zip(rs.shard_offsets()).collect(),
+            Self::ClusterSharded(cs) => cs.shards().into_iter().zip(cs.shard_offsets()).collect(),
+        }
+    }
+
+    /// Whether the data of every shard has a metric with which to compare
+    /// instances that are not in the data, e.g. queries.
+    fn has_metric(&self) -> bool {
+        self.trees_with_offsets()
+            .iter()
+            .all(|(tree, _)| tree.data().has_metric())
+    }
+
+    /// Returns the trees of all shards along with the offsets of their indices.
+    fn trees_with_offsets(&self) -> Vec<(&Tree<I, U, D>, usize)> {
+        self.shards_with_offsets()
+            .into_iter()
+            .map(|(shard, offset)| (shard.tree(), offset))
+            .collect()
+    }
+
+    /// Returns the shards that a query is sent to for RNN search.
+    ///
+    /// Only cluster-sharded datasets skip any shards; otherwise every shard is
+    /// visited.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radius` - The search radius.
+    pub fn routed_shards(&self, query: &I, radius: U) -> Vec<usize> {
+        match self {
+            Self::ClusterSharded(cs) => cs.route(query, radius),
+            _ => (0..self.num_shards()).collect(),
+        }
+    }
+
+    /// Returns the layout chosen from a memory budget, if the index was built
+    /// with `new_auto`.
+    ///
+    /// `new_auto` only chooses between a single shard and random sharding, so
+    /// this is always `None` for a cluster-sharded `Cakes`.
+    pub fn auto_layout(&self) -> Option<&AutoLayout> {
+        match self {
+            Self::SingleShard(ss) => ss.auto_layout(),
+            Self::RandomlySharded(rs) => rs.shards()[0].auto_layout(),
+            Self::ClusterSharded(_) => None,
+        }
+    }
+
     /// Returns the number of shards in the dataset.
     pub fn num_shards(&self) -> usize {
         match self {
             Self::SingleShard(_) => 1,
             Self::RandomlySharded(rs) => rs.num_shards(),
+            Self::ClusterSharded(cs) => cs.num_shards(),
         }
     }
 
@@ -137,6 +251,7 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         match self {
             Self::SingleShard(ss) => ss.shard_cardinalities(),
             Self::RandomlySharded(rs) => rs.shard_cardinalities(),
+            Self::ClusterSharded(cs) => cs.shard_cardinalities(),
         }
     }
 
@@ -145,11 +260,89 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         self.shard_cardinalities().iter().sum()
     }
 
+    /// Returns the instance at the given index, or `None` if the index is out
+    /// of bounds.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the instance, as returned by search.
+    pub fn get(&self, index: usize) -> Option<&I> {
+        match self {
+            Self::SingleShard(ss) => (index < ss.data().cardinality()).then(|| &ss.data()[index]),
+            Self::RandomlySharded(rs) => rs.locate(index).ok().map(|(s, i)| &rs.shards()[s].data()[i]),
+            Self::ClusterSharded(cs) => cs.locate(index).ok().map(|(s, i)| &cs.shards()[s].data()[i]),
+        }
+    }
+
+    /// Returns the index of an instance before the dataset was reordered.
+    ///
+    /// Search results use indices into the reordered dataset. For sharded
+    /// datasets, the original index is the index into the concatenation of the
+    /// shards, each in their order before building the trees.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the instance, as returned by search.
+    ///
+    /// # Errors
+    ///
+    /// * If `index` is not a valid index in the dataset.
+    pub fn original_index(&self, index: usize) -> Result<usize, String> {
+        match self {
+            Self::SingleShard(ss) => ss.original_index(index),
+            Self::RandomlySharded(rs) => rs.original_index(index),
+            Self::ClusterSharded(cs) => cs.original_index(index),
+        }
+    }
+
+    /// Returns the original index, as given by `original_index`, of the
+    /// instance at `index` in the shard at position `s`, which starts at
+    /// `offset`.
+    fn shard_original_index(&self, s: usize, shard: &SingleShard<I, U, D>, offset: usize, index: usize) -> usize {
+        match self {
+            Self::ClusterSharded(cs) => cs.shard_original_index(s, index),
+            _ => offset + shard.data().original_index(index),
+        }
+    }
+
+    /// Returns the index returned by search for the instance at `index` in the
+    /// shard at position `s`, which starts at `offset`.
+    fn global_index(&self, s: usize, offset: usize, index: usize) -> usize {
+        match self {
+            Self::RandomlySharded(rs) => rs.global_index(s, index),
+            _ => offset + index,
+        }
+    }
+
+    /// Replaces indices into the concatenation of the shards, i.e. an index
+    /// into a shard plus its offset, with the indices returned by search.
+    ///
+    /// These differ only for the shards merged by `compact_shards`.
+    fn issued_hits(&self, hits: Vec<(usize, U)>) -> Vec<(usize, U)> {
+        match self {
+            Self::RandomlySharded(rs) => hits.into_iter().map(|(i, d)| (rs.issued_index(i), d)).collect(),
+            _ => hits,
+        }
+    }
+
+    /// Replaces the indices in search results with the original indices.
+    fn hits_to_original(&self, hits: Vec<(usize, U)>) -> Vec<(usize, U)> {
+        hits.into_iter()
+            .map(|(i, d)| {
+                let i = self
+                    .original_index(i)
+                    .unwrap_or_else(|e| unreachable!("Search results have valid indices. {e}"));
+                (i, d)
+            })
+            .collect()
+    }
+
     /// Returns the tuned RNN algorithm.
     pub fn tuned_rnn_algorithm(&self) -> rnn::Algorithm {
         match self {
             Self::SingleShard(ss) => ss.tuned_rnn_algorithm(),
             Self::RandomlySharded(rs) => rs.tuned_rnn_algorithm(),
+            Self::ClusterSharded(cs) => cs.tuned_rnn_algorithm(),
         }
     }
 
@@ -169,6 +362,34 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         queries.par_iter().map(|q| self.rnn_search(q, radius, algo)).collect()
     }
 
+    /// Performs RNN search on a batch of queries, sharing the traversal of the
+    /// tree(s) among nearby queries.
+    ///
+    /// The queries are grouped by building a small tree over them. `Cluster`s
+    /// are pruned or confirmed against each group before the search is refined
+    /// for each query, so the hits are the same as for per-query search. If the
+    /// data has no metric with which to compare the queries, e.g. for a
+    /// `MatrixDataset`, each query is searched on its own instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `queries` - The queries to search.
+    /// * `radius` - The search radius.
+    ///
+    /// # Returns
+    ///
+    /// A vector of vectors of tuples containing the index of the instance and
+    /// the distance to the query.
+    pub fn batch_rnn_search_grouped(&self, queries: &[&I], radius: U) -> Vec<Vec<(usize, U)>> {
+        if !self.has_metric() {
+            return self.batch_rnn_search(queries, radius, rnn::Algorithm::default());
+        }
+        batch::rnn_search(&self.trees_with_offsets(), queries, radius)
+            .into_iter()
+            .map(|hits| self.issued_hits(hits))
+            .collect()
+    }
+
     /// Performs an RNN search with the given algorithm.
     ///
     /// # Arguments
@@ -185,9 +406,143 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         match self {
             Self::SingleShard(ss) => ss.rnn_search(query, radius, algo),
             Self::RandomlySharded(rs) => rs.rnn_search(query, radius, algo),
+            Self::ClusterSharded(cs) => cs.rnn_search(query, radius, algo),
+        }
+    }
+
+    /// Performs an RNN search with the given algorithm and returns the original
+    /// indices of the hits.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radius` - The search radius.
+    /// * `algo` - The algorithm to use.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the original index of the instance and
+    /// the distance to the query.
+    pub fn rnn_search_original(&self, query: &I, radius: U, algo: rnn::Algorithm) -> Vec<(usize, U)> {
+        self.hits_to_original(self.rnn_search(query, radius, algo))
+    }
+
+    /// Performs an RNN search around an instance that is already in the dataset,
+    /// leaving out the instance itself.
+    ///
+    /// This saves callers from having to map the index through the permutation
+    /// of the dataset, cloning the instance and dropping the self-hit.
+    ///
+    /// For sharded datasets, the original index is the index into the
+    /// concatenation of the shards, each in their order before building the trees.
+    ///
+    /// # Arguments
+    ///
+    /// * `original_index` - The index of the instance before the dataset was reordered.
+    /// * `radius` - The search radius.
+    /// * `exclude_duplicates` - Whether to also leave out instances identical to the query.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the index of the instance and the distance
+    /// to the query.
+    ///
+    /// # Errors
+    ///
+    /// * If `original_index` is not a valid index in the dataset.
+    pub fn rnn_by_index(
+        &self,
+        original_index: usize,
+        radius: U,
+        exclude_duplicates: bool,
+    ) -> Result<Vec<(usize, U)>, String> {
+        match self {
+            Self::SingleShard(ss) => ss.rnn_by_index(original_index, radius, exclude_duplicates),
+            Self::RandomlySharded(rs) => rs.rnn_by_index(original_index, radius, exclude_duplicates),
+            Self::ClusterSharded(cs) => cs.rnn_by_index(original_index, radius, exclude_duplicates),
         }
     }
 
+    /// Performs RNN search at several radii at once, sharing one traversal of
+    /// the tree(s) across all radii.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radii` - The search radii.
+    ///
+    /// # Returns
+    ///
+    /// For each radius, in the same order as `radii`, a vector of tuples
+    /// containing the index of the instance and the distance to the query,
+    /// sorted by increasing distance. The result for a smaller radius is a
+    /// prefix of the result for any larger radius.
+    pub fn rnn_search_multi(&self, query: &I, radii: &[U]) -> Vec<Vec<(usize, U)>> {
+        let shard_hits = self
+            .shards_with_offsets()
+            .into_par_iter()
+            .enumerate()
+            .map(|(s, (shard, offset))| {
+                rnn::clustered::search_multi(shard.tree(), query, radii)
+                    .into_iter()
+                    .map(|hits| {
+                        hits.into_iter()
+                            .map(|(i, d)| (self.global_index(s, offset, i), d))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        (0..radii.len())
+            .map(|r| {
+                let mut hits = shard_hits.iter().flat_map(|h| h[r].iter().copied()).collect::<Vec<_>>();
+                hits.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Greater));
+                hits
+            })
+            .collect()
+    }
+
+    /// Counts the instances within the search radius of the query, without
+    /// collecting the hits.
+    ///
+    /// Clusters that lie entirely inside the query ball are counted by their
+    /// cardinality, without computing distances to their instances.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radius` - The search radius.
+    ///
+    /// # Returns
+    ///
+    /// The number of instances within `radius` of the query.
+    pub fn rnn_count(&self, query: &I, radius: U) -> usize {
+        self.rnn_count_multi(query, &[radius])[0]
+    }
+
+    /// Counts the instances within each of several radii of the query, sharing
+    /// one traversal of the tree(s) across all radii.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radii` - The search radii.
+    ///
+    /// # Returns
+    ///
+    /// The number of instances within each radius of the query, in the same
+    /// order as `radii`.
+    pub fn rnn_count_multi(&self, query: &I, radii: &[U]) -> Vec<usize> {
+        self.trees()
+            .into_par_iter()
+            .map(|tree| rnn::clustered::count(tree, query, radii))
+            .reduce(
+                || vec![0; radii.len()],
+                |a, b| a.into_iter().zip(b).map(|(a, b)| a + b).collect(),
+            )
+    }
+
     /// Performs Linear RNN search on a batch of queries.
     ///
     /// # Arguments
@@ -218,6 +573,7 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         match self {
             Self::SingleShard(ss) => ss.linear_rnn_search(query, radius),
             Self::RandomlySharded(rs) => rs.linear_rnn_search(query, radius),
+            Self::ClusterSharded(cs) => cs.linear_rnn_search(query, radius),
         }
     }
 
@@ -226,6 +582,7 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         match self {
             Self::SingleShard(ss) => ss.tuned_knn_algorithm(),
             Self::RandomlySharded(rs) => rs.tuned_knn_algorithm(),
+            Self::ClusterSharded(cs) => cs.tuned_knn_algorithm(),
         }
     }
 
@@ -245,6 +602,34 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         queries.par_iter().map(|q| self.knn_search(q, k, algo)).collect()
     }
 
+    /// Performs KNN search on a batch of queries, sharing the traversal of the
+    /// tree(s) among nearby queries.
+    ///
+    /// The queries are grouped by building a small tree over them, and the
+    /// search for each group is bounded by the nearest neighbors of its center.
+    /// The hits are the same as for per-query search. If the data has no metric
+    /// with which to compare the queries, e.g. for a `MatrixDataset`, each
+    /// query is searched on its own instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `queries` - The queries to search.
+    /// * `k` - The number of nearest neighbors to return.
+    ///
+    /// # Returns
+    ///
+    /// A vector of vectors of tuples containing the index of the instance and
+    /// the distance to the query.
+    pub fn batch_knn_search_grouped(&self, queries: &[&I], k: usize) -> Vec<Vec<(usize, U)>> {
+        if !self.has_metric() {
+            return self.batch_knn_search(queries, k, knn::Algorithm::default());
+        }
+        batch::knn_search(&self.trees_with_offsets(), queries, k)
+            .into_iter()
+            .map(|hits| self.issued_hits(hits))
+            .collect()
+    }
+
     /// Performs a KNN search with the given algorithm.
     ///
     /// # Arguments
@@ -260,9 +645,117 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         match self {
             Self::SingleShard(ss) => ss.knn_search(query, k, algo),
             Self::RandomlySharded(rs) => rs.knn_search(query, k, algo),
+            Self::ClusterSharded(cs) => cs.knn_search(query, k, algo),
+        }
+    }
+
+    /// Performs KNN search with all shards searched in parallel.
+    ///
+    /// The shards share an upper bound on the distance to the `k`-th nearest
+    /// neighbor, which each shard tightens as it finds hits and uses to prune
+    /// its `Cluster`s. The results are exact. With a single shard, this is the
+    /// same as Greedy-Sieve search, and cluster-sharded datasets use their
+    /// routed search.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `k` - The number of nearest neighbors to return.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the index of the instance and the distance
+    /// to the query.
+    pub fn par_knn_search(&self, query: &I, k: usize) -> Vec<(usize, U)> {
+        match self {
+            Self::SingleShard(ss) => ss.knn_search(query, k, knn::Algorithm::GreedySieve),
+            Self::RandomlySharded(rs) => rs.par_knn_search(query, k),
+            Self::ClusterSharded(cs) => cs.knn_search(query, k, knn::Algorithm::GreedySieve),
+        }
+    }
+
+    /// Performs a KNN search with the given algorithm and returns the original
+    /// indices of the hits.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `k` - The number of nearest neighbors to return.
+    /// * `algo` - The algorithm to use.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the original index of the instance and
+    /// the distance to the query.
+    pub fn knn_search_original(&self, query: &I, k: usize, algo: knn::Algorithm) -> Vec<(usize, U)> {
+        self.hits_to_original(self.knn_search(query, k, algo))
+    }
+
+    /// Performs a KNN search around an instance that is already in the dataset,
+    /// leaving out the instance itself.
+    ///
+    /// This saves callers from having to map the index through the permutation
+    /// of the dataset, cloning the instance, searching for `k + 1` neighbors
+    /// and dropping the self-hit. The distances from the query to the centers
+    /// of the clusters that contain it are never computed.
+    ///
+    /// For sharded datasets, the original index is the index into the
+    /// concatenation of the shards, each in their order before building the trees.
+    ///
+    /// # Arguments
+    ///
+    /// * `original_index` - The index of the instance before the dataset was reordered.
+    /// * `k` - The number of nearest neighbors to return.
+    /// * `exclude_duplicates` - Whether to also leave out instances identical to the query.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the index of the instance and the distance to the query.
+    ///
+    /// # Errors
+    ///
+    /// * If `original_index` is not a valid index in the dataset.
+    pub fn knn_by_index(
+        &self,
+        original_index: usize,
+        k: usize,
+        exclude_duplicates: bool,
+    ) -> Result<Vec<(usize, U)>, String> {
+        match self {
+            Self::SingleShard(ss) => ss.knn_by_index(original_index, k, exclude_duplicates),
+            Self::RandomlySharded(rs) => rs.knn_by_index(original_index, k, exclude_duplicates),
+            Self::ClusterSharded(cs) => cs.knn_by_index(original_index, k, exclude_duplicates),
         }
     }
 
+    /// Returns an iterator over the nearest neighbors of the query, in
+    /// non-decreasing order of distance.
+    ///
+    /// This is useful when the number of neighbors needed is not known in
+    /// advance. Clusters are only expanded as more neighbors are pulled from
+    /// the iterator, so taking the first `k` items costs about as much as a
+    /// KNN search with the `GreedySieve` algorithm.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    ///
+    /// # Returns
+    ///
+    /// An iterator of tuples containing the index of the instance and the
+    /// distance to the query.
+    pub fn nearest_iter<'a>(&'a self, query: &'a I) -> impl Iterator<Item = (usize, U)> + 'a {
+        let trees = self
+            .shards_with_offsets()
+            .into_iter()
+            .map(|(shard, o)| (shard.tree(), o))
+            .collect();
+        knn::greedy_sieve::NearestIter::new(trees, query).map(move |(i, d)| match self {
+            Self::RandomlySharded(rs) => (rs.issued_index(i), d),
+            _ => (i, d),
+        })
+    }
+
     /// Automatically finds the best RNN algorithm to use.
     ///
     /// # Arguments
@@ -273,9 +766,95 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         match self {
             Self::SingleShard(ss) => ss.auto_tune_rnn(radius, tuning_depth),
             Self::RandomlySharded(rs) => rs.auto_tune_rnn(radius, tuning_depth),
+            Self::ClusterSharded(cs) => cs.auto_tune_rnn(radius, tuning_depth),
         }
     }
 
+    /// Returns the shard on which the choice of algorithm is tuned.
+    fn tuning_shard(&self) -> &SingleShard<I, U, D> {
+        match self {
+            Self::SingleShard(ss) => ss,
+            Self::RandomlySharded(rs) => rs.tuning_shard(),
+            Self::ClusterSharded(cs) => cs.tuning_shard(),
+        }
+    }
+
+    /// Returns the shard on which the choice of algorithm is tuned.
+    fn tuning_shard_mut(&mut self) -> &mut SingleShard<I, U, D> {
+        match self {
+            Self::SingleShard(ss) => ss,
+            Self::RandomlySharded(rs) => rs.tuning_shard_mut(),
+            Self::ClusterSharded(cs) => cs.tuning_shard_mut(),
+        }
+    }
+
+    /// Finds the best RNN algorithm to use on the given queries.
+    ///
+    /// Every algorithm, including `Linear`, is timed on each query and its
+    /// recall is measured against `Linear`. The fastest algorithm whose mean
+    /// recall is at least `min_recall` is chosen. For sharded instances, the
+    /// tuning is done on the same shard as for `auto_tune_rnn`.
+    ///
+    /// The full report is saved as JSON alongside the index, in
+    /// `rnn-tuning.json`.
+    ///
+    /// # Arguments
+    ///
+    /// * `queries` - The queries to tune on. These should resemble the queries
+    ///   expected in use.
+    /// * `radius` - The search radius.
+    /// * `min_recall` - The smallest acceptable mean recall.
+    ///
+    /// # Errors
+    ///
+    /// * If `queries` is empty.
+    pub fn auto_tune_rnn_with_queries(
+        &mut self,
+        queries: &[I],
+        radius: U,
+        min_recall: f64,
+    ) -> Result<&TuningReport, String> {
+        self.tuning_shard_mut()
+            .auto_tune_rnn_with_queries(queries, radius, min_recall)
+    }
+
+    /// Finds the best KNN algorithm to use on the given queries.
+    ///
+    /// This is the KNN counterpart of `auto_tune_rnn_with_queries`. The full
+    /// report is saved as JSON alongside the index, in `knn-tuning.json`.
+    ///
+    /// # Arguments
+    ///
+    /// * `queries` - The queries to tune on. These should resemble the queries
+    ///   expected in use.
+    /// * `k` - The number of nearest neighbors to search for.
+    /// * `min_recall` - The smallest acceptable mean recall.
+    ///
+    /// # Errors
+    ///
+    /// * If `queries` is empty.
+    pub fn auto_tune_knn_with_queries(
+        &mut self,
+        queries: &[I],
+        k: usize,
+        min_recall: f64,
+    ) -> Result<&TuningReport, String> {
+        self.tuning_shard_mut()
+            .auto_tune_knn_with_queries(queries, k, min_recall)
+    }
+
+    /// Returns the report from the last call to `auto_tune_rnn_with_queries`,
+    /// unless the algorithm has since been tuned with `auto_tune_rnn`.
+    pub fn rnn_tuning_report(&self) -> Option<&TuningReport> {
+        self.tuning_shard().rnn_tuning_report()
+    }
+
+    /// Returns the report from the last call to `auto_tune_knn_with_queries`,
+    /// unless the algorithm has since been tuned with `auto_tune_knn`.
+    pub fn knn_tuning_report(&self) -> Option<&TuningReport> {
+        self.tuning_shard().knn_tuning_report()
+    }
+
     /// Automatically finds the best KNN algorithm to use.
     ///
     /// # Arguments
@@ -286,6 +865,7 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         match self {
             Self::SingleShard(ss) => ss.auto_tune_knn(k, tuning_depth),
             Self::RandomlySharded(rs) => rs.auto_tune_knn(k, tuning_depth),
+            Self::ClusterSharded(cs) => cs.auto_tune_knn(k, tuning_depth),
         }
     }
 
@@ -318,6 +898,7 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         match self {
             Self::SingleShard(ss) => ss.linear_knn_search(query, k),
             Self::RandomlySharded(rs) => rs.linear_knn_search(query, k),
+            Self::ClusterSharded(cs) => cs.linear_knn_search(query, k),
         }
     }
 
@@ -355,6 +936,180 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
         self.rnn_search(query, radius, algo)
     }
 
+    /// Performs filtered RNN search on every shard and merges the hits.
+    ///
+    /// The filters take the position of the shard and the index in that shard.
+    fn rnn_filtered<P, C>(&self, query: &I, radius: U, keep: P, has_any: C) -> Vec<(usize, U)>
+    where
+        P: Fn(usize, usize) -> bool + Send + Sync,
+        C: Fn(usize, &Cluster<U>) -> bool + Send + Sync,
+    {
+        self.shards_with_offsets()
+            .into_par_iter()
+            .enumerate()
+            .flat_map(|(s, (shard, o))| {
+                shard
+                    .rnn_filtered(query, radius, |i| keep(s, i), |c| has_any(s, c))
+                    .into_par_iter()
+                    .map(move |(i, d)| (self.global_index(s, o, i), d))
+            })
+            .collect()
+    }
+
+    /// Performs filtered KNN search on every shard and merges the hits.
+    ///
+    /// The filters take the position of the shard and the index in that shard.
+    fn knn_filtered<P, C>(&self, query: &I, k: usize, keep: P, has_any: C) -> Vec<(usize, U)>
+    where
+        P: Fn(usize, usize) -> bool + Send + Sync,
+        C: Fn(usize, &Cluster<U>) -> bool + Send + Sync,
+    {
+        let hits = self
+            .shards_with_offsets()
+            .into_par_iter()
+            .enumerate()
+            .flat_map(|(s, (shard, o))| {
+                shard
+                    .knn_filtered(query, k, |i| keep(s, i), |c| has_any(s, c))
+                    .into_par_iter()
+                    .map(move |(i, d)| (self.global_index(s, o, i), d))
+            })
+            .collect();
+
+        knn::Hits::from_vec(k, hits).extract()
+    }
+
+    /// Performs an RNN search that keeps only the instances whose original
+    /// index satisfies the `predicate`.
+    ///
+    /// Instances that fail the `predicate` are dropped before computing their
+    /// distances to the query.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radius` - The search radius.
+    /// * `predicate` - Whether to keep the instance with the given original index.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the index of the instance and the distance to the query.
+    pub fn rnn_search_filtered<P>(&self, query: &I, radius: U, predicate: P) -> Vec<(usize, U)>
+    where
+        P: Fn(usize) -> bool + Send + Sync,
+    {
+        let shards = self.shards_with_offsets();
+        let keep = |s: usize, i: usize| predicate(self.shard_original_index(s, shards[s].0, shards[s].1, i));
+        self.rnn_filtered(query, radius, keep, |_, _| true)
+    }
+
+    /// Performs a KNN search that keeps only the instances whose original
+    /// index satisfies the `predicate`.
+    ///
+    /// The search keeps traversing the tree(s) until `k` passing instances are
+    /// found, so that filtering does not cost any recall. Instances that fail
+    /// the `predicate` are dropped before computing their distances to the query.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `k` - The number of nearest neighbors to return.
+    /// * `predicate` - Whether to keep the instance with the given original index.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the index of the instance and the distance
+    /// to the query. There may be fewer than `k` hits if fewer than `k`
+    /// instances satisfy the `predicate`.
+    pub fn knn_search_filtered<P>(&self, query: &I, k: usize, predicate: P) -> Vec<(usize, U)>
+    where
+        P: Fn(usize) -> bool + Send + Sync,
+    {
+        let shards = self.shards_with_offsets();
+        let keep = |s: usize, i: usize| predicate(self.shard_original_index(s, shards[s].0, shards[s].1, i));
+        self.knn_filtered(query, k, keep, |_, _| true)
+    }
+
+    /// Summarizes which instances have an original index that satisfies the
+    /// `predicate`.
+    ///
+    /// The summary can be reused for many filtered searches with
+    /// `knn_search_summarized` and `rnn_search_summarized`, which skip the
+    /// clusters that have no passing instances.
+    ///
+    /// # Arguments
+    ///
+    /// * `predicate` - Whether to keep the instance with the given original index.
+    pub fn filter_summary<P>(&self, predicate: P) -> FilterSummary
+    where
+        P: Fn(usize) -> bool + Send + Sync,
+    {
+        let passes = self
+            .shards_with_offsets()
+            .into_iter()
+            .enumerate()
+            .map(|(s, (shard, o))| {
+                (0..shard.data().cardinality())
+                    .into_par_iter()
+                    .map(|i| predicate(self.shard_original_index(s, shard, o, i)))
+                    .collect()
+            })
+            .collect::<Vec<_>>();
+        FilterSummary::new(&passes)
+    }
+
+    /// Performs an RNN search that keeps only the instances that pass the
+    /// filter in the `summary`.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radius` - The search radius.
+    /// * `summary` - The summary of the filter, built from this `Cakes`.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the index of the instance and the distance to the query.
+    ///
+    /// # Panics
+    ///
+    /// * If the `summary` was built from a different `Cakes`.
+    pub fn rnn_search_summarized(&self, query: &I, radius: U, summary: &FilterSummary) -> Vec<(usize, U)> {
+        self.rnn_filtered(
+            query,
+            radius,
+            |s, i| summary.passes(s, i),
+            |s, c| summary.count_in(s, c.indices()) > 0,
+        )
+    }
+
+    /// Performs a KNN search that keeps only the instances that pass the
+    /// filter in the `summary`.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `k` - The number of nearest neighbors to return.
+    /// * `summary` - The summary of the filter, built from this `Cakes`.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the index of the instance and the distance
+    /// to the query. There may be fewer than `k` hits if fewer than `k`
+    /// instances pass the filter.
+    ///
+    /// # Panics
+    ///
+    /// * If the `summary` was built from a different `Cakes`.
+    pub fn knn_search_summarized(&self, query: &I, k: usize, summary: &FilterSummary) -> Vec<(usize, U)> {
+        self.knn_filtered(
+            query,
+            k,
+            |s, i| summary.passes(s, i),
+            |s, c| summary.count_in(s, c.indices()) > 0,
+        )
+    }
+
     /// Performs KNN search on a batch of queries with the tuned algorithm.
     ///
     /// If the algorithm has not been tuned, this will use the default algorithm.
@@ -390,6 +1145,243 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Cakes<I, U, D> {
     }
 }
 
+impl<I: Instance, U: Number, M: Instance> Cakes<I, U, VecDataset<I, U, M>> {
+    /// Merges runs of consecutive small shards of a randomly sharded CAKES
+    /// instance into larger shards.
+    ///
+    /// Only shards with fewer than `min_cardinality` instances are merged, and
+    /// the sample shard is never merged. Neither the original indices nor the
+    /// indices returned by search change. Call `save` afterwards to persist the
+    /// merged shards.
+    ///
+    /// # Arguments
+    ///
+    /// * `min_cardinality` - The cardinality below which shards are merged.
+    /// * `seed` - The seed to use for the random number generator.
+    /// * `criteria` - The criteria to use for partitioning the merged trees.
+    ///
+    /// # Errors
+    ///
+    /// * If this `Cakes` is not randomly sharded.
+    pub fn compact_shards(
+        &mut self,
+        min_cardinality: usize,
+        seed: Option<u64>,
+        criteria: &PartitionCriteria<U>,
+    ) -> Result<(), String> {
+        match self {
+            Self::RandomlySharded(rs) => {
+                rs.compact(min_cardinality, seed, criteria);
+                Ok(())
+            }
+            _ => Err("Only a randomly sharded Cakes can be compacted.".to_string()),
+        }
+    }
+
+    /// Creates a new CAKES instance whose layout is chosen from a memory budget.
+    ///
+    /// The memory cost per instance, including its share of the tree, is
+    /// estimated from a random sample of the dataset. If the whole dataset
+    /// fits within `memory_budget_bytes`, a single shard is used. Otherwise the
+    /// dataset is randomly sharded so that each shard fits within the budget.
+    /// The decision is recorded in the index and saved along with it.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The dataset to search.
+    /// * `memory_budget_bytes` - The memory budget, in bytes, for the tree and
+    ///   dataset of any one shard.
+    /// * `seed` - The seed to use for the random number generator.
+    /// * `criteria` - The criteria to use for partitioning the tree(s).
+    #[must_use]
+    pub fn new_auto(
+        data: VecDataset<I, U, M>,
+        memory_budget_bytes: usize,
+        seed: Option<u64>,
+        criteria: &PartitionCriteria<U>,
+    ) -> Self {
+        let layout = AutoLayout::estimate(&data, memory_budget_bytes, seed, criteria);
+
+        let mut shards = match layout.max_shard_cardinality {
+            None => vec![SingleShard::new(data, seed, criteria)],
+            Some(max_cardinality) => data
+                .make_shards(max_cardinality)
+                .into_par_iter()
+                .map(|d| SingleShard::new(d, seed, criteria))
+                .collect(),
+        };
+        shards[0].set_auto_layout(layout);
+
+        if shards.len() == 1 {
+            Self::SingleShard(shards.pop().unwrap_or_else(|| unreachable!("There is one shard.")))
+        } else {
+            Self::RandomlySharded(RandomlySharded::new(shards))
+        }
+    }
+
+    /// Creates a new CAKES instance that shards the dataset by its top-level
+    /// clusters.
+    ///
+    /// Each shard holds the instances of one `Cluster`, and queries are only
+    /// sent to the shards whose ball can contain hits. The search results are
+    /// exact, and the original indices refer to the instances in `data`.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The dataset to shard and search.
+    /// * `max_shard_cardinality` - The maximum cardinality of each shard.
+    /// * `seed` - The seed to use for the random number generator.
+    /// * `criteria` - The criteria to use for partitioning the tree of each shard.
+    #[must_use]
+    pub fn new_cluster_sharded(
+        data: VecDataset<I, U, M>,
+        max_shard_cardinality: usize,
+        seed: Option<u64>,
+        criteria: &PartitionCriteria<U>,
+    ) -> Self {
+        Self::ClusterSharded(ClusterSharded::from_dataset(
+            data,
+            max_shard_cardinality,
+            seed,
+            criteria,
+        ))
+    }
+
+    /// Returns the metadata of an instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the instance, as returned by search.
+    ///
+    /// # Errors
+    ///
+    /// * If `index` is not a valid index in the dataset.
+    pub fn metadata_of(&self, index: usize) -> Result<&M, String> {
+        match self {
+            Self::SingleShard(ss) => ss.original_index(index).map(|_| ss.data().metadata_of(index)),
+            Self::RandomlySharded(rs) => rs
+                .locate(index)
+                .map(|(s, index)| rs.shards()[s].data().metadata_of(index)),
+            Self::ClusterSharded(cs) => cs
+                .locate(index)
+                .map(|(s, index)| cs.shards()[s].data().metadata_of(index)),
+        }
+    }
+
+    /// Attaches the original index and the metadata to each hit in search results.
+    fn hits_with_metadata(&self, hits: Vec<(usize, U)>) -> Vec<(usize, U, &M)> {
+        hits.into_iter()
+            .map(|(i, d)| {
+                let (o, m) = self
+                    .original_index(i)
+                    .and_then(|o| self.metadata_of(i).map(|m| (o, m)))
+                    .unwrap_or_else(|e| unreachable!("Search results have valid indices. {e}"));
+                (o, d, m)
+            })
+            .collect()
+    }
+
+    /// Performs an RNN search with the given algorithm and attaches the metadata
+    /// to each hit.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radius` - The search radius.
+    /// * `algo` - The algorithm to use.
+    ///
+    /// # Returns
+    ///
+    /// A vector of 3-tuples containing the original index of the instance, the
+    /// distance to the query and the metadata of the instance.
+    pub fn rnn_search_with_metadata(&self, query: &I, radius: U, algo: rnn::Algorithm) -> Vec<(usize, U, &M)> {
+        self.hits_with_metadata(self.rnn_search(query, radius, algo))
+    }
+
+    /// Performs an RNN search that keeps only the instances whose metadata
+    /// satisfies the `predicate`.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radius` - The search radius.
+    /// * `predicate` - Whether to keep the instance with the given metadata.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the index of the instance and the distance to the query.
+    pub fn rnn_search_by_metadata<P>(&self, query: &I, radius: U, predicate: P) -> Vec<(usize, U)>
+    where
+        P: Fn(&M) -> bool + Send + Sync,
+    {
+        let shards = self.shards_with_offsets();
+        let keep = |s: usize, i: usize| predicate(shards[s].0.data().metadata_of(i));
+        self.rnn_filtered(query, radius, keep, |_, _| true)
+    }
+
+    /// Performs a KNN search that keeps only the instances whose metadata
+    /// satisfies the `predicate`.
+    ///
+    /// The search keeps traversing the tree(s) until `k` passing instances are
+    /// found, so that filtering does not cost any recall.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `k` - The number of nearest neighbors to return.
+    /// * `predicate` - Whether to keep the instance with the given metadata.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the index of the instance and the distance
+    /// to the query. There may be fewer than `k` hits if fewer than `k`
+    /// instances satisfy the `predicate`.
+    pub fn knn_search_by_metadata<P>(&self, query: &I, k: usize, predicate: P) -> Vec<(usize, U)>
+    where
+        P: Fn(&M) -> bool + Send + Sync,
+    {
+        let shards = self.shards_with_offsets();
+        let keep = |s: usize, i: usize| predicate(shards[s].0.data().metadata_of(i));
+        self.knn_filtered(query, k, keep, |_, _| true)
+    }
+
+    /// Summarizes which instances have metadata that satisfies the `predicate`.
+    ///
+    /// See `filter_summary`.
+    ///
+    /// # Arguments
+    ///
+    /// * `predicate` - Whether to keep the instance with the given metadata.
+    pub fn metadata_filter_summary<P>(&self, predicate: P) -> FilterSummary
+    where
+        P: Fn(&M) -> bool + Send + Sync,
+    {
+        let passes = self
+            .shards_with_offsets()
+            .into_iter()
+            .map(|(shard, _)| shard.data().metadata().par_iter().map(&predicate).collect())
+            .collect::<Vec<_>>();
+        FilterSummary::new(&passes)
+    }
+
+    /// Performs a KNN search with the given algorithm and attaches the metadata
+    /// to each hit.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `k` - The number of nearest neighbors to return.
+    /// * `algo` - The algorithm to use.
+    ///
+    /// # Returns
+    ///
+    /// A vector of 3-tuples containing the original index of the instance, the
+    /// distance to the query and the metadata of the instance.
+    pub fn knn_search_with_metadata(&self, query: &I, k: usize, algo: knn::Algorithm) -> Vec<(usize, U, &M)> {
+        self.hits_with_metadata(self.knn_search(query, k, algo))
+    }
+}
+
 impl<I, U, D> Index<usize> for Cakes<I, U, D>
 where
     I: Instance,
@@ -398,20 +1390,19 @@ where
 {
     type Output = I;
 
+    /// Returns the instance at the given index, as returned by search.
+    ///
+    /// # Panics
+    ///
+    /// * If `index` is not smaller than the total cardinality. Use `get` to
+    ///   check instead.
+    #[allow(clippy::panic)]
     fn index(&self, index: usize) -> &Self::Output {
-        match self {
-            Self::SingleShard(ss) => ss.data().index(index),
-            Self::RandomlySharded(rs) => {
-                let i = rs
-                    .offsets()
-                    .iter()
-                    .enumerate()
-                    .find(|(_, &o)| o > index)
-                    .map_or_else(|| rs.num_shards() - 1, |(i, _)| i - 1);
-
-                let index = index - rs.offsets()[i];
-                rs.shards()[i].data().index(index)
-            }
-        }
+        self.get(index).unwrap_or_else(|| {
+            panic!(
+                "Index {index} out of bounds for a Cakes with {} instances.",
+                self.total_cardinality()
+            )
+        })
     }
 }
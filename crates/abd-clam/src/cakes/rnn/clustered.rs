@@ -1,5 +1,7 @@
 //! Clustered search for the ranged nearest neighbors of a query.
 
+use core::cmp::Ordering;
+
 use distances::Number;
 
 use crate::{Cluster, Dataset, Instance, Tree};
@@ -28,6 +30,179 @@ where
     leaf_search(tree.data(), confirmed, straddlers, query, radius)
 }
 
+/// Clustered search for the ranged nearest neighbors of a query, leaving out
+/// the query itself and, optionally, its exact duplicates.
+///
+/// When the query is an instance in the tree, `self_index` should be its index
+/// in the (permuted) dataset. The query is then known to lie inside every
+/// `Cluster` on the path from the root to its leaf, so those `Cluster`s
+/// overlap the query ball and their centers are never compared to the query.
+/// Only the sibling of each `Cluster` on the path is searched as usual.
+///
+/// # Arguments
+///
+/// * `tree` - The tree to search.
+/// * `query` - The query to search around.
+/// * `radius` - The radius to search within.
+/// * `self_index` - The index of the query in the dataset, if it is in the dataset.
+/// * `exclude_duplicates` - Whether to leave out instances at distance zero from the query.
+///
+/// # Returns
+///
+/// A vector of 2-tuples, where the first element is the index of the instance
+/// and the second element is the distance from the query to the instance.
+pub fn search_excluding<I, U, D>(
+    tree: &Tree<I, U, D>,
+    query: &I,
+    radius: U,
+    self_index: Option<usize>,
+    exclude_duplicates: bool,
+) -> Vec<(usize, U)>
+where
+    I: Instance,
+    U: Number,
+    D: Dataset<I, U>,
+{
+    let data = tree.data();
+
+    let [confirmed, straddlers] = match self_index {
+        Some(index) if tree.root.indices().contains(&index) => {
+            let [mut confirmed, mut straddlers] = [Vec::new(), Vec::new()];
+            let mut c = &tree.root;
+            loop {
+                // The query is inside `c`, so the distance to its center is at
+                // most its radius. That distance is only used for singletons,
+                // whose instances are all identical to the query.
+                if c.radius() + c.radius() <= radius {
+                    confirmed.push((c, U::zero()));
+                    break;
+                }
+                let Some([l, r]) = c.children() else {
+                    straddlers.push((c, U::zero()));
+                    break;
+                };
+                let (on_path, sibling) = if l.indices().contains(&index) { (l, r) } else { (r, l) };
+                let [mut c_sibling, mut s_sibling] = tree_search(data, sibling, query, radius);
+                confirmed.append(&mut c_sibling);
+                straddlers.append(&mut s_sibling);
+                c = on_path;
+            }
+            [confirmed, straddlers]
+        }
+        _ => tree_search(data, &tree.root, query, radius),
+    };
+
+    leaf_search(data, confirmed, straddlers, query, radius)
+        .into_iter()
+        .filter(|&(i, d)| self_index != Some(i) && !(exclude_duplicates && d == U::zero()))
+        .collect()
+}
+
+/// Clustered search for the ranged nearest neighbors of a query at several
+/// radii at once.
+///
+/// The tree is traversed only once, with the largest radius, and the distances
+/// from the query to `Cluster` centers found during that traversal are reused
+/// for singleton `Cluster`s. The hits for each smaller radius are then a
+/// prefix of the hits for the largest radius.
+///
+/// # Arguments
+///
+/// * `tree` - The tree to search.
+/// * `query` - The query to search around.
+/// * `radii` - The radii to search within.
+///
+/// # Returns
+///
+/// For each radius, in the same order as `radii`, the indices of the hits and
+/// their distances to the query, sorted by increasing distance.
+pub fn search_multi<I, U, D>(tree: &Tree<I, U, D>, query: &I, radii: &[U]) -> Vec<Vec<(usize, U)>>
+where
+    I: Instance,
+    U: Number,
+    D: Dataset<I, U>,
+{
+    let Some(max_radius) = max_radius(radii) else {
+        return Vec::new();
+    };
+
+    let [confirmed, straddlers] = tree_search(tree.data(), &tree.root, query, max_radius);
+    let mut hits = leaf_search(tree.data(), confirmed, straddlers, query, max_radius);
+    hits.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Greater));
+
+    radii
+        .iter()
+        .map(|&radius| hits[..hits.partition_point(|&(_, d)| d <= radius)].to_vec())
+        .collect()
+}
+
+/// Counts the instances within each of several radii of a query, without
+/// collecting the hits.
+///
+/// The tree is traversed only once for all radii. Each `Cluster` is visited
+/// with the radii whose query balls it straddles. For each of those radii, a
+/// `Cluster` that lies entirely inside the query ball contributes its
+/// cardinality to the count without any distance computations, and one that
+/// lies entirely outside contributes nothing. The children of a `Cluster` are
+/// visited only with the radii for which it still straddles the ball, so a
+/// small radius never scans a large `Cluster` that is confirmed only for a
+/// larger radius. Distances to the instances of a straddling leaf are computed
+/// at most once and are shared by all radii.
+///
+/// # Arguments
+///
+/// * `tree` - The tree to search.
+/// * `query` - The query to search around.
+/// * `radii` - The radii to count within.
+///
+/// # Returns
+///
+/// The number of instances within each radius of the query, in the same order
+/// as `radii`.
+pub fn count<I, U, D>(tree: &Tree<I, U, D>, query: &I, radii: &[U]) -> Vec<usize>
+where
+    I: Instance,
+    U: Number,
+    D: Dataset<I, U>,
+{
+    let data = tree.data();
+    let mut counts = vec![0; radii.len()];
+    let mut candidates = vec![(&tree.root, (0..radii.len()).collect::<Vec<_>>())];
+
+    while let Some((c, active)) = candidates.pop() {
+        let d = c.distance_to_instance(data, query);
+
+        let mut straddled = Vec::new();
+        for r in active {
+            if d + c.radius() <= radii[r] {
+                counts[r] += c.cardinality();
+            } else if d <= c.radius() + radii[r] {
+                straddled.push(r);
+            }
+        }
+        if straddled.is_empty() {
+            continue;
+        }
+
+        if let Some([left, right]) = c.children() {
+            candidates.push((left, straddled.clone()));
+            candidates.push((right, straddled));
+        } else {
+            let distances = data.query_to_many(query, &c.indices().collect::<Vec<_>>());
+            for r in straddled {
+                counts[r] += distances.iter().filter(|&&d| d <= radii[r]).count();
+            }
+        }
+    }
+
+    counts
+}
+
+/// Returns the largest of the given radii, or `None` if there are none.
+fn max_radius<U: Number>(radii: &[U]) -> Option<U> {
+    radii.iter().copied().reduce(|a, b| if a < b { b } else { a })
+}
+
 /// Perform coarse-grained tree search.
 ///
 /// # Arguments
@@ -49,6 +224,36 @@ where
     I: Instance,
     U: Number,
     D: Dataset<I, U>,
+{
+    tree_search_filtered(data, root, query, radius, |_| true)
+}
+
+/// Perform coarse-grained tree search, skipping the `Cluster`s for which
+/// `has_any` is `false`.
+///
+/// # Arguments
+///
+/// * `data` - The dataset to search.
+/// * `root` - The root of the tree to search.
+/// * `query` - The query to search around.
+/// * `radius` - The radius to search within.
+/// * `has_any` - Whether the given `Cluster` may contain any instance of interest.
+///
+/// # Returns
+///
+/// See `tree_search`.
+pub fn tree_search_filtered<'a, I, U, D, C>(
+    data: &D,
+    root: &'a Cluster<U>,
+    query: &I,
+    radius: U,
+    has_any: C,
+) -> [Vec<(&'a Cluster<U>, U)>; 2]
+where
+    I: Instance,
+    U: Number,
+    D: Dataset<I, U>,
+    C: Fn(&Cluster<U>) -> bool,
 {
     let mut confirmed = Vec::new();
     let mut straddlers = Vec::new();
@@ -58,6 +263,7 @@ where
     while !candidates.is_empty() {
         (terminal, non_terminal) = candidates
             .into_iter()
+            .filter(|&c| has_any(c))
             .map(|c| (c, c.distance_to_instance(data, query)))
             .filter(|&(c, d)| d <= (c.radius() + radius))
             .partition(|&(c, d)| (c.radius() + d) <= radius);
@@ -111,3 +317,52 @@ where
 
     hits.chain(linear::search(data, query, radius, &indices)).collect()
 }
+
+/// Clustered search for the ranged nearest neighbors of a query, keeping only
+/// those instances that pass a filter.
+///
+/// Instances that do not pass the filter are dropped before computing their
+/// distances to the query, and `Cluster`s for which `has_any` is `false` are
+/// never visited.
+///
+/// # Arguments
+///
+/// * `tree` - The tree to search.
+/// * `query` - The query to search around.
+/// * `radius` - The radius to search within.
+/// * `keep` - Whether the instance at the given index passes the filter.
+/// * `has_any` - Whether the given `Cluster` may contain any instance that passes the filter.
+///
+/// # Returns
+///
+/// A vector of 2-tuples, where the first element is the index of the instance
+/// and the second element is the distance from the query to the instance.
+pub fn search_filtered<I, U, D, P, C>(tree: &Tree<I, U, D>, query: &I, radius: U, keep: P, has_any: C) -> Vec<(usize, U)>
+where
+    I: Instance,
+    U: Number,
+    D: Dataset<I, U>,
+    P: Fn(usize) -> bool,
+    C: Fn(&Cluster<U>) -> bool,
+{
+    let data = tree.data();
+    let [confirmed, straddlers] = tree_search_filtered(data, &tree.root, query, radius, has_any);
+
+    let hits = confirmed.into_iter().flat_map(|(c, d)| {
+        let indices = c.indices().filter(|&i| keep(i)).collect::<Vec<_>>();
+        let distances = if c.is_singleton() {
+            vec![d; indices.len()]
+        } else {
+            data.query_to_many(query, &indices)
+        };
+        indices.into_iter().zip(distances)
+    });
+
+    let indices = straddlers
+        .into_iter()
+        .flat_map(|(c, _)| c.indices())
+        .filter(|&i| keep(i))
+        .collect::<Vec<_>>();
+
+    hits.chain(linear::search(data, query, radius, &indices)).collect()
+}
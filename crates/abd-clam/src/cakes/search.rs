@@ -67,6 +67,30 @@ pub trait Search<I: Instance, U: Number, D: Dataset<I, U>>: Send + Sync {
     /// Performs RNN-Search using the naive linear algorithm.
     fn linear_rnn_search(&self, query: &I, radius: U) -> Vec<(usize, U)>;
 
+    /// Performs an RNN-Search around an instance that is already in the search
+    /// structure, leaving out the instance itself.
+    ///
+    /// # Arguments
+    ///
+    /// * `original_index` - The index of the instance before the data was reordered.
+    /// * `radius` - The radius to use for the search.
+    /// * `exclude_duplicates` - Whether to also leave out instances identical to the query.
+    ///
+    /// # Returns
+    ///
+    /// A vector of 2-tuples containing the index of the instance and its
+    /// distance to the query.
+    ///
+    /// # Errors
+    ///
+    /// * If `original_index` is not a valid index.
+    fn rnn_by_index(
+        &self,
+        original_index: usize,
+        radius: U,
+        exclude_duplicates: bool,
+    ) -> Result<Vec<(usize, U)>, String>;
+
     /// Returns the best KNN-Search algorithm.
     ///
     /// If the algorithm has not been tuned, this will return the default variant.
@@ -86,6 +110,26 @@ pub trait Search<I: Instance, U: Number, D: Dataset<I, U>>: Send + Sync {
     /// distance to the query.
     fn knn_search(&self, query: &I, k: usize, algo: knn::Algorithm) -> Vec<(usize, U)>;
 
+    /// Performs a KNN-Search around an instance that is already in the search
+    /// structure, leaving out the instance itself.
+    ///
+    /// # Arguments
+    ///
+    /// * `original_index` - The index of the instance before the data was reordered.
+    /// * `k` - The number of neighbors to search for.
+    /// * `exclude_duplicates` - Whether to also leave out instances identical to the query.
+    ///
+    /// # Returns
+    ///
+    /// A vector of 2-tuples containing the index of the instance and its
+    /// distance to the query.
+    ///
+    /// # Errors
+    ///
+    /// * If `original_index` is not a valid index.
+    fn knn_by_index(&self, original_index: usize, k: usize, exclude_duplicates: bool)
+        -> Result<Vec<(usize, U)>, String>;
+
     /// Auto-tunes the RNN-Search algorithm and sets it as the best.
     ///
     /// # Arguments
@@ -2,11 +2,21 @@
 
 use core::ops::AddAssign;
 
+use std::path::Path;
+
 use distances::Number;
 use rayon::prelude::*;
 
 use super::{Search, SingleShard};
-use crate::{knn, rnn, Dataset, Instance};
+use crate::{knn, rnn, utils, Dataset, Instance, PartitionCriteria, VecDataset};
+
+/// The name of the file in which the cardinalities of the non-sample shards
+/// are saved, so that the shards need not be read to find them.
+pub const CARDINALITIES_FILE: &str = "shard-cardinalities.json";
+
+/// The name of the file, in the directory of a shard, in which the indices
+/// issued for its instances before it was merged by `compact` are saved.
+const ISSUED_IDS_FILE: &str = "issued-ids";
 
 /// Cakes search with sharded datasets.
 ///
@@ -26,6 +36,58 @@ pub struct RandomlySharded<I: Instance, U: Number, D: Dataset<I, U>> {
     shards: Vec<SingleShard<I, U, D>>,
     /// The Dataset.
     offsets: Vec<usize>,
+    /// For each of the full shards, the indices that search returned for its
+    /// instances before it was merged by `compact`, if it was.
+    issued: Vec<Option<IssuedIds>>,
+}
+
+/// The indices that search returned for the instances of a shard before it
+/// was merged with other shards by `compact`.
+///
+/// Merging rebuilds the tree and so reorders the instances, but the indices
+/// already returned by search must keep referring to the same instances.
+#[derive(Debug)]
+struct IssuedIds {
+    /// The issued index, relative to the offset of the shard, of the instance
+    /// at each index in the shard.
+    ids: Vec<usize>,
+    /// The index in the shard of the instance with each issued index.
+    positions: Vec<usize>,
+}
+
+impl IssuedIds {
+    /// Creates the issued indices from the issued index of the instance at
+    /// each index in the shard.
+    fn new(ids: Vec<usize>) -> Self {
+        Self {
+            positions: utils::inverse_permutation(&ids),
+            ids,
+        }
+    }
+}
+
+/// Loads the indices issued for the instances of a shard before it was merged
+/// by `compact`, if it was.
+///
+/// # Arguments
+///
+/// * `shard_dir` - The directory to which the shard was saved.
+///
+/// # Returns
+///
+/// The issued index, relative to the offset of the shard, of the instance at
+/// each index in the shard, or `None` if the shard was never merged.
+///
+/// # Errors
+///
+/// * If the file of issued indices exists but cannot be read.
+pub fn load_issued_ids(shard_dir: &Path) -> Result<Option<Vec<usize>>, String> {
+    let path = shard_dir.join(ISSUED_IDS_FILE);
+    if path.exists() {
+        utils::read_indices(&path).map(Some)
+    } else {
+        Ok(None)
+    }
 }
 
 impl<I: Instance, U: Number, D: Dataset<I, U>> RandomlySharded<I, U, D> {
@@ -44,13 +106,15 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> RandomlySharded<I, U, D> {
         let offsets = new_shards
             .iter()
             .scan(sample_shard.data().cardinality(), |o, d| {
+                let offset = *o;
                 o.add_assign(d.data().cardinality());
-                Some(*o)
+                Some(offset)
             })
             .collect::<Vec<_>>();
 
         Self {
             sample_shard,
+            issued: new_shards.iter().map(|_| None).collect(),
             shards: new_shards,
             offsets,
         }
@@ -65,6 +129,326 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> RandomlySharded<I, U, D> {
     pub fn offsets(&self) -> &[usize] {
         &self.offsets
     }
+
+    /// Returns the offsets of the indices of all shards, including the sample shard.
+    pub fn shard_offsets(&self) -> Vec<usize> {
+        core::iter::once(0).chain(self.offsets.iter().copied()).collect()
+    }
+
+    /// Finds the shard that contains the given index.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - An index across all shards, as returned by search.
+    ///
+    /// # Returns
+    ///
+    /// The position of the shard in `shards` and the index within that shard.
+    ///
+    /// # Errors
+    ///
+    /// * If `index` is not smaller than the total cardinality of the shards.
+    pub fn locate(&self, index: usize) -> Result<(usize, usize), String> {
+        let (s, relative) = self.shard_of(index)?;
+        let local = self
+            .shard_indices(s)
+            .1
+            .map_or(relative, |issued| issued.positions[relative]);
+        Ok((s, local))
+    }
+
+    /// Returns the index returned by search for the instance at the given
+    /// index in the shard at position `s` in `shards`.
+    pub fn global_index(&self, s: usize, index: usize) -> usize {
+        let (offset, issued) = self.shard_indices(s);
+        offset + issued.map_or(index, |issued| issued.ids[index])
+    }
+
+    /// Returns the index returned by search for the instance at the given
+    /// index in the concatenation of the shards, e.g. an index into a shard
+    /// plus the offset from `shard_offsets`.
+    pub fn issued_index(&self, index: usize) -> usize {
+        let (s, relative) = self
+            .shard_of(index)
+            .unwrap_or_else(|e| unreachable!("Indices in the shards are in bounds. {e}"));
+        self.global_index(s, relative)
+    }
+
+    /// Returns the offset of the shard at position `s` in `shards`, along with
+    /// the indices issued for its instances before it was merged, if it was.
+    fn shard_indices(&self, s: usize) -> (usize, Option<&IssuedIds>) {
+        if s == 0 {
+            (0, None)
+        } else {
+            (self.offsets[s - 1], self.issued[s - 1].as_ref())
+        }
+    }
+
+    /// Finds the shard whose range of indices contains the given index.
+    ///
+    /// The ranges of the shards are the same for the indices returned by
+    /// search, the original indices and the indices into the concatenation of
+    /// the shards.
+    ///
+    /// # Returns
+    ///
+    /// The position of the shard in `shards` and the index relative to the
+    /// offset of the shard.
+    ///
+    /// # Errors
+    ///
+    /// * If `index` is not smaller than the total cardinality of the shards.
+    fn shard_of(&self, index: usize) -> Result<(usize, usize), String> {
+        let cardinality = self.shard_cardinalities().into_iter().sum::<usize>();
+        if index >= cardinality {
+            return Err(format!(
+                "Index {index} is out of bounds for sharded data with cardinality {cardinality}."
+            ));
+        }
+
+        let shard = self.offsets.iter().take_while(|&&o| o <= index).count();
+        let offset = if shard == 0 { 0 } else { self.offsets[shard - 1] };
+
+        Ok((shard, index - offset))
+    }
+
+    /// Adds a shard after all existing shards.
+    ///
+    /// The indices of the instances in the existing shards do not change, and
+    /// the new instances get the indices that follow them.
+    ///
+    /// # Arguments
+    ///
+    /// * `shard` - The shard to add.
+    /// * `save_dir` - The directory to which this structure was saved, if the
+    ///   new shard should also be saved there.
+    ///
+    /// # Returns
+    ///
+    /// The offset of the indices of the new shard.
+    ///
+    /// # Errors
+    ///
+    /// * If `save_dir` does not contain a saved sharded structure.
+    /// * If `save_dir` holds a different number of shards than this structure.
+    /// * If the shard cannot be saved.
+    #[allow(clippy::similar_names)]
+    pub fn append_shard(&mut self, shard: SingleShard<I, U, D>, save_dir: Option<&Path>) -> Result<usize, String> {
+        if let Some(path) = save_dir {
+            if !path.join("sample_shard").exists() {
+                return Err(format!("No sharded Cakes was saved in {}", path.display()));
+            }
+
+            let shards_dir = path.join("shards");
+            let mut num_saved = 0;
+            while shards_dir.join(format!("shard_{num_saved}")).exists() {
+                num_saved += 1;
+            }
+            if num_saved != self.shards.len() {
+                return Err(format!(
+                    "{} holds {num_saved} shards but this structure has {}.",
+                    path.display(),
+                    self.shards.len()
+                ));
+            }
+
+            let shard_dir = shards_dir.join(format!("shard_{num_saved}"));
+            std::fs::create_dir_all(&shard_dir).map_err(|e| format!("Failed to create directory: {e:?}"))?;
+            shard.save(&shard_dir)?;
+        }
+
+        let offset = self.shard_cardinalities().into_iter().sum();
+        self.offsets.push(offset);
+        self.shards.push(shard);
+        self.issued.push(None);
+
+        if let Some(path) = save_dir {
+            self.save_cardinalities(path)?;
+        }
+
+        Ok(offset)
+    }
+
+    /// Saves the cardinalities of the shards, other than the sample shard, so
+    /// that `LazyRandomlySharded` can be loaded without reading every shard.
+    ///
+    /// # Errors
+    ///
+    /// * If the file cannot be written.
+    fn save_cardinalities(&self, path: &Path) -> Result<(), String> {
+        let cardinalities = self.shards.iter().map(|s| s.data().cardinality()).collect::<Vec<_>>();
+        let contents = serde_json::to_string(&cardinalities).map_err(|e| e.to_string())?;
+        std::fs::write(path.join(CARDINALITIES_FILE), contents).map_err(|e| e.to_string())
+    }
+
+    /// Performs KNN search on all shards in parallel.
+    ///
+    /// Each shard runs Greedy-Sieve search and publishes the distance to its
+    /// current `k`-th nearest neighbor to a bound shared by all shards, which
+    /// every shard uses to prune its `Cluster`s. The results are exact.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `k` - The number of nearest neighbors to return.
+    ///
+    /// # Returns
+    ///
+    /// A vector of tuples containing the index of the instance and the distance
+    /// to the query.
+    pub fn par_knn_search(&self, query: &I, k: usize) -> Vec<(usize, U)> {
+        let bound = knn::SharedBound::new();
+        let hits = self
+            .shards()
+            .into_par_iter()
+            .enumerate()
+            .flat_map(|(s, shard)| {
+                knn::greedy_sieve::search_bounded(shard.tree(), query, k, &bound)
+                    .into_par_iter()
+                    .map(move |(i, d)| (self.global_index(s, i), d))
+            })
+            .collect();
+        knn::Hits::from_vec(k, hits).extract()
+    }
+
+    /// Returns the sample shard, on which the choice of algorithm is tuned.
+    pub const fn tuning_shard(&self) -> &SingleShard<I, U, D> {
+        &self.sample_shard
+    }
+
+    /// Returns the sample shard, on which the choice of algorithm is tuned.
+    pub fn tuning_shard_mut(&mut self) -> &mut SingleShard<I, U, D> {
+        &mut self.sample_shard
+    }
+
+    /// Returns the index of an instance before the shards were reordered.
+    ///
+    /// The original index is the index into the concatenation of the shards,
+    /// each in its order from before its tree was built.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - An index across all shards, as returned by search.
+    ///
+    /// # Errors
+    ///
+    /// * If `index` is not smaller than the total cardinality of the shards.
+    pub fn original_index(&self, index: usize) -> Result<usize, String> {
+        let (s, local_index) = self.locate(index)?;
+        let offset = self.shard_indices(s).0;
+        self.shards()[s].original_index(local_index).map(|i| i + offset)
+    }
+}
+
+impl<I: Instance, U: Number, M: Instance> RandomlySharded<I, U, VecDataset<I, U, M>> {
+    /// Merges runs of consecutive small shards into larger shards.
+    ///
+    /// Starting after the sample shard, consecutive shards with fewer than
+    /// `min_cardinality` instances are merged until the merged shard has at
+    /// least `min_cardinality` instances, and the tree is rebuilt for every
+    /// shard that was merged. Shards with at least `min_cardinality` instances
+    /// are left as they are, so a merged shard may end up smaller than
+    /// `min_cardinality` if it is followed by a large shard. The sample shard is never
+    /// merged. The instances of a merged shard keep the order they had before
+    /// their trees were built, so the original indices do not change. The
+    /// indices that search returned before the merge are recorded for each
+    /// merged shard, so search keeps returning the same index for each
+    /// instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `min_cardinality` - The cardinality below which shards are merged.
+    /// * `seed` - The seed to use for the random number generator.
+    /// * `criteria` - The criteria to use for partitioning the merged trees.
+    pub fn compact(&mut self, min_cardinality: usize, seed: Option<u64>, criteria: &PartitionCriteria<U>) {
+        let mut runs = Vec::<Vec<_>>::new();
+        let mut run_cardinality = min_cardinality;
+        let old_shards = core::mem::take(&mut self.shards);
+        for shard in old_shards.into_iter().zip(core::mem::take(&mut self.issued)) {
+            let cardinality = shard.0.data().cardinality();
+            // A shard that is already large enough is never merged, so it
+            // closes the current run and stays in a run of its own.
+            if run_cardinality >= min_cardinality || cardinality >= min_cardinality {
+                runs.push(Vec::new());
+                run_cardinality = 0;
+            }
+            run_cardinality += cardinality;
+            runs.last_mut()
+                .unwrap_or_else(|| unreachable!("We just pushed a run."))
+                .push(shard);
+        }
+
+        let (shards, issued): (Vec<_>, Vec<_>) = runs
+            .into_par_iter()
+            .map(|mut run| {
+                if run.len() == 1 {
+                    return run.pop().unwrap_or_else(|| unreachable!("The run has one shard."));
+                }
+
+                let (name, metric, is_expensive) = {
+                    let data = run[0].0.data();
+                    (data.name().to_string(), data.metric(), data.is_metric_expensive())
+                };
+
+                // The issued index, relative to the start of the run, of each
+                // instance in the order of the merged dataset.
+                let mut run_ids = Vec::new();
+                let mut pairs = Vec::new();
+                for (shard, issued) in run {
+                    let data = shard.into_data();
+                    let positions = data
+                        .permuted_indices()
+                        .map_or_else(|| (0..data.cardinality()).collect(), utils::inverse_permutation);
+                    let start = run_ids.len();
+                    run_ids.extend(
+                        positions
+                            .into_iter()
+                            .map(|p| start + issued.as_ref().map_or(p, |issued| issued.ids[p])),
+                    );
+                    pairs.extend(unpermuted(data));
+                }
+
+                let (instances, metadata): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+                let data = VecDataset::new(name, instances, metric, is_expensive)
+                    .assign_metadata(metadata)
+                    .unwrap_or_else(|_| unreachable!("There is one metadata item per instance."));
+                let shard = SingleShard::new(data, seed, criteria);
+
+                let ids = shard
+                    .data()
+                    .permuted_indices()
+                    .map_or_else(|| run_ids.clone(), |p| p.iter().map(|&i| run_ids[i]).collect());
+                (shard, Some(IssuedIds::new(ids)))
+            })
+            .unzip();
+
+        self.offsets = shards
+            .iter()
+            .scan(self.sample_shard.data().cardinality(), |o, s| {
+                let offset = *o;
+                o.add_assign(s.data().cardinality());
+                Some(offset)
+            })
+            .collect();
+        self.shards = shards;
+        self.issued = issued;
+    }
+}
+
+/// Returns the instances and metadata of a dataset in their order from before
+/// it was permuted.
+fn unpermuted<I: Instance, U: Number, M: Instance>(data: VecDataset<I, U, M>) -> Vec<(I, M)> {
+    let permutation = data.permuted_indices().map(<[usize]>::to_vec);
+    let metadata = data.metadata().to_vec();
+    let pairs = data.data_owned().into_iter().zip(metadata);
+    match permutation {
+        Some(permutation) => {
+            let mut pairs = permutation.into_iter().zip(pairs).collect::<Vec<_>>();
+            pairs.sort_by_key(|&(i, _)| i);
+            pairs.into_iter().map(|(_, p)| p).collect()
+        }
+        None => pairs.collect(),
+    }
 }
 
 impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for RandomlySharded<I, U, D> {
@@ -95,8 +479,25 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for RandomlyShard
             }
             shard.save(&shard_dir)?;
         }
+        for (i, issued) in self.issued.iter().enumerate() {
+            let path = shards_dir.join(format!("shard_{i}")).join(ISSUED_IDS_FILE);
+            match issued {
+                Some(issued) => utils::write_indices(&path, &issued.ids)?,
+                None if path.exists() => std::fs::remove_file(&path).map_err(|e| e.to_string())?,
+                None => (),
+            }
+        }
 
-        Ok(())
+        // Remove any shards left over from a previous save with more shards.
+        for i in self.shards.len().. {
+            let shard_dir = shards_dir.join(format!("shard_{i}"));
+            if !shard_dir.exists() {
+                break;
+            }
+            std::fs::remove_dir_all(&shard_dir).map_err(|e| format!("Failed to remove directory: {e:?}"))?;
+        }
+
+        self.save_cardinalities(path)
     }
 
     #[allow(clippy::similar_names)]
@@ -116,16 +517,20 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for RandomlyShard
         let mut shards = vec![SingleShard::load(&sample_shard_dir, metric, is_expensive)?];
 
         let shards_dir = path.join("shards");
+        let mut issued = Vec::new();
         for i in 0.. {
             let shard_dir = shards_dir.join(format!("shard_{i}"));
             if !shard_dir.exists() {
                 break;
             }
             let shard = SingleShard::load(&shard_dir, metric, is_expensive)?;
+            issued.push(load_issued_ids(&shard_dir)?.map(IssuedIds::new));
             shards.push(shard);
         }
 
-        Ok(Self::new(shards))
+        let mut sharded = Self::new(shards);
+        sharded.issued = issued;
+        Ok(sharded)
     }
 
     fn num_shards(&self) -> usize {
@@ -149,12 +554,12 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for RandomlyShard
             .chain(
                 self.shards
                     .par_iter()
-                    .zip(self.offsets.par_iter())
-                    .map(|(shard, &o)| {
+                    .enumerate()
+                    .map(|(s, shard)| {
                         shard
                             .rnn_search(query, radius, algo)
                             .into_par_iter()
-                            .map(move |(i, d)| (i + o, d))
+                            .map(move |(i, d)| (self.global_index(s + 1, i), d))
                     })
                     .flatten(),
             )
@@ -165,6 +570,30 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for RandomlyShard
         self.rnn_search(query, radius, rnn::Algorithm::Linear)
     }
 
+    fn rnn_by_index(
+        &self,
+        original_index: usize,
+        radius: U,
+        exclude_duplicates: bool,
+    ) -> Result<Vec<(usize, U)>, String> {
+        let (owner, local_index) = self.shard_of(original_index)?;
+        let shards = self.shards();
+        let index = shards[owner].permuted_index(local_index)?;
+        let query = &shards[owner].data()[index];
+
+        Ok(shards
+            .into_par_iter()
+            .enumerate()
+            .flat_map(|(s, shard)| {
+                let self_index = if s == owner { Some(index) } else { None };
+                shard
+                    .rnn_excluding(query, radius, self_index, exclude_duplicates)
+                    .into_par_iter()
+                    .map(move |(i, d)| (self.global_index(s, i), d))
+            })
+            .collect())
+    }
+
     fn tuned_knn_algorithm(&self) -> knn::Algorithm {
         self.sample_shard.tuned_knn_algorithm()
     }
@@ -173,10 +602,10 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for RandomlyShard
         let initial_hits = self.sample_shard.knn_search(query, k, algo);
         let mut hits_queue = knn::Hits::from_vec(k, initial_hits);
 
-        for (shard, &o) in self.shards.iter().zip(self.offsets.iter()) {
+        for (s, shard) in self.shards.iter().enumerate() {
             let radius = hits_queue.peek();
             let new_hits = shard.rnn_search(query, radius, rnn::Algorithm::Clustered);
-            hits_queue.push_batch(new_hits.into_iter().map(|(i, d)| (i + o, d)));
+            hits_queue.push_batch(new_hits.into_iter().map(|(i, d)| (self.global_index(s + 1, i), d)));
         }
 
         hits_queue.extract()
@@ -194,13 +623,39 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for RandomlyShard
         let initial_hits = self.sample_shard.knn_search(query, k, knn::Algorithm::Linear);
         let mut hits_queue = knn::Hits::from_vec(k, initial_hits);
 
-        for (shard, &o) in self.shards.iter().zip(self.offsets.iter()) {
+        for (s, shard) in self.shards.iter().enumerate() {
             let new_hits = shard.knn_search(query, k, knn::Algorithm::Linear);
-            hits_queue.push_batch(new_hits.into_iter().map(|(i, d)| (i + o, d)));
+            hits_queue.push_batch(new_hits.into_iter().map(|(i, d)| (self.global_index(s + 1, i), d)));
         }
 
         hits_queue.extract()
     }
+
+    fn knn_by_index(
+        &self,
+        original_index: usize,
+        k: usize,
+        exclude_duplicates: bool,
+    ) -> Result<Vec<(usize, U)>, String> {
+        let (owner, local_index) = self.shard_of(original_index)?;
+        let shards = self.shards();
+        let index = shards[owner].permuted_index(local_index)?;
+        let query = &shards[owner].data()[index];
+
+        let hits = shards
+            .into_par_iter()
+            .enumerate()
+            .flat_map(|(s, shard)| {
+                let self_index = if s == owner { Some(index) } else { None };
+                shard
+                    .knn_excluding(query, k, self_index, exclude_duplicates)
+                    .into_par_iter()
+                    .map(move |(i, d)| (self.global_index(s, i), d))
+            })
+            .collect();
+
+        Ok(knn::Hits::from_vec(k, hits).extract())
+    }
 }
 
 #[cfg(test)]
@@ -9,7 +9,7 @@ use rayon::prelude::*;
 
 use crate::{knn, rnn, Cluster, Dataset, Instance, PartitionCriteria, Tree};
 
-use super::Search;
+use super::{AutoLayout, Search, TuningReport};
 
 /// CLAM-Accelerated K-nearest-neighbor Entropy-scaling Search.
 ///
@@ -28,6 +28,12 @@ pub struct SingleShard<I: Instance, U: Number, D: Dataset<I, U>> {
     best_rnn: Option<rnn::Algorithm>,
     /// Best knn-search algorithm.
     best_knn: Option<knn::Algorithm>,
+    /// The layout chosen from a memory budget, if any.
+    auto_layout: Option<AutoLayout>,
+    /// The report from tuning rnn-search on a set of queries, if any.
+    rnn_report: Option<TuningReport>,
+    /// The report from tuning knn-search on a set of queries, if any.
+    knn_report: Option<TuningReport>,
 }
 
 impl<I: Instance, U: Number, D: Dataset<I, U>> SingleShard<I, U, D> {
@@ -43,6 +49,9 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> SingleShard<I, U, D> {
             tree: Tree::new(data, seed).partition(criteria),
             best_rnn: None,
             best_knn: None,
+            auto_layout: None,
+            rnn_report: None,
+            knn_report: None,
         }
     }
 
@@ -51,11 +60,240 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> SingleShard<I, U, D> {
         self.tree.data()
     }
 
+    /// Returns the layout chosen from a memory budget, if any.
+    pub const fn auto_layout(&self) -> Option<&AutoLayout> {
+        self.auto_layout.as_ref()
+    }
+
+    /// Records the layout chosen from a memory budget.
+    pub fn set_auto_layout(&mut self, layout: AutoLayout) {
+        self.auto_layout = Some(layout);
+    }
+
+    /// Moves the dataset out of the shard, discarding the tree.
+    ///
+    /// The dataset stays in the order in which the tree was built.
+    pub fn into_data(self) -> D {
+        self.tree.data
+    }
+
     /// Returns a reference to the tree.
     pub const fn tree(&self) -> &Tree<I, U, D> {
         &self.tree
     }
 
+    /// Returns the index of an instance before the dataset was reordered.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the instance after the dataset was reordered.
+    ///
+    /// # Errors
+    ///
+    /// * If `index` is not a valid index in the dataset.
+    pub fn original_index(&self, index: usize) -> Result<usize, String> {
+        if index < self.data().cardinality() {
+            Ok(self.data().original_index(index))
+        } else {
+            Err(format!(
+                "Index {index} is out of bounds for a dataset with cardinality {}.",
+                self.data().cardinality()
+            ))
+        }
+    }
+
+    /// Returns the index of an instance after the dataset was reordered.
+    ///
+    /// # Arguments
+    ///
+    /// * `original_index` - The index of the instance before the dataset was reordered.
+    ///
+    /// # Errors
+    ///
+    /// * If `original_index` is not a valid index in the dataset.
+    pub fn permuted_index(&self, original_index: usize) -> Result<usize, String> {
+        self.data().permuted_index(original_index).ok_or_else(|| {
+            format!(
+                "Index {original_index} is out of bounds for a dataset with cardinality {}.",
+                self.data().cardinality()
+            )
+        })
+    }
+
+    /// Performs a KNN-Search that leaves out the query itself and, optionally,
+    /// its exact duplicates.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `k` - The number of neighbors to search for.
+    /// * `self_index` - The index of the query in the dataset, if it is in the dataset.
+    /// * `exclude_duplicates` - Whether to leave out instances identical to the query.
+    ///
+    /// # Returns
+    ///
+    /// A vector of 2-tuples containing the index of the instance and its
+    /// distance to the query.
+    pub fn knn_excluding(
+        &self,
+        query: &I,
+        k: usize,
+        self_index: Option<usize>,
+        exclude_duplicates: bool,
+    ) -> Vec<(usize, U)> {
+        knn::greedy_sieve::search_excluding(&self.tree, query, k, self_index, exclude_duplicates)
+    }
+
+    /// Performs an RNN-Search that leaves out the query itself and, optionally,
+    /// its exact duplicates.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radius` - The radius to use for the search.
+    /// * `self_index` - The index of the query in the dataset, if it is in the dataset.
+    /// * `exclude_duplicates` - Whether to leave out instances identical to the query.
+    ///
+    /// # Returns
+    ///
+    /// A vector of 2-tuples containing the index of the instance and its
+    /// distance to the query.
+    pub fn rnn_excluding(
+        &self,
+        query: &I,
+        radius: U,
+        self_index: Option<usize>,
+        exclude_duplicates: bool,
+    ) -> Vec<(usize, U)> {
+        rnn::clustered::search_excluding(&self.tree, query, radius, self_index, exclude_duplicates)
+    }
+
+    /// Performs a KNN-Search that keeps only those instances that pass a filter.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `k` - The number of neighbors to search for.
+    /// * `keep` - Whether the instance at the given index passes the filter.
+    /// * `has_any` - Whether the given `Cluster` may contain any instance that passes the filter.
+    ///
+    /// # Returns
+    ///
+    /// A vector of 2-tuples containing the index of the instance and its
+    /// distance to the query.
+    pub fn knn_filtered<P, C>(&self, query: &I, k: usize, keep: P, has_any: C) -> Vec<(usize, U)>
+    where
+        P: Fn(usize) -> bool,
+        C: Fn(&Cluster<U>) -> bool,
+    {
+        knn::greedy_sieve::search_filtered(&self.tree, query, k, None, false, keep, has_any)
+    }
+
+    /// Performs an RNN-Search that keeps only those instances that pass a filter.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query instance.
+    /// * `radius` - The radius to use for the search.
+    /// * `keep` - Whether the instance at the given index passes the filter.
+    /// * `has_any` - Whether the given `Cluster` may contain any instance that passes the filter.
+    ///
+    /// # Returns
+    ///
+    /// A vector of 2-tuples containing the index of the instance and its
+    /// distance to the query.
+    pub fn rnn_filtered<P, C>(&self, query: &I, radius: U, keep: P, has_any: C) -> Vec<(usize, U)>
+    where
+        P: Fn(usize) -> bool,
+        C: Fn(&Cluster<U>) -> bool,
+    {
+        rnn::clustered::search_filtered(&self.tree, query, radius, keep, has_any)
+    }
+
+    /// Returns the report from tuning rnn-search on a set of queries, if any.
+    pub const fn rnn_tuning_report(&self) -> Option<&TuningReport> {
+        self.rnn_report.as_ref()
+    }
+
+    /// Returns the report from tuning knn-search on a set of queries, if any.
+    pub const fn knn_tuning_report(&self) -> Option<&TuningReport> {
+        self.knn_report.as_ref()
+    }
+
+    /// Finds the best RNN algorithm to use on the given queries.
+    ///
+    /// Every algorithm, including `Linear`, is timed on each query and its
+    /// recall is measured against `Linear`. The fastest algorithm, by mean
+    /// time, whose mean recall is at least `min_recall` is chosen. The full
+    /// report is kept and saved alongside the index.
+    ///
+    /// The queries are supplied by the caller and should resemble those
+    /// expected in use. Instances of the indexed dataset may be used, but they
+    /// will find themselves at distance zero.
+    ///
+    /// # Arguments
+    ///
+    /// * `queries` - The queries to tune on.
+    /// * `radius` - The search radius.
+    /// * `min_recall` - The smallest acceptable mean recall.
+    ///
+    /// # Errors
+    ///
+    /// * If `queries` is empty.
+    pub fn auto_tune_rnn_with_queries(
+        &mut self,
+        queries: &[I],
+        radius: U,
+        min_recall: f64,
+    ) -> Result<&TuningReport, String> {
+        let algorithms = core::iter::once(&rnn::Algorithm::Linear)
+            .chain(rnn::Algorithm::variants())
+            .map(|a| (*a, a.name()))
+            .collect::<Vec<_>>();
+        let (best, report) = TuningReport::run("rnn", radius.as_f64(), &algorithms, queries, min_recall, |a, q| {
+            self.rnn_search(q, radius, a)
+        })?;
+        self.best_rnn = Some(best);
+        Ok(self.rnn_report.insert(report))
+    }
+
+    /// Finds the best KNN algorithm to use on the given queries.
+    ///
+    /// Every algorithm, including `Linear`, is timed on each query and its
+    /// recall is measured against `Linear`. The fastest algorithm, by mean
+    /// time, whose mean recall is at least `min_recall` is chosen. The full
+    /// report is kept and saved alongside the index.
+    ///
+    /// The queries are supplied by the caller and should resemble those
+    /// expected in use. Instances of the indexed dataset may be used, but they
+    /// will find themselves at distance zero.
+    ///
+    /// # Arguments
+    ///
+    /// * `queries` - The queries to tune on.
+    /// * `k` - The number of nearest neighbors to search for.
+    /// * `min_recall` - The smallest acceptable mean recall.
+    ///
+    /// # Errors
+    ///
+    /// * If `queries` is empty.
+    pub fn auto_tune_knn_with_queries(
+        &mut self,
+        queries: &[I],
+        k: usize,
+        min_recall: f64,
+    ) -> Result<&TuningReport, String> {
+        let algorithms = core::iter::once(&knn::Algorithm::Linear)
+            .chain(knn::Algorithm::variants())
+            .map(|a| (*a, a.name()))
+            .collect::<Vec<_>>();
+        let (best, report) = TuningReport::run("knn", k.as_f64(), &algorithms, queries, min_recall, |a, q| {
+            self.knn_search(q, k, a)
+        })?;
+        self.best_knn = Some(best);
+        Ok(self.knn_report.insert(report))
+    }
+
     /// A helper function for sampling query indices for tuning.
     ///
     /// # Arguments
@@ -103,6 +341,18 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for SingleShard<I
         let best_algo_file = path.join("best-algo.txt");
         std::fs::write(best_algo_file, format!("{best_rnn}\n{best_knn}")).map_err(|e| e.to_string())?;
 
+        match &self.auto_layout {
+            Some(layout) => layout.save(path)?,
+            None => AutoLayout::remove(path)?,
+        }
+
+        for (search, report) in [("rnn", &self.rnn_report), ("knn", &self.knn_report)] {
+            match report {
+                Some(report) => report.save(path)?,
+                None => TuningReport::remove(path, search)?,
+            }
+        }
+
         Ok(())
     }
 
@@ -148,10 +398,17 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for SingleShard<I
         let tree_dir = path.join("tree");
         let tree = Tree::<I, U, D>::load(&tree_dir, metric, is_expensive)?;
 
+        let auto_layout = AutoLayout::load(path)?;
+        let rnn_report = TuningReport::load(path, "rnn")?;
+        let knn_report = TuningReport::load(path, "knn")?;
+
         Ok(Self {
             tree,
             best_rnn,
             best_knn,
+            auto_layout,
+            rnn_report,
+            knn_report,
         })
     }
 
@@ -164,6 +421,7 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for SingleShard<I
     }
 
     fn auto_tune_rnn(&mut self, radius: U, tuning_depth: usize) {
+        self.rnn_report = None;
         let queries = self
             .sample_query_indices(tuning_depth)
             .into_iter()
@@ -197,7 +455,18 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for SingleShard<I
         self.rnn_search(query, radius, rnn::Algorithm::Linear)
     }
 
+    fn rnn_by_index(
+        &self,
+        original_index: usize,
+        radius: U,
+        exclude_duplicates: bool,
+    ) -> Result<Vec<(usize, U)>, String> {
+        let index = self.permuted_index(original_index)?;
+        Ok(self.rnn_excluding(&self.data()[index], radius, Some(index), exclude_duplicates))
+    }
+
     fn auto_tune_knn(&mut self, k: usize, tuning_depth: usize) {
+        self.knn_report = None;
         let queries = self
             .sample_query_indices(tuning_depth)
             .into_iter()
@@ -230,4 +499,14 @@ impl<I: Instance, U: Number, D: Dataset<I, U>> Search<I, U, D> for SingleShard<I
     fn linear_knn_search(&self, query: &I, k: usize) -> Vec<(usize, U)> {
         self.knn_search(query, k, knn::Algorithm::Linear)
     }
+
+    fn knn_by_index(
+        &self,
+        original_index: usize,
+        k: usize,
+        exclude_duplicates: bool,
+    ) -> Result<Vec<(usize, U)>, String> {
+        let index = self.permuted_index(original_index)?;
+        Ok(self.knn_excluding(&self.data()[index], k, Some(index), exclude_duplicates))
+    }
 }
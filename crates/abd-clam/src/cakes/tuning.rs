@@ -0,0 +1,207 @@
+//! Tuning the choice of search algorithm on a set of queries.
+
+use std::path::Path;
+
+use distances::Number;
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+/// Summary statistics, in seconds, of the time taken by each query.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Timings {
+    /// The fastest query.
+    pub min: f64,
+    /// The median query.
+    pub p50: f64,
+    /// The 90th percentile.
+    pub p90: f64,
+    /// The 99th percentile.
+    pub p99: f64,
+    /// The slowest query.
+    pub max: f64,
+    /// The mean over all queries.
+    pub mean: f64,
+}
+
+impl Timings {
+    /// Summarizes the times taken by each query.
+    ///
+    /// # Arguments
+    ///
+    /// * `times` - The time, in seconds, taken by each query. Must not be empty.
+    fn from_times(mut times: Vec<f64>) -> Self {
+        times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(core::cmp::Ordering::Greater));
+        Self {
+            min: times[0],
+            p50: utils::percentile(&times, 50).unwrap_or_default(),
+            p90: utils::percentile(&times, 90).unwrap_or_default(),
+            p99: utils::percentile(&times, 99).unwrap_or_default(),
+            max: times[times.len() - 1],
+            mean: times.iter().sum::<f64>() / times.len().as_f64(),
+        }
+    }
+}
+
+/// How one algorithm fared on the tuning queries.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AlgorithmReport {
+    /// The name of the algorithm.
+    pub algorithm: String,
+    /// The time taken by each query.
+    pub timings: Timings,
+    /// The mean recall over the queries, measured against linear search.
+    pub recall: f64,
+}
+
+/// The results of tuning the choice of RNN or KNN search algorithm.
+///
+/// This is saved as JSON alongside the index so that the choice of algorithm
+/// can be inspected later.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TuningReport {
+    /// The kind of search that was tuned, either `rnn` or `knn`.
+    pub search: String,
+    /// The search radius for RNN search, or `k` for KNN search.
+    pub parameter: f64,
+    /// The number of queries used for tuning.
+    pub num_queries: usize,
+    /// The smallest mean recall an algorithm needed to be chosen.
+    pub min_recall: f64,
+    /// The results for each algorithm, in the order they were run.
+    pub algorithms: Vec<AlgorithmReport>,
+    /// The name of the chosen algorithm.
+    pub best: String,
+}
+
+impl TuningReport {
+    /// Runs every algorithm on every query and chooses the algorithm with the
+    /// lowest mean time among those whose mean recall is at least `min_recall`.
+    ///
+    /// Queries are run one at a time so that the timings are not skewed by
+    /// contention between threads.
+    ///
+    /// # Arguments
+    ///
+    /// * `search` - The kind of search being tuned.
+    /// * `parameter` - The search radius or `k`.
+    /// * `algorithms` - The algorithms, with their names. The first one must be
+    ///   linear search.
+    /// * `queries` - The queries to tune on.
+    /// * `min_recall` - The smallest acceptable mean recall.
+    /// * `run` - Runs one algorithm on one query.
+    ///
+    /// # Returns
+    ///
+    /// The chosen algorithm and the report.
+    ///
+    /// # Errors
+    ///
+    /// * If `queries` is empty.
+    pub(crate) fn run<A, Q, U, F>(
+        search: &str,
+        parameter: f64,
+        algorithms: &[(A, &str)],
+        queries: &[Q],
+        min_recall: f64,
+        run: F,
+    ) -> Result<(A, Self), String>
+    where
+        A: Copy,
+        U: Number,
+        F: Fn(A, &Q) -> Vec<(usize, U)>,
+    {
+        if queries.is_empty() {
+            return Err("At least one query is needed for tuning.".to_string());
+        }
+
+        let (linear, _) = algorithms[0];
+        let expected = queries.iter().map(|q| run(linear, q)).collect::<Vec<_>>();
+
+        let reports = algorithms
+            .iter()
+            .map(|&(algo, name)| {
+                let (times, recalls): (Vec<_>, Vec<_>) = queries
+                    .iter()
+                    .zip(expected.iter())
+                    .map(|(q, expected)| {
+                        let start = std::time::Instant::now();
+                        let hits = run(algo, q);
+                        let elapsed = start.elapsed().as_secs_f64();
+                        (elapsed, utils::recall(hits, expected.clone()))
+                    })
+                    .unzip();
+                AlgorithmReport {
+                    algorithm: name.to_string(),
+                    timings: Timings::from_times(times),
+                    recall: recalls.iter().sum::<f64>() / recalls.len().as_f64(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let (best, _) = reports
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.recall >= min_recall)
+            .min_by(|(_, a), (_, b)| {
+                a.timings
+                    .mean
+                    .partial_cmp(&b.timings.mean)
+                    .unwrap_or(core::cmp::Ordering::Greater)
+            })
+            .unwrap_or((0, &reports[0]));
+
+        let report = Self {
+            search: search.to_string(),
+            parameter,
+            num_queries: queries.len(),
+            min_recall,
+            best: reports[best].algorithm.clone(),
+            algorithms: reports,
+        };
+        Ok((algorithms[best].0, report))
+    }
+
+    /// Saves the report as JSON in the given directory.
+    ///
+    /// # Errors
+    ///
+    /// * If the file cannot be written.
+    pub(crate) fn save(&self, dir: &Path) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(dir.join(Self::file_name(&self.search)), contents).map_err(|e| e.to_string())
+    }
+
+    /// Loads the report for the given kind of search from the given
+    /// directory, if it was saved there.
+    ///
+    /// # Errors
+    ///
+    /// * If the file exists but cannot be read or parsed.
+    pub(crate) fn load(dir: &Path, search: &str) -> Result<Option<Self>, String> {
+        let path = dir.join(Self::file_name(search));
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&contents).map(Some).map_err(|e| e.to_string())
+    }
+
+    /// Removes a previously saved report for the given kind of search.
+    ///
+    /// # Errors
+    ///
+    /// * If the file exists but cannot be removed.
+    pub(crate) fn remove(dir: &Path, search: &str) -> Result<(), String> {
+        let path = dir.join(Self::file_name(search));
+        if path.exists() {
+            std::fs::remove_file(path).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// The name of the file in which the report for a kind of search is saved.
+    fn file_name(search: &str) -> String {
+        format!("{search}-tuning.json")
+    }
+}
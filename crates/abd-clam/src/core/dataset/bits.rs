@@ -0,0 +1,236 @@
+//! A bit-packed binary vector.
+
+use distances::{
+    number::{Float, UInt},
+    Number,
+};
+
+use super::Instance;
+
+/// The number of bits in a word.
+const WORD_BITS: usize = 64;
+
+/// A binary vector, packed 64 bits to a `u64` word.
+///
+/// Bit `i` is stored in bit `i % 64` of word `i / 64`, and the padding bits
+/// past the end of the vector are always zero. This uses one bit per element,
+/// instead of the 8 to 64 bits of a `Vec` of numbers, and the distance
+/// functions in `distances::bits` count bits a whole word at a time. They are
+/// exposed as associated functions, so that they can be passed as the metric
+/// of a `VecDataset`, e.g. `BitVector::hamming::<u32>`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BitVector {
+    /// The packed bits.
+    words: Vec<u64>,
+    /// The number of bits in the vector.
+    len: usize,
+}
+
+impl BitVector {
+    /// Creates a vector of `len` bits, all zero.
+    #[must_use]
+    pub fn zeros(len: usize) -> Self {
+        Self {
+            words: vec![0; num_words(len)],
+            len,
+        }
+    }
+
+    /// Creates a vector from packed words.
+    ///
+    /// # Arguments
+    ///
+    /// * `words`: The packed bits.
+    /// * `len`: The number of bits in the vector.
+    ///
+    /// # Errors
+    ///
+    /// * If the number of words does not match `len`.
+    /// * If any padding bits past `len` are set.
+    pub fn from_words(words: Vec<u64>, len: usize) -> Result<Self, String> {
+        if words.len() != num_words(len) {
+            return Err(format!(
+                "Expected {} words for {len} bits, got {}",
+                num_words(len),
+                words.len()
+            ));
+        }
+        let padding = words.last().map_or(0, |&w| w & !last_word_mask(len));
+        if padding != 0 {
+            return Err(format!("Bits past the end of a vector of {len} bits must be zero"));
+        }
+        Ok(Self { words, len })
+    }
+
+    /// Creates a vector from a slice of `bool`s.
+    #[must_use]
+    pub fn from_bools(bits: &[bool]) -> Self {
+        let words = bits
+            .chunks(WORD_BITS)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0, |w, (i, &b)| w | (<u64 as From<bool>>::from(b) << i))
+            })
+            .collect();
+        Self { words, len: bits.len() }
+    }
+
+    /// Returns the bits as a `Vec` of `bool`s.
+    #[must_use]
+    pub fn to_bools(&self) -> Vec<bool> {
+        (0..self.len).map(|i| self.get(i)).collect()
+    }
+
+    /// Returns the bit at index `i`, or `false` if `i` is out of bounds.
+    #[must_use]
+    pub fn get(&self, i: usize) -> bool {
+        i < self.len && (self.words[i / WORD_BITS] >> (i % WORD_BITS)) & 1 == 1
+    }
+
+    /// Sets the bit at index `i`.
+    ///
+    /// # Panics
+    ///
+    /// * If `i` is out of bounds.
+    pub fn set(&mut self, i: usize, value: bool) {
+        assert!(i < self.len, "Index {i} is out of bounds for {} bits", self.len);
+        let mask = 1 << (i % WORD_BITS);
+        if value {
+            self.words[i / WORD_BITS] |= mask;
+        } else {
+            self.words[i / WORD_BITS] &= !mask;
+        }
+    }
+
+    /// Returns the packed words.
+    #[must_use]
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    /// Returns the number of bits in the vector.
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the vector has no bits.
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of bits that are set.
+    #[must_use]
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// The Hamming distance between two binary vectors.
+    ///
+    /// # Panics
+    ///
+    /// * If the vectors have different lengths.
+    #[must_use]
+    pub fn hamming<U: UInt>(x: &Self, y: &Self) -> U {
+        check_lengths(x, y);
+        distances::bits::hamming(&x.words, &y.words)
+    }
+
+    /// The Jaccard distance between two binary vectors. If either vector has
+    /// no bits set, the distance is one.
+    ///
+    /// # Panics
+    ///
+    /// * If the vectors have different lengths.
+    #[must_use]
+    pub fn jaccard<U: Float>(x: &Self, y: &Self) -> U {
+        check_lengths(x, y);
+        distances::bits::jaccard(&x.words, &y.words)
+    }
+
+    /// The Tanimoto distance between two binary vectors. This is the same as
+    /// the Jaccard distance.
+    ///
+    /// # Panics
+    ///
+    /// * If the vectors have different lengths.
+    #[must_use]
+    pub fn tanimoto<U: Float>(x: &Self, y: &Self) -> U {
+        check_lengths(x, y);
+        distances::bits::tanimoto(&x.words, &y.words)
+    }
+
+    /// The Dice distance between two binary vectors. If either vector has no
+    /// bits set, the distance is one.
+    ///
+    /// This is not a metric, since it does not satisfy the triangle
+    /// inequality. `Cakes` searches with it may miss some neighbors.
+    ///
+    /// # Panics
+    ///
+    /// * If the vectors have different lengths.
+    #[must_use]
+    pub fn dice<U: Float>(x: &Self, y: &Self) -> U {
+        check_lengths(x, y);
+        distances::bits::dice(&x.words, &y.words)
+    }
+}
+
+impl Instance for BitVector {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.len.to_le_bytes().to_vec();
+        bytes.extend(self.words.iter().flat_map(|w| w.to_le_bytes()));
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let len_bytes = <usize as Number>::num_bytes();
+        if bytes.len() < len_bytes {
+            return Err(format!("Expected at least {len_bytes} bytes, got {}", bytes.len()));
+        }
+        let (len, words) = bytes.split_at(len_bytes);
+        let len = <usize as Number>::from_le_bytes(len);
+        if words.len() % 8 != 0 {
+            return Err(format!(
+                "Expected a multiple of 8 bytes for the words, got {}",
+                words.len()
+            ));
+        }
+        let words = words.chunks_exact(8).map(<u64 as Number>::from_le_bytes).collect();
+        Self::from_words(words, len)
+    }
+
+    fn type_name() -> String {
+        "BitVector".to_string()
+    }
+}
+
+/// Checks that two vectors, about to be compared, have the same length.
+///
+/// # Panics
+///
+/// * If the vectors have different lengths.
+fn check_lengths(x: &BitVector, y: &BitVector) {
+    assert_eq!(
+        x.len, y.len,
+        "Cannot compare binary vectors of {} and {} bits",
+        x.len, y.len
+    );
+}
+
+/// Returns the number of words needed for `len` bits.
+const fn num_words(len: usize) -> usize {
+    (len + WORD_BITS - 1) / WORD_BITS
+}
+
+/// Returns the mask of the bits of the last word that are within a vector of
+/// `len` bits.
+const fn last_word_mask(len: usize) -> u64 {
+    match len % WORD_BITS {
+        0 => u64::MAX,
+        r => (1 << r) - 1,
+    }
+}
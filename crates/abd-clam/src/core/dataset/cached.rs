@@ -0,0 +1,314 @@
+//! A wrapper around a `Dataset` that memoizes the distances between its
+//! instances.
+
+use core::{hash::BuildHasher, ops::Index};
+
+use std::{
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+};
+
+use distances::Number;
+use rayon::prelude::*;
+
+use super::{Dataset, Instance};
+
+/// The number of independently locked shards of the cache.
+const NUM_SHARDS: usize = 16;
+
+/// The capacity of the cache of a `CachedDataset` created by `load`.
+const DEFAULT_CAPACITY: usize = 1 << 20;
+
+/// Statistics on the use of the cache of a `CachedDataset`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct CacheStats {
+    /// The number of distances that were found in the cache.
+    pub hits: usize,
+    /// The number of distances that had to be computed.
+    pub misses: usize,
+    /// The number of distances that were evicted to make room for others.
+    pub evictions: usize,
+    /// The number of distances currently in the cache.
+    pub len: usize,
+}
+
+impl CacheStats {
+    /// Returns the fraction of lookups that were found in the cache, or zero
+    /// if there were no lookups.
+    #[must_use]
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits.as_f64() / lookups.as_f64()
+        }
+    }
+}
+
+/// One independently locked part of the cache, which evicts its oldest
+/// distances first.
+#[derive(Debug, Default)]
+struct Shard<U> {
+    /// The cached distances.
+    distances: HashMap<(usize, usize), U>,
+    /// The keys of the cached distances, from oldest to newest.
+    order: VecDeque<(usize, usize)>,
+}
+
+impl<U> Shard<U> {
+    /// Inserts a distance, evicting the oldest distance if the shard holds
+    /// more than `capacity` distances. Returns whether a distance was evicted.
+    fn insert(&mut self, key: (usize, usize), distance: U, capacity: usize) -> bool {
+        if self.distances.insert(key, distance).is_some() {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.distances.remove(&oldest);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// A `Dataset` that memoizes the distances between pairs of its instances.
+///
+/// Building a tree computes many distances more than once, e.g. the distances
+/// from the center and the poles of a `Cluster` are computed when it is
+/// partitioned, and again when its children are created. When the metric is
+/// expensive, wrapping the dataset in a `CachedDataset` avoids recomputing
+/// them. The tree built on the wrapper is the same as the tree built on the
+/// wrapped dataset.
+///
+/// The cache holds at most `capacity` distances and is split into shards that
+/// are locked independently, so that it may be used from many threads. When a
+/// shard is full, its oldest distance is evicted. Distances are keyed by the
+/// identities of the instances, not their indices, so the cache stays valid
+/// when the dataset is permuted. The metric is assumed to be symmetric.
+///
+/// Only distances between instances in the dataset are cached. Distances from
+/// queries, i.e. `query_to_one` and `query_to_many`, are passed through to the
+/// wrapped dataset.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct CachedDataset<U: Number, D> {
+    /// The wrapped dataset.
+    data: D,
+    /// The identity of the instance at each index.
+    ids: Vec<usize>,
+    /// The maximum number of distances in each shard.
+    shard_capacity: usize,
+    /// The shards of the cache.
+    shards: Vec<Mutex<Shard<U>>>,
+    /// Used to assign keys to shards.
+    hasher: RandomState,
+    /// The number of cache hits.
+    hits: AtomicUsize,
+    /// The number of cache misses.
+    misses: AtomicUsize,
+    /// The number of evicted distances.
+    evictions: AtomicUsize,
+}
+
+impl<U: Number, D> CachedDataset<U, D> {
+    /// Wraps a dataset in a cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `data`: The dataset to wrap.
+    /// * `capacity`: The maximum number of distances to cache. With a capacity
+    ///   of zero, nothing is cached.
+    pub fn new<I: Instance>(data: D, capacity: usize) -> Self
+    where
+        D: Dataset<I, U>,
+    {
+        Self {
+            ids: (0..data.cardinality()).collect(),
+            data,
+            shard_capacity: (capacity + NUM_SHARDS - 1) / NUM_SHARDS,
+            shards: (0..NUM_SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            evictions: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the wrapped dataset.
+    pub const fn inner(&self) -> &D {
+        &self.data
+    }
+
+    /// Unwraps the dataset, dropping the cache.
+    pub fn into_inner(self) -> D {
+        self.data
+    }
+
+    /// Returns the maximum number of distances in the cache.
+    pub const fn capacity(&self) -> usize {
+        self.shard_capacity * NUM_SHARDS
+    }
+
+    /// Returns statistics on the use of the cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            len: self.shards.iter().map(|s| lock(s).distances.len()).sum(),
+        }
+    }
+
+    /// Empties the cache and resets its statistics.
+    pub fn clear_cache(&self) {
+        for shard in &self.shards {
+            let mut shard = lock(shard);
+            shard.distances.clear();
+            shard.order.clear();
+        }
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+        self.evictions.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns the cached distance between the instances at two indices, or
+    /// computes and caches it with `distance`.
+    fn get_or_insert(&self, left: usize, right: usize, distance: impl FnOnce() -> U) -> U {
+        let (a, b) = (self.ids[left], self.ids[right]);
+        let key = if a <= b { (a, b) } else { (b, a) };
+        let shard = &self.shards[self.shard_of(key)];
+
+        let cached = lock(shard).distances.get(&key).copied();
+        if let Some(d) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return d;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // The lock is not held while computing the distance, so another thread
+        // may compute and insert the same distance in the meantime.
+        let d = distance();
+        if self.shard_capacity > 0 && lock(shard).insert(key, d, self.shard_capacity) {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        d
+    }
+
+    /// Returns the index of the shard that holds a key.
+    fn shard_of(&self, key: (usize, usize)) -> usize {
+        #[allow(clippy::cast_possible_truncation)]
+        let index = self.hasher.hash_one(key) as usize;
+        index % NUM_SHARDS
+    }
+}
+
+/// Locks a shard, ignoring poisoning since a shard is always left consistent.
+fn lock<U>(shard: &Mutex<Shard<U>>) -> MutexGuard<'_, Shard<U>> {
+    shard.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<U: Number, D: Index<usize>> Index<usize> for CachedDataset<U, D> {
+    type Output = D::Output;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        self.data.index(index)
+    }
+}
+
+impl<I: Instance, U: Number, D: Dataset<I, U>> Dataset<I, U> for CachedDataset<U, D> {
+    fn type_name() -> String {
+        D::type_name()
+    }
+
+    fn name(&self) -> &str {
+        self.data.name()
+    }
+
+    fn cardinality(&self) -> usize {
+        self.data.cardinality()
+    }
+
+    fn is_metric_expensive(&self) -> bool {
+        self.data.is_metric_expensive()
+    }
+
+    fn metric(&self) -> fn(&I, &I) -> U {
+        self.data.metric()
+    }
+
+    fn has_metric(&self) -> bool {
+        self.data.has_metric()
+    }
+
+    fn set_permuted_indices(&mut self, indices: Option<&[usize]>) {
+        self.data.set_permuted_indices(indices);
+    }
+
+    fn swap(&mut self, left: usize, right: usize) -> Result<(), String> {
+        self.data.swap(left, right)?;
+        self.ids.swap(left, right);
+        Ok(())
+    }
+
+    fn permuted_indices(&self) -> Option<&[usize]> {
+        self.data.permuted_indices()
+    }
+
+    fn original_index(&self, index: usize) -> usize {
+        self.data.original_index(index)
+    }
+
+    fn permuted_index(&self, original_index: usize) -> Option<usize> {
+        self.data.permuted_index(original_index)
+    }
+
+    fn permute_instances(&mut self, permutation: &[usize]) -> Result<(), String> {
+        self.data.permute_instances(permutation)?;
+        self.ids = permutation.iter().map(|&index| self.ids[index]).collect();
+        Ok(())
+    }
+
+    fn one_to_one(&self, left: usize, right: usize) -> U {
+        self.get_or_insert(left, right, || self.data.one_to_one(left, right))
+    }
+
+    fn one_to_many(&self, left: usize, right: &[usize]) -> Vec<U> {
+        if self.is_metric_expensive() {
+            right.par_iter().map(|&r| self.one_to_one(left, r)).collect()
+        } else {
+            right.iter().map(|&r| self.one_to_one(left, r)).collect()
+        }
+    }
+
+    fn query_to_one(&self, query: &I, index: usize) -> U {
+        self.data.query_to_one(query, index)
+    }
+
+    /// Each shard is wrapped in its own empty cache. The capacity is divided
+    /// among the shards, rounding up, so that together they hold about as many
+    /// distances as this cache.
+    fn make_shards(self, max_cardinality: usize) -> Vec<Self> {
+        let capacity = self.capacity();
+        let shards = self.data.make_shards(max_cardinality);
+        let shard_capacity = (capacity + shards.len().max(1) - 1) / shards.len().max(1);
+        shards.into_iter().map(|data| Self::new(data, shard_capacity)).collect()
+    }
+
+    fn save(&self, path: &Path) -> Result<(), String> {
+        self.data.save(path)
+    }
+
+    /// Loads the wrapped dataset and wraps it in an empty cache with a
+    /// capacity of 2^20 distances.
+    fn load(path: &Path, metric: fn(&I, &I) -> U, is_expensive: bool) -> Result<Self, String> {
+        D::load(path, metric, is_expensive).map(|data| Self::new(data, DEFAULT_CAPACITY))
+    }
+}
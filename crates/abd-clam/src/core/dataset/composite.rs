@@ -0,0 +1,202 @@
+//! Instances made of several fields, and metrics that combine the distances
+//! between their fields.
+
+use distances::Number;
+
+use super::Instance;
+use crate::utils::{decode_fields, encode_fields};
+
+/// Implements `Instance` for tuples of instances.
+macro_rules! impl_instance_tuple {
+    ($num_fields:literal; $($name:ident: $idx:tt),+) => {
+        impl<$($name: Instance),+> Instance for ($($name,)+) {
+            fn to_bytes(&self) -> Vec<u8> {
+                encode_fields(&[$(self.$idx.to_bytes()),+])
+            }
+
+            fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+                let mut fields = decode_fields(bytes, $num_fields)?.into_iter();
+                Ok(($($name::from_bytes(fields.next().unwrap_or_default())?,)+))
+            }
+
+            fn type_name() -> String {
+                format!("({})", [$($name::type_name()),+].join(", "))
+            }
+        }
+    };
+}
+
+impl_instance_tuple!(1; A: 0);
+impl_instance_tuple!(2; A: 0, B: 1);
+impl_instance_tuple!(3; A: 0, B: 1, C: 2);
+impl_instance_tuple!(4; A: 0, B: 1, C: 2, D: 3);
+impl_instance_tuple!(5; A: 0, B: 1, C: 2, D: 3, E: 4);
+impl_instance_tuple!(6; A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+
+/// Implements `Instance` for a struct whose fields are all instances.
+///
+/// The struct must also implement `Debug` and `Clone`, which may be derived.
+/// Generic structs are not supported.
+///
+/// # Examples
+///
+/// ```
+/// use abd_clam::{impl_instance, Instance};
+///
+/// #[derive(Clone, Debug, PartialEq)]
+/// struct Record {
+///     embedding: Vec<f32>,
+///     name: String,
+///     tags: Vec<u32>,
+/// }
+///
+/// impl_instance!(Record { embedding, name, tags });
+///
+/// let record = Record {
+///     embedding: vec![0.5, 1.5],
+///     name: "a".to_string(),
+///     tags: vec![1, 2, 3],
+/// };
+/// assert_eq!(Record::from_bytes(&record.to_bytes()).unwrap(), record);
+/// ```
+#[macro_export]
+macro_rules! impl_instance {
+    ($ty:ident { $($field:ident),+ $(,)? }) => {
+        impl $crate::Instance for $ty {
+            fn to_bytes(&self) -> Vec<u8> {
+                $crate::utils::encode_fields(&[$($crate::Instance::to_bytes(&self.$field)),+])
+            }
+
+            fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+                let num_fields = [$(stringify!($field)),+].len();
+                let mut fields = $crate::utils::decode_fields(bytes, num_fields)?.into_iter();
+                Ok(Self {
+                    $($field: $crate::Instance::from_bytes(fields.next().unwrap_or_default())?),+
+                })
+            }
+
+            fn type_name() -> String {
+                stringify!($ty).to_string()
+            }
+        }
+    };
+}
+
+/// How a `CompositeMetric` combines the distances between fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Combination {
+    /// The sum of the weighted distances.
+    WeightedSum,
+    /// The maximum of the weighted distances.
+    WeightedMax,
+}
+
+/// The distance function of one field of a composite instance.
+type FieldMetric<I, U> = Box<dyn Fn(&I, &I) -> U + Send + Sync>;
+
+/// A metric on composite instances that combines the distances between their
+/// fields.
+///
+/// Each field is given by a function that borrows it from an instance, a
+/// distance function on that field, and a non-negative weight. If every field
+/// metric is a metric, then so is their weighted sum or maximum.
+///
+/// A `VecDataset` needs a function pointer as its metric, so a composite metric
+/// is usually stored in a `static` and called from a plain function, e.g.
+///
+/// ```
+/// use std::sync::OnceLock;
+///
+/// use abd_clam::CompositeMetric;
+///
+/// type Record = (Vec<f32>, String);
+///
+/// static METRIC: OnceLock<CompositeMetric<Record, f32>> = OnceLock::new();
+///
+/// fn metric(x: &Record, y: &Record) -> f32 {
+///     METRIC
+///         .get_or_init(|| {
+///             CompositeMetric::weighted_sum()
+///                 .with_field(1.0, |r: &Record| r.0.as_slice(), distances::vectors::euclidean)
+///                 .with_field(0.1, |r: &Record| r.1.as_str(), |x: &str, y: &str| {
+///                     distances::strings::levenshtein::<u32>(x, y) as f32
+///                 })
+///         })
+///         .distance(x, y)
+/// }
+///
+/// let x = (vec![0.0, 0.0], "abc".to_string());
+/// let y = (vec![3.0, 4.0], "abd".to_string());
+/// assert!((metric(&x, &y) - 5.1).abs() < 1e-6);
+/// ```
+pub struct CompositeMetric<I, U: Number> {
+    /// How the distances between fields are combined.
+    combination: Combination,
+    /// The weight and distance function of each field.
+    fields: Vec<(U, FieldMetric<I, U>)>,
+}
+
+impl<I, U: Number> CompositeMetric<I, U> {
+    /// Creates a metric with no fields, which combines the distances between
+    /// fields in the given way.
+    #[must_use]
+    pub fn new(combination: Combination) -> Self {
+        Self {
+            combination,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Creates a metric that sums the weighted distances between fields.
+    #[must_use]
+    pub fn weighted_sum() -> Self {
+        Self::new(Combination::WeightedSum)
+    }
+
+    /// Creates a metric that takes the maximum of the weighted distances
+    /// between fields.
+    #[must_use]
+    pub fn weighted_max() -> Self {
+        Self::new(Combination::WeightedMax)
+    }
+
+    /// Adds a field to the metric.
+    ///
+    /// # Arguments
+    ///
+    /// * `weight`: The weight of the distance between the fields.
+    /// * `field`: A function that borrows the field from an instance.
+    /// * `metric`: The distance function on the field.
+    #[must_use]
+    pub fn with_field<F>(mut self, weight: U, field: fn(&I) -> &F, metric: fn(&F, &F) -> U) -> Self
+    where
+        I: 'static,
+        U: 'static,
+        F: ?Sized + 'static,
+    {
+        self.fields
+            .push((weight, Box::new(move |x: &I, y: &I| metric(field(x), field(y)))));
+        self
+    }
+
+    /// Returns how the distances between fields are combined.
+    #[must_use]
+    pub const fn combination(&self) -> Combination {
+        self.combination
+    }
+
+    /// Returns the number of fields in the metric.
+    #[must_use]
+    pub fn num_fields(&self) -> usize {
+        self.fields.len()
+    }
+
+    /// Computes the distance between two instances.
+    pub fn distance(&self, x: &I, y: &I) -> U {
+        let distances = self.fields.iter().map(|(w, metric)| *w * metric(x, y));
+        match self.combination {
+            Combination::WeightedSum => distances.fold(U::zero(), |acc, d| acc + d),
+            Combination::WeightedMax => distances.fold(U::zero(), |acc, d| if d > acc { d } else { acc }),
+        }
+    }
+}
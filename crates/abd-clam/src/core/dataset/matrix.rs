@@ -0,0 +1,363 @@
+//! A dataset backed by a precomputed matrix of pairwise distances.
+
+use core::ops::Index;
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+    sync::Arc,
+};
+
+use distances::Number;
+
+use super::{Dataset, Instance};
+
+/// How the distances of a `MatrixDataset` are stored.
+#[derive(Debug)]
+enum Storage<U: Number> {
+    /// A square matrix in row-major order. It need not be symmetric.
+    Square(Vec<U>),
+    /// The upper triangle of a symmetric matrix with a zero diagonal, in
+    /// row-major order, as produced by `scipy.spatial.distance.pdist`.
+    Condensed(Vec<U>),
+}
+
+/// A `Dataset` whose distances are read from a precomputed matrix.
+///
+/// The instances are identifiers: the instance at each index is the row of the
+/// matrix that holds its distances, i.e. its original index. Permuting the
+/// dataset reorders the identifiers but leaves the matrix untouched.
+///
+/// Distances can only be read, not computed, so queries must be identifiers
+/// too. An in-sample query is the identifier of an instance. Out-of-sample
+/// queries must come with their distances to every instance, which are added
+/// with `add_query` and are given identifiers starting at the size of the
+/// matrix.
+///
+/// Since a `MatrixDataset` has no function to compute distances, its `metric`
+/// must not be called, and `has_metric` is `false`. `Tree`, `Graph` and the
+/// `Cakes` searches read distances through `one_to_one` and `query_to_one` and
+/// so work as usual. The grouped batch searches of `Cakes`, which compare
+/// queries to each other, fall back to searching for each query on its own.
+#[derive(Debug)]
+pub struct MatrixDataset<U: Number> {
+    /// The name of the dataset.
+    name: String,
+    /// The number of rows in the matrix.
+    size: usize,
+    /// The stored distances, shared between shards.
+    matrix: Arc<Storage<U>>,
+    /// The identifier of the instance at each index.
+    ids: Vec<usize>,
+    /// The distances from each out-of-sample query to every instance.
+    queries: Vec<Vec<U>>,
+    /// The reordering of the dataset after building the tree.
+    permuted_indices: Option<Vec<usize>>,
+    /// The inverse of `permuted_indices`.
+    inverse_indices: Option<Vec<usize>>,
+}
+
+impl<U: Number> MatrixDataset<U> {
+    /// Creates a dataset from a square matrix of distances.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the dataset.
+    /// * `matrix`: The matrix, where `matrix[i][j]` is the distance from
+    ///   instance `i` to instance `j`.
+    ///
+    /// # Errors
+    ///
+    /// * If the matrix is not square.
+    pub fn from_square(name: String, matrix: Vec<Vec<U>>) -> Result<Self, String> {
+        let size = matrix.len();
+        if let Some((i, row)) = matrix.iter().enumerate().find(|(_, row)| row.len() != size) {
+            return Err(format!(
+                "Expected a square matrix with {size} columns, but row {i} has {} columns",
+                row.len()
+            ));
+        }
+        let matrix = Storage::Square(matrix.into_iter().flatten().collect());
+        Ok(Self::new(name, size, matrix))
+    }
+
+    /// Creates a dataset from a condensed distance matrix.
+    ///
+    /// The condensed form holds the upper triangle of a symmetric matrix with a
+    /// zero diagonal, in row-major order. This is the output of
+    /// `scipy.spatial.distance.pdist`, and needs about half the memory of the
+    /// square form.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the dataset.
+    /// * `condensed`: The distances `d(i, j)` for all `i < j`.
+    ///
+    /// # Errors
+    ///
+    /// * If the length of `condensed` is not `n * (n - 1) / 2` for any `n`.
+    pub fn from_condensed(name: String, condensed: Vec<U>) -> Result<Self, String> {
+        // Solve `n * (n - 1) / 2 = len` for `n`, then check the solution.
+        let size = (0..=condensed.len() + 1)
+            .find(|&n: &usize| n * n.saturating_sub(1) / 2 >= condensed.len())
+            .unwrap_or_else(|| unreachable!("`len + 1` is always large enough."));
+        if size * size.saturating_sub(1) / 2 == condensed.len() {
+            Ok(Self::new(name, size, Storage::Condensed(condensed)))
+        } else {
+            Err(format!(
+                "A condensed matrix must have n * (n - 1) / 2 elements, but {} is not of that form",
+                condensed.len()
+            ))
+        }
+    }
+
+    /// Creates a dataset over all rows of the matrix.
+    fn new(name: String, size: usize, matrix: Storage<U>) -> Self {
+        Self {
+            name,
+            size,
+            matrix: Arc::new(matrix),
+            ids: (0..size).collect(),
+            queries: Vec::new(),
+            permuted_indices: None,
+            inverse_indices: None,
+        }
+    }
+
+    /// Adds an out-of-sample query.
+    ///
+    /// # Arguments
+    ///
+    /// * `distances`: The distance from the query to each row of the matrix,
+    ///   in the original order of the rows.
+    ///
+    /// # Returns
+    ///
+    /// The identifier to use for the query in searches.
+    ///
+    /// # Errors
+    ///
+    /// * If there is not one distance for each row of the matrix.
+    pub fn add_query(&mut self, distances: Vec<U>) -> Result<usize, String> {
+        if distances.len() == self.size {
+            self.queries.push(distances);
+            Ok(self.size + self.queries.len() - 1)
+        } else {
+            Err(format!(
+                "Expected {} distances for the query, got {}",
+                self.size,
+                distances.len()
+            ))
+        }
+    }
+
+    /// Returns the number of rows in the matrix. This is the cardinality of the
+    /// dataset unless it was split into shards.
+    #[must_use]
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the number of out-of-sample queries that were added.
+    #[must_use]
+    pub fn num_queries(&self) -> usize {
+        self.queries.len()
+    }
+
+    /// Returns the distance between two identifiers.
+    ///
+    /// # Panics
+    ///
+    /// * If `y` is not the identifier of a row of the matrix, or if `x` is not
+    ///   the identifier of a row or of an out-of-sample query.
+    #[must_use]
+    pub fn distance(&self, x: usize, y: usize) -> U {
+        if x >= self.size {
+            return self.queries[x - self.size][y];
+        }
+        match self.matrix.as_ref() {
+            Storage::Square(values) => values[x * self.size + y],
+            Storage::Condensed(values) => match x.cmp(&y) {
+                core::cmp::Ordering::Equal => U::zero(),
+                core::cmp::Ordering::Less => values[condensed_index(self.size, x, y)],
+                core::cmp::Ordering::Greater => values[condensed_index(self.size, y, x)],
+            },
+        }
+    }
+}
+
+/// Returns the index in a condensed matrix of `n` rows of the distance between
+/// rows `i` and `j`, where `i < j`.
+const fn condensed_index(n: usize, i: usize, j: usize) -> usize {
+    n * i - i * (i + 1) / 2 + (j - i - 1)
+}
+
+/// The metric of a `MatrixDataset`, which cannot compute distances.
+///
+/// Nothing in this crate calls it, since `has_metric` is `false`.
+///
+/// # Panics
+///
+/// * Always, since there is no function to compute distances.
+#[allow(clippy::panic, clippy::trivially_copy_pass_by_ref)]
+fn no_metric<U: Number>(_: &usize, _: &usize) -> U {
+    panic!("A MatrixDataset has no metric. Its distances are read with `one_to_one` and `query_to_one`, and `has_metric` is `false`.")
+}
+
+impl<U: Number> Index<usize> for MatrixDataset<U> {
+    type Output = usize;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        self.ids.index(index)
+    }
+}
+
+impl<U: Number> Dataset<usize, U> for MatrixDataset<U> {
+    fn type_name() -> String {
+        format!("MatrixDataset<{}>", U::type_name())
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn cardinality(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn is_metric_expensive(&self) -> bool {
+        false
+    }
+
+    fn metric(&self) -> fn(&usize, &usize) -> U {
+        no_metric
+    }
+
+    fn has_metric(&self) -> bool {
+        false
+    }
+
+    fn set_permuted_indices(&mut self, indices: Option<&[usize]>) {
+        self.permuted_indices = indices.map(<[usize]>::to_vec);
+        self.inverse_indices = indices.map(crate::utils::inverse_permutation);
+    }
+
+    fn swap(&mut self, left: usize, right: usize) -> Result<(), String> {
+        self.ids.swap(left, right);
+        Ok(())
+    }
+
+    fn permuted_indices(&self) -> Option<&[usize]> {
+        self.permuted_indices.as_deref()
+    }
+
+    fn permuted_index(&self, original_index: usize) -> Option<usize> {
+        crate::utils::permuted_index(self.inverse_indices.as_deref(), original_index, self.cardinality())
+    }
+
+    fn permute_instances(&mut self, permutation: &[usize]) -> Result<(), String> {
+        if permutation.len() != self.ids.len() {
+            return Err(format!(
+                "Invalid permutation. Expected permutation of length {}, got permutation of length {}",
+                self.cardinality(),
+                permutation.len()
+            ));
+        }
+
+        self.ids = permutation.iter().map(|&index| self.ids[index]).collect();
+        self.set_permuted_indices(Some(permutation));
+
+        Ok(())
+    }
+
+    fn one_to_one(&self, left: usize, right: usize) -> U {
+        self.distance(self.ids[left], self.ids[right])
+    }
+
+    fn query_to_one(&self, query: &usize, index: usize) -> U {
+        self.distance(*query, self.ids[index])
+    }
+
+    fn make_shards(mut self, max_cardinality: usize) -> Vec<Self> {
+        let mut shards = Vec::new();
+
+        while self.ids.len() > max_cardinality {
+            let at = self.ids.len() - max_cardinality;
+            shards.push(Self {
+                name: format!("{}-shard-{}", self.name, shards.len()),
+                size: self.size,
+                matrix: Arc::clone(&self.matrix),
+                ids: self.ids.split_off(at),
+                queries: self.queries.clone(),
+                permuted_indices: None,
+                inverse_indices: None,
+            });
+        }
+
+        self.name = format!("{}-shard-{}", self.name, shards.len());
+        shards.push(self);
+
+        shards
+    }
+
+    fn save(&self, path: &Path) -> Result<(), String> {
+        let mut handle = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+
+        Self::type_name().save(&mut handle)?;
+        self.name.save(&mut handle)?;
+        self.size.save(&mut handle)?;
+        let (is_square, values) = match self.matrix.as_ref() {
+            Storage::Square(values) => (true, values),
+            Storage::Condensed(values) => (false, values),
+        };
+        is_square.save(&mut handle)?;
+        values.save(&mut handle)?;
+        self.ids.save(&mut handle)?;
+        self.permuted_indices.clone().unwrap_or_default().save(&mut handle)?;
+        self.queries.len().save(&mut handle)?;
+        for query in &self.queries {
+            query.save(&mut handle)?;
+        }
+
+        Ok(())
+    }
+
+    /// Loads a dataset saved with `save`. The `metric` and `is_expensive`
+    /// arguments are ignored.
+    fn load(path: &Path, _: fn(&usize, &usize) -> U, _: bool) -> Result<Self, String> {
+        let mut handle = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+
+        let type_name = String::load(&mut handle)?;
+        if type_name != Self::type_name() {
+            return Err(format!(
+                "Invalid type. File has data of type {type_name} but dataset was constructed with type {}",
+                Self::type_name()
+            ));
+        }
+
+        let name = String::load(&mut handle)?;
+        let size = usize::load(&mut handle)?;
+        let values = if bool::load(&mut handle)? {
+            Storage::Square(Vec::load(&mut handle)?)
+        } else {
+            Storage::Condensed(Vec::load(&mut handle)?)
+        };
+        let ids = Vec::load(&mut handle)?;
+        let permuted_indices = Some(Vec::load(&mut handle)?).filter(|p: &Vec<usize>| !p.is_empty());
+        let num_queries = usize::load(&mut handle)?;
+        let queries = (0..num_queries)
+            .map(|_| Vec::load(&mut handle))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            name,
+            size,
+            matrix: Arc::new(values),
+            ids,
+            queries,
+            inverse_indices: permuted_indices.as_deref().map(crate::utils::inverse_permutation),
+            permuted_indices,
+        })
+    }
+}
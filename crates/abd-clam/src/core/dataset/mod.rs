@@ -8,12 +8,24 @@ use distances::Number;
 use rand::prelude::*;
 use rayon::prelude::*;
 
+mod bits;
+mod cached;
+mod composite;
 mod instance;
+mod matrix;
+mod sparse;
 mod vec2d;
+mod view;
 
+pub use bits::BitVector;
+pub use cached::{CacheStats, CachedDataset};
+pub use composite::{Combination, CompositeMetric};
 pub use instance::Instance;
+pub use matrix::MatrixDataset;
+pub use sparse::SparseVector;
 #[allow(clippy::module_name_repetitions)]
 pub use vec2d::VecDataset;
+pub use view::DatasetView;
 
 /// A common interface for datasets used in CLAM.
 pub trait Dataset<I: Instance, U: Number>: Debug + Send + Sync + Index<usize, Output = I> {
@@ -45,6 +57,15 @@ pub trait Dataset<I: Instance, U: Number>: Debug + Send + Sync + Index<usize, Ou
     /// then CLAM can make certain guarantees about the exactness of search results.
     fn metric(&self) -> fn(&I, &I) -> U;
 
+    /// Whether `metric` can be called to compute distances.
+    ///
+    /// This is `false` for datasets that only hold precomputed distances, such
+    /// as `MatrixDataset`. Searches that need to compare instances outside the
+    /// dataset, e.g. to group queries, then fall back to per-query search.
+    fn has_metric(&self) -> bool {
+        true
+    }
+
     /// Sets the permutation of indices that was used to reorder the dataset.
     ///
     /// This is primarily used when permuting the dataset to reorder it after
@@ -140,6 +161,32 @@ pub trait Dataset<I: Instance, U: Number>: Debug + Send + Sync + Index<usize, Ou
         self.permuted_indices().map_or(index, |indices| indices[index])
     }
 
+    /// Get the index after the dataset was reordered. This is the inverse of
+    /// `original_index`.
+    ///
+    /// The default implementation searches the permutation, which takes time
+    /// linear in the cardinality. Datasets that store a permutation should
+    /// also store its inverse, computed once in `set_permuted_indices`, and
+    /// override this method.
+    ///
+    /// # Arguments
+    ///
+    /// * `original_index` - The index of an instance before the dataset was reordered.
+    ///
+    /// # Returns
+    ///
+    /// * The current index of the instance, if `original_index` is a valid index.
+    /// * `None` otherwise.
+    fn permuted_index(&self, original_index: usize) -> Option<usize> {
+        if original_index < self.cardinality() {
+            self.permuted_indices().map_or(Some(original_index), |indices| {
+                crate::utils::position_of(indices, original_index)
+            })
+        } else {
+            None
+        }
+    }
+
     /// Calculates the distance between two indexed instances in the dataset.
     ///
     /// # Arguments
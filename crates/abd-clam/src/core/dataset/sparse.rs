@@ -0,0 +1,170 @@
+//! A sparse vector of numbers.
+
+use distances::{number::Float, Number};
+
+use super::Instance;
+
+/// A sparse vector, stored as the indices of its non-zero elements, in strictly
+/// increasing order, and the values of those elements.
+///
+/// This is suited to high-dimensional data in which most elements are zero,
+/// such as bag-of-words or sets of items. The distance functions in
+/// `distances::sparse` are exposed as associated functions, so that they can
+/// be passed as the metric of a `VecDataset`, e.g.
+/// `SparseVector::<f32>::cosine::<f32>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SparseVector<T: Number> {
+    /// The indices of the non-zero elements.
+    indices: Vec<usize>,
+    /// The values of the non-zero elements.
+    values: Vec<T>,
+}
+
+impl<T: Number> SparseVector<T> {
+    /// Creates a new sparse vector.
+    ///
+    /// # Arguments
+    ///
+    /// * `indices`: The indices of the non-zero elements, in strictly
+    ///   increasing order.
+    /// * `values`: The values of the non-zero elements.
+    ///
+    /// # Errors
+    ///
+    /// * If `indices` and `values` have different lengths.
+    /// * If `indices` are not in strictly increasing order.
+    pub fn new(indices: Vec<usize>, values: Vec<T>) -> Result<Self, String> {
+        if indices.len() != values.len() {
+            return Err(format!(
+                "Expected as many values as indices, got {} values and {} indices",
+                values.len(),
+                indices.len()
+            ));
+        }
+        if let Some(&[a, b]) = indices.windows(2).find(|w| w[0] >= w[1]) {
+            return Err(format!(
+                "Indices must be strictly increasing, but {a} is followed by {b}"
+            ));
+        }
+        Ok(Self { indices, values })
+    }
+
+    /// Creates a sparse vector from the non-zero elements of a dense vector.
+    #[must_use]
+    pub fn from_dense(dense: &[T]) -> Self {
+        let (indices, values) = dense
+            .iter()
+            .enumerate()
+            .filter(|(_, &v)| v != T::zero())
+            .map(|(i, &v)| (i, v))
+            .unzip();
+        Self { indices, values }
+    }
+
+    /// Returns the dense form of the vector, with `dimensionality` elements.
+    ///
+    /// Elements at indices beyond `dimensionality` are dropped.
+    #[must_use]
+    pub fn to_dense(&self, dimensionality: usize) -> Vec<T> {
+        let mut dense = vec![T::zero(); dimensionality];
+        for (&i, &v) in self.indices.iter().zip(self.values.iter()) {
+            if let Some(d) = dense.get_mut(i) {
+                *d = v;
+            }
+        }
+        dense
+    }
+
+    /// Returns the indices of the non-zero elements.
+    #[must_use]
+    pub fn indices(&self) -> &[usize] {
+        &self.indices
+    }
+
+    /// Returns the values of the non-zero elements.
+    #[must_use]
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+
+    /// Returns the number of non-zero elements.
+    #[must_use]
+    pub fn nnz(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Whether all elements are zero.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// The cosine distance between two sparse vectors.
+    #[must_use]
+    pub fn cosine<U: Float>(x: &Self, y: &Self) -> U {
+        distances::sparse::cosine(&x.indices, &x.values, &y.indices, &y.values)
+    }
+
+    /// The Euclidean distance between two sparse vectors.
+    #[must_use]
+    pub fn euclidean<U: Float>(x: &Self, y: &Self) -> U {
+        distances::sparse::euclidean(&x.indices, &x.values, &y.indices, &y.values)
+    }
+
+    /// The squared Euclidean distance between two sparse vectors.
+    #[must_use]
+    pub fn euclidean_sq<U: Number>(x: &Self, y: &Self) -> U {
+        distances::sparse::euclidean_sq(&x.indices, &x.values, &y.indices, &y.values)
+    }
+
+    /// The Manhattan distance between two sparse vectors.
+    #[must_use]
+    pub fn manhattan<U: Number>(x: &Self, y: &Self) -> U {
+        distances::sparse::manhattan(&x.indices, &x.values, &y.indices, &y.values)
+    }
+
+    /// The Jaccard distance between the sets of indices of the non-zero
+    /// elements of two sparse vectors.
+    #[must_use]
+    pub fn jaccard<U: Float>(x: &Self, y: &Self) -> U {
+        distances::sparse::jaccard(&x.indices, &y.indices)
+    }
+}
+
+impl<T: Number> Instance for SparseVector<T> {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.nnz().to_le_bytes().to_vec();
+        bytes.extend(self.indices.iter().flat_map(|i| i.to_le_bytes()));
+        bytes.extend(self.values.iter().flat_map(|v| v.to_le_bytes()));
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let index_bytes = <usize as Number>::num_bytes();
+        if bytes.len() < index_bytes {
+            return Err(format!("Expected at least {index_bytes} bytes, got {}", bytes.len()));
+        }
+        let (nnz, rest) = bytes.split_at(index_bytes);
+        let nnz = <usize as Number>::from_le_bytes(nnz);
+
+        let expected = nnz * (index_bytes + T::num_bytes());
+        if rest.len() != expected {
+            return Err(format!(
+                "Expected {expected} bytes for {nnz} non-zero elements, got {}",
+                rest.len()
+            ));
+        }
+        let (indices, values) = rest.split_at(nnz * index_bytes);
+        let indices = indices
+            .chunks_exact(index_bytes)
+            .map(<usize as Number>::from_le_bytes)
+            .collect();
+        let values = values.chunks_exact(T::num_bytes()).map(T::from_le_bytes).collect();
+
+        Self::new(indices, values)
+    }
+
+    fn type_name() -> String {
+        format!("SparseVector<{}>", T::type_name())
+    }
+}
@@ -36,6 +36,8 @@ pub struct VecDataset<I: Instance, U: Number, M: Instance> {
     is_expensive: bool,
     /// The reordering of the dataset after building the tree.
     permuted_indices: Option<Vec<usize>>,
+    /// The inverse of `permuted_indices`.
+    inverse_indices: Option<Vec<usize>>,
     /// Metadata about the dataset.
     metadata: Vec<M>,
 }
@@ -57,6 +59,7 @@ impl<I: Instance, U: Number> VecDataset<I, U, usize> {
             metric,
             is_expensive,
             permuted_indices: None,
+            inverse_indices: None,
             metadata,
         }
     }
@@ -91,6 +94,7 @@ impl<I: Instance, U: Number, M: Instance> VecDataset<I, U, M> {
                 metric: self.metric,
                 is_expensive: self.is_expensive,
                 permuted_indices: self.permuted_indices,
+                inverse_indices: self.inverse_indices,
                 metadata,
             })
         } else {
@@ -164,6 +168,7 @@ impl<I: Instance, U: Number, M: Instance> Dataset<I, U> for VecDataset<I, U, M>
 
     fn set_permuted_indices(&mut self, indices: Option<&[usize]>) {
         self.permuted_indices = indices.map(<[usize]>::to_vec);
+        self.inverse_indices = indices.map(crate::utils::inverse_permutation);
     }
 
     fn swap(&mut self, left: usize, right: usize) -> Result<(), String> {
@@ -176,6 +181,10 @@ impl<I: Instance, U: Number, M: Instance> Dataset<I, U> for VecDataset<I, U, M>
         self.permuted_indices.as_deref()
     }
 
+    fn permuted_index(&self, original_index: usize) -> Option<usize> {
+        crate::utils::permuted_index(self.inverse_indices.as_deref(), original_index, self.cardinality())
+    }
+
     fn permute_instances(&mut self, permutation: &[usize]) -> Result<(), String> {
         if permutation.len() != self.data.len() {
             return Err(format!(
@@ -351,6 +360,7 @@ impl<I: Instance, U: Number, M: Instance> Dataset<I, U> for VecDataset<I, U, M>
             data,
             metric,
             is_expensive,
+            inverse_indices: permutation.as_deref().map(crate::utils::inverse_permutation),
             permuted_indices: permutation,
             metadata,
         })
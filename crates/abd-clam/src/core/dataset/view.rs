@@ -0,0 +1,279 @@
+//! A view of a subset of a `Dataset`.
+
+use core::ops::{Index, Range};
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use distances::Number;
+
+use super::{Dataset, Instance};
+
+/// A `Dataset` of a subset of the instances of a borrowed parent `Dataset`.
+///
+/// The view holds the indices of its instances in the parent, so creating one
+/// copies no instances and leaves the parent untouched. Building a tree on a
+/// view permutes those indices instead of the parent's instances, and
+/// distances are computed by the parent, so a view works over any `Dataset`,
+/// including a `MatrixDataset` or a `CachedDataset`.
+///
+/// Unlike `make_shards`, which consumes the dataset, any number of views may
+/// borrow the same parent at once, e.g. for the folds of a cross-validation or
+/// the instances with each label. A view over the instances of a `Cluster` is
+/// made with `from_range` and `Cluster::indices`.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct DatasetView<'a, D> {
+    /// The dataset that holds the instances.
+    parent: &'a D,
+    /// The name of the view.
+    name: String,
+    /// The index in the parent of the instance at each index of the view.
+    indices: Vec<usize>,
+    /// The reordering of the view after building the tree.
+    permuted_indices: Option<Vec<usize>>,
+    /// The inverse of `permuted_indices`.
+    inverse_indices: Option<Vec<usize>>,
+}
+
+impl<'a, D> DatasetView<'a, D> {
+    /// Creates a view of the instances at the given indices of the parent.
+    ///
+    /// # Arguments
+    ///
+    /// * `parent`: The dataset that holds the instances.
+    /// * `indices`: The indices in the parent of the instances in the view, in
+    ///   the order they will have in the view.
+    ///
+    /// # Errors
+    ///
+    /// * If any index is out of bounds for the parent.
+    pub fn new<I: Instance, U: Number>(parent: &'a D, indices: Vec<usize>) -> Result<Self, String>
+    where
+        D: Dataset<I, U>,
+    {
+        if let Some(&i) = indices.iter().find(|&&i| i >= parent.cardinality()) {
+            return Err(format!(
+                "Index {i} is out of bounds for dataset {} with cardinality {}",
+                parent.name(),
+                parent.cardinality()
+            ));
+        }
+        Ok(Self {
+            parent,
+            name: format!("{}-view", parent.name()),
+            indices,
+            permuted_indices: None,
+            inverse_indices: None,
+        })
+    }
+
+    /// Creates a view of a contiguous range of indices of the parent.
+    ///
+    /// After a tree is built on the parent, the instances of each `Cluster`
+    /// occupy the contiguous range given by `Cluster::indices`.
+    ///
+    /// # Errors
+    ///
+    /// * If the range is out of bounds for the parent.
+    pub fn from_range<I: Instance, U: Number>(parent: &'a D, range: Range<usize>) -> Result<Self, String>
+    where
+        D: Dataset<I, U>,
+    {
+        if range.end > parent.cardinality() {
+            return Err(format!(
+                "Range {range:?} is out of bounds for dataset {} with cardinality {}",
+                parent.name(),
+                parent.cardinality()
+            ));
+        }
+        Self::new(parent, range.collect())
+    }
+
+    /// Renames the view.
+    #[must_use]
+    pub fn with_name(mut self, name: String) -> Self {
+        self.name = name;
+        self
+    }
+
+    /// Returns the parent dataset.
+    #[must_use]
+    pub const fn parent(&self) -> &'a D {
+        self.parent
+    }
+
+    /// Returns the index in the parent of the instance at each index of the
+    /// view.
+    #[must_use]
+    pub fn parent_indices(&self) -> &[usize] {
+        &self.indices
+    }
+
+    /// Returns the index in the parent of the instance at the given index of
+    /// the view.
+    #[must_use]
+    pub fn parent_index(&self, index: usize) -> usize {
+        self.indices[index]
+    }
+
+    /// Loads a view of the given parent that was saved with `save`.
+    ///
+    /// # Errors
+    ///
+    /// * If the file cannot be read or was not saved by a view of this type.
+    /// * If any saved index is out of bounds for the parent.
+    pub fn load_with<I: Instance, U: Number>(parent: &'a D, path: &Path) -> Result<Self, String>
+    where
+        D: Dataset<I, U>,
+    {
+        let mut handle = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+
+        let type_name = String::load(&mut handle)?;
+        let expected = <Self as Dataset<I, U>>::type_name();
+        if type_name != expected {
+            return Err(format!(
+                "Invalid type. File has data of type {type_name} but dataset was constructed with type {expected}"
+            ));
+        }
+
+        let name = String::load(&mut handle)?;
+        let indices = Vec::load(&mut handle)?;
+        let permuted_indices = Some(Vec::load(&mut handle)?).filter(|p: &Vec<usize>| !p.is_empty());
+
+        let mut view = Self::new(parent, indices)?.with_name(name);
+        Dataset::<I, U>::set_permuted_indices(&mut view, permuted_indices.as_deref());
+        Ok(view)
+    }
+
+    /// Maps indices in the view to indices in the parent.
+    fn to_parent(&self, indices: &[usize]) -> Vec<usize> {
+        indices.iter().map(|&i| self.indices[i]).collect()
+    }
+}
+
+impl<D: Index<usize>> Index<usize> for DatasetView<'_, D> {
+    type Output = D::Output;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        self.parent.index(self.indices[index])
+    }
+}
+
+impl<I: Instance, U: Number, D: Dataset<I, U>> Dataset<I, U> for DatasetView<'_, D> {
+    fn type_name() -> String {
+        format!("DatasetView<{}>", D::type_name())
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn cardinality(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn is_metric_expensive(&self) -> bool {
+        self.parent.is_metric_expensive()
+    }
+
+    fn metric(&self) -> fn(&I, &I) -> U {
+        self.parent.metric()
+    }
+
+    fn has_metric(&self) -> bool {
+        self.parent.has_metric()
+    }
+
+    fn set_permuted_indices(&mut self, indices: Option<&[usize]>) {
+        self.permuted_indices = indices.map(<[usize]>::to_vec);
+        self.inverse_indices = indices.map(crate::utils::inverse_permutation);
+    }
+
+    fn swap(&mut self, left: usize, right: usize) -> Result<(), String> {
+        self.indices.swap(left, right);
+        Ok(())
+    }
+
+    fn permuted_indices(&self) -> Option<&[usize]> {
+        self.permuted_indices.as_deref()
+    }
+
+    fn permuted_index(&self, original_index: usize) -> Option<usize> {
+        crate::utils::permuted_index(self.inverse_indices.as_deref(), original_index, self.cardinality())
+    }
+
+    fn permute_instances(&mut self, permutation: &[usize]) -> Result<(), String> {
+        if permutation.len() != self.indices.len() {
+            return Err(format!(
+                "Invalid permutation. Expected permutation of length {}, got permutation of length {}",
+                self.cardinality(),
+                permutation.len()
+            ));
+        }
+
+        self.indices = self.to_parent(permutation);
+        self.set_permuted_indices(Some(permutation));
+
+        Ok(())
+    }
+
+    fn one_to_one(&self, left: usize, right: usize) -> U {
+        self.parent.one_to_one(self.indices[left], self.indices[right])
+    }
+
+    fn one_to_many(&self, left: usize, right: &[usize]) -> Vec<U> {
+        self.parent.one_to_many(self.indices[left], &self.to_parent(right))
+    }
+
+    fn query_to_one(&self, query: &I, index: usize) -> U {
+        self.parent.query_to_one(query, self.indices[index])
+    }
+
+    fn query_to_many(&self, query: &I, indices: &[usize]) -> Vec<U> {
+        self.parent.query_to_many(query, &self.to_parent(indices))
+    }
+
+    fn make_shards(mut self, max_cardinality: usize) -> Vec<Self> {
+        let mut shards = Vec::new();
+
+        while self.indices.len() > max_cardinality {
+            let at = self.indices.len() - max_cardinality;
+            shards.push(Self {
+                parent: self.parent,
+                name: format!("{}-shard-{}", self.name, shards.len()),
+                indices: self.indices.split_off(at),
+                permuted_indices: None,
+                inverse_indices: None,
+            });
+        }
+
+        self.name = format!("{}-shard-{}", self.name, shards.len());
+        shards.push(self);
+
+        shards
+    }
+
+    /// Saves the name, indices and permutation of the view, but not the
+    /// parent. The view is loaded with `load_with`.
+    fn save(&self, path: &Path) -> Result<(), String> {
+        let mut handle = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+
+        Self::type_name().save(&mut handle)?;
+        self.name.save(&mut handle)?;
+        self.indices.save(&mut handle)?;
+        self.permuted_indices.clone().unwrap_or_default().save(&mut handle)
+    }
+
+    /// A view cannot be loaded without its parent, so this always fails. Use
+    /// `load_with` instead.
+    fn load(_: &Path, _: fn(&I, &I) -> U, _: bool) -> Result<Self, String> {
+        Err(format!(
+            "A {} borrows its parent dataset and must be loaded with `DatasetView::load_with`",
+            Self::type_name()
+        ))
+    }
+}
@@ -20,13 +20,20 @@ mod cakes;
 ///anomaly detection
 pub mod chaoda;
 mod core;
+pub mod readers;
 pub mod utils;
 
 pub use crate::{
-    cakes::{knn, rnn, Cakes},
+    cakes::{
+        knn, rnn, AlgorithmReport, AutoLayout, Cakes, CakesHandle, FilterSummary, LazyRandomlySharded, Timings,
+        TuningReport,
+    },
     core::{
         cluster::{Cluster, PartitionCriteria, PartitionCriterion, Tree},
-        dataset::{Dataset, Instance, VecDataset},
+        dataset::{
+            BitVector, CacheStats, CachedDataset, Combination, CompositeMetric, Dataset, DatasetView, Instance,
+            MatrixDataset, SparseVector, VecDataset,
+        },
         graph::{criteria::MetaMLScorer, Edge, Graph},
     },
 };
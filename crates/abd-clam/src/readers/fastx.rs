@@ -0,0 +1,345 @@
+//! Reading sequences from FASTA and FASTQ files.
+//!
+//! Records may span several lines and the two formats may be mixed in one
+//! file. Records are read one at a time, so that large files can be loaded in
+//! chunks with `SequenceReader::next_dataset`.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use distances::Number;
+
+use crate::{Instance, VecDataset};
+
+/// A record read from a FASTA or FASTQ file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SequenceRecord {
+    /// The identifier of the record, i.e. the first word of its header.
+    pub id: String,
+    /// The rest of the header, if any.
+    pub description: String,
+    /// The sequence, with line breaks removed.
+    pub sequence: String,
+    /// The quality string of a FASTQ record, with line breaks removed.
+    pub quality: Option<String>,
+}
+
+/// The metadata of a sequence in a `VecDataset` read by a `SequenceReader`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SequenceMetadata {
+    /// The identifier of the record.
+    pub id: String,
+    /// The quality string of the record, if it was read from a FASTQ file and
+    /// quality strings were kept.
+    pub quality: Option<String>,
+}
+
+impl Instance for SequenceMetadata {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.id.len().to_le_bytes().to_vec();
+        bytes.extend_from_slice(self.id.as_bytes());
+        if let Some(quality) = &self.quality {
+            bytes.push(1);
+            bytes.extend_from_slice(quality.as_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let num_bytes = <usize as Number>::num_bytes();
+        if bytes.len() < num_bytes {
+            return Err(format!("Expected at least {num_bytes} bytes, got {}", bytes.len()));
+        }
+        let (id_len, rest) = bytes.split_at(num_bytes);
+        let id_len = <usize as Number>::from_le_bytes(id_len);
+        if rest.len() < id_len {
+            return Err(format!(
+                "Expected at least {id_len} bytes for the id, got {}",
+                rest.len()
+            ));
+        }
+        let (id, quality) = rest.split_at(id_len);
+        let id = String::from_utf8(id.to_vec()).map_err(|e| e.to_string())?;
+        let quality = match quality.split_first() {
+            None => None,
+            Some((_, quality)) => Some(String::from_utf8(quality.to_vec()).map_err(|e| e.to_string())?),
+        };
+        Ok(Self { id, quality })
+    }
+
+    fn type_name() -> String {
+        "SequenceMetadata".to_string()
+    }
+}
+
+/// A streaming reader of records from a FASTA or FASTQ file.
+///
+/// The reader is an `Iterator` over the records that pass its length filters.
+/// Records whose sequences are shorter than the minimum length or longer than
+/// the maximum length are skipped.
+pub struct SequenceReader<R: BufRead> {
+    /// The source of lines.
+    reader: R,
+    /// The number of lines read so far.
+    line_number: usize,
+    /// A header line that was read while looking for the end of a record.
+    pending_header: Option<String>,
+    /// The minimum length of a sequence to keep.
+    min_length: usize,
+    /// The maximum length of a sequence to keep.
+    max_length: usize,
+    /// Whether to keep the quality strings of FASTQ records.
+    keep_quality: bool,
+    /// The number of records that were read, including those that were skipped.
+    num_read: usize,
+}
+
+impl SequenceReader<BufReader<File>> {
+    /// Opens a FASTA or FASTQ file for reading.
+    ///
+    /// # Errors
+    ///
+    /// * If the file cannot be opened.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        File::open(path)
+            .map(|file| Self::new(BufReader::new(file)))
+            .map_err(|e| format!("Failed to open {}. {e}", path.display()))
+    }
+}
+
+impl<R: BufRead> SequenceReader<R> {
+    /// Creates a reader of the records in `reader`.
+    pub const fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line_number: 0,
+            pending_header: None,
+            min_length: 0,
+            max_length: usize::MAX,
+            keep_quality: true,
+            num_read: 0,
+        }
+    }
+
+    /// Skips records whose sequences are shorter than `min_length`.
+    #[must_use]
+    pub const fn with_min_length(mut self, min_length: usize) -> Self {
+        self.min_length = min_length;
+        self
+    }
+
+    /// Skips records whose sequences are longer than `max_length`.
+    #[must_use]
+    pub const fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Sets whether to keep the quality strings of FASTQ records. They are
+    /// kept by default.
+    #[must_use]
+    pub const fn with_quality(mut self, keep_quality: bool) -> Self {
+        self.keep_quality = keep_quality;
+        self
+    }
+
+    /// Returns the number of records read so far, including those that were
+    /// skipped by the length filters.
+    pub const fn num_read(&self) -> usize {
+        self.num_read
+    }
+
+    /// Reads up to `max_records` of the next records into a `VecDataset`.
+    ///
+    /// The metadata of each sequence holds the id and, if kept, the quality
+    /// string of its record. Calling this repeatedly streams a large file
+    /// through memory one chunk at a time.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the dataset.
+    /// * `max_records` - The maximum number of records to read, or `None` to
+    ///   read all remaining records. Must not be zero.
+    /// * `metric` - The distance function.
+    /// * `is_expensive` - Whether the metric is expensive to compute.
+    ///
+    /// # Returns
+    ///
+    /// The dataset, or `None` if there were no more records.
+    ///
+    /// # Errors
+    ///
+    /// * If `max_records` is zero, since `None` would then not mean that there
+    ///   are no more records.
+    /// * If a record cannot be read or parsed.
+    pub fn next_dataset<U: Number>(
+        &mut self,
+        name: String,
+        max_records: Option<usize>,
+        metric: fn(&String, &String) -> U,
+        is_expensive: bool,
+    ) -> Result<Option<VecDataset<String, U, SequenceMetadata>>, String> {
+        if max_records == Some(0) {
+            return Err("At least one record must be read at a time.".to_string());
+        }
+        let records = self
+            .by_ref()
+            .take(max_records.unwrap_or(usize::MAX))
+            .collect::<Result<Vec<_>, _>>()?;
+        if records.is_empty() {
+            return Ok(None);
+        }
+
+        let (sequences, metadata): (Vec<_>, Vec<_>) = records
+            .into_iter()
+            .map(|r| {
+                let metadata = SequenceMetadata {
+                    id: r.id,
+                    quality: r.quality,
+                };
+                (r.sequence, metadata)
+            })
+            .unzip();
+
+        VecDataset::new(name, sequences, metric, is_expensive)
+            .assign_metadata(metadata)
+            .map(Some)
+    }
+
+    /// Reads the next line, without its line ending, or `None` at the end of
+    /// the file.
+    fn next_line(&mut self) -> Result<Option<String>, String> {
+        let mut line = String::new();
+        let num_bytes = self
+            .reader
+            .read_line(&mut line)
+            .map_err(|e| format!("Failed to read line {}. {e}", self.line_number + 1))?;
+        if num_bytes == 0 {
+            Ok(None)
+        } else {
+            self.line_number += 1;
+            Ok(Some(line.trim_end().to_string()))
+        }
+    }
+
+    /// Reads the next non-empty line, or `None` at the end of the file.
+    fn next_nonempty_line(&mut self) -> Result<Option<String>, String> {
+        while let Some(line) = self.next_line()? {
+            if !line.is_empty() {
+                return Ok(Some(line));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the next record, whether or not it passes the length filters.
+    fn next_record(&mut self) -> Result<Option<SequenceRecord>, String> {
+        let header = match self.pending_header.take() {
+            Some(header) => header,
+            None => match self.next_nonempty_line()? {
+                Some(header) => header,
+                None => return Ok(None),
+            },
+        };
+
+        let (marker, header) = header.split_at(header.chars().next().map_or(0, char::len_utf8));
+        let (id, description) = header
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .map_or_else(|| (header.trim(), ""), |(id, description)| (id, description.trim()));
+        let (id, description) = (id.to_string(), description.to_string());
+
+        match marker {
+            ">" => self.fasta_record(id, description).map(Some),
+            "@" => self.fastq_record(id, description).map(Some),
+            _ => Err(format!(
+                "Line {} should be a header starting with `>` or `@`.",
+                self.line_number
+            )),
+        }
+    }
+
+    /// Reads the rest of a FASTA record after its header.
+    fn fasta_record(&mut self, id: String, description: String) -> Result<SequenceRecord, String> {
+        let mut sequence = String::new();
+        while let Some(line) = self.next_line()? {
+            if line.starts_with('>') || line.starts_with('@') {
+                self.pending_header = Some(line);
+                break;
+            }
+            sequence.push_str(line.trim());
+        }
+        Ok(SequenceRecord {
+            id,
+            description,
+            sequence,
+            quality: None,
+        })
+    }
+
+    /// Reads the rest of a FASTQ record after its header.
+    ///
+    /// The sequence ends at the `+` separator line, and the quality string is
+    /// read until it is as long as the sequence, since quality lines may start
+    /// with `@`.
+    fn fastq_record(&mut self, id: String, description: String) -> Result<SequenceRecord, String> {
+        let start = self.line_number;
+
+        let mut sequence = String::new();
+        loop {
+            match self.next_line()? {
+                Some(line) if line.starts_with('+') => break,
+                Some(line) => sequence.push_str(line.trim()),
+                None => return Err(format!("The FASTQ record at line {start} has no `+` line.")),
+            }
+        }
+
+        let mut quality = String::new();
+        while quality.len() < sequence.len() {
+            match self.next_line()? {
+                Some(line) => quality.push_str(line.trim()),
+                None => break,
+            }
+        }
+        if quality.len() != sequence.len() {
+            return Err(format!(
+                "The FASTQ record at line {start} has a sequence of length {} but a quality string of length {}.",
+                sequence.len(),
+                quality.len()
+            ));
+        }
+
+        Ok(SequenceRecord {
+            id,
+            description,
+            sequence,
+            quality: Some(quality),
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for SequenceReader<R> {
+    type Item = Result<SequenceRecord, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_record() {
+                Ok(Some(mut record)) => {
+                    self.num_read += 1;
+                    if (self.min_length..=self.max_length).contains(&record.sequence.len()) {
+                        if !self.keep_quality {
+                            record.quality = None;
+                        }
+                        return Some(Ok(record));
+                    }
+                }
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Readers for common file formats of datasets.
+//!
+//! Each reader of vectors can read a contiguous range of rows, so that a large
+//! file can be loaded one shard at a time. The rows can then be turned into a
+//! `VecDataset` with `vec_dataset`, which records the position of each row in
+//! the file as its metadata. Sequences are streamed from FASTA and FASTQ files
+//! with a `SequenceReader`, and tabular data is read from delimited text files
+//! with a `TabularReader` when the `csv` feature is enabled.
+
+use core::ops::Range;
+
+use distances::Number;
+
+use crate::VecDataset;
+
+mod fastx;
+mod npy;
+#[cfg(feature = "csv")]
+mod tabular;
+mod texmex;
+
+pub use fastx::{SequenceMetadata, SequenceReader, SequenceRecord};
+pub use npy::{npy_shape, read_npy};
+#[cfg(feature = "csv")]
+pub use tabular::{Column, MissingValues, TabularReader};
+pub use texmex::{read_bvecs, read_fvecs, read_ground_truth, read_ivecs, vecs_shape};
+
+/// Creates a `VecDataset` from rows read from a file.
+///
+/// The metadata of each instance is the index of its row in the file, so that
+/// instances from different shards of the same file can be told apart.
+///
+/// # Arguments
+///
+/// * `name` - The name of the dataset.
+/// * `rows` - The rows read from the file.
+/// * `first_row` - The index in the file of the first of `rows`.
+/// * `metric` - The distance function.
+/// * `is_expensive` - Whether the metric is expensive to compute.
+pub fn vec_dataset<T: Number, U: Number>(
+    name: String,
+    rows: Vec<Vec<T>>,
+    first_row: usize,
+    metric: fn(&Vec<T>, &Vec<T>) -> U,
+    is_expensive: bool,
+) -> VecDataset<Vec<T>, U, usize> {
+    let metadata = (first_row..first_row + rows.len()).collect();
+    VecDataset::new(name, rows, metric, is_expensive)
+        .assign_metadata(metadata)
+        .unwrap_or_else(|_| unreachable!("There is one row index for each row."))
+}
+
+/// Checks that a range of rows is within a file of `num_rows` rows, and
+/// returns the whole file if no range is given.
+///
+/// # Errors
+///
+/// * If the range ends after the last row or is reversed.
+fn check_rows(rows: Option<Range<usize>>, num_rows: usize) -> Result<Range<usize>, String> {
+    let rows = rows.unwrap_or(0..num_rows);
+    if rows.start > rows.end || rows.end > num_rows {
+        Err(format!(
+            "Rows {rows:?} are out of bounds for a file with {num_rows} rows."
+        ))
+    } else {
+        Ok(rows)
+    }
+}
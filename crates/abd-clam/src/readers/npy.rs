@@ -0,0 +1,194 @@
+//! Reading 2-d arrays from numpy's `.npy` format.
+//!
+//! See <https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html>
+//! for a description of the format.
+
+use core::ops::Range;
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use distances::Number;
+
+use super::check_rows;
+
+/// The magic string at the start of every `.npy` file.
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// The parsed header of a `.npy` file.
+struct Header {
+    /// The kind of the elements, one of `f`, `i` or `u`.
+    kind: char,
+    /// Whether the elements are stored in big endian order.
+    big_endian: bool,
+    /// The number of bytes in each element.
+    num_bytes: usize,
+    /// The number of rows and columns in the array.
+    shape: (usize, usize),
+    /// The number of bytes before the data.
+    data_offset: usize,
+}
+
+/// Returns the number of rows and columns of the 2-d array in a `.npy` file.
+///
+/// # Errors
+///
+/// * If the file cannot be read.
+/// * If the file does not hold a 2-d array in C order.
+pub fn npy_shape<P: AsRef<Path>>(path: P) -> Result<(usize, usize), String> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path).map_err(|e| format!("Failed to open {}. {e}", path.display()))?);
+    read_header(&mut reader)
+        .map(|header| header.shape)
+        .map_err(|e| format!("Failed to read {}. {e}", path.display()))
+}
+
+/// Reads rows of a 2-d array from a `.npy` file.
+///
+/// The element type of the array must match `T` exactly; no conversions are
+/// made.
+///
+/// # Arguments
+///
+/// * `path` - The file to read.
+/// * `rows` - The range of rows to read, or `None` to read all rows.
+///
+/// # Errors
+///
+/// * If the file cannot be read.
+/// * If the file does not hold a 2-d array in C order.
+/// * If the element type of the array is not `T`.
+/// * If `rows` is out of bounds.
+pub fn read_npy<T: Number, P: AsRef<Path>>(path: P, rows: Option<Range<usize>>) -> Result<Vec<Vec<T>>, String> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path).map_err(|e| format!("Failed to open {}. {e}", path.display()))?);
+    let header = read_header(&mut reader).map_err(|e| format!("Failed to read {}. {e}", path.display()))?;
+
+    if element_kind::<T>() != Some(header.kind) || T::num_bytes() != header.num_bytes {
+        return Err(format!(
+            "{} holds elements of kind `{}{}`, which cannot be read as {}.",
+            path.display(),
+            header.kind,
+            header.num_bytes,
+            T::type_name()
+        ));
+    }
+
+    let (num_rows, dim) = header.shape;
+    let rows = check_rows(rows, num_rows)?;
+    let row_bytes = dim * header.num_bytes;
+
+    let start = header.data_offset + rows.start * row_bytes;
+    reader
+        .seek(SeekFrom::Start(start as u64))
+        .map_err(|e| format!("Failed to seek in {}. {e}", path.display()))?;
+
+    let decode = if header.big_endian {
+        T::from_be_bytes
+    } else {
+        T::from_le_bytes
+    };
+
+    let mut buffer = vec![0; row_bytes];
+    rows.map(|i| {
+        reader
+            .read_exact(&mut buffer)
+            .map_err(|e| format!("Failed to read row {i} of {}. {e}", path.display()))?;
+        Ok(buffer.chunks_exact(header.num_bytes).map(decode).collect())
+    })
+    .collect()
+}
+
+/// Returns the `.npy` kind of elements of type `T`.
+fn element_kind<T: Number>() -> Option<char> {
+    match T::type_name() {
+        "f32" | "f64" => Some('f'),
+        "i8" | "i16" | "i32" | "i64" | "i128" | "isize" => Some('i'),
+        "u8" | "u16" | "u32" | "u64" | "u128" | "usize" => Some('u'),
+        _ => None,
+    }
+}
+
+/// Reads and parses the header of a `.npy` file.
+fn read_header<R: Read>(reader: &mut R) -> Result<Header, String> {
+    let mut preamble = [0; 8];
+    reader.read_exact(&mut preamble).map_err(|e| e.to_string())?;
+    if &preamble[..6] != MAGIC {
+        return Err("It is not a `.npy` file.".to_string());
+    }
+
+    let (len_bytes, prefix_len) = match preamble[6] {
+        1 => (2, 10),
+        2 | 3 => (4, 12),
+        v => return Err(format!("Version {v} of the `.npy` format is not supported.")),
+    };
+    let mut len = [0; 4];
+    reader.read_exact(&mut len[..len_bytes]).map_err(|e| e.to_string())?;
+    let header_len = u32::from_le_bytes(len) as usize;
+
+    let mut dict = vec![0; header_len];
+    reader.read_exact(&mut dict).map_err(|e| e.to_string())?;
+    let dict = String::from_utf8(dict).map_err(|e| e.to_string())?;
+
+    let descr = dict_value(&dict, "descr")?;
+    let descr = descr.trim_matches(['\'', '"']);
+    let mut chars = descr.chars();
+    let big_endian = match chars.next() {
+        Some('<' | '|') => false,
+        Some('>') => true,
+        _ => return Err(format!("Unsupported element type `{descr}`.")),
+    };
+    let kind = chars
+        .next()
+        .ok_or_else(|| format!("Unsupported element type `{descr}`."))?;
+    let num_bytes = chars
+        .as_str()
+        .parse()
+        .map_err(|_| format!("Unsupported element type `{descr}`."))?;
+
+    if dict_value(&dict, "fortran_order")? != "False" {
+        return Err("Arrays in Fortran order are not supported.".to_string());
+    }
+
+    let shape = dict_value(&dict, "shape")?;
+    let shape = shape
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<usize>().map_err(|e| format!("Bad shape `{shape}`. {e}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let shape = match shape.as_slice() {
+        &[num_rows, dim] => (num_rows, dim),
+        _ => return Err(format!("Expected a 2-d array but found shape {shape:?}.")),
+    };
+
+    Ok(Header {
+        kind,
+        big_endian,
+        num_bytes,
+        shape,
+        data_offset: prefix_len + header_len,
+    })
+}
+
+/// Returns the value of a key in the python dict literal of a `.npy` header.
+fn dict_value<'a>(dict: &'a str, key: &str) -> Result<&'a str, String> {
+    let start = dict
+        .find(&format!("'{key}'"))
+        .ok_or_else(|| format!("The header has no `{key}`."))?;
+    let rest = dict[start + key.len() + 2..]
+        .trim_start()
+        .trim_start_matches(':')
+        .trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')').map(|i| i + 1)
+    } else {
+        rest.find([',', '}'])
+    };
+    end.map(|end| rest[..end].trim())
+        .ok_or_else(|| format!("The header has a malformed `{key}`."))
+}
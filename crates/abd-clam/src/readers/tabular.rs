@@ -0,0 +1,311 @@
+//! Reading tabular data from delimited text files, such as CSV and TSV.
+
+use core::{fmt::Display, str::FromStr};
+use std::path::Path;
+
+use distances::Number;
+
+use crate::{Instance, VecDataset};
+
+/// A column of a delimited text file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Column {
+    /// The column at the given index, starting from 0.
+    Index(usize),
+    /// The column with the given name in the header.
+    Name(String),
+}
+
+/// What to do with a missing value in a feature column.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissingValues {
+    /// Fail with an error naming the row and column.
+    Error,
+    /// Skip the rows that have missing values.
+    SkipRow,
+    /// Replace missing values with the given value.
+    Fill(f64),
+}
+
+/// A reader of `VecDataset`s from delimited text files.
+///
+/// Each row of the file is an instance. The caller picks which columns form
+/// the feature vector of each instance and, optionally, which column holds its
+/// metadata.
+#[derive(Clone, Debug)]
+pub struct TabularReader {
+    /// The delimiter between fields.
+    delimiter: u8,
+    /// Whether the first row of the file is a header.
+    has_headers: bool,
+    /// The feature columns, or `None` for all columns except the metadata.
+    features: Option<Vec<Column>>,
+    /// The metadata column, if any.
+    metadata: Option<Column>,
+    /// What to do with missing values.
+    missing: MissingValues,
+    /// The fields, other than empty ones, that count as missing values.
+    missing_tokens: Vec<String>,
+}
+
+impl Default for TabularReader {
+    fn default() -> Self {
+        Self::csv()
+    }
+}
+
+impl TabularReader {
+    /// Creates a reader of comma-separated files with headers.
+    #[must_use]
+    pub fn csv() -> Self {
+        Self {
+            delimiter: b',',
+            has_headers: true,
+            features: None,
+            metadata: None,
+            missing: MissingValues::Error,
+            missing_tokens: vec!["NA".to_string(), "N/A".to_string(), "null".to_string()],
+        }
+    }
+
+    /// Creates a reader of tab-separated files with headers.
+    #[must_use]
+    pub fn tsv() -> Self {
+        Self::csv().with_delimiter(b'\t')
+    }
+
+    /// Sets the delimiter between fields.
+    #[must_use]
+    pub const fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets whether the first row of the file is a header.
+    #[must_use]
+    pub const fn with_headers(mut self, has_headers: bool) -> Self {
+        self.has_headers = has_headers;
+        self
+    }
+
+    /// Sets the columns that form the feature vectors, in order. By default,
+    /// all columns except the metadata column are used.
+    #[must_use]
+    pub fn with_features(mut self, features: Vec<Column>) -> Self {
+        self.features = Some(features);
+        self
+    }
+
+    /// Sets the column that holds the metadata of each instance.
+    #[must_use]
+    pub fn with_metadata(mut self, metadata: Column) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Sets what to do with missing values in feature columns.
+    #[must_use]
+    pub const fn with_missing(mut self, missing: MissingValues) -> Self {
+        self.missing = missing;
+        self
+    }
+
+    /// Sets the fields, other than empty ones, that count as missing values.
+    #[must_use]
+    pub fn with_missing_tokens(mut self, tokens: Vec<String>) -> Self {
+        self.missing_tokens = tokens;
+        self
+    }
+
+    /// Reads the feature vectors from a file.
+    ///
+    /// Any metadata column is ignored.
+    ///
+    /// # Errors
+    ///
+    /// * If the file cannot be read.
+    /// * If a column cannot be found.
+    /// * If a field cannot be parsed as a `T`.
+    /// * If a value is missing and the policy is `MissingValues::Error`.
+    pub fn read<T: Number, P: AsRef<Path>>(&self, path: P) -> Result<Vec<Vec<T>>, String> {
+        self.read_rows(path.as_ref(), false).map(|(features, _)| features)
+    }
+
+    /// Reads a `VecDataset` from a file, with the metadata column parsed as
+    /// `M`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file to read.
+    /// * `name` - The name of the dataset.
+    /// * `metric` - The distance function.
+    /// * `is_expensive` - Whether the metric is expensive to compute.
+    ///
+    /// # Errors
+    ///
+    /// * If no metadata column was set.
+    /// * For the same reasons as `read`.
+    /// * If a metadata field cannot be parsed as an `M`.
+    pub fn read_dataset<T, U, M, P>(
+        &self,
+        path: P,
+        name: String,
+        metric: fn(&Vec<T>, &Vec<T>) -> U,
+        is_expensive: bool,
+    ) -> Result<VecDataset<Vec<T>, U, M>, String>
+    where
+        T: Number,
+        U: Number,
+        M: Instance + FromStr,
+        M::Err: Display,
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        if self.metadata.is_none() {
+            return Err("A metadata column must be set to read a dataset.".to_string());
+        }
+
+        let (features, metadata) = self.read_rows(path, true)?;
+        let metadata = metadata
+            .into_iter()
+            .map(|(line, field)| {
+                field
+                    .parse()
+                    .map_err(|e| format!("Line {line} of {}: bad metadata `{field}`. {e}", path.display()))
+            })
+            .collect::<Result<Vec<M>, _>>()?;
+
+        VecDataset::new(name, features, metric, is_expensive).assign_metadata(metadata)
+    }
+
+    /// Reads the feature vectors and, if `with_metadata`, the metadata fields
+    /// along with their line numbers.
+    #[allow(clippy::type_complexity)]
+    fn read_rows<T: Number>(
+        &self,
+        path: &Path,
+        with_metadata: bool,
+    ) -> Result<(Vec<Vec<T>>, Vec<(u64, String)>), String> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .has_headers(self.has_headers)
+            .from_path(path)
+            .map_err(|e| format!("Failed to open {}. {e}", path.display()))?;
+
+        let headers = if self.has_headers {
+            let headers = reader
+                .headers()
+                .map_err(|e| format!("Failed to read the header of {}. {e}", path.display()))?;
+            Some(headers.iter().map(|h| h.trim().to_string()).collect::<Vec<_>>())
+        } else {
+            None
+        };
+
+        let mut features = Vec::new();
+        let mut metadata = Vec::new();
+        // With headers, the columns are known up front, so bad columns are
+        // reported even if there are no rows. Otherwise, they are resolved
+        // from the first row.
+        let mut columns = match &headers {
+            Some(h) => Some(self.resolve_columns(Some(h), h.len(), path)?),
+            None => None,
+        };
+
+        for record in reader.records() {
+            let record = record.map_err(|e| format!("Failed to read {}. {e}", path.display()))?;
+            let line = record.position().map_or(0, csv::Position::line);
+
+            let (feature_columns, metadata_column) = match &columns {
+                Some(columns) => columns,
+                None => columns.insert(self.resolve_columns(headers.as_deref(), record.len(), path)?),
+            };
+
+            let row = feature_columns
+                .iter()
+                .map(|&column| {
+                    let field = record
+                        .get(column)
+                        .ok_or_else(|| format!("Line {line} of {} has no column {column}.", path.display()))?;
+                    self.parse_field(field).map_err(|e| {
+                        let name = headers
+                            .as_ref()
+                            .map_or_else(String::new, |h| format!(" (`{}`)", h[column]));
+                        format!("Line {line}, column {column}{name} of {}: {e}", path.display())
+                    })
+                })
+                .collect::<Result<Option<Vec<T>>, _>>()?;
+
+            let Some(row) = row else {
+                continue;
+            };
+            features.push(row);
+
+            if with_metadata {
+                if let Some(column) = metadata_column {
+                    let field = record
+                        .get(*column)
+                        .ok_or_else(|| format!("Line {line} of {} has no column {column}.", path.display()))?;
+                    metadata.push((line, field.trim().to_string()));
+                }
+            }
+        }
+
+        Ok((features, metadata))
+    }
+
+    /// Returns the indices of the feature columns and of the metadata column.
+    fn resolve_columns(
+        &self,
+        headers: Option<&[String]>,
+        num_columns: usize,
+        path: &Path,
+    ) -> Result<(Vec<usize>, Option<usize>), String> {
+        let resolve = |column: &Column| match column {
+            Column::Index(i) if *i < num_columns => Ok(*i),
+            Column::Index(i) => Err(format!("{} has no column {i}.", path.display())),
+            Column::Name(name) => headers
+                .ok_or_else(|| format!("Column `{name}` cannot be found without headers."))?
+                .iter()
+                .position(|h| h == name)
+                .ok_or_else(|| format!("{} has no column named `{name}`.", path.display())),
+        };
+
+        let metadata = self.metadata.as_ref().map(resolve).transpose()?;
+        let features = match &self.features {
+            Some(features) => features.iter().map(resolve).collect::<Result<_, _>>()?,
+            None => (0..num_columns).filter(|&i| Some(i) != metadata).collect(),
+        };
+
+        Ok((features, metadata))
+    }
+
+    /// Parses a feature field, returning `None` if it is missing and rows with
+    /// missing values are skipped.
+    fn parse_field<T: Number>(&self, field: &str) -> Result<Option<T>, String> {
+        let field = field.trim();
+        if field.is_empty() || self.missing_tokens.iter().any(|t| t == field) {
+            return match self.missing {
+                MissingValues::Error => Err("missing value.".to_string()),
+                MissingValues::SkipRow => Ok(None),
+                MissingValues::Fill(value) => Ok(Some(T::from(value))),
+            };
+        }
+
+        if T::type_name().starts_with('f') {
+            field
+                .parse::<f64>()
+                .map(|v| Some(T::from(v)))
+                .map_err(|e| format!("`{field}` is not a number. {e}"))
+        } else {
+            let v = field
+                .parse::<i128>()
+                .map_err(|e| format!("`{field}` is not an integer. {e}"))?;
+            let t = T::from(v);
+            if <i128 as Number>::from(t) == v {
+                Ok(Some(t))
+            } else {
+                Err(format!("`{field}` does not fit in {}.", T::type_name()))
+            }
+        }
+    }
+}
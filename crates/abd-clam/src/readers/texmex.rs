@@ -0,0 +1,184 @@
+//! Reading the `.fvecs`, `.ivecs` and `.bvecs` formats of the TEXMEX corpus.
+//!
+//! Each row is stored as a little endian `i32` holding the dimensionality,
+//! followed by that many little endian values. All rows in a file have the
+//! same dimensionality, so a range of rows can be read by seeking past the
+//! rows before it. See <http://corpus-texmex.irisa.fr/> for details.
+
+use core::ops::Range;
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use distances::Number;
+
+use super::check_rows;
+
+/// Reads rows of `f32` vectors from a `.fvecs` file.
+///
+/// # Arguments
+///
+/// * `path` - The file to read.
+/// * `rows` - The range of rows to read, or `None` to read all rows.
+///
+/// # Errors
+///
+/// * If the file cannot be read.
+/// * If the rows do not all have the same dimensionality.
+/// * If `rows` is out of bounds.
+pub fn read_fvecs<P: AsRef<Path>>(path: P, rows: Option<Range<usize>>) -> Result<Vec<Vec<f32>>, String> {
+    read_vecs(path.as_ref(), rows)
+}
+
+/// Reads rows of `i32` vectors from a `.ivecs` file.
+///
+/// # Arguments
+///
+/// * `path` - The file to read.
+/// * `rows` - The range of rows to read, or `None` to read all rows.
+///
+/// # Errors
+///
+/// * If the file cannot be read.
+/// * If the rows do not all have the same dimensionality.
+/// * If `rows` is out of bounds.
+pub fn read_ivecs<P: AsRef<Path>>(path: P, rows: Option<Range<usize>>) -> Result<Vec<Vec<i32>>, String> {
+    read_vecs(path.as_ref(), rows)
+}
+
+/// Reads rows of `u8` vectors from a `.bvecs` file.
+///
+/// # Arguments
+///
+/// * `path` - The file to read.
+/// * `rows` - The range of rows to read, or `None` to read all rows.
+///
+/// # Errors
+///
+/// * If the file cannot be read.
+/// * If the rows do not all have the same dimensionality.
+/// * If `rows` is out of bounds.
+pub fn read_bvecs<P: AsRef<Path>>(path: P, rows: Option<Range<usize>>) -> Result<Vec<Vec<u8>>, String> {
+    read_vecs(path.as_ref(), rows)
+}
+
+/// Reads the true nearest neighbors of queries from a `.ivecs` file.
+///
+/// Row `i` of the file holds the indices of the nearest neighbors of query
+/// `i`, in order of increasing distance. These can be compared with search
+/// results to measure recall.
+///
+/// # Arguments
+///
+/// * `path` - The file to read.
+/// * `rows` - The range of queries to read, or `None` to read all queries.
+///
+/// # Errors
+///
+/// * If the file cannot be read.
+/// * If the rows do not all have the same number of neighbors.
+/// * If any index is negative.
+/// * If `rows` is out of bounds.
+pub fn read_ground_truth<P: AsRef<Path>>(path: P, rows: Option<Range<usize>>) -> Result<Vec<Vec<usize>>, String> {
+    let path = path.as_ref();
+    read_ivecs(path, rows)?
+        .into_iter()
+        .map(|neighbors| {
+            neighbors
+                .into_iter()
+                .map(|i| usize::try_from(i).map_err(|_| format!("{} has a negative index {i}.", path.display())))
+                .collect()
+        })
+        .collect()
+}
+
+/// Returns the number of rows and the dimensionality of a `.fvecs`, `.ivecs`
+/// or `.bvecs` file.
+///
+/// The size of each value is told from the extension of the file.
+///
+/// # Errors
+///
+/// * If the file cannot be read.
+/// * If the extension is not one of `fvecs`, `ivecs` or `bvecs`.
+/// * If the size of the file is not a multiple of the size of a row.
+pub fn vecs_shape<P: AsRef<Path>>(path: P) -> Result<(usize, usize), String> {
+    let path = path.as_ref();
+    let num_bytes = match path.extension().and_then(|e| e.to_str()) {
+        Some("fvecs" | "ivecs") => 4,
+        Some("bvecs") => 1,
+        _ => {
+            return Err(format!(
+                "{} is not a `.fvecs`, `.ivecs` or `.bvecs` file.",
+                path.display()
+            ))
+        }
+    };
+    let mut reader = BufReader::new(File::open(path).map_err(|e| format!("Failed to open {}. {e}", path.display()))?);
+    shape(path, &mut reader, num_bytes)
+}
+
+/// Reads rows of vectors whose values have the type `T`.
+fn read_vecs<T: Number>(path: &Path, rows: Option<Range<usize>>) -> Result<Vec<Vec<T>>, String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| format!("Failed to open {}. {e}", path.display()))?);
+    let (num_rows, dim) = shape(path, &mut reader, T::num_bytes())?;
+    let rows = check_rows(rows, num_rows)?;
+
+    let row_bytes = 4 + dim * T::num_bytes();
+    reader
+        .seek(SeekFrom::Start((rows.start * row_bytes) as u64))
+        .map_err(|e| format!("Failed to seek in {}. {e}", path.display()))?;
+
+    let mut buffer = vec![0; row_bytes];
+    rows.map(|i| {
+        reader
+            .read_exact(&mut buffer)
+            .map_err(|e| format!("Failed to read row {i} of {}. {e}", path.display()))?;
+        let row_dim = i32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+        if usize::try_from(row_dim).ok() != Some(dim) {
+            return Err(format!(
+                "Row {i} of {} has dimensionality {row_dim} but the first row has {dim}.",
+                path.display()
+            ));
+        }
+        Ok(buffer[4..].chunks_exact(T::num_bytes()).map(T::from_le_bytes).collect())
+    })
+    .collect()
+}
+
+/// Returns the number of rows and the dimensionality of a file whose values
+/// have `num_bytes` bytes each, leaving `reader` at the start of the file.
+fn shape<R: Read + Seek>(path: &Path, reader: &mut R, num_bytes: usize) -> Result<(usize, usize), String> {
+    let file_len = reader
+        .seek(SeekFrom::End(0))
+        .map_err(|e| format!("Failed to seek in {}. {e}", path.display()))?;
+    if file_len == 0 {
+        return Ok((0, 0));
+    }
+    reader
+        .seek(SeekFrom::Start(0))
+        .map_err(|e| format!("Failed to seek in {}. {e}", path.display()))?;
+
+    let mut dim = [0; 4];
+    reader
+        .read_exact(&mut dim)
+        .map_err(|e| format!("Failed to read {}. {e}", path.display()))?;
+    let dim = usize::try_from(i32::from_le_bytes(dim))
+        .map_err(|_| format!("{} has a negative dimensionality.", path.display()))?;
+    reader
+        .seek(SeekFrom::Start(0))
+        .map_err(|e| format!("Failed to seek in {}. {e}", path.display()))?;
+
+    let row_bytes = (4 + dim * num_bytes) as u64;
+    if file_len % row_bytes == 0 {
+        let num_rows = usize::try_from(file_len / row_bytes).map_err(|e| e.to_string())?;
+        Ok((num_rows, dim))
+    } else {
+        Err(format!(
+            "The size of {} is not a multiple of the size of a row with dimensionality {dim}.",
+            path.display()
+        ))
+    }
+}
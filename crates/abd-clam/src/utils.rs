@@ -5,6 +5,8 @@ use core::{
     f64::{consts::SQRT_2, EPSILON},
 };
 
+use std::path::Path;
+
 use distances::{number::Float, Number};
 
 /// Return the index and value of the minimum value in the given slice of values.
@@ -127,6 +129,78 @@ pub(crate) fn next_ema(ratio: f64, parent_ema: f64) -> f64 {
     alpha.mul_add(ratio, (1. - alpha) * parent_ema)
 }
 
+/// Return the inverse of a permutation, i.e. the position of each value in
+/// `permutation`.
+///
+/// Values that are out of bounds are skipped, and the positions of values
+/// that do not appear are `usize::MAX`.
+pub(crate) fn inverse_permutation(permutation: &[usize]) -> Vec<usize> {
+    let mut inverse = vec![usize::MAX; permutation.len()];
+    for (i, &p) in permutation.iter().enumerate() {
+        if let Some(position) = inverse.get_mut(p) {
+            *position = i;
+        }
+    }
+    inverse
+}
+
+/// Return the index of an instance after a dataset was reordered, given the
+/// inverse of the permutation, if the dataset was reordered.
+///
+/// # Arguments
+///
+/// * `inverse` - The inverse of the permutation, from `inverse_permutation`.
+/// * `original_index` - The index of the instance before the dataset was reordered.
+/// * `cardinality` - The cardinality of the dataset.
+pub(crate) fn permuted_index(inverse: Option<&[usize]>, original_index: usize, cardinality: usize) -> Option<usize> {
+    inverse.map_or_else(
+        || (original_index < cardinality).then_some(original_index),
+        |inverse| inverse.get(original_index).copied().filter(|&i| i < cardinality),
+    )
+}
+
+/// Write indices to a file as little-endian `u64`s, so that they can be read
+/// on platforms with a different pointer width.
+///
+/// # Errors
+///
+/// * If the file cannot be written.
+pub(crate) fn write_indices(path: &Path, indices: &[usize]) -> Result<(), String> {
+    let bytes = indices
+        .iter()
+        .flat_map(|&i| i.as_u64().to_le_bytes())
+        .collect::<Vec<_>>();
+    std::fs::write(path, bytes).map_err(|e| e.to_string())
+}
+
+/// Read indices written by `write_indices`.
+///
+/// # Errors
+///
+/// * If the file cannot be read.
+/// * If the file does not hold a whole number of `u64`s.
+/// * If an index does not fit in a `usize`.
+pub(crate) fn read_indices(path: &Path) -> Result<Vec<usize>, String> {
+    let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+    if bytes.len() % core::mem::size_of::<u64>() != 0 {
+        return Err(format!(
+            "{} has {} bytes, which is not a whole number of u64s.",
+            path.display(),
+            bytes.len()
+        ));
+    }
+    bytes
+        .chunks_exact(core::mem::size_of::<u64>())
+        .map(|b| {
+            let i = u64::from_le_bytes(
+                b.try_into()
+                    .unwrap_or_else(|_| unreachable!("Chunks have the exact size.")),
+            );
+            usize::try_from(i).map_err(|e| format!("Index {i} in {} does not fit in usize: {e}", path.display()))
+        })
+        .collect()
+}
+
 /// Return the index of the given value in the given slice of values.
 pub(crate) fn position_of<T: Eq + Copy>(values: &[T], v: T) -> Option<usize> {
     values
@@ -295,6 +369,112 @@ pub fn median<T: Number>(data: &[T]) -> Option<T> {
     }
 }
 
+/// Returns the nearest-rank percentile of sorted values.
+///
+/// This is the smallest value such that at least `p` percent of the values are
+/// no greater than it.
+///
+/// # Arguments
+///
+/// * `sorted` - The values, sorted in non-decreasing order.
+/// * `p` - The percentile, from 1 to 100.
+///
+/// # Returns
+///
+/// The percentile, or `None` if there are no values.
+pub fn percentile<T: Copy>(sorted: &[T], p: usize) -> Option<T> {
+    let rank = (sorted.len() * p + 99) / 100;
+    sorted.get(rank.max(1) - 1).copied()
+}
+
+/// The fraction of the expected hits of a search that were found, matching
+/// hits by their distances so that ties are not penalized.
+///
+/// If nothing was expected, the recall is 1.
+///
+/// # Arguments
+///
+/// * `hits` - The indices and distances of the hits that were found.
+/// * `expected` - The indices and distances of the hits that were expected,
+///   e.g. from linear search.
+#[must_use]
+pub fn recall<U: Number>(mut hits: Vec<(usize, U)>, mut expected: Vec<(usize, U)>) -> f64 {
+    if expected.is_empty() {
+        return 1.0;
+    }
+
+    hits.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Greater));
+    expected.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Greater));
+
+    let mut hits = hits.into_iter().map(|(_, d)| d).peekable();
+    let mut expected_distances = expected.iter().map(|&(_, d)| d).peekable();
+
+    let mut num_common = 0_usize;
+    while let (Some(&hit), Some(&e)) = (hits.peek(), expected_distances.peek()) {
+        if hit.abs_diff(e) <= U::epsilon() {
+            num_common += 1;
+            hits.next();
+            expected_distances.next();
+        } else if hit < e {
+            hits.next();
+        } else {
+            expected_distances.next();
+        }
+    }
+
+    num_common.as_f64() / expected.len().as_f64()
+}
+
+/// Encodes the bytes of the fields of a composite instance, each prefixed with
+/// its length, so that they can be split apart by `decode_fields`.
+///
+/// This is used by the `Instance` implementations for tuples and by the
+/// `impl_instance!` macro.
+#[must_use]
+pub fn encode_fields(fields: &[Vec<u8>]) -> Vec<u8> {
+    fields
+        .iter()
+        .flat_map(|field| field.len().to_le_bytes().into_iter().chain(field.iter().copied()))
+        .collect()
+}
+
+/// Splits bytes made by `encode_fields` back into the bytes of each field.
+///
+/// # Arguments
+///
+/// * `bytes` - The encoded bytes.
+/// * `num_fields` - The expected number of fields.
+///
+/// # Errors
+///
+/// * If the bytes are truncated or do not hold exactly `num_fields` fields.
+pub fn decode_fields(mut bytes: &[u8], num_fields: usize) -> Result<Vec<&[u8]>, String> {
+    let len_bytes = <usize as Number>::num_bytes();
+    let mut fields = Vec::with_capacity(num_fields);
+    while !bytes.is_empty() {
+        if bytes.len() < len_bytes {
+            return Err(format!("Expected at least {len_bytes} bytes, got {}", bytes.len()));
+        }
+        let (len, rest) = bytes.split_at(len_bytes);
+        let len = <usize as Number>::from_le_bytes(len);
+        if rest.len() < len {
+            return Err(format!(
+                "Expected at least {len} bytes for field {}, got {}",
+                fields.len(),
+                rest.len()
+            ));
+        }
+        let (field, rest) = rest.split_at(len);
+        fields.push(field);
+        bytes = rest;
+    }
+    if fields.len() == num_fields {
+        Ok(fields)
+    } else {
+        Err(format!("Expected {num_fields} fields, got {}", fields.len()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rand::prelude::*;
@@ -464,4 +644,23 @@ mod tests {
                 )
             });
     }
+
+    #[test]
+    fn test_percentile() {
+        let values = (1..=10).collect::<Vec<u32>>();
+        assert_eq!(percentile(&values, 50), Some(5));
+        assert_eq!(percentile(&values, 90), Some(9));
+        assert_eq!(percentile(&values, 99), Some(10));
+        assert_eq!(percentile(&values, 100), Some(10));
+        assert_eq!(percentile(&[7_u32], 1), Some(7));
+        assert_eq!(percentile::<u32>(&[], 50), None);
+    }
+
+    #[test]
+    fn test_recall() {
+        let expected = vec![(0, 1.0_f32), (1, 2.0), (2, 2.0)];
+        assert!((recall(vec![(2, 2.0), (0, 1.0), (5, 2.0)], expected.clone()) - 1.0).abs() < f64::EPSILON);
+        assert!((recall(vec![(0, 1.0), (3, 4.0)], expected) - 1.0 / 3.0).abs() < f64::EPSILON);
+        assert!((recall(Vec::<(usize, f32)>::new(), Vec::new()) - 1.0).abs() < f64::EPSILON);
+    }
 }
@@ -1,8 +1,11 @@
 //! Tests for Cakes.
 
-use abd_clam::{knn, rnn, Cakes, Instance, PartitionCriteria, VecDataset};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use abd_clam::{knn, rnn, Cakes, CakesHandle, Dataset, Instance, LazyRandomlySharded, PartitionCriteria, VecDataset};
 use distances::Number;
 use float_cmp::approx_eq;
+use rayon::prelude::*;
 use test_case::test_case;
 
 mod utils;
@@ -246,3 +249,877 @@ fn save_load_sharded(num_shards: u64) {
     let trees = cakes.trees();
     assert_eq!(trees.len(), num_shards as usize);
 }
+
+/// `offsets` holds the index of the first instance of each shard after the
+/// sample shard, so that hits in a shard map to the right global index.
+#[test]
+fn sharded_offsets() {
+    let shards = [100, 50, 70, 30]
+        .into_iter()
+        .enumerate()
+        .map(|(i, n)| utils::gen_dataset(n, 10, i.as_u64(), utils::euclidean))
+        .collect();
+    let cakes = Cakes::new_randomly_sharded(shards, Some(42), &PartitionCriteria::default());
+
+    let Cakes::RandomlySharded(rs) = &cakes else {
+        unreachable!("We just built a randomly sharded Cakes.")
+    };
+    assert_eq!(rs.offsets(), &[100, 150, 220]);
+    assert_eq!(rs.shard_offsets(), vec![0, 100, 150, 220]);
+    for (index, location) in [
+        (0, (0, 0)),
+        (99, (0, 99)),
+        (100, (1, 0)),
+        (219, (2, 69)),
+        (249, (3, 29)),
+    ] {
+        assert_eq!(rs.locate(index).unwrap(), location);
+    }
+    assert!(rs.locate(250).is_err());
+
+    for index in [0, 99, 100, 149, 150, 249] {
+        let query = &cakes[index];
+        let hits = cakes.knn_search(query, 1, knn::Algorithm::Linear);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(&cakes[hits[0].0], query);
+        assert_eq!(hits[0].1, 0.0);
+    }
+}
+
+#[test]
+fn by_index() {
+    // Every instance on the line is duplicated once.
+    let data = (-50..=50)
+        .flat_map(|x| [vec![x.as_f32()], vec![x.as_f32()]])
+        .collect::<Vec<_>>();
+    let metadata = (0..data.len()).collect();
+    let data = utils::gen_dataset_from(data, utils::euclidean, metadata);
+    let cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+
+    for original_index in [0, 1, 100, 101, 201] {
+        let x = ((original_index / 2).as_i64() - 50).as_f32();
+
+        let hits = cakes.knn_by_index(original_index, 3, false).unwrap();
+        assert_eq!(hits.len(), 3);
+        assert!(hits
+            .iter()
+            .all(|&(i, _)| cakes.shards()[0].original_index(i) != original_index));
+        assert_eq!(hits.iter().filter(|&&(_, d)| d == 0.0).count(), 1);
+
+        let hits = cakes.knn_by_index(original_index, 3, true).unwrap();
+        assert_eq!(hits.len(), 3);
+        assert!(hits.iter().all(|&(_, d)| d == 1.0 || d == 2.0));
+
+        let hits = cakes.rnn_by_index(original_index, 1.0, false).unwrap();
+        let expected = if x.abs() < 50.0 { 5 } else { 3 };
+        assert_eq!(hits.len(), expected);
+        assert!(hits.iter().all(|&(i, d)| cakes[i][0] == x + d || cakes[i][0] == x - d));
+
+        let hits = cakes.rnn_by_index(original_index, 1.0, true).unwrap();
+        assert_eq!(hits.len(), expected - 1);
+    }
+
+    assert!(cakes.knn_by_index(202, 3, false).is_err());
+    assert!(cakes.rnn_by_index(202, 1.0, false).is_err());
+}
+
+#[test]
+fn by_index_sharded() {
+    let data = utils::gen_dataset(1000, 10, 42, utils::euclidean);
+    let shards = data.make_shards(100);
+    let instances = shards.iter().flat_map(|s| s.data().to_vec()).collect::<Vec<_>>();
+
+    let cakes = Cakes::new_randomly_sharded(shards, Some(42), &PartitionCriteria::default());
+
+    for original_index in [0, 99, 100, 550, 999] {
+        let query = &instances[original_index];
+
+        let hits = cakes.knn_by_index(original_index, 10, false).unwrap();
+        let expected = cakes.linear_knn_search(query, 11);
+        assert_eq!(hits.len(), 10);
+        assert!(hits.iter().all(|&(_, d)| d > 0.0));
+        assert!(hits
+            .iter()
+            .all(|&(i, d)| (utils::euclidean::<_, f32>(&cakes[i], query) - d).abs() < f32::EPSILON));
+        let recall = utils::compute_recall(hits, expected.into_iter().filter(|&(_, d)| d > 0.0).collect());
+        assert!(approx_eq!(f32, recall, 1.0), "Recall: {}", recall);
+
+        let hits = cakes.rnn_by_index(original_index, 0.5, false).unwrap();
+        let expected = cakes.linear_rnn_search(query, 0.5);
+        assert_eq!(hits.len() + 1, expected.len());
+    }
+}
+
+#[test]
+fn original_indices_and_metadata() {
+    let data = utils::gen_dataset(1000, 10, 42, utils::euclidean);
+    let instances = data.data().to_vec();
+    let cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+
+    let (num_shards, shard_size) = (10, 100);
+    let shards = (0..num_shards)
+        .map(|s| {
+            let data = instances[s * shard_size..(s + 1) * shard_size].to_vec();
+            let metadata = (s * shard_size..(s + 1) * shard_size).collect();
+            utils::gen_dataset_from(data, utils::euclidean, metadata)
+        })
+        .collect();
+    let sharded_cakes = Cakes::new_randomly_sharded(shards, Some(42), &PartitionCriteria::default());
+
+    let queries = utils::gen_dataset(10, 10, 43, utils::euclidean);
+    for query in queries.data() {
+        for cakes in [&cakes, &sharded_cakes] {
+            let hits = cakes.knn_search(query, 10, knn::Algorithm::GreedySieve);
+            let original_hits = cakes.knn_search_original(query, 10, knn::Algorithm::GreedySieve);
+            assert_eq!(hits.len(), original_hits.len());
+            for (&(i, d), &(o, od)) in hits.iter().zip(original_hits.iter()) {
+                assert_eq!(o, cakes.original_index(i).unwrap());
+                assert_eq!(d, od);
+                assert_eq!(&cakes[i], &instances[o]);
+            }
+
+            let hits = cakes.rnn_search_with_metadata(query, 1.0, rnn::Algorithm::Clustered);
+            for &(o, d, &m) in &hits {
+                assert_eq!(o, m);
+                assert!((utils::euclidean::<_, f32>(&instances[o], query) - d).abs() < f32::EPSILON);
+            }
+        }
+    }
+
+    assert!(sharded_cakes.original_index(1000).is_err());
+    assert!(sharded_cakes.metadata_of(1000).is_err());
+}
+
+#[test]
+fn filtered() {
+    let data = utils::gen_dataset(2000, 10, 42, utils::euclidean);
+    let instances = data.data().to_vec();
+    let labels = instances.iter().map(|x| x[0] > -0.25).collect::<Vec<_>>();
+    let cakes = Cakes::new(
+        data.assign_metadata(labels.clone()).unwrap(),
+        Some(42),
+        &PartitionCriteria::default(),
+    );
+
+    let shards = (0..4)
+        .map(|s| {
+            let data = instances[s * 500..(s + 1) * 500].to_vec();
+            utils::gen_dataset_from(data, utils::euclidean, labels[s * 500..(s + 1) * 500].to_vec())
+        })
+        .collect();
+    let sharded_cakes = Cakes::new_randomly_sharded(shards, Some(42), &PartitionCriteria::default());
+
+    let cluster_sharded_cakes = Cakes::new_cluster_sharded(
+        utils::gen_dataset_from(instances.clone(), utils::euclidean, labels.clone()),
+        300,
+        Some(42),
+        &PartitionCriteria::default(),
+    );
+
+    let queries = utils::gen_dataset(10, 10, 43, utils::euclidean);
+    for query in queries.data() {
+        let linear_hits = instances
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| labels[i])
+            .map(|(i, x)| (i, utils::euclidean::<_, f32>(x, query)))
+            .collect::<Vec<_>>();
+        let linear_knn = {
+            let mut hits = linear_hits.clone();
+            hits.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap());
+            hits.truncate(10);
+            hits
+        };
+        let linear_rnn = linear_hits.into_iter().filter(|&(_, d)| d <= 1.0).collect::<Vec<_>>();
+
+        for cakes in [&cakes, &sharded_cakes, &cluster_sharded_cakes] {
+            let hits = cakes.knn_search_filtered(query, 1, |o| o == 510);
+            assert_eq!(hits.len(), 1);
+            assert_eq!(cakes.original_index(hits[0].0).unwrap(), 510);
+
+            let summary = cakes.metadata_filter_summary(|&l| l);
+            assert_eq!(summary.num_passing(), labels.iter().filter(|&&l| l).count());
+
+            let knn_hits = [
+                cakes.knn_search_by_metadata(query, 10, |&l| l),
+                cakes.knn_search_filtered(query, 10, |i| labels[i]),
+                cakes.knn_search_summarized(query, 10, &summary),
+            ];
+            for hits in knn_hits {
+                assert_eq!(hits.len(), 10);
+                assert!(hits.iter().all(|&(i, _)| *cakes.metadata_of(i).unwrap()));
+                let recall = utils::compute_recall(hits, linear_knn.clone());
+                assert!(approx_eq!(f32, recall, 1.0), "Recall: {}", recall);
+            }
+
+            let rnn_hits = [
+                cakes.rnn_search_by_metadata(query, 1.0, |&l| l),
+                cakes.rnn_search_filtered(query, 1.0, |i| labels[i]),
+                cakes.rnn_search_summarized(query, 1.0, &summary),
+            ];
+            for hits in rnn_hits {
+                assert_eq!(hits.len(), linear_rnn.len());
+                assert!(hits.iter().all(|&(i, _)| *cakes.metadata_of(i).unwrap()));
+            }
+        }
+    }
+
+    // Asking for more neighbors than there are passing instances.
+    let summary = cakes.filter_summary(|i| i < 5);
+    assert_eq!(summary.num_passing(), 5);
+    let hits = cakes.knn_search_summarized(queries.data()[0].as_ref(), 10, &summary);
+    assert_eq!(hits.len(), 5);
+}
+
+#[test]
+fn nearest_iter() {
+    let data = utils::gen_dataset(1000, 10, 42, utils::euclidean);
+    let shards = utils::gen_dataset(1000, 10, 42, utils::euclidean).make_shards(300);
+
+    let cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+    let sharded_cakes = Cakes::new_randomly_sharded(shards, Some(42), &PartitionCriteria::default());
+
+    let queries = utils::gen_dataset(10, 10, 43, utils::euclidean);
+    for query in queries.data() {
+        for cakes in [&cakes, &sharded_cakes] {
+            let hits = cakes.nearest_iter(query).take(25).collect::<Vec<_>>();
+            assert!(hits.windows(2).all(|w| w[0].1 <= w[1].1));
+            let recall = utils::compute_recall(hits, cakes.linear_knn_search(query, 25));
+            assert!(approx_eq!(f32, recall, 1.0), "Recall: {}", recall);
+
+            let mut all_hits = cakes.nearest_iter(query).collect::<Vec<_>>();
+            assert!(all_hits.windows(2).all(|w| w[0].1 <= w[1].1));
+            all_hits.sort_by_key(|&(i, _)| i);
+            assert!(all_hits.iter().map(|&(i, _)| i).eq(0..1000));
+        }
+    }
+}
+
+#[test]
+fn rnn_count() {
+    let data = utils::gen_dataset(2000, 10, 42, utils::euclidean);
+    let shards = utils::gen_dataset(2000, 10, 42, utils::euclidean).make_shards(500);
+
+    let cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+    let sharded_cakes = Cakes::new_randomly_sharded(shards, Some(42), &PartitionCriteria::default());
+
+    let radii = [0.0, 0.5, 1.0, 0.25, 2.0];
+    let queries = utils::gen_dataset(10, 10, 43, utils::euclidean);
+    for query in queries.data().iter().chain(core::iter::once(&cakes[0])) {
+        for cakes in [&cakes, &sharded_cakes] {
+            let expected = radii
+                .iter()
+                .map(|&r| cakes.linear_rnn_search(query, r).len())
+                .collect::<Vec<_>>();
+            assert_eq!(cakes.rnn_count_multi(query, &radii), expected);
+            assert_eq!(cakes.rnn_count(query, 1.0), expected[2]);
+        }
+    }
+
+    assert!(cakes.rnn_count_multi(&cakes[0], &[]).is_empty());
+}
+
+/// The number of distances computed by `counted_euclidean`.
+static NUM_DISTANCES: AtomicUsize = AtomicUsize::new(0);
+
+fn counted_euclidean(x: &Vec<f32>, y: &Vec<f32>) -> f32 {
+    NUM_DISTANCES.fetch_add(1, Ordering::Relaxed);
+    utils::euclidean(x, y)
+}
+
+#[test]
+fn rnn_count_mixed_radii() {
+    let data = utils::gen_dataset(2000, 10, 42, counted_euclidean);
+    let cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+
+    let query = cakes[0].clone();
+    let radii = [1e-3, 1e6];
+    let expected = radii
+        .iter()
+        .map(|&r| cakes.linear_rnn_search(&query, r).len())
+        .collect::<Vec<_>>();
+
+    NUM_DISTANCES.store(0, Ordering::Relaxed);
+    assert_eq!(cakes.rnn_count(&query, radii[0]), expected[0]);
+    let num_tiny = NUM_DISTANCES.load(Ordering::Relaxed);
+
+    NUM_DISTANCES.store(0, Ordering::Relaxed);
+    assert_eq!(cakes.rnn_count_multi(&query, &radii), expected);
+    let num_multi = NUM_DISTANCES.load(Ordering::Relaxed);
+
+    // The huge radius confirms the root, which must not make the tiny radius
+    // scan every instance.
+    assert!(num_tiny < 2000, "{num_tiny} distances computed");
+    assert_eq!(num_multi, num_tiny);
+}
+
+#[test]
+fn rnn_search_multi() {
+    let data = utils::gen_dataset(2000, 10, 42, utils::euclidean);
+    let shards = utils::gen_dataset(2000, 10, 42, utils::euclidean).make_shards(500);
+
+    let cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+    let sharded_cakes = Cakes::new_randomly_sharded(shards, Some(42), &PartitionCriteria::default());
+
+    let radii = [0.5, 0.0, 1.0, 0.25];
+    let queries = utils::gen_dataset(10, 10, 43, utils::euclidean);
+    for query in queries.data().iter().chain(core::iter::once(&cakes[0])) {
+        for cakes in [&cakes, &sharded_cakes] {
+            let results = cakes.rnn_search_multi(query, &radii);
+            assert_eq!(results.len(), radii.len());
+
+            for (&radius, hits) in radii.iter().zip(results.iter()) {
+                let mut indices = hits.iter().map(|&(i, _)| i).collect::<Vec<_>>();
+                indices.sort_unstable();
+                let mut linear_indices = cakes
+                    .linear_rnn_search(query, radius)
+                    .into_iter()
+                    .map(|(i, _)| i)
+                    .collect::<Vec<_>>();
+                linear_indices.sort_unstable();
+                assert_eq!(indices, linear_indices, "radius {radius}");
+                assert!(hits.windows(2).all(|w| w[0].1 <= w[1].1));
+            }
+        }
+    }
+
+    assert!(cakes.rnn_search_multi(&cakes[0], &[]).is_empty());
+}
+
+#[test]
+fn batch_grouped() {
+    let data = utils::gen_dataset(2000, 10, 42, utils::euclidean);
+    let shards = utils::gen_dataset(2000, 10, 42, utils::euclidean).make_shards(500);
+
+    let cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+    let sharded_cakes = Cakes::new_randomly_sharded(shards, Some(42), &PartitionCriteria::default());
+
+    let queries = utils::gen_dataset(100, 10, 43, utils::euclidean);
+    let queries = queries
+        .data()
+        .iter()
+        .chain((0..100).map(|i| &cakes[i * 7]))
+        .collect::<Vec<_>>();
+
+    let sorted_indices = |hits: &[(usize, f32)]| {
+        let mut indices = hits.iter().map(|&(i, _)| i).collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices
+    };
+
+    for cakes in [&cakes, &sharded_cakes] {
+        for radius in [0.0, 0.5, 1.0] {
+            let grouped = cakes.batch_rnn_search_grouped(&queries, radius);
+            let expected = cakes.batch_linear_rnn_search(&queries, radius);
+            assert_eq!(grouped.len(), queries.len());
+            for (hits, expected) in grouped.iter().zip(expected.iter()) {
+                assert_eq!(sorted_indices(hits), sorted_indices(expected), "radius {radius}");
+            }
+        }
+
+        for k in [1, 10, 100] {
+            let grouped = cakes.batch_knn_search_grouped(&queries, k);
+            let expected = cakes.batch_linear_knn_search(&queries, k);
+            assert_eq!(grouped.len(), queries.len());
+            for (hits, expected) in grouped.iter().zip(expected.iter()) {
+                assert_eq!(sorted_indices(hits), sorted_indices(expected), "k {k}");
+            }
+        }
+    }
+
+    assert!(cakes.batch_knn_search_grouped(&[], 10).is_empty());
+}
+
+#[test]
+fn cluster_sharded() {
+    let data = utils::gen_dataset(2000, 2, 42, utils::euclidean);
+    let instances = data.data().to_vec();
+    let data = data.assign_metadata((0..2000).collect()).unwrap();
+
+    let cakes = Cakes::new_cluster_sharded(data, 250, Some(42), &PartitionCriteria::default());
+    assert!(cakes.num_shards() > 1);
+    assert_eq!(cakes.total_cardinality(), 2000);
+
+    for i in [0, 1, 999, 1999] {
+        let original = cakes.original_index(i).unwrap();
+        assert_eq!(cakes[i], instances[original]);
+        assert_eq!(*cakes.metadata_of(i).unwrap(), original);
+    }
+
+    let queries = utils::gen_dataset(20, 2, 43, utils::euclidean);
+    let radius = 0.05;
+    let mut visits = 0;
+    for query in queries.data() {
+        visits += cakes.routed_shards(query, radius).len();
+
+        let mut hits = cakes.rnn_search(query, radius, rnn::Algorithm::Clustered);
+        let mut expected = cakes.linear_rnn_search(query, radius);
+        hits.sort_by_key(|&(i, _)| i);
+        expected.sort_by_key(|&(i, _)| i);
+        assert_eq!(hits, expected);
+
+        let hits = cakes.knn_search(query, 10, knn::Algorithm::GreedySieve);
+        let expected = cakes.linear_knn_search(query, 10);
+        assert_eq!(hits.len(), 10);
+        let recall = utils::compute_recall(hits, expected);
+        assert!(approx_eq!(f32, recall, 1.0), "Recall: {}", recall);
+    }
+    assert!(visits < queries.cardinality() * cakes.num_shards());
+
+    for original_index in [0, 500, 1999] {
+        let query = &instances[original_index];
+        let hits = cakes.knn_by_index(original_index, 10, false).unwrap();
+        let expected = cakes.linear_knn_search(query, 11);
+        assert_eq!(hits.len(), 10);
+        assert!(hits
+            .iter()
+            .all(|&(i, _)| cakes.original_index(i).unwrap() != original_index));
+        let recall = utils::compute_recall(
+            hits,
+            expected
+                .into_iter()
+                .filter(|&(i, _)| cakes.original_index(i).unwrap() != original_index)
+                .collect(),
+        );
+        assert!(approx_eq!(f32, recall, 1.0), "Recall: {}", recall);
+    }
+
+    let tmp_dir = tempdir::TempDir::new("cluster-sharded-cakes-test").unwrap();
+    cakes.save(tmp_dir.path()).unwrap();
+    let permutation_bytes = std::fs::metadata(tmp_dir.path().join("permutation")).unwrap().len();
+    assert_eq!(permutation_bytes, 2000 * 8);
+    let loaded = Cakes::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(tmp_dir.path(), utils::euclidean, false).unwrap();
+    assert_eq!(loaded.shard_cardinalities(), cakes.shard_cardinalities());
+    for i in [0, 1, 999, 1999] {
+        assert_eq!(loaded.original_index(i), cakes.original_index(i));
+    }
+    for original_index in [0, 500, 1999] {
+        assert_eq!(
+            loaded.knn_by_index(original_index, 10, false),
+            cakes.knn_by_index(original_index, 10, false)
+        );
+    }
+    assert!(loaded.knn_by_index(2000, 10, false).is_err());
+    assert_eq!(loaded.get(1999), Some(&loaded[1999]));
+    assert_eq!(loaded.get(2000), None);
+    assert!(std::panic::catch_unwind(|| loaded[2000].clone()).is_err());
+    let query = &queries.data()[0];
+    assert_eq!(loaded.routed_shards(query, radius), cakes.routed_shards(query, radius));
+
+    // Saving an index with fewer shards into the same directory removes the
+    // shards left over from the earlier save.
+    let data = utils::gen_dataset(2000, 2, 42, utils::euclidean)
+        .assign_metadata((0..2000).collect::<Vec<usize>>())
+        .unwrap();
+    let fewer = Cakes::new_cluster_sharded(data, 1000, Some(42), &PartitionCriteria::default());
+    assert!(fewer.num_shards() < cakes.num_shards());
+    fewer.save(tmp_dir.path()).unwrap();
+    let loaded = Cakes::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(tmp_dir.path(), utils::euclidean, false).unwrap();
+    assert_eq!(loaded.shard_cardinalities(), fewer.shard_cardinalities());
+}
+
+#[test]
+fn lazy_sharded() {
+    let shards = utils::gen_dataset(2000, 10, 42, utils::euclidean).make_shards(250);
+    let cakes = Cakes::new_randomly_sharded(shards, Some(42), &PartitionCriteria::default());
+
+    let tmp_dir = tempdir::TempDir::new("lazy-sharded-cakes-test").unwrap();
+    cakes.save(tmp_dir.path()).unwrap();
+
+    let lazy =
+        LazyRandomlySharded::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(tmp_dir.path(), utils::euclidean, false, 2)
+            .unwrap();
+    assert_eq!(lazy.num_shards(), cakes.num_shards());
+    assert_eq!(lazy.shard_cardinalities(), cakes.shard_cardinalities());
+    assert_eq!(lazy.num_resident(), 0);
+
+    let queries = utils::gen_dataset(10, 10, 43, utils::euclidean);
+    for query in queries.data() {
+        let mut hits = lazy.rnn_search(query, 0.5, rnn::Algorithm::Clustered).unwrap();
+        let mut expected = cakes.rnn_search(query, 0.5, rnn::Algorithm::Clustered);
+        hits.sort_by_key(|&(i, _)| i);
+        expected.sort_by_key(|&(i, _)| i);
+        assert_eq!(hits, expected);
+        assert!(lazy.num_resident() <= 2);
+
+        let hits = lazy.knn_search(query, 10, knn::Algorithm::GreedySieve).unwrap();
+        let expected = cakes.linear_knn_search(query, 10);
+        let recall = utils::compute_recall(hits, expected);
+        assert!(approx_eq!(f32, recall, 1.0), "Recall: {}", recall);
+        assert!(lazy.num_resident() <= 2);
+    }
+    assert_eq!(lazy.num_resident(), 2);
+
+    // Each search starts with the resident shards, so they are hits for every
+    // search after the first, instead of being evicted before they are used.
+    let num_searches = 2 * queries.cardinality();
+    let stats = lazy.cache_stats();
+    assert_eq!(stats.hits + stats.misses, num_searches * (lazy.num_shards() - 1));
+    assert_eq!(stats.hits, (num_searches - 1) * 2);
+    assert_eq!(stats.len, 2);
+
+    // Searching from many threads at once never holds more shards than allowed.
+    let all_hits = queries
+        .data()
+        .par_iter()
+        .map(|query| lazy.rnn_search(query, 0.5, rnn::Algorithm::Clustered).unwrap())
+        .collect::<Vec<_>>();
+    for (query, mut hits) in queries.data().iter().zip(all_hits) {
+        let mut expected = cakes.rnn_search(query, 0.5, rnn::Algorithm::Clustered);
+        hits.sort_by_key(|&(i, _)| i);
+        expected.sort_by_key(|&(i, _)| i);
+        assert_eq!(hits, expected);
+    }
+    assert!(lazy.num_resident() <= 2);
+
+    // An index saved before the cardinalities were recorded can still be opened.
+    std::fs::remove_file(tmp_dir.path().join("shard-cardinalities.json")).unwrap();
+    let lazy =
+        LazyRandomlySharded::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(tmp_dir.path(), utils::euclidean, false, 2)
+            .unwrap();
+    assert_eq!(lazy.shard_cardinalities(), cakes.shard_cardinalities());
+
+    assert!(LazyRandomlySharded::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(
+        tmp_dir.path(),
+        utils::euclidean,
+        false,
+        0
+    )
+    .is_err());
+}
+
+#[test]
+fn par_knn_search() {
+    let data = utils::gen_dataset(2000, 10, 42, utils::euclidean);
+    let shards = utils::gen_dataset(2000, 10, 42, utils::euclidean).make_shards(300);
+
+    let cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+    let sharded_cakes = Cakes::new_randomly_sharded(shards, Some(42), &PartitionCriteria::default());
+
+    let queries = utils::gen_dataset(20, 10, 43, utils::euclidean);
+    for query in queries.data() {
+        for k in [1, 10, 100, 500] {
+            for cakes in [&cakes, &sharded_cakes] {
+                let hits = cakes.par_knn_search(query, k);
+                assert_eq!(hits.len(), k);
+                let expected = cakes.linear_knn_search(query, k);
+                let recall = utils::compute_recall(hits, expected);
+                assert!(approx_eq!(f32, recall, 1.0), "Recall: {}", recall);
+            }
+        }
+    }
+}
+
+#[test]
+fn append_and_compact_shards() {
+    let shards = utils::gen_dataset(1000, 10, 42, utils::euclidean).make_shards(250);
+    let mut cakes = Cakes::new_randomly_sharded(shards, Some(42), &PartitionCriteria::default());
+
+    let tmp_dir = tempdir::TempDir::new("append-shard-test").unwrap();
+    cakes.save(tmp_dir.path()).unwrap();
+
+    let query = utils::gen_dataset(1, 10, 43, utils::euclidean).data()[0].clone();
+    let before = cakes.rnn_search(&query, 0.5, rnn::Algorithm::Clustered);
+
+    let mut new_instances = Vec::new();
+    for seed in 44..47 {
+        let data = utils::gen_dataset(100, 10, seed, utils::euclidean);
+        new_instances.extend(data.data().iter().cloned());
+        let offset = cakes
+            .append_shard(data, Some(42), &PartitionCriteria::default(), Some(tmp_dir.path()))
+            .unwrap();
+        assert_eq!(offset + 100, cakes.total_cardinality());
+    }
+    assert_eq!(cakes.num_shards(), 7);
+    assert_eq!(cakes.total_cardinality(), 1300);
+
+    // Indices already issued still refer to the same instances.
+    let mut after = cakes.rnn_search(&query, 0.5, rnn::Algorithm::Clustered);
+    after.retain(|&(i, _)| i < 1000);
+    let mut before = before;
+    before.sort_by_key(|&(i, _)| i);
+    after.sort_by_key(|&(i, _)| i);
+    assert_eq!(before, after);
+
+    let loaded = Cakes::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(tmp_dir.path(), utils::euclidean, false).unwrap();
+    assert_eq!(loaded.shard_cardinalities(), cakes.shard_cardinalities());
+    let lazy =
+        LazyRandomlySharded::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(tmp_dir.path(), utils::euclidean, false, 2)
+            .unwrap();
+    assert_eq!(lazy.shard_cardinalities(), cakes.shard_cardinalities());
+    let mut lazy_hits = lazy.rnn_search(&query, 0.5, rnn::Algorithm::Clustered).unwrap();
+    let mut hits = cakes.rnn_search(&query, 0.5, rnn::Algorithm::Clustered);
+    lazy_hits.sort_by_key(|&(i, _)| i);
+    hits.sort_by_key(|&(i, _)| i);
+    assert_eq!(lazy_hits, hits);
+
+    // A directory holding a different number of shards is not extended.
+    let mut stale =
+        Cakes::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(tmp_dir.path(), utils::euclidean, false).unwrap();
+    let data = utils::gen_dataset(100, 10, 47, utils::euclidean);
+    stale
+        .append_shard(data, Some(42), &PartitionCriteria::default(), None)
+        .unwrap();
+    let data = utils::gen_dataset(100, 10, 48, utils::euclidean);
+    assert!(stale
+        .append_shard(data, Some(42), &PartitionCriteria::default(), Some(tmp_dir.path()))
+        .is_err());
+
+    let originals = (0..cakes.total_cardinality())
+        .map(|i| (cakes.original_index(i).unwrap(), cakes[i].clone()))
+        .collect::<std::collections::HashMap<_, _>>();
+    for (i, instance) in new_instances.iter().enumerate() {
+        assert_eq!(&originals[&(1000 + i)], instance);
+    }
+
+    let issued = (0..cakes.total_cardinality())
+        .map(|i| (cakes[i].clone(), cakes.original_index(i).unwrap()))
+        .collect::<Vec<_>>();
+    let sorted = |mut hits: Vec<(usize, f32)>| {
+        hits.sort_by_key(|&(i, _)| i);
+        hits
+    };
+    let before = sorted(cakes.rnn_search(&query, 0.5, rnn::Algorithm::Clustered));
+    let before_knn = cakes.knn_search(&query, 10, knn::Algorithm::Linear);
+
+    cakes
+        .compact_shards(250, Some(42), &PartitionCriteria::default())
+        .unwrap();
+    assert_eq!(cakes.shard_cardinalities(), vec![250, 250, 250, 250, 300]);
+    for i in 0..cakes.total_cardinality() {
+        assert_eq!(originals[&cakes.original_index(i).unwrap()], cakes[i]);
+    }
+
+    // Indices issued before compaction still refer to the same instances.
+    for (i, (instance, original)) in issued.iter().enumerate() {
+        assert_eq!(&cakes[i], instance);
+        assert_eq!(cakes.original_index(i).unwrap(), *original);
+    }
+    for algo in [rnn::Algorithm::Clustered, rnn::Algorithm::Linear] {
+        assert_eq!(sorted(cakes.rnn_search(&query, 0.5, algo)), before);
+    }
+    assert_eq!(sorted(cakes.batch_rnn_search_grouped(&[&query], 0.5).remove(0)), before);
+    assert_eq!(sorted(cakes.rnn_search_filtered(&query, 0.5, |_| true)), before);
+    assert_eq!(sorted(cakes.rnn_search_multi(&query, &[0.5]).remove(0)), before);
+    assert_eq!(
+        sorted(cakes.knn_search(&query, 10, knn::Algorithm::GreedySieve)),
+        sorted(before_knn.clone())
+    );
+    assert_eq!(
+        sorted(cakes.nearest_iter(&query).take(10).collect()),
+        sorted(before_knn)
+    );
+
+    let hits = cakes.knn_search(&query, 10, knn::Algorithm::GreedySieve);
+    let expected = cakes.linear_knn_search(&query, 10);
+    let recall = utils::compute_recall(hits, expected);
+    assert!(approx_eq!(f32, recall, 1.0), "Recall: {}", recall);
+
+    cakes.save(tmp_dir.path()).unwrap();
+    let loaded = Cakes::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(tmp_dir.path(), utils::euclidean, false).unwrap();
+    assert_eq!(loaded.shard_cardinalities(), cakes.shard_cardinalities());
+    for (i, (instance, _)) in issued.iter().enumerate() {
+        assert_eq!(&loaded[i], instance);
+    }
+    assert_eq!(
+        sorted(loaded.rnn_search(&query, 0.5, rnn::Algorithm::Clustered)),
+        before
+    );
+
+    let lazy =
+        LazyRandomlySharded::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(tmp_dir.path(), utils::euclidean, false, 2)
+            .unwrap();
+    assert_eq!(
+        sorted(lazy.rnn_search(&query, 0.5, rnn::Algorithm::Clustered).unwrap()),
+        before
+    );
+
+    let data = utils::gen_dataset(100, 10, 42, utils::euclidean);
+    let mut single = Cakes::new(data, Some(42), &PartitionCriteria::default());
+    let data = utils::gen_dataset(100, 10, 43, utils::euclidean);
+    assert!(single
+        .append_shard(data, Some(42), &PartitionCriteria::default(), None)
+        .is_err());
+}
+
+#[test]
+fn compact_only_small_shards() {
+    let shards = utils::gen_dataset(500, 10, 42, utils::euclidean).make_shards(250);
+    let mut cakes = Cakes::new_randomly_sharded(shards, Some(42), &PartitionCriteria::default());
+    for (seed, cardinality) in [(43, 5), (44, 1000), (45, 5), (46, 5)] {
+        let data = utils::gen_dataset(cardinality, 10, seed, utils::euclidean);
+        cakes
+            .append_shard(data, Some(42), &PartitionCriteria::default(), None)
+            .unwrap();
+    }
+    let query = utils::gen_dataset(1, 10, 47, utils::euclidean).data()[0].clone();
+    let before = cakes.knn_search(&query, 10, knn::Algorithm::Linear);
+
+    // The large shard is neither merged into the run before it nor used to
+    // fill it.
+    cakes
+        .compact_shards(100, Some(42), &PartitionCriteria::default())
+        .unwrap();
+    assert_eq!(cakes.shard_cardinalities(), vec![250, 250, 5, 1000, 10]);
+    assert_eq!(cakes.knn_search(&query, 10, knn::Algorithm::Linear), before);
+}
+
+#[test]
+fn new_auto() {
+    let criteria = PartitionCriteria::default();
+
+    let data = utils::gen_dataset(1000, 10, 42, utils::euclidean);
+    let cakes = Cakes::new_auto(data, usize::MAX, Some(42), &criteria);
+    let layout = cakes.auto_layout().unwrap();
+    assert!(!layout.is_sharded());
+    assert_eq!(cakes.num_shards(), 1);
+    assert_eq!(layout.cardinality, 1000);
+    assert!(layout.bytes_per_instance >= layout.instance_bytes);
+
+    let data = utils::gen_dataset(1000, 10, 42, utils::euclidean);
+    let budget = layout.estimated_total_bytes / 3;
+    let cakes = Cakes::new_auto(data, budget, Some(42), &criteria);
+    let layout = cakes.auto_layout().unwrap().clone();
+    let max_cardinality = layout.max_shard_cardinality.unwrap();
+    assert!(cakes.num_shards() > 1);
+    assert_eq!(cakes.total_cardinality(), 1000);
+    assert!(cakes.shard_cardinalities().iter().all(|&c| c <= max_cardinality));
+
+    let query = utils::gen_dataset(1, 10, 43, utils::euclidean).data()[0].clone();
+    let hits = cakes.knn_search(&query, 10, knn::Algorithm::GreedySieve);
+    let expected = cakes.linear_knn_search(&query, 10);
+    let recall = utils::compute_recall(hits, expected);
+    assert!(approx_eq!(f32, recall, 1.0), "Recall: {}", recall);
+
+    let tmp_dir = tempdir::TempDir::new("auto-layout-test").unwrap();
+    cakes.save(tmp_dir.path()).unwrap();
+    let loaded = Cakes::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(tmp_dir.path(), utils::euclidean, false).unwrap();
+    assert_eq!(loaded.auto_layout(), Some(&layout));
+
+    // Saving an index built without `new_auto` into the same directory drops
+    // the stale layout.
+    let data = utils::gen_dataset(1000, 10, 42, utils::euclidean);
+    Cakes::new_randomly_sharded(data.make_shards(max_cardinality), Some(42), &criteria)
+        .save(tmp_dir.path())
+        .unwrap();
+    let loaded = Cakes::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(tmp_dir.path(), utils::euclidean, false).unwrap();
+    assert_eq!(loaded.auto_layout(), None);
+}
+
+#[test]
+fn tune_with_queries() {
+    let data = utils::gen_dataset(1000, 10, 42, utils::euclidean);
+    let mut cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+    let queries = utils::gen_dataset(20, 10, 43, utils::euclidean).data().to_vec();
+
+    assert!(cakes.auto_tune_knn_with_queries(&[], 10, 1.0).is_err());
+
+    let report = cakes.auto_tune_knn_with_queries(&queries, 10, 1.0).unwrap().clone();
+    assert_eq!(report.search, "knn");
+    assert_eq!(report.num_queries, 20);
+    assert_eq!(report.algorithms.len(), 1 + knn::Algorithm::variants().len());
+    assert_eq!(report.algorithms[0].algorithm, knn::Algorithm::Linear.name());
+    assert_eq!(report.best, cakes.tuned_knn_algorithm().name());
+    for algo in &report.algorithms {
+        let t = &algo.timings;
+        assert!(t.min <= t.p50 && t.p50 <= t.p90 && t.p90 <= t.p99 && t.p99 <= t.max);
+        assert!(approx_eq!(f64, algo.recall, 1.0), "{}: {}", algo.algorithm, algo.recall);
+    }
+
+    let rnn_report = cakes.auto_tune_rnn_with_queries(&queries, 0.5, 1.0).unwrap().clone();
+    assert_eq!(rnn_report.best, cakes.tuned_rnn_algorithm().name());
+
+    let tmp_dir = tempdir::TempDir::new("tuning-report-test").unwrap();
+    cakes.save(tmp_dir.path()).unwrap();
+    assert!(tmp_dir.path().join("knn-tuning.json").exists());
+    let loaded = Cakes::<Vec<f32>, f32, VecDataset<_, _, usize>>::load(tmp_dir.path(), utils::euclidean, false).unwrap();
+    assert_eq!(loaded.knn_tuning_report(), Some(&report));
+    assert_eq!(loaded.rnn_tuning_report(), Some(&rnn_report));
+
+    cakes.auto_tune_knn(10, 7);
+    assert!(cakes.knn_tuning_report().is_none());
+    cakes.save(tmp_dir.path()).unwrap();
+    assert!(!tmp_dir.path().join("knn-tuning.json").exists());
+}
+
+#[test]
+fn handle_hot_swap() {
+    let data = utils::gen_dataset(1000, 10, 42, utils::euclidean);
+    let handle = std::sync::Arc::new(CakesHandle::new(Cakes::new(
+        data,
+        Some(42),
+        &PartitionCriteria::default(),
+    )));
+    let query = utils::gen_dataset(1, 10, 43, utils::euclidean).data()[0].clone();
+
+    // A snapshot taken before the swap keeps searching the old index.
+    let old = handle.snapshot();
+    assert_eq!(old.total_cardinality(), 1000);
+
+    let readers = (0..4)
+        .map(|_| {
+            let handle = std::sync::Arc::clone(&handle);
+            let query = query.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    let cakes = handle.snapshot();
+                    let hits = cakes.knn_search(&query, 10, knn::Algorithm::GreedySieve);
+                    let expected = cakes.linear_knn_search(&query, 10);
+                    let recall = utils::compute_recall(hits, expected);
+                    assert!(approx_eq!(f32, recall, 1.0), "Recall: {}", recall);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let swap = handle.replace_in_background(|_| {
+        let data = utils::gen_dataset(2000, 10, 44, utils::euclidean);
+        Ok(Cakes::new(data, Some(42), &PartitionCriteria::default()))
+    });
+    let previous = swap.join().unwrap().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    assert!(std::sync::Arc::ptr_eq(&old, &previous));
+    assert_eq!(handle.generation(), 1);
+    assert_eq!(handle.snapshot().total_cardinality(), 2000);
+    assert_eq!(old.total_cardinality(), 1000);
+    assert_eq!(handle.knn_search(&query, 10, knn::Algorithm::Linear).len(), 10);
+
+    let failed = handle.replace_in_background(|_| Err("could not build".to_string()));
+    assert!(failed.join().unwrap().is_err());
+    assert_eq!(handle.generation(), 1);
+}
+
+#[test]
+fn handle_stale_swap() {
+    let handle = std::sync::Arc::new(CakesHandle::new(Cakes::new(
+        utils::gen_dataset(100, 10, 42, utils::euclidean),
+        Some(42),
+        &PartitionCriteria::default(),
+    )));
+
+    // A slow replacement is started from generation 0 and held until a
+    // faster one has been swapped in.
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let slow = handle.replace_in_background(move |_| {
+        started_tx.send(()).unwrap();
+        release_rx.recv().unwrap();
+        let data = utils::gen_dataset(200, 10, 43, utils::euclidean);
+        Ok(Cakes::new(data, Some(42), &PartitionCriteria::default()))
+    });
+    started_rx.recv().unwrap();
+
+    let fast = handle.replace_in_background(|_| {
+        let data = utils::gen_dataset(300, 10, 44, utils::euclidean);
+        Ok(Cakes::new(data, Some(42), &PartitionCriteria::default()))
+    });
+    assert_eq!(fast.join().unwrap().unwrap().total_cardinality(), 100);
+
+    release_tx.send(()).unwrap();
+    assert!(slow.join().unwrap().is_err());
+    assert_eq!(handle.generation(), 1);
+    assert_eq!(handle.snapshot().total_cardinality(), 300);
+}
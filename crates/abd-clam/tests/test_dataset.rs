@@ -1,6 +1,13 @@
 //! Tests for the dataset module.
 
-use abd_clam::{Dataset, VecDataset};
+use std::sync::OnceLock;
+
+use distances::Number;
+
+use abd_clam::{
+    chaoda::pretrained_models, impl_instance, knn, rnn, BitVector, CachedDataset, Cakes, CompositeMetric, Dataset,
+    DatasetView, Graph, Instance, MatrixDataset, PartitionCriteria, SparseVector, Tree, VecDataset,
+};
 use rand::prelude::*;
 use tempdir::TempDir;
 use test_case::test_case;
@@ -50,8 +57,10 @@ fn original_indices() {
 
     for (i, (p, v)) in permutation.into_iter().zip(permuted_data).enumerate() {
         assert_eq!(dataset.original_index(i), p);
+        assert_eq!(dataset.permuted_index(p), Some(i));
         assert_eq!(dataset[i], v);
     }
+    assert_eq!(dataset.permuted_index(6), None);
 }
 
 #[test]
@@ -129,3 +138,388 @@ fn load_errors() {
     let other = VecDataset::<Vec<f32>, f32, usize>::load(&tmp_file, utils::euclidean, false);
     assert!(other.is_err());
 }
+
+#[test]
+fn sparse_vectors() {
+    assert!(SparseVector::new(vec![0, 2], vec![1_f32]).is_err());
+    assert!(SparseVector::new(vec![2, 2], vec![1_f32, 2.]).is_err());
+
+    let mut rng = StdRng::seed_from_u64(42);
+    let dense = (0..500)
+        .map(|_| {
+            (0..100)
+                .map(|_| {
+                    if rng.gen_bool(0.1) {
+                        rng.gen_range(-1_f32..1.)
+                    } else {
+                        0.
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let sparse = dense.iter().map(|x| SparseVector::from_dense(x)).collect::<Vec<_>>();
+
+    for (x, s) in dense.iter().zip(sparse.iter()) {
+        assert_eq!(&s.to_dense(100), x);
+        assert_eq!(SparseVector::<f32>::from_bytes(&s.to_bytes()).unwrap(), *s);
+    }
+
+    let metric = SparseVector::<f32>::euclidean::<f32>;
+    let data = VecDataset::new("sparse".to_string(), sparse, metric, false);
+    let cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+
+    let dense_data = VecDataset::new("dense".to_string(), dense.clone(), utils::euclidean::<f32, f32>, false);
+    let dense_cakes = Cakes::new(dense_data, Some(42), &PartitionCriteria::default());
+
+    for query in dense.iter().take(10) {
+        let sparse_hits = cakes.knn_search(&SparseVector::from_dense(query), 5, knn::Algorithm::RepeatedRnn);
+        let dense_hits = dense_cakes.knn_search(query, 5, knn::Algorithm::Linear);
+        let mut sparse_distances = sparse_hits.into_iter().map(|(_, d)| d).collect::<Vec<_>>();
+        let mut dense_distances = dense_hits.into_iter().map(|(_, d)| d).collect::<Vec<_>>();
+        sparse_distances.sort_by(f32::total_cmp);
+        dense_distances.sort_by(f32::total_cmp);
+        for (s, d) in sparse_distances.into_iter().zip(dense_distances) {
+            assert!((s - d).abs() <= 1e-5, "{s} vs {d}");
+        }
+    }
+
+    let tmp_dir = TempDir::new("sparse").unwrap();
+    let tmp_file = tmp_dir.path().join("dataset.save");
+    cakes.shards()[0].save(&tmp_file).unwrap();
+    let other = VecDataset::<SparseVector<f32>, f32, usize>::load(&tmp_file, metric, false).unwrap();
+    assert_eq!(other.data(), cakes.shards()[0].data());
+}
+
+#[test]
+fn bit_vectors() {
+    assert!(BitVector::from_words(vec![0, 0], 64).is_err());
+    assert!(BitVector::from_words(vec![1 << 10], 10).is_err());
+
+    let mut rng = StdRng::seed_from_u64(42);
+    let bools = (0..500)
+        .map(|_| (0..100).map(|_| rng.gen_bool(0.3)).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let bits = bools.iter().map(|x| BitVector::from_bools(x)).collect::<Vec<_>>();
+
+    for (x, b) in bools.iter().zip(bits.iter()) {
+        assert_eq!(&b.to_bools(), x);
+        assert_eq!(b.count_ones(), x.iter().filter(|&&v| v).count());
+        assert_eq!(BitVector::from_bytes(&b.to_bytes()).unwrap(), *b);
+    }
+
+    let mut b = BitVector::zeros(70);
+    b.set(67, true);
+    assert!(b.get(67) && !b.get(66) && !b.get(80));
+    b.set(67, false);
+    assert_eq!(b, BitVector::zeros(70));
+    assert!((BitVector::jaccard::<f32>(&b, &b) - 1.).abs() <= f32::EPSILON);
+    assert!((BitVector::dice::<f32>(&b, &b) - 1.).abs() <= f32::EPSILON);
+    let mismatched = std::panic::catch_unwind(|| BitVector::hamming::<u32>(&b, &BitVector::zeros(64)));
+    assert!(mismatched.is_err());
+
+    let data = VecDataset::new("bits".to_string(), bits, BitVector::hamming::<u32>, false);
+    let cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+
+    for (q, query) in bools.iter().enumerate().take(10) {
+        let hits = cakes.knn_search(&BitVector::from_bools(query), 5, knn::Algorithm::RepeatedRnn);
+        let mut hits = hits.into_iter().map(|(_, d)| d).collect::<Vec<_>>();
+        hits.sort_unstable();
+
+        let mut linear = bools
+            .iter()
+            .map(|x| u32::try_from(x.iter().zip(query).filter(|(a, b)| a != b).count()).unwrap())
+            .collect::<Vec<_>>();
+        linear.sort_unstable();
+        assert_eq!(hits, linear[..5], "query {q}");
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Record {
+    embedding: Vec<f32>,
+    name: String,
+    tags: Vec<u32>,
+}
+
+impl_instance!(Record { embedding, name, tags });
+
+static RECORD_SUM: OnceLock<CompositeMetric<Record, f32>> = OnceLock::new();
+static RECORD_MAX: OnceLock<CompositeMetric<Record, f32>> = OnceLock::new();
+
+fn record_fields(metric: CompositeMetric<Record, f32>) -> CompositeMetric<Record, f32> {
+    metric
+        .with_field(1., |r: &Record| r.embedding.as_slice(), distances::vectors::euclidean)
+        .with_field(
+            0.1,
+            |r: &Record| r.name.as_str(),
+            |x: &str, y: &str| distances::strings::levenshtein::<u16>(x, y).as_f32(),
+        )
+        .with_field(2., |r: &Record| r.tags.as_slice(), distances::sets::jaccard)
+}
+
+fn record_sum(x: &Record, y: &Record) -> f32 {
+    RECORD_SUM
+        .get_or_init(|| record_fields(CompositeMetric::weighted_sum()))
+        .distance(x, y)
+}
+
+fn record_max(x: &Record, y: &Record) -> f32 {
+    RECORD_MAX
+        .get_or_init(|| record_fields(CompositeMetric::weighted_max()))
+        .distance(x, y)
+}
+
+#[test]
+fn composite_instances() {
+    let tuple = (vec![1_u8, 2], "abc".to_string(), 7_i64, true);
+    assert_eq!(
+        <(Vec<u8>, String, i64, bool)>::from_bytes(&tuple.to_bytes()).unwrap(),
+        tuple
+    );
+    assert_eq!(
+        <(Vec<u8>, String, i64, bool)>::type_name(),
+        "(Vec<u8>, String, i64, bool)"
+    );
+    assert!(<(String, String)>::from_bytes(&tuple.to_bytes()).is_err());
+
+    let mut rng = StdRng::seed_from_u64(42);
+    let records = (0..300)
+        .map(|_| Record {
+            embedding: (0..4).map(|_| rng.gen_range(-1_f32..1.)).collect(),
+            name: (0..5).map(|_| ['a', 'b', 'c'][rng.gen_range(0..3)]).collect(),
+            tags: (0..3).map(|_| rng.gen_range(0..10)).collect(),
+        })
+        .collect::<Vec<_>>();
+
+    let (x, y) = (&records[0], &records[1]);
+    assert_eq!(Record::from_bytes(&x.to_bytes()).unwrap(), *x);
+    let parts = [
+        distances::vectors::euclidean::<f32, f32>(&x.embedding, &y.embedding),
+        0.1 * distances::strings::levenshtein::<u16>(&x.name, &y.name).as_f32(),
+        2. * distances::sets::jaccard::<u32, f32>(&x.tags, &y.tags),
+    ];
+    assert!((record_sum(x, y) - parts.iter().sum::<f32>()).abs() <= 1e-6);
+    assert!((record_max(x, y) - parts.iter().copied().fold(0., f32::max)).abs() <= 1e-6);
+
+    let data = VecDataset::new("records".to_string(), records.clone(), record_sum, false);
+    let cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+    for query in records.iter().take(10) {
+        let mut hits = cakes
+            .knn_search(query, 5, knn::Algorithm::RepeatedRnn)
+            .into_iter()
+            .map(|(_, d)| d)
+            .collect::<Vec<_>>();
+        hits.sort_by(f32::total_cmp);
+        let mut linear = records.iter().map(|r| record_sum(query, r)).collect::<Vec<_>>();
+        linear.sort_by(f32::total_cmp);
+        for (h, l) in hits.into_iter().zip(linear) {
+            assert!((h - l).abs() <= 1e-5, "{h} vs {l}");
+        }
+    }
+}
+
+#[test]
+fn distance_matrices() {
+    assert!(MatrixDataset::<f32>::from_square("bad".to_string(), vec![vec![0.; 3], vec![0.; 2]]).is_err());
+    assert!(MatrixDataset::<f32>::from_condensed("bad".to_string(), vec![0.; 4]).is_err());
+
+    let (cardinality, num_queries) = (300, 5);
+    let points = symagen::random_data::random_tabular_seedable::<f32>(cardinality + num_queries, 5, -1., 1., 42);
+    let (queries, points) = (&points[cardinality..], &points[..cardinality]);
+    let euclidean = |x: &Vec<f32>, y: &Vec<f32>| distances::vectors::euclidean::<f32, f32>(x, y);
+
+    let square = points
+        .iter()
+        .map(|x| points.iter().map(|y| euclidean(x, y)).collect())
+        .collect();
+    let condensed = (0..cardinality)
+        .flat_map(|i| ((i + 1)..cardinality).map(move |j| (i, j)))
+        .map(|(i, j)| euclidean(&points[i], &points[j]))
+        .collect();
+    let square = MatrixDataset::from_square("square".to_string(), square).unwrap();
+    let mut condensed = MatrixDataset::from_condensed("condensed".to_string(), condensed).unwrap();
+    assert_eq!(condensed.size(), cardinality);
+    for (i, j) in [(0, 0), (0, 1), (7, 3), (299, 298)] {
+        assert_eq!(square.one_to_one(i, j), condensed.one_to_one(i, j));
+    }
+
+    let query_ids = queries
+        .iter()
+        .map(|q| condensed.add_query(points.iter().map(|x| euclidean(q, x)).collect()))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert!(condensed.add_query(vec![0.; 3]).is_err());
+
+    let criteria = PartitionCriteria::default();
+    let tree = Tree::new(condensed, Some(42)).partition(&criteria).with_ratios(true);
+    let data = tree.data();
+    for i in (0..cardinality).step_by(17) {
+        let (x, y) = (data[i], data[(i + 1) % cardinality]);
+        assert_eq!(data.original_index(i), x);
+        assert_eq!(
+            data.one_to_one(i, (i + 1) % cardinality),
+            euclidean(&points[x], &points[y])
+        );
+    }
+
+    let graph = Graph::from_tree(&tree, &pretrained_models::get_meta_ml_scorers().first().unwrap().1, 4).unwrap();
+    assert_eq!(
+        graph.with_adjacency_matrix().with_distance_matrix().population(),
+        cardinality
+    );
+
+    let tmp_dir = TempDir::new("matrix").unwrap();
+    let path = tmp_dir.path().join("matrix.bin");
+    data.save(&path).unwrap();
+    let loaded = MatrixDataset::<f32>::load(&path, data.metric(), false).unwrap();
+    assert_eq!(loaded.permuted_indices(), data.permuted_indices());
+    assert_eq!(loaded.num_queries(), num_queries);
+    assert_eq!(loaded.one_to_one(3, 5), data.one_to_one(3, 5));
+
+    let cakes = Cakes::new(loaded, Some(42), &criteria);
+    for (query, &id) in queries.iter().zip(query_ids.iter()) {
+        let mut hits = cakes
+            .knn_search(&id, 5, knn::Algorithm::RepeatedRnn)
+            .into_iter()
+            .map(|(_, d)| d)
+            .collect::<Vec<_>>();
+        hits.sort_by(f32::total_cmp);
+        let mut linear = points.iter().map(|x| euclidean(query, x)).collect::<Vec<_>>();
+        linear.sort_by(f32::total_cmp);
+        assert_eq!(hits, linear[..5]);
+    }
+
+    // Grouped batch searches cannot compare the queries with each other, so
+    // they fall back to searching for each query on its own.
+    let batch = query_ids.iter().chain([3, 250].iter()).collect::<Vec<_>>();
+    let sorted = |hits: Vec<Vec<(usize, f32)>>| {
+        hits.into_iter()
+            .map(|mut h| {
+                h.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+                h.into_iter().map(|(_, d)| d).collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        sorted(cakes.batch_knn_search_grouped(&batch, 5)),
+        sorted(cakes.batch_knn_search(&batch, 5, knn::Algorithm::Linear))
+    );
+    assert_eq!(
+        sorted(cakes.batch_rnn_search_grouped(&batch, 0.5)),
+        sorted(cakes.batch_rnn_search(&batch, 0.5, rnn::Algorithm::Linear))
+    );
+
+    let cakes = Cakes::new(square, Some(42), &criteria);
+    let hits = cakes.knn_search(&7, 1, knn::Algorithm::Linear);
+    assert_eq!(hits.into_iter().map(|(_, d)| d).collect::<Vec<_>>(), vec![0.]);
+}
+
+#[test]
+fn cached_distances() {
+    let data = utils::gen_dataset(1000, 10, 42, utils::euclidean);
+    let criteria = PartitionCriteria::default();
+    let tree = Tree::new(data, Some(42)).partition(&criteria);
+
+    for capacity in [0, 1000, 1 << 20] {
+        let data = CachedDataset::new(utils::gen_dataset(1000, 10, 42, utils::euclidean), capacity);
+        let cached = Tree::new(data, Some(42)).partition(&criteria);
+
+        assert_eq!(cached.data().permuted_indices(), tree.data().permuted_indices());
+        let (clusters, cached_clusters) = (tree.root().subtree(), cached.root().subtree());
+        assert_eq!(clusters.len(), cached_clusters.len());
+        for (c, cc) in clusters.into_iter().zip(cached_clusters) {
+            assert_eq!(c.name(), cc.name());
+            assert_eq!(c.arg_center(), cc.arg_center());
+            assert_eq!(c.arg_radial(), cc.arg_radial());
+            assert_eq!(c.radius(), cc.radius());
+        }
+
+        let stats = cached.data().cache_stats();
+        assert!(stats.len <= cached.data().capacity());
+        if capacity == 0 {
+            assert_eq!((stats.hits, stats.len), (0, 0));
+        } else {
+            assert!(stats.hits > 0, "{stats:?}");
+        }
+        if capacity == 1000 {
+            assert!(stats.evictions > 0, "{stats:?}");
+        }
+
+        let data = cached.data();
+        for i in (0..data.cardinality()).step_by(37) {
+            assert_eq!(data.one_to_one(i, 0), tree.data().one_to_one(i, 0));
+        }
+        data.clear_cache();
+        assert_eq!(data.cache_stats().hits + data.cache_stats().len, 0);
+    }
+}
+
+#[test]
+fn cached_shards() {
+    let capacity = 1 << 20;
+    let data = CachedDataset::new(utils::gen_dataset(1000, 10, 42, utils::euclidean), capacity);
+    let shards = data.make_shards(250);
+    assert_eq!(shards.len(), 4);
+    assert!(shards.iter().all(|s| s.capacity() == capacity / 4));
+    assert_eq!(shards.iter().map(|s| s.cardinality()).sum::<usize>(), 1000);
+}
+
+#[test]
+fn dataset_views() {
+    let parent = utils::gen_dataset(1000, 10, 42, utils::euclidean);
+    assert!(DatasetView::new(&parent, vec![0, 1000]).is_err());
+    assert!(DatasetView::from_range(&parent, 900..1001).is_err());
+
+    let evens = (0..parent.cardinality()).step_by(2).collect::<Vec<_>>();
+    let view = DatasetView::new(&parent, evens.clone()).unwrap();
+    let criteria = PartitionCriteria::default();
+    let cakes = Cakes::new(view, Some(42), &criteria);
+    assert!(parent.permuted_indices().is_none());
+
+    for q in [1, 3, 501] {
+        let query = &parent[q];
+        let mut hits = cakes
+            .knn_search(query, 5, knn::Algorithm::RepeatedRnn)
+            .into_iter()
+            .map(|(_, d)| d)
+            .collect::<Vec<f32>>();
+        hits.sort_by(f32::total_cmp);
+        let mut linear = parent.query_to_many(query, &evens);
+        linear.sort_by(f32::total_cmp);
+        assert_eq!(hits, linear[..5]);
+    }
+
+    let tree = Tree::new(utils::gen_dataset(1000, 10, 42, utils::euclidean), Some(42)).partition(&criteria);
+    let data = tree.data();
+    let [left, _] = tree.root().children().unwrap();
+    let view = DatasetView::from_range(data, left.indices()).unwrap();
+    assert_eq!(view.cardinality(), left.cardinality());
+    for i in 0..view.cardinality() {
+        assert_eq!(view[i], data[left.offset() + i]);
+        assert_eq!(view.one_to_one(i, 0), data.one_to_one(left.offset() + i, left.offset()));
+    }
+
+    let view_tree = Tree::new(view, Some(42)).partition(&criteria);
+    let view = view_tree.data();
+    assert_eq!(view.cardinality(), left.cardinality());
+    for i in 0..view.cardinality() {
+        assert_eq!(view[i], data[view.parent_index(i)]);
+    }
+
+    let tmp_dir = TempDir::new("view").unwrap();
+    let path = tmp_dir.path().join("view.bin");
+    view.save(&path).unwrap();
+    assert!(DatasetView::<VecDataset<Vec<f32>, f32, usize>>::load(&path, utils::euclidean, false).is_err());
+    let loaded = DatasetView::load_with(data, &path).unwrap();
+    assert_eq!(loaded.parent_indices(), view.parent_indices());
+    assert_eq!(loaded.permuted_indices(), view.permuted_indices());
+    assert_eq!(loaded.name(), view.name());
+
+    let shards = loaded.make_shards(100);
+    assert_eq!(
+        shards.iter().map(Dataset::cardinality).sum::<usize>(),
+        left.cardinality()
+    );
+    assert!(shards.iter().all(|s| s.cardinality() <= 100));
+}
@@ -0,0 +1,281 @@
+//! Tests for the readers module.
+
+use abd_clam::{readers, Dataset};
+use rand::prelude::*;
+use tempdir::TempDir;
+
+mod utils;
+
+/// Writes rows in the TEXMEX format, with values encoded by `to_bytes`.
+fn write_vecs<T: Copy>(path: &std::path::Path, rows: &[Vec<T>], to_bytes: fn(T) -> Vec<u8>) {
+    let mut bytes = Vec::new();
+    for row in rows {
+        bytes.extend_from_slice(&i32::try_from(row.len()).unwrap().to_le_bytes());
+        row.iter().for_each(|&v| bytes.extend(to_bytes(v)));
+    }
+    std::fs::write(path, bytes).unwrap();
+}
+
+#[test]
+fn npy() {
+    let tmp_dir = TempDir::new("readers").unwrap();
+    let data = symagen::random_data::random_tabular(100, 8, -1_f32, 1., &mut StdRng::seed_from_u64(42));
+    let array = ndarray::Array2::from_shape_fn((100, 8), |(i, j)| data[i][j]);
+    let path = tmp_dir.path().join("data.npy");
+    ndarray_npy::write_npy(&path, &array).unwrap();
+
+    assert_eq!(readers::npy_shape(&path).unwrap(), (100, 8));
+    assert_eq!(readers::read_npy::<f32, _>(&path, None).unwrap(), data);
+    assert_eq!(readers::read_npy::<f32, _>(&path, Some(40..60)).unwrap(), data[40..60]);
+    assert!(readers::read_npy::<f32, _>(&path, Some(90..101)).is_err());
+    assert!(readers::read_npy::<f64, _>(&path, None).is_err());
+    assert!(readers::read_npy::<i32, _>(&path, None).is_err());
+
+    let ints = ndarray::Array2::from_shape_fn((10, 3), |(i, j)| i64::try_from(i * 3 + j).unwrap() - 15);
+    let path = tmp_dir.path().join("ints.npy");
+    ndarray_npy::write_npy(&path, &ints).unwrap();
+    let rows = readers::read_npy::<i64, _>(&path, Some(2..4)).unwrap();
+    assert_eq!(rows, vec![vec![-9, -8, -7], vec![-6, -5, -4]]);
+}
+
+#[test]
+fn texmex() {
+    let tmp_dir = TempDir::new("readers").unwrap();
+    let data = symagen::random_data::random_tabular(50, 4, -1_f32, 1., &mut StdRng::seed_from_u64(42));
+
+    let path = tmp_dir.path().join("data.fvecs");
+    write_vecs(&path, &data, |v| v.to_le_bytes().to_vec());
+    assert_eq!(readers::vecs_shape(&path).unwrap(), (50, 4));
+    assert_eq!(readers::read_fvecs(&path, None).unwrap(), data);
+    assert_eq!(readers::read_fvecs(&path, Some(10..20)).unwrap(), data[10..20]);
+    assert!(readers::read_fvecs(&path, Some(45..51)).is_err());
+
+    let bytes = (0..20)
+        .map(|i| (0..6).map(|j| i * 6 + j).collect::<Vec<u8>>())
+        .collect::<Vec<_>>();
+    let path = tmp_dir.path().join("data.bvecs");
+    write_vecs(&path, &bytes, |v| vec![v]);
+    assert_eq!(readers::vecs_shape(&path).unwrap(), (20, 6));
+    assert_eq!(readers::read_bvecs(&path, Some(5..7)).unwrap(), bytes[5..7]);
+
+    let neighbors = (0..10).map(|i| vec![i, i + 1, i + 2]).collect::<Vec<Vec<i32>>>();
+    let path = tmp_dir.path().join("gt.ivecs");
+    write_vecs(&path, &neighbors, |v| v.to_le_bytes().to_vec());
+    assert_eq!(readers::read_ivecs(&path, None).unwrap(), neighbors);
+    let truth = readers::read_ground_truth(&path, Some(3..5)).unwrap();
+    assert_eq!(truth, vec![vec![3, 4, 5], vec![4, 5, 6]]);
+
+    let mut ragged = neighbors;
+    ragged[5].push(0);
+    write_vecs(&path, &ragged, |v| v.to_le_bytes().to_vec());
+    assert!(readers::read_ivecs(&path, None).is_err());
+}
+
+#[test]
+fn sharded_loading() {
+    let tmp_dir = TempDir::new("readers").unwrap();
+    let data = symagen::random_data::random_tabular(100, 4, -1_f32, 1., &mut StdRng::seed_from_u64(42));
+    let path = tmp_dir.path().join("data.fvecs");
+    write_vecs(&path, &data, |v| v.to_le_bytes().to_vec());
+
+    let shards = [0..40, 40..80, 80..100]
+        .into_iter()
+        .map(|rows| {
+            let start = rows.start;
+            let rows = readers::read_fvecs(&path, Some(rows)).unwrap();
+            readers::vec_dataset(
+                format!("shard-{start}"),
+                rows,
+                start,
+                utils::euclidean::<f32, f32>,
+                false,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(shards.iter().map(Dataset::cardinality).sum::<usize>(), 100);
+    for shard in &shards {
+        for (i, &row) in shard.metadata().iter().enumerate() {
+            assert_eq!(shard[i], data[row]);
+        }
+    }
+}
+
+#[test]
+fn fasta_and_fastq() {
+    let contents = "\
+>seq0 first record
+ACGT
+ACG
+
+>seq1
+AC
+@read2 a read
+ACGTAC
+GT
++
+@@III
+III
+>seq3
+ACGTACGTACGT
+";
+
+    let records = readers::SequenceReader::new(contents.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(records.len(), 4);
+    assert_eq!(records[0].id, "seq0");
+    assert_eq!(records[0].description, "first record");
+    assert_eq!(records[0].sequence, "ACGTACG");
+    assert_eq!(records[0].quality, None);
+    assert_eq!(records[2].id, "read2");
+    assert_eq!(records[2].sequence, "ACGTACGT");
+    assert_eq!(records[2].quality.as_deref(), Some("@@IIIIII"));
+    assert_eq!(records[3].sequence, "ACGTACGTACGT");
+
+    let mut reader = readers::SequenceReader::new(contents.as_bytes())
+        .with_min_length(3)
+        .with_max_length(10)
+        .with_quality(false);
+    let data = reader
+        .next_dataset("seqs".to_string(), None, utils::levenshtein::<u32>, true)
+        .unwrap()
+        .unwrap();
+    assert_eq!(reader.num_read(), 4);
+    assert_eq!(data.cardinality(), 2);
+    let ids = data.metadata().iter().map(|m| m.id.as_str()).collect::<Vec<_>>();
+    assert_eq!(ids, ["seq0", "read2"]);
+    assert!(data.metadata().iter().all(|m| m.quality.is_none()));
+
+    let bad = ">seq0\nACGT\n@read1\nACGT\n+\nII\n";
+    assert!(readers::SequenceReader::new(bad.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .is_err());
+    let bad = "ACGT\n>seq0\nACGT\n";
+    assert!(readers::SequenceReader::new(bad.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .is_err());
+}
+
+#[test]
+fn fastq_streaming() {
+    let tmp_dir = TempDir::new("readers").unwrap();
+    let path = tmp_dir.path().join("reads.fastq");
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut contents = String::new();
+    for i in 0..25 {
+        let seq = (0..20)
+            .map(|_| ['A', 'C', 'G', 'T'][rng.gen_range(0..4)])
+            .collect::<String>();
+        contents.push_str(&format!("@read{i}\n{seq}\n+\n{}\n", "I".repeat(20)));
+    }
+    std::fs::write(&path, contents).unwrap();
+
+    let mut reader = readers::SequenceReader::open(&path).unwrap();
+    assert!(reader
+        .next_dataset("reads".to_string(), Some(0), utils::levenshtein::<u32>, true)
+        .is_err());
+    assert_eq!(reader.num_read(), 0);
+    let mut sizes = Vec::new();
+    while let Some(data) = reader
+        .next_dataset("reads".to_string(), Some(10), utils::levenshtein::<u32>, true)
+        .unwrap()
+    {
+        sizes.push(data.cardinality());
+        let metadata = data.metadata()[0].clone();
+        assert_eq!(metadata.quality.as_deref(), Some("IIIIIIIIIIIIIIIIIIII"));
+        let bytes = abd_clam::Instance::to_bytes(&metadata);
+        assert_eq!(
+            <readers::SequenceMetadata as abd_clam::Instance>::from_bytes(&bytes).unwrap(),
+            metadata
+        );
+    }
+    assert_eq!(sizes, [10, 10, 5]);
+}
+
+#[cfg(feature = "csv")]
+#[test]
+fn tabular() {
+    let tmp_dir = TempDir::new("readers").unwrap();
+    let path = tmp_dir.path().join("data.tsv");
+    let contents = "\
+id\tx\ty\tlabel
+0\t1.5\t2\ttrue
+1\t\t3\tfalse
+2\t-4\tNA\ttrue
+3\t0.25\t8\tfalse
+";
+    std::fs::write(&path, contents).unwrap();
+
+    let reader = readers::TabularReader::tsv()
+        .with_features(vec![readers::Column::Name("x".to_string()), readers::Column::Index(2)])
+        .with_metadata(readers::Column::Name("label".to_string()));
+
+    let err = reader.read::<f32, _>(&path).unwrap_err();
+    assert!(err.contains("Line 3, column 1 (`x`)"), "{err}");
+
+    let data = reader
+        .clone()
+        .with_missing(readers::MissingValues::SkipRow)
+        .read_dataset::<f32, f32, bool, _>(&path, "data".to_string(), utils::euclidean, false)
+        .unwrap();
+    assert_eq!(data.cardinality(), 2);
+    assert_eq!(data[0], vec![1.5, 2.]);
+    assert_eq!(data[1], vec![0.25, 8.]);
+    assert_eq!(data.metadata(), [true, false]);
+
+    let rows = reader
+        .clone()
+        .with_missing(readers::MissingValues::Fill(0.))
+        .read::<f64, _>(&path)
+        .unwrap();
+    assert_eq!(rows, vec![vec![1.5, 2.], vec![0., 3.], vec![-4., 0.], vec![0.25, 8.]]);
+
+    let ids = readers::TabularReader::tsv()
+        .with_features(vec![readers::Column::Name("id".to_string())])
+        .read::<u8, _>(&path)
+        .unwrap();
+    assert_eq!(ids, vec![vec![0], vec![1], vec![2], vec![3]]);
+
+    let err = readers::TabularReader::tsv()
+        .with_features(vec![readers::Column::Name("x".to_string())])
+        .with_missing(readers::MissingValues::Fill(0.))
+        .read::<i32, _>(&path)
+        .unwrap_err();
+    assert!(err.contains("not an integer"), "{err}");
+
+    let err = readers::TabularReader::tsv()
+        .with_features(vec![readers::Column::Name("z".to_string())])
+        .read::<f32, _>(&path)
+        .unwrap_err();
+    assert!(err.contains("no column named `z`"), "{err}");
+
+    // Bad columns are reported from the header alone, even without any rows.
+    let empty = tmp_dir.path().join("empty.tsv");
+    std::fs::write(&empty, "id\tx\ty\tlabel\n").unwrap();
+    let err = readers::TabularReader::tsv()
+        .with_features(vec![readers::Column::Name("z".to_string())])
+        .read::<f32, _>(&empty)
+        .unwrap_err();
+    assert!(err.contains("no column named `z`"), "{err}");
+    let err = readers::TabularReader::tsv()
+        .with_features(vec![readers::Column::Index(4)])
+        .read::<f32, _>(&empty)
+        .unwrap_err();
+    assert!(err.contains("no column 4"), "{err}");
+    let rows = readers::TabularReader::tsv()
+        .with_features(vec![readers::Column::Name("x".to_string())])
+        .read::<f32, _>(&empty)
+        .unwrap();
+    assert!(rows.is_empty());
+
+    let path = tmp_dir.path().join("data.csv");
+    std::fs::write(&path, "1,2,a\n3,4,b\n").unwrap();
+    let data = readers::TabularReader::csv()
+        .with_headers(false)
+        .with_metadata(readers::Column::Index(2))
+        .read_dataset::<i64, i64, String, _>(&path, "data".to_string(), utils::euclidean_sq, false)
+        .unwrap();
+    assert_eq!(data[1], vec![3, 4]);
+    assert_eq!(data.metadata(), ["a".to_string(), "b".to_string()]);
+}
@@ -0,0 +1,204 @@
+//! A minimal HTTP/1.1 reader and writer for JSON requests and responses.
+//!
+//! Each connection carries a single request and is closed after the response.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+};
+
+use distances::Number;
+
+/// The largest request body that will be read, in bytes.
+const MAX_BODY_BYTES: usize = 64 * 1024 * 1024;
+
+/// The longest request line or header line that will be read, in bytes,
+/// including the line ending.
+const MAX_LINE_BYTES: usize = 8 * 1024;
+
+/// The largest number of headers that will be read.
+const MAX_HEADERS: usize = 100;
+
+/// The most bytes of an unread request that are discarded before closing its
+/// connection.
+const MAX_LINGER_BYTES: u64 = 64 * 1024;
+
+/// An HTTP request.
+#[derive(Debug)]
+pub struct Request {
+    /// The method, e.g. `GET` or `POST`.
+    pub method: String,
+    /// The path, without any query string.
+    pub path: String,
+    /// The body of the request.
+    pub body: String,
+}
+
+/// An HTTP response with a JSON body.
+#[derive(Debug)]
+pub struct Response {
+    /// The status code.
+    pub status: u16,
+    /// The JSON body.
+    pub body: String,
+}
+
+impl Response {
+    /// Creates a response with a status code of 200.
+    pub fn ok(value: &impl serde::Serialize) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => Self { status: 200, body },
+            Err(e) => Self::error(500, &e.to_string()),
+        }
+    }
+
+    /// Creates an error response whose body is `{"error": message}`.
+    pub fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            body: serde_json::json!({ "error": message }).to_string(),
+        }
+    }
+
+    /// Returns the reason phrase for the status code.
+    const fn reason(&self) -> &str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            413 => "Payload Too Large",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+
+    /// Writes the response to the stream.
+    ///
+    /// # Errors
+    ///
+    /// * If the stream cannot be written to.
+    pub fn write_to(&self, stream: &mut TcpStream) -> Result<(), String> {
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason(),
+            self.body.len()
+        );
+        stream
+            .write_all(head.as_bytes())
+            .and_then(|()| stream.write_all(self.body.as_bytes()))
+            .and_then(|()| stream.flush())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Reads a request from the stream.
+///
+/// The body is read as it arrives rather than allocated up front from the
+/// `Content-Length`, so a client cannot make the server allocate more memory
+/// than it sends.
+///
+/// # Errors
+///
+/// * If the stream cannot be read from, or a read times out.
+/// * If the request line or headers are malformed.
+/// * If the request line or a header is longer than `MAX_LINE_BYTES`, or
+///   there are more than `MAX_HEADERS` headers.
+/// * If the body is larger than `MAX_BODY_BYTES` or shorter than its
+///   `Content-Length`.
+pub fn read_request(stream: &TcpStream) -> Result<Request, Response> {
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    read_line(&mut reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(Response::error(400, "Malformed request line."));
+    };
+    let method = method.to_string();
+    let path = target.split('?').next().unwrap_or(target).to_string();
+
+    let mut content_length = 0;
+    for num_headers in 0.. {
+        read_line(&mut reader, &mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if num_headers == MAX_HEADERS {
+            return Err(Response::error(431, "Too many headers."));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| Response::error(400, "Invalid Content-Length."))?;
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_BYTES {
+        return Err(Response::error(413, "Request body is too large."));
+    }
+
+    let mut body = Vec::new();
+    reader
+        .take(content_length.as_u64())
+        .read_to_end(&mut body)
+        .map_err(|e| read_error(&e))?;
+    if body.len() != content_length {
+        return Err(Response::error(
+            400,
+            "Request body is shorter than its Content-Length.",
+        ));
+    }
+    let body = String::from_utf8(body).map_err(|e| Response::error(400, &e.to_string()))?;
+
+    Ok(Request { method, path, body })
+}
+
+/// Reads a line of at most `MAX_LINE_BYTES` bytes into `line`, replacing its
+/// contents.
+///
+/// # Errors
+///
+/// * If the stream cannot be read from, or a read times out.
+/// * If the line is longer than `MAX_LINE_BYTES`.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> Result<(), Response> {
+    line.clear();
+    reader
+        .take(MAX_LINE_BYTES.as_u64())
+        .read_line(line)
+        .map_err(|e| read_error(&e))?;
+    if line.len() == MAX_LINE_BYTES && !line.ends_with('\n') {
+        return Err(Response::error(431, "Request line or header is too long."));
+    }
+    Ok(())
+}
+
+/// Closes a connection whose request was not read in full.
+///
+/// Closing a connection with unread data resets it, which can discard the
+/// response before the client reads it. The connection is instead shut down
+/// for writing and up to `MAX_LINGER_BYTES` of what is left of the request are
+/// discarded, within the read timeout of the stream.
+pub fn linger(stream: &TcpStream) {
+    if stream.shutdown(Shutdown::Write).is_ok() {
+        // The connection is being closed anyway, so errors are not relevant.
+        let _ = io::copy(&mut stream.take(MAX_LINGER_BYTES), &mut io::sink());
+    }
+}
+
+/// Converts an error from reading a request to a response.
+fn read_error(e: &io::Error) -> Response {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            Response::error(408, "Timed out reading the request.")
+        }
+        _ => Response::error(400, &e.to_string()),
+    }
+}
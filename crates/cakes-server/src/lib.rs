@@ -0,0 +1,460 @@
+#![deny(clippy::correctness)]
+#![warn(
+    missing_docs,
+    clippy::all,
+    clippy::suspicious,
+    clippy::style,
+    clippy::complexity,
+    clippy::perf,
+    clippy::pedantic,
+    clippy::nursery,
+    clippy::missing_docs_in_private_items,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::cast_lossless
+)]
+
+//! An HTTP server for searching a saved `Cakes` index.
+//!
+//! The server loads a directory written by `Cakes::save` for `f32` vectors and
+//! answers JSON requests on the following endpoints:
+//!
+//! * `GET /health` - Whether the server is up, and the generation of the index.
+//! * `GET /stats` - The shape of the index and the latency of each endpoint.
+//! * `POST /knn` - `{"query": [..], "k": 10, "algorithm": "GreedySieve"}`
+//! * `POST /rnn` - `{"query": [..], "radius": 0.5, "algorithm": "Clustered"}`
+//! * `POST /batch` - `{"queries": [[..], ..], "k": 10}` or with `"radius"`
+//!   instead of `"k"`.
+//!
+//! The `algorithm` is optional and defaults to the tuned algorithm of the
+//! index. Search endpoints respond with `{"hits": [{"index": i, "distance": d}]}`,
+//! or `{"results": [..]}` with one list of hits per query for `/batch`. The
+//! `index` of a hit is the row of the instance in the data from which the index
+//! was built, as for the `clam` CLI, and does not change when the index is
+//! rebuilt or swapped. Errors are reported as `{"error": message}`.
+//!
+//! Each connection is served on its own thread, up to a limit on the number of
+//! concurrent connections, beyond which connections are answered with a 503.
+//! Reads from a connection time out, so that idle clients cannot hold on to
+//! their threads.
+
+use std::{
+    net::{TcpListener, TcpStream},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use abd_clam::{knn, rnn, Cakes, CakesHandle, VecDataset};
+use serde::{Deserialize, Serialize};
+
+mod http;
+mod stats;
+
+use http::{Request, Response};
+pub use stats::{EndpointStats, LatencySummary};
+
+/// The default maximum number of connections served at once.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 256;
+
+/// The default time after which a read from a connection times out.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The dataset type of the indices served.
+pub type Data = VecDataset<Vec<f32>, f32, usize>;
+
+/// The metric type of the indices served.
+pub type Metric = fn(&Vec<f32>, &Vec<f32>) -> f32;
+
+/// Returns the metric with the given name.
+///
+/// # Errors
+///
+/// * If the metric is not one of `euclidean`, `euclidean_sq`, `cosine`,
+///   `manhattan` or `chebyshev`.
+pub fn metric_from_name(name: &str) -> Result<Metric, String> {
+    match name {
+        "euclidean" => Ok(|x, y| distances::vectors::euclidean(x, y)),
+        "euclidean_sq" => Ok(|x, y| distances::vectors::euclidean_sq(x, y)),
+        "cosine" => Ok(|x, y| distances::vectors::cosine(x, y)),
+        "manhattan" => Ok(|x, y| distances::vectors::manhattan(x, y)),
+        "chebyshev" => Ok(|x, y| distances::vectors::chebyshev(x, y)),
+        _ => Err(format!("Unknown metric: {name}")),
+    }
+}
+
+/// A hit in the results of a search.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Hit {
+    /// The original index of the instance, i.e. its row in the data from which
+    /// the index was built.
+    pub index: usize,
+    /// The distance from the query to the instance.
+    pub distance: f32,
+}
+
+/// The body of a `/knn` request.
+#[derive(Debug, Deserialize)]
+struct KnnRequest {
+    /// The query.
+    query: Vec<f32>,
+    /// The number of neighbors to search for.
+    k: usize,
+    /// The name of the algorithm to use.
+    algorithm: Option<String>,
+}
+
+/// The body of an `/rnn` request.
+#[derive(Debug, Deserialize)]
+struct RnnRequest {
+    /// The query.
+    query: Vec<f32>,
+    /// The search radius.
+    radius: f32,
+    /// The name of the algorithm to use.
+    algorithm: Option<String>,
+}
+
+/// The body of a `/batch` request, which has exactly one of `k` and `radius`.
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    /// The queries.
+    queries: Vec<Vec<f32>>,
+    /// The number of neighbors to search for.
+    k: Option<usize>,
+    /// The search radius.
+    radius: Option<f32>,
+    /// The name of the algorithm to use.
+    algorithm: Option<String>,
+}
+
+/// The body of a `/stats` response.
+#[derive(Debug, Serialize)]
+struct StatsResponse {
+    /// The number of times the index has been replaced.
+    generation: u64,
+    /// The total number of instances in the index.
+    cardinality: usize,
+    /// The dimensionality of the instances.
+    dimensionality: usize,
+    /// The cardinality of each shard.
+    shard_cardinalities: Vec<usize>,
+    /// The tuned RNN algorithm.
+    tuned_rnn_algorithm: String,
+    /// The tuned KNN algorithm.
+    tuned_knn_algorithm: String,
+    /// The latency of each endpoint.
+    latency: std::collections::BTreeMap<String, LatencySummary>,
+}
+
+/// Serves searches over a `Cakes` index.
+#[allow(clippy::module_name_repetitions)]
+pub struct Server {
+    /// The index.
+    handle: CakesHandle<Vec<f32>, f32, Data>,
+    /// The latency of each endpoint.
+    stats: EndpointStats,
+    /// The maximum number of connections served at once.
+    max_connections: usize,
+    /// The time after which a read from a connection times out.
+    read_timeout: Duration,
+    /// The number of connections being served.
+    active: AtomicUsize,
+}
+
+/// Releases a connection slot when the connection has been served.
+struct ConnectionSlot<'a>(&'a AtomicUsize);
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Server {
+    /// Creates a server for the given index.
+    #[must_use]
+    pub fn new(cakes: Cakes<Vec<f32>, f32, Data>) -> Self {
+        Self {
+            handle: CakesHandle::new(cakes),
+            stats: EndpointStats::default(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the maximum number of connections served at once.
+    ///
+    /// Connections beyond the limit are answered with a 503 without reading
+    /// their requests.
+    #[must_use]
+    pub const fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Sets the time after which a read from a connection times out.
+    ///
+    /// A request whose line, headers or body are not received in time is
+    /// answered with a 408.
+    #[must_use]
+    pub const fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Loads the index saved in the given directory and creates a server for it.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The directory to which the `Cakes` was saved.
+    /// * `metric` - The metric of the index.
+    ///
+    /// # Errors
+    ///
+    /// * If the index cannot be loaded.
+    pub fn load(path: &Path, metric: Metric) -> Result<Self, String> {
+        Cakes::load(path, metric, false).map(Self::new)
+    }
+
+    /// Returns the handle to the index, which can be used to replace it.
+    pub const fn handle(&self) -> &CakesHandle<Vec<f32>, f32, Data> {
+        &self.handle
+    }
+
+    /// Returns the latency of each endpoint.
+    pub const fn stats(&self) -> &EndpointStats {
+        &self.stats
+    }
+
+    /// Accepts connections on the listener forever, serving each one on its
+    /// own thread.
+    pub fn serve(self: &Arc<Self>, listener: &TcpListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if self.active.fetch_add(1, Ordering::SeqCst) >= self.max_connections {
+                        self.active.fetch_sub(1, Ordering::SeqCst);
+                        self.reject(stream);
+                        continue;
+                    }
+                    let server = Arc::clone(self);
+                    std::thread::spawn(move || {
+                        let _slot = ConnectionSlot(&server.active);
+                        server.serve_connection(stream);
+                    });
+                }
+                Err(e) => log::warn!("Failed to accept a connection: {e}"),
+            }
+        }
+    }
+
+    /// Answers a connection beyond the limit with a 503, without reading its
+    /// request.
+    fn reject(&self, mut stream: TcpStream) {
+        let start = Instant::now();
+        let response = Response::error(503, "Too many connections.");
+        if let Err(e) = stream
+            .set_write_timeout(Some(self.read_timeout))
+            .map_err(|e| e.to_string())
+            .and_then(|()| response.write_to(&mut stream))
+        {
+            log::warn!("Failed to reject a connection: {e}");
+        }
+        // The listener must not wait on a rejected client, so only what it has
+        // already sent is discarded.
+        if stream.set_nonblocking(true).is_ok() {
+            http::linger(&stream);
+        }
+        self.stats.record("rejected", start.elapsed(), true);
+    }
+
+    /// Reads one request from the connection, answers it and records its
+    /// latency.
+    fn serve_connection(&self, mut stream: TcpStream) {
+        let start = Instant::now();
+        if let Err(e) = stream
+            .set_read_timeout(Some(self.read_timeout))
+            .and_then(|()| stream.set_write_timeout(Some(self.read_timeout)))
+        {
+            log::warn!("Failed to set the timeouts of a connection: {e}");
+            return;
+        }
+        let (endpoint, response, read_in_full) = match http::read_request(&stream) {
+            Ok(request) => {
+                let (endpoint, response) = self.respond(&request);
+                (endpoint, response, true)
+            }
+            Err(response) => ("invalid", response, false),
+        };
+        self.stats
+            .record(endpoint, start.elapsed(), response.status != 200);
+        if let Err(e) = response.write_to(&mut stream) {
+            log::warn!("Failed to write a response to {endpoint}: {e}");
+        }
+        if !read_in_full {
+            http::linger(&stream);
+        }
+    }
+
+    /// Answers a request.
+    ///
+    /// # Returns
+    ///
+    /// The name under which the latency of the request is recorded, and the
+    /// response. Requests to unknown routes share a name, so that clients
+    /// cannot grow the stats without bound.
+    fn respond(&self, request: &Request) -> (&'static str, Response) {
+        let (endpoint, result) = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/health") => (
+                "GET /health",
+                Ok(Response::ok(&serde_json::json!({
+                    "status": "ok",
+                    "generation": self.handle.generation(),
+                }))),
+            ),
+            ("GET", "/stats") => ("GET /stats", Ok(Response::ok(&self.index_stats()))),
+            ("POST", "/knn") => ("POST /knn", parse(&request.body).and_then(|r| self.knn(&r))),
+            ("POST", "/rnn") => ("POST /rnn", parse(&request.body).and_then(|r| self.rnn(&r))),
+            ("POST", "/batch") => (
+                "POST /batch",
+                parse(&request.body).and_then(|r| self.batch(&r)),
+            ),
+            (_, "/health" | "/stats" | "/knn" | "/rnn" | "/batch") => (
+                "method not allowed",
+                Err(Response::error(405, "Method not allowed.")),
+            ),
+            _ => ("not found", Err(Response::error(404, "Not found."))),
+        };
+        (endpoint, result.unwrap_or_else(|response| response))
+    }
+
+    /// Describes the index and the latency of each endpoint.
+    fn index_stats(&self) -> StatsResponse {
+        let cakes = self.handle.snapshot();
+        let cardinality = cakes.total_cardinality();
+        StatsResponse {
+            generation: self.handle.generation(),
+            cardinality,
+            dimensionality: if cardinality == 0 { 0 } else { cakes[0].len() },
+            shard_cardinalities: cakes.shard_cardinalities(),
+            tuned_rnn_algorithm: cakes.tuned_rnn_algorithm().name().to_string(),
+            tuned_knn_algorithm: cakes.tuned_knn_algorithm().name().to_string(),
+            latency: self.stats.summarize(),
+        }
+    }
+
+    /// Answers a `/knn` request.
+    fn knn(&self, request: &KnnRequest) -> Result<Response, Response> {
+        let cakes = self.handle.snapshot();
+        check_dimensionality(&cakes, &request.query)?;
+        let algo = knn_algorithm(&cakes, request.algorithm.as_deref())?;
+        let hits = cakes.knn_search_original(&request.query, request.k, algo);
+        Ok(Response::ok(&serde_json::json!({ "hits": to_hits(hits) })))
+    }
+
+    /// Answers an `/rnn` request.
+    fn rnn(&self, request: &RnnRequest) -> Result<Response, Response> {
+        let cakes = self.handle.snapshot();
+        check_dimensionality(&cakes, &request.query)?;
+        let algo = rnn_algorithm(&cakes, request.algorithm.as_deref())?;
+        let hits = cakes.rnn_search_original(&request.query, request.radius, algo);
+        Ok(Response::ok(&serde_json::json!({ "hits": to_hits(hits) })))
+    }
+
+    /// Answers a `/batch` request.
+    fn batch(&self, request: &BatchRequest) -> Result<Response, Response> {
+        let cakes = self.handle.snapshot();
+        for query in &request.queries {
+            check_dimensionality(&cakes, query)?;
+        }
+        let queries = request.queries.iter().collect::<Vec<_>>();
+        let results = match (request.k, request.radius) {
+            (Some(k), None) => {
+                let algo = knn_algorithm(&cakes, request.algorithm.as_deref())?;
+                cakes.batch_knn_search(&queries, k, algo)
+            }
+            (None, Some(radius)) => {
+                let algo = rnn_algorithm(&cakes, request.algorithm.as_deref())?;
+                cakes.batch_rnn_search(&queries, radius, algo)
+            }
+            _ => {
+                return Err(Response::error(
+                    400,
+                    "Exactly one of `k` and `radius` must be given.",
+                ))
+            }
+        };
+        let results = results
+            .into_iter()
+            .map(|hits| {
+                hits.into_iter()
+                    .map(|(i, d)| cakes.original_index(i).map(|i| (i, d)))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(to_hits)
+                    .map_err(|e| Response::error(500, &e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Response::ok(&serde_json::json!({ "results": results })))
+    }
+}
+
+/// Parses the body of a request.
+fn parse<'a, T: Deserialize<'a>>(body: &'a str) -> Result<T, Response> {
+    serde_json::from_str(body).map_err(|e| Response::error(400, &e.to_string()))
+}
+
+/// Checks that the query has the same dimensionality as the instances.
+fn check_dimensionality(cakes: &Cakes<Vec<f32>, f32, Data>, query: &[f32]) -> Result<(), Response> {
+    let dimensionality = if cakes.total_cardinality() == 0 {
+        0
+    } else {
+        cakes[0].len()
+    };
+    if query.len() == dimensionality {
+        Ok(())
+    } else {
+        Err(Response::error(
+            400,
+            &format!(
+                "Expected a query of dimensionality {dimensionality}, got {}.",
+                query.len()
+            ),
+        ))
+    }
+}
+
+/// Returns the named KNN algorithm, or the tuned one if no name is given.
+fn knn_algorithm(
+    cakes: &Cakes<Vec<f32>, f32, Data>,
+    name: Option<&str>,
+) -> Result<knn::Algorithm, Response> {
+    name.map_or_else(
+        || Ok(cakes.tuned_knn_algorithm()),
+        |name| knn::Algorithm::from_name(name).map_err(|e| Response::error(400, &e)),
+    )
+}
+
+/// Returns the named RNN algorithm, or the tuned one if no name is given.
+fn rnn_algorithm(
+    cakes: &Cakes<Vec<f32>, f32, Data>,
+    name: Option<&str>,
+) -> Result<rnn::Algorithm, Response> {
+    name.map_or_else(
+        || Ok(cakes.tuned_rnn_algorithm()),
+        |name| rnn::Algorithm::from_name(name).map_err(|e| Response::error(400, &e)),
+    )
+}
+
+/// Converts search results to hits, sorted by increasing distance.
+fn to_hits(mut hits: Vec<(usize, f32)>) -> Vec<Hit> {
+    hits.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+    hits.into_iter()
+        .map(|(index, distance)| Hit { index, distance })
+        .collect()
+}
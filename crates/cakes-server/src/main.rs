@@ -0,0 +1,81 @@
+#![deny(clippy::correctness)]
+#![warn(
+    missing_docs,
+    clippy::all,
+    clippy::suspicious,
+    clippy::style,
+    clippy::complexity,
+    clippy::perf,
+    clippy::pedantic,
+    clippy::nursery,
+    clippy::missing_docs_in_private_items,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::cast_lossless
+)]
+
+//! Serve searches over a saved `Cakes` index with HTTP and JSON.
+
+use std::{net::TcpListener, path::PathBuf, sync::Arc, time::Duration};
+
+use cakes_server::{metric_from_name, Server};
+use clap::Parser;
+use log::info;
+
+fn main() -> Result<(), String> {
+    env_logger::Builder::from_default_env()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let args = Args::parse();
+
+    if !args.index_dir.exists() {
+        return Err(format!(
+            "Index directory {} does not exist.",
+            args.index_dir.display()
+        ));
+    }
+
+    let metric = metric_from_name(&args.metric)?;
+    info!("Loading the index from {} ...", args.index_dir.display());
+    let server = Arc::new(
+        Server::load(&args.index_dir, metric)?
+            .with_max_connections(args.max_connections)
+            .with_read_timeout(Duration::from_secs(args.read_timeout_secs)),
+    );
+
+    let listener = TcpListener::bind(&args.address).map_err(|e| e.to_string())?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    info!("Serving on http://{address}");
+    server.serve(&listener);
+
+    Ok(())
+}
+
+/// Command line arguments for the Cakes search server.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path to the directory to which the `Cakes` index was saved.
+    #[arg(long)]
+    index_dir: PathBuf,
+
+    /// Name of the metric of the index. One of `euclidean`, `euclidean_sq`,
+    /// `cosine`, `manhattan` or `chebyshev`.
+    #[arg(long, default_value = "euclidean")]
+    metric: String,
+
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:7878")]
+    address: String,
+
+    /// Maximum number of connections served at once. Further connections are
+    /// answered with a 503.
+    #[arg(long, default_value_t = cakes_server::DEFAULT_MAX_CONNECTIONS)]
+    max_connections: usize,
+
+    /// Seconds after which a read from a connection times out.
+    #[arg(long, default_value_t = cakes_server::DEFAULT_READ_TIMEOUT.as_secs())]
+    read_timeout_secs: u64,
+}
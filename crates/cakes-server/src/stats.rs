@@ -0,0 +1,104 @@
+//! Per-endpoint latency tracking.
+
+use core::cmp::Ordering;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use abd_clam::utils;
+use distances::Number;
+use serde::Serialize;
+
+/// The number of most recent requests kept per endpoint for percentiles.
+const WINDOW: usize = 1024;
+
+/// The latencies recorded for one endpoint.
+#[derive(Debug, Default)]
+struct Latencies {
+    /// The number of requests served.
+    count: usize,
+    /// The number of requests that failed.
+    errors: usize,
+    /// The latencies, in milliseconds, of the most recent requests.
+    recent: VecDeque<f64>,
+}
+
+/// A summary of the latencies of one endpoint.
+#[derive(Debug, Serialize)]
+pub struct LatencySummary {
+    /// The number of requests served.
+    pub count: usize,
+    /// The number of requests that failed.
+    pub errors: usize,
+    /// The mean latency, in milliseconds, over the recent requests.
+    pub mean_ms: f64,
+    /// The median latency, in milliseconds, over the recent requests.
+    pub p50_ms: f64,
+    /// The 90th percentile latency, in milliseconds, over the recent requests.
+    pub p90_ms: f64,
+    /// The 99th percentile latency, in milliseconds, over the recent requests.
+    pub p99_ms: f64,
+    /// The largest latency, in milliseconds, over the recent requests.
+    pub max_ms: f64,
+}
+
+/// Latencies of all endpoints.
+#[derive(Debug, Default)]
+pub struct EndpointStats {
+    /// The latencies, keyed by endpoint.
+    endpoints: Mutex<BTreeMap<String, Latencies>>,
+}
+
+impl EndpointStats {
+    /// Records a request to an endpoint.
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint` - The endpoint, e.g. `POST /knn`.
+    /// * `elapsed` - The time taken to serve the request.
+    /// * `is_error` - Whether the request failed.
+    pub fn record(&self, endpoint: &str, elapsed: Duration, is_error: bool) {
+        let mut endpoints = self
+            .endpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let latencies = endpoints.entry(endpoint.to_string()).or_default();
+        latencies.count += 1;
+        if is_error {
+            latencies.errors += 1;
+        }
+        if latencies.recent.len() == WINDOW {
+            latencies.recent.pop_front();
+        }
+        latencies.recent.push_back(elapsed.as_secs_f64() * 1e3);
+        drop(endpoints);
+    }
+
+    /// Summarizes the latencies of each endpoint.
+    pub fn summarize(&self) -> BTreeMap<String, LatencySummary> {
+        let endpoints = self
+            .endpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        endpoints
+            .iter()
+            .map(|(endpoint, latencies)| {
+                let mut times = latencies.recent.iter().copied().collect::<Vec<_>>();
+                times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Greater));
+                let percentile = |p| utils::percentile(&times, p).unwrap_or_default();
+                let summary = LatencySummary {
+                    count: latencies.count,
+                    errors: latencies.errors,
+                    mean_ms: times.iter().sum::<f64>() / times.len().max(1).as_f64(),
+                    p50_ms: percentile(50),
+                    p90_ms: percentile(90),
+                    p99_ms: percentile(99),
+                    max_ms: times.last().copied().unwrap_or_default(),
+                };
+                (endpoint.clone(), summary)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+use abd_clam::{knn, Cakes, PartitionCriteria, VecDataset};
+use cakes_server::{metric_from_name, Server};
+use rand::prelude::*;
+use serde_json::Value;
+
+#[allow(clippy::ptr_arg)]
+fn euclidean(x: &Vec<f32>, y: &Vec<f32>) -> f32 {
+    distances::vectors::euclidean(x, y)
+}
+
+fn gen_data(cardinality: usize, dimensionality: usize, seed: u64) -> Vec<Vec<f32>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..cardinality)
+        .map(|_| {
+            (0..dimensionality)
+                .map(|_| rng.gen_range(-1.0..1.0))
+                .collect()
+        })
+        .collect()
+}
+
+/// Starts a server on an unused port on localhost.
+fn start(server: Server) -> (Arc<Server>, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = Arc::new(server);
+    let serving = Arc::clone(&server);
+    std::thread::spawn(move || serving.serve(&listener));
+    (server, address)
+}
+
+/// Sends a request and returns the status code and JSON body of the response.
+fn request(address: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn endpoints() {
+    let data = VecDataset::new(
+        "server-test".to_string(),
+        gen_data(1000, 10, 42),
+        euclidean,
+        false,
+    );
+    let cakes = Cakes::new(data, Some(42), &PartitionCriteria::default());
+
+    let tmp_dir = tempdir::TempDir::new("server-test").unwrap();
+    cakes.save(tmp_dir.path()).unwrap();
+    let server = Server::load(tmp_dir.path(), metric_from_name("euclidean").unwrap()).unwrap();
+    let (_, address) = start(server);
+
+    let (status, body) = request(address, "GET", "/health", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "ok");
+
+    let queries = gen_data(3, 10, 43);
+    let query = serde_json::to_string(&queries[0]).unwrap();
+
+    let (status, body) = request(
+        address,
+        "POST",
+        "/knn",
+        &format!(r#"{{"query": {query}, "k": 10}}"#),
+    );
+    assert_eq!(status, 200);
+    let hits = body["hits"].as_array().unwrap();
+    assert_eq!(hits.len(), 10);
+    let expected = cakes.knn_search(&queries[0], 10, knn::Algorithm::Linear);
+    let max_expected = expected.iter().map(|&(_, d)| d).fold(0.0, f32::max);
+    let max_hit = hits.last().unwrap()["distance"].as_f64().unwrap();
+    assert!((max_hit - f64::from(max_expected)).abs() < 1e-5);
+
+    let (status, body) = request(
+        address,
+        "POST",
+        "/rnn",
+        &format!(r#"{{"query": {query}, "radius": 1.5, "algorithm": "Linear"}}"#),
+    );
+    assert_eq!(status, 200);
+    let hits = body["hits"].as_array().unwrap();
+    assert!(hits.iter().all(|h| h["distance"].as_f64().unwrap() <= 1.5));
+
+    let batch = serde_json::to_string(&queries).unwrap();
+    let (status, body) = request(
+        address,
+        "POST",
+        "/batch",
+        &format!(r#"{{"queries": {batch}, "k": 5}}"#),
+    );
+    assert_eq!(status, 200);
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|r| r.as_array().unwrap().len() == 5));
+
+    let (status, _) = request(
+        address,
+        "POST",
+        "/batch",
+        &format!(r#"{{"queries": {batch}}}"#),
+    );
+    assert_eq!(status, 400);
+    let (status, body) = request(address, "POST", "/knn", r#"{"query": [1.0], "k": 10}"#);
+    assert_eq!(status, 400);
+    assert!(body["error"].as_str().unwrap().contains("dimensionality"));
+    let (status, _) = request(
+        address,
+        "POST",
+        "/knn",
+        &format!(r#"{{"query": {query}, "k": 1, "algorithm": "Nope"}}"#),
+    );
+    assert_eq!(status, 400);
+    let (status, _) = request(address, "GET", "/knn", "");
+    assert_eq!(status, 405);
+    let (status, _) = request(address, "GET", "/nowhere", "");
+    assert_eq!(status, 404);
+
+    let (status, body) = request(address, "GET", "/stats", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["cardinality"], 1000);
+    assert_eq!(body["dimensionality"], 10);
+    let knn_latency = &body["latency"]["POST /knn"];
+    assert_eq!(knn_latency["count"], 3);
+    assert_eq!(knn_latency["errors"], 2);
+    assert!(knn_latency["p50_ms"].as_f64().unwrap() <= knn_latency["max_ms"].as_f64().unwrap());
+
+    // Hits are the rows of the source data, not positions in the tree.
+    let rows = gen_data(1000, 10, 42);
+    let picked = [0, 17, 999];
+    for &row in &picked {
+        let query = serde_json::to_string(&rows[row]).unwrap();
+        let (_, body) = request(
+            address,
+            "POST",
+            "/knn",
+            &format!(r#"{{"query": {query}, "k": 1}}"#),
+        );
+        assert_eq!(body["hits"][0]["index"], row);
+        let (_, body) = request(
+            address,
+            "POST",
+            "/rnn",
+            &format!(r#"{{"query": {query}, "radius": 0.0}}"#),
+        );
+        assert_eq!(body["hits"][0]["index"], row);
+    }
+    let batch_rows = serde_json::to_string(&picked.map(|row| &rows[row])).unwrap();
+    let (_, body) = request(
+        address,
+        "POST",
+        "/batch",
+        &format!(r#"{{"queries": {batch_rows}, "k": 1}}"#),
+    );
+    for (result, &row) in body["results"].as_array().unwrap().iter().zip(&picked) {
+        assert_eq!(result[0]["index"], row);
+    }
+}
+
+#[test]
+fn swap_index() {
+    let data = VecDataset::new(
+        "server-test".to_string(),
+        gen_data(100, 4, 42),
+        euclidean,
+        false,
+    );
+    let (server, address) = start(Server::new(Cakes::new(
+        data,
+        Some(42),
+        &PartitionCriteria::default(),
+    )));
+
+    let data = VecDataset::new(
+        "server-test".to_string(),
+        gen_data(200, 4, 43),
+        euclidean,
+        false,
+    );
+    server
+        .handle()
+        .replace(Cakes::new(data, Some(42), &PartitionCriteria::default()));
+
+    let (_, body) = request(address, "GET", "/health", "");
+    assert_eq!(body["generation"], 1);
+    let (_, body) = request(address, "GET", "/stats", "");
+    assert_eq!(body["cardinality"], 200);
+}
+
+/// Reads the status code of the response on a stream to which a request was
+/// written.
+fn read_status(stream: &mut TcpStream) -> u16 {
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+#[test]
+fn limits() {
+    let cakes = || {
+        let data = VecDataset::new(
+            "server-test".to_string(),
+            gen_data(100, 4, 42),
+            euclidean,
+            false,
+        );
+        Cakes::new(data, Some(42), &PartitionCriteria::default())
+    };
+    let server = Server::new(cakes()).with_read_timeout(Duration::from_millis(200));
+    let (server, address) = start(server);
+
+    // Unknown routes share one entry in the stats.
+    for i in 0..20 {
+        let (status, _) = request(address, "GET", &format!("/nowhere/{i}"), "");
+        assert_eq!(status, 404);
+    }
+    let latency = server.stats().summarize();
+    assert_eq!(latency["not found"].count, 20);
+    assert!(latency.keys().all(|k| !k.contains("nowhere")));
+
+    // A header line that is too long is rejected.
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "GET /health HTTP/1.1\r\nX-Long: {}",
+        "a".repeat(9000)
+    )
+    .unwrap();
+    assert_eq!(read_status(&mut stream), 431);
+
+    // A body shorter than its Content-Length times out instead of waiting
+    // forever, without the server allocating the claimed length.
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "POST /knn HTTP/1.1\r\nContent-Length: 60000000\r\n\r\n{{\"query\""
+    )
+    .unwrap();
+    assert_eq!(read_status(&mut stream), 408);
+
+    // An idle connection holds the only slot, so another connection is
+    // rejected until the idle one times out.
+    let server = Server::new(cakes())
+        .with_max_connections(1)
+        .with_read_timeout(Duration::from_millis(200));
+    let (server, address) = start(server);
+    let idle = TcpStream::connect(address).unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    let mut rejected = TcpStream::connect(address).unwrap();
+    assert_eq!(read_status(&mut rejected), 503);
+    drop(idle);
+    std::thread::sleep(Duration::from_millis(300));
+    let (status, _) = request(address, "GET", "/health", "");
+    assert_eq!(status, 200);
+    assert_eq!(server.stats().summarize()["rejected"].count, 1);
+}
@@ -0,0 +1,412 @@
+//! The subcommands, generic over the type of instances and distances.
+
+use core::cmp::Ordering;
+use std::{io::Write, path::Path};
+
+use abd_clam::{knn, rnn, utils, Cakes, Dataset, Instance, PartitionCriteria, VecDataset};
+use distances::Number;
+use rand::prelude::*;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// The type of the indices built and loaded by the CLI.
+pub type Index<I, U> = Cakes<I, U, VecDataset<I, U, usize>>;
+
+/// The kind of search to perform.
+#[derive(Clone, Copy, Debug)]
+pub enum Search {
+    /// K-nearest neighbor search with the given `k`.
+    Knn(usize),
+    /// Ranged nearest neighbor search with the given radius.
+    Rnn(f64),
+}
+
+impl Search {
+    /// Creates the kind of search from the `--k` and `--radius` arguments.
+    ///
+    /// # Errors
+    ///
+    /// * If not exactly one of `k` and `radius` is given.
+    pub fn new(k: Option<usize>, radius: Option<f64>) -> Result<Self, String> {
+        match (k, radius) {
+            (Some(k), None) => Ok(Self::Knn(k)),
+            (None, Some(radius)) => Ok(Self::Rnn(radius)),
+            _ => Err("Exactly one of `--k` and `--radius` must be given.".to_string()),
+        }
+    }
+}
+
+/// Builds an index over the given instances.
+///
+/// # Arguments
+///
+/// * `name` - The name of the dataset.
+/// * `data` - The instances.
+/// * `metric` - The distance function.
+/// * `is_expensive` - Whether the metric is expensive to compute.
+/// * `criteria` - The criteria for partitioning the trees.
+/// * `seed` - The seed for the random number generator.
+/// * `shard_size` - The maximum cardinality of each shard, if the index
+///   should be randomly sharded.
+pub fn build<I: Instance, U: Number>(
+    name: String,
+    data: Vec<I>,
+    metric: fn(&I, &I) -> U,
+    is_expensive: bool,
+    criteria: &PartitionCriteria<U>,
+    seed: Option<u64>,
+    shard_size: Option<usize>,
+) -> Index<I, U> {
+    let data = VecDataset::new(name, data, metric, is_expensive);
+    match shard_size {
+        Some(max_cardinality) if max_cardinality < data.cardinality() => {
+            Cakes::new_randomly_sharded(data.make_shards(max_cardinality), seed, criteria)
+        }
+        _ => Cakes::new(data, seed, criteria),
+    }
+}
+
+/// Summarizes an index and the trees of its shards.
+pub fn inspect<I: Instance, U: Number>(cakes: &Index<I, U>) -> Value {
+    let trees = cakes
+        .trees()
+        .into_iter()
+        .map(|tree| {
+            let clusters = tree.root().subtree();
+            let leaves = clusters.iter().filter(|c| c.is_leaf()).collect::<Vec<_>>();
+            json!({
+                "cardinality": tree.cardinality(),
+                "depth": tree.depth(),
+                "radius": tree.radius().as_f64(),
+                "lfd": tree.root().lfd(),
+                "num_clusters": clusters.len(),
+                "num_leaves": leaves.len(),
+                "mean_leaf_cardinality": tree.cardinality().as_f64() / leaves.len().as_f64(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "cardinality": cakes.total_cardinality(),
+        "num_shards": cakes.num_shards(),
+        "shard_cardinalities": cakes.shard_cardinalities(),
+        "tuned_rnn_algorithm": cakes.tuned_rnn_algorithm().name(),
+        "tuned_knn_algorithm": cakes.tuned_knn_algorithm().name(),
+        "rnn_tuning_report": cakes.rnn_tuning_report(),
+        "knn_tuning_report": cakes.knn_tuning_report(),
+        "trees": trees,
+    })
+}
+
+/// Searches the index for each query.
+///
+/// # Arguments
+///
+/// * `cakes` - The index.
+/// * `queries` - The queries.
+/// * `search` - The kind of search.
+/// * `algorithm` - The name of the algorithm to use, or `None` for the tuned
+///   algorithm.
+///
+/// # Returns
+///
+/// For each query, the hits sorted by increasing distance. The indices are
+/// those of the instances in the file the index was built from.
+///
+/// # Errors
+///
+/// * If the algorithm is not recognized.
+pub fn search<I: Instance, U: Number>(
+    cakes: &Index<I, U>,
+    queries: &[I],
+    search: Search,
+    algorithm: Option<&str>,
+) -> Result<Vec<Vec<(usize, U)>>, String> {
+    let queries = queries.iter().collect::<Vec<_>>();
+    let results = match search {
+        Search::Knn(k) => {
+            let algo = algorithm.map_or_else(
+                || Ok(cakes.tuned_knn_algorithm()),
+                knn::Algorithm::from_name,
+            )?;
+            cakes.batch_knn_search(&queries, k, algo)
+        }
+        Search::Rnn(radius) => {
+            let algo = algorithm.map_or_else(
+                || Ok(cakes.tuned_rnn_algorithm()),
+                rnn::Algorithm::from_name,
+            )?;
+            cakes.batch_rnn_search(&queries, U::from(radius), algo)
+        }
+    };
+
+    results
+        .into_iter()
+        .map(|mut hits| {
+            hits.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Greater));
+            hits.into_iter()
+                .map(|(i, d)| cakes.original_index(i).map(|i| (i, d)))
+                .collect()
+        })
+        .collect()
+}
+
+/// A hit in the JSON output of `search`.
+#[derive(Serialize)]
+struct Hit {
+    /// The index of the instance.
+    index: usize,
+    /// The distance from the query to the instance.
+    distance: f64,
+}
+
+/// Writes the results of `search` as CSV, with one row per hit, or as JSON,
+/// with one list of hits per query.
+///
+/// # Arguments
+///
+/// * `results` - The hits for each query.
+/// * `out` - Where to write the results.
+/// * `as_json` - Whether to write JSON rather than CSV.
+///
+/// # Errors
+///
+/// * If the results cannot be written.
+pub fn write_results<U: Number, W: Write>(
+    results: &[Vec<(usize, U)>],
+    out: W,
+    as_json: bool,
+) -> Result<(), String> {
+    if as_json {
+        let results = results
+            .iter()
+            .enumerate()
+            .map(|(query, hits)| {
+                let hits = hits
+                    .iter()
+                    .map(|&(index, d)| Hit {
+                        index,
+                        distance: d.as_f64(),
+                    })
+                    .collect::<Vec<_>>();
+                json!({ "query": query, "hits": hits })
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_writer_pretty(out, &results).map_err(|e| e.to_string())
+    } else {
+        let mut writer = csv::Writer::from_writer(out);
+        writer
+            .write_record(["query", "rank", "index", "distance"])
+            .map_err(|e| e.to_string())?;
+        for (query, hits) in results.iter().enumerate() {
+            for (rank, &(index, d)) in hits.iter().enumerate() {
+                writer
+                    .write_record([
+                        query.to_string(),
+                        rank.to_string(),
+                        index.to_string(),
+                        d.to_string(),
+                    ])
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        writer.flush().map_err(|e| e.to_string())
+    }
+}
+
+/// Tunes the choice of algorithm and saves the index with the result.
+///
+/// # Arguments
+///
+/// * `cakes` - The index.
+/// * `path` - The directory the index is saved in.
+/// * `queries` - The queries to tune on. If `None`, the centers of the
+///   clusters at `depth` are used, as in `Cakes::auto_tune_knn`.
+/// * `search` - The kind of search to tune.
+/// * `min_recall` - The smallest acceptable mean recall, when tuning on
+///   queries.
+/// * `depth` - The depth of the clusters whose centers are used as queries,
+///   when no queries are given.
+///
+/// # Returns
+///
+/// The tuning report when tuning on queries, or the chosen algorithm.
+///
+/// # Errors
+///
+/// * If `queries` is empty.
+/// * If the index cannot be saved.
+pub fn tune<I: Instance, U: Number>(
+    cakes: &mut Index<I, U>,
+    path: &Path,
+    queries: Option<&[I]>,
+    search: Search,
+    min_recall: f64,
+    depth: usize,
+) -> Result<Value, String> {
+    let summary = match (queries, search) {
+        (Some(queries), Search::Knn(k)) => {
+            json!(cakes.auto_tune_knn_with_queries(queries, k, min_recall)?)
+        }
+        (Some(queries), Search::Rnn(radius)) => {
+            json!(cakes.auto_tune_rnn_with_queries(queries, U::from(radius), min_recall)?)
+        }
+        (None, Search::Knn(k)) => {
+            cakes.auto_tune_knn(k, depth);
+            json!({ "best": cakes.tuned_knn_algorithm().name() })
+        }
+        (None, Search::Rnn(radius)) => {
+            cakes.auto_tune_rnn(U::from(radius), depth);
+            json!({ "best": cakes.tuned_rnn_algorithm().name() })
+        }
+    };
+    cakes.save(path)?;
+    Ok(summary)
+}
+
+/// Checks that an index is consistent and that its tuned KNN search agrees
+/// with linear search.
+///
+/// The checks are:
+///
+/// * `permutation` - Every instance in the source file is in the index
+///   exactly once.
+/// * `trees` - The children of every cluster split its instances, and every
+///   instance is within the radius of each cluster that holds it.
+/// * `recall` - The mean recall of the tuned KNN algorithm against linear
+///   search on the queries is 1.
+///
+/// # Arguments
+///
+/// * `cakes` - The index.
+/// * `queries` - The queries for the recall check. If `None`, `num_queries`
+///   instances are sampled from the index.
+/// * `k` - The number of neighbors for the recall check.
+/// * `num_queries` - The number of instances to sample as queries.
+/// * `seed` - The seed for sampling the queries.
+///
+/// # Returns
+///
+/// Whether every check passed, and a report of the checks.
+pub fn validate<I: Instance, U: Number>(
+    cakes: &Index<I, U>,
+    queries: Option<&[I]>,
+    k: usize,
+    num_queries: usize,
+    seed: Option<u64>,
+) -> (bool, Value) {
+    let permutation = check_permutation(cakes);
+    let trees = check_trees(cakes);
+
+    let sampled;
+    let queries = if let Some(queries) = queries {
+        queries
+    } else {
+        let mut rng = seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+        let cardinality = cakes.total_cardinality();
+        sampled = rand::seq::index::sample(&mut rng, cardinality, num_queries.min(cardinality))
+            .into_iter()
+            .map(|i| cakes[i].clone())
+            .collect::<Vec<_>>();
+        &sampled
+    };
+    let recall = check_recall(cakes, queries, k);
+
+    let ok = [&permutation, &trees, &recall]
+        .iter()
+        .all(|check| check.is_ok());
+    let checks = [
+        ("permutation", permutation),
+        ("trees", trees),
+        ("recall", recall),
+    ]
+    .into_iter()
+    .map(|(name, check)| match check {
+        Ok(detail) => json!({ "check": name, "ok": true, "detail": detail }),
+        Err(detail) => json!({ "check": name, "ok": false, "detail": detail }),
+    })
+    .collect::<Vec<_>>();
+
+    (ok, json!({ "ok": ok, "checks": checks }))
+}
+
+/// Checks that the original indices of the instances form a permutation.
+fn check_permutation<I: Instance, U: Number>(cakes: &Index<I, U>) -> Result<String, String> {
+    let cardinality = cakes.total_cardinality();
+    let mut seen = vec![false; cardinality];
+    for i in 0..cardinality {
+        let original = cakes.original_index(i)?;
+        if original >= cardinality || seen[original] {
+            return Err(format!(
+                "Original index {original} of instance {i} is out of range or repeated."
+            ));
+        }
+        seen[original] = true;
+    }
+    Ok(format!("{cardinality} instances"))
+}
+
+/// Checks the structure of the tree of every shard.
+fn check_trees<I: Instance, U: Number>(cakes: &Index<I, U>) -> Result<String, String> {
+    let mut num_clusters = 0;
+    for (s, tree) in cakes.trees().into_iter().enumerate() {
+        let data = tree.data();
+        for c in tree.root().subtree() {
+            num_clusters += 1;
+            if let Some([left, right]) = c.children() {
+                let split = left.offset() == c.offset()
+                    && right.offset() == left.offset() + left.cardinality()
+                    && left.cardinality() + right.cardinality() == c.cardinality();
+                if !split {
+                    return Err(format!(
+                        "The children of cluster {} in shard {s} do not split it.",
+                        c.name()
+                    ));
+                }
+            }
+
+            let indices = c.indices().collect::<Vec<_>>();
+            let center = &data[c.arg_center()];
+            if data
+                .query_to_many(center, &indices)
+                .into_iter()
+                .any(|d| d > c.radius())
+            {
+                return Err(format!(
+                    "Cluster {} in shard {s} has instances beyond its radius.",
+                    c.name()
+                ));
+            }
+        }
+    }
+    Ok(format!("{num_clusters} clusters"))
+}
+
+/// Checks that tuned KNN search finds the same neighbors as linear search.
+fn check_recall<I: Instance, U: Number>(
+    cakes: &Index<I, U>,
+    queries: &[I],
+    k: usize,
+) -> Result<String, String> {
+    let algo = cakes.tuned_knn_algorithm();
+    let recall = queries
+        .iter()
+        .map(|query| {
+            let hits = cakes.knn_search(query, k, algo);
+            let expected = cakes.linear_knn_search(query, k);
+            utils::recall(hits, expected)
+        })
+        .sum::<f64>()
+        / queries.len().max(1).as_f64();
+
+    let detail = format!(
+        "mean recall {recall} of {} over {} queries",
+        algo.name(),
+        queries.len()
+    );
+    if recall < 1.0 {
+        Err(detail)
+    } else {
+        Ok(detail)
+    }
+}
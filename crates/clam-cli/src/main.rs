@@ -0,0 +1,477 @@
+#![deny(clippy::correctness)]
+#![warn(
+    missing_docs,
+    clippy::all,
+    clippy::suspicious,
+    clippy::style,
+    clippy::complexity,
+    clippy::perf,
+    clippy::pedantic,
+    clippy::nursery,
+    clippy::missing_docs_in_private_items,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::panic,
+    clippy::cast_lossless
+)]
+
+//! A command line interface for building, inspecting, searching, tuning and
+//! validating Cakes indices.
+//!
+//! Indices are saved in the `Cakes::save` directory format, along with a
+//! `clam.json` manifest that records the kind of instances and the metric so
+//! that later subcommands need only the directory. Indices saved by other means
+//! have no manifest, and their kind and metric are given with `--kind` and
+//! `--metric` instead.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use abd_clam::{Cakes, PartitionCriteria};
+use clap::{Args, Parser, Subcommand};
+use distances::Number;
+
+mod commands;
+mod manifest;
+mod readers;
+
+use commands::{Index, Search};
+use manifest::{Kind, Manifest};
+use readers::Format;
+
+fn main() -> Result<(), String> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Build(args) => build(&args),
+        Command::Inspect { index } => {
+            let manifest = index.manifest()?;
+            let summary = match manifest.kind {
+                Kind::Vectors => commands::inspect(&load_vectors(&index.path, &manifest)?),
+                Kind::Sequences => commands::inspect(&load_sequences(&index.path, &manifest)?),
+            };
+            let summary = serde_json::json!({ "manifest": manifest, "index": summary });
+            print_json(&summary)
+        }
+        Command::Search(args) => search(&args),
+        Command::Tune(args) => tune(&args),
+        Command::Validate(args) => validate(&args),
+    }
+}
+
+/// Build, inspect, search, tune and validate Cakes indices.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// The subcommand to run.
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// The subcommands.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Build an index from a `.npy`, CSV or FASTA file.
+    Build(BuildArgs),
+    /// Print a summary of an index and the trees of its shards as JSON.
+    Inspect {
+        /// The index to inspect.
+        #[command(flatten)]
+        index: IndexArgs,
+    },
+    /// Search an index for each query in a file, writing CSV or JSON.
+    Search(SearchArgs),
+    /// Tune the choice of search algorithm of an index and save the result.
+    Tune(TuneArgs),
+    /// Check that an index is consistent and that its search is exact.
+    Validate(ValidateArgs),
+}
+
+/// Which index to use.
+#[derive(Args, Debug)]
+struct IndexArgs {
+    /// The directory of the index.
+    #[arg(value_name = "INDEX")]
+    path: PathBuf,
+
+    /// The kind of instances in the index. Required, along with `--metric`,
+    /// if the index has no `clam.json` manifest. Overrides the manifest.
+    #[arg(long, value_enum)]
+    kind: Option<Kind>,
+
+    /// The metric of the index. Required, along with `--kind`, if the index
+    /// has no `clam.json` manifest. Overrides the manifest.
+    #[arg(long)]
+    metric: Option<String>,
+}
+
+impl IndexArgs {
+    /// Returns the description of the index, from the flags and the manifest.
+    fn manifest(&self) -> Result<Manifest, String> {
+        Manifest::resolve(&self.path, self.kind, self.metric.as_deref())
+    }
+}
+
+/// Where to read instances from.
+#[derive(Args, Debug)]
+struct InputArgs {
+    /// The format of the file, one of `npy`, `fvecs`, `bvecs`, `csv` or
+    /// `fasta`. Guessed from the extension if not given.
+    #[arg(long)]
+    format: Option<String>,
+
+    /// Whether the first row of a CSV file is a header.
+    #[arg(long)]
+    has_headers: bool,
+}
+
+/// Arguments for `build`.
+#[derive(Args, Debug)]
+struct BuildArgs {
+    /// The file of instances.
+    input: PathBuf,
+
+    /// How to read the file.
+    #[command(flatten)]
+    input_args: InputArgs,
+
+    /// The directory to save the index to. It is created if it does not exist.
+    #[arg(long)]
+    output: PathBuf,
+
+    /// The metric. One of `euclidean`, `euclidean_sq`, `cosine`, `manhattan`
+    /// or `chebyshev` for vectors, or `levenshtein`, `needleman_wunsch` or
+    /// `hamming` for sequences.
+    #[arg(long)]
+    metric: String,
+
+    /// Stop partitioning clusters with at most this many instances.
+    #[arg(long)]
+    min_cardinality: Option<usize>,
+
+    /// Stop partitioning clusters at this depth.
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// Randomly shard the index so that no shard has more than this many
+    /// instances.
+    #[arg(long)]
+    shard_size: Option<usize>,
+
+    /// The seed for the random number generator.
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+/// Arguments for `search`.
+#[derive(Args, Debug)]
+struct SearchArgs {
+    /// The index to use.
+    #[command(flatten)]
+    index: IndexArgs,
+
+    /// The file of queries.
+    #[arg(long)]
+    queries: PathBuf,
+
+    /// How to read the queries.
+    #[command(flatten)]
+    input_args: InputArgs,
+
+    /// The number of neighbors for KNN search.
+    #[arg(long)]
+    k: Option<usize>,
+
+    /// The radius for RNN search.
+    #[arg(long)]
+    radius: Option<f64>,
+
+    /// The search algorithm. Defaults to the tuned algorithm.
+    #[arg(long)]
+    algorithm: Option<String>,
+
+    /// The file to write the results to. Defaults to standard output.
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Write the results as JSON rather than CSV.
+    #[arg(long)]
+    json: bool,
+}
+
+/// Arguments for `tune`.
+#[derive(Args, Debug)]
+struct TuneArgs {
+    /// The index to use.
+    #[command(flatten)]
+    index: IndexArgs,
+
+    /// The file of queries to tune on. If not given, the centers of the
+    /// clusters at `--depth` are used.
+    #[arg(long)]
+    queries: Option<PathBuf>,
+
+    /// How to read the queries.
+    #[command(flatten)]
+    input_args: InputArgs,
+
+    /// The number of neighbors for KNN search.
+    #[arg(long)]
+    k: Option<usize>,
+
+    /// The radius for RNN search.
+    #[arg(long)]
+    radius: Option<f64>,
+
+    /// The smallest acceptable mean recall when tuning on queries.
+    #[arg(long, default_value = "1.0")]
+    min_recall: f64,
+
+    /// The depth of the clusters whose centers are used when no queries are given.
+    #[arg(long, default_value = "7")]
+    depth: usize,
+}
+
+/// Arguments for `validate`.
+#[derive(Args, Debug)]
+struct ValidateArgs {
+    /// The index to use.
+    #[command(flatten)]
+    index: IndexArgs,
+
+    /// The file of queries for the recall check. If not given, instances are
+    /// sampled from the index.
+    #[arg(long)]
+    queries: Option<PathBuf>,
+
+    /// How to read the queries.
+    #[command(flatten)]
+    input_args: InputArgs,
+
+    /// The number of neighbors for the recall check.
+    #[arg(long, default_value = "10")]
+    k: usize,
+
+    /// The number of instances to sample as queries.
+    #[arg(long, default_value = "100")]
+    num_queries: usize,
+
+    /// The seed for sampling the queries.
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+/// Runs `build`.
+fn build(args: &BuildArgs) -> Result<(), String> {
+    let format = Format::new(&args.input, args.input_args.format.as_deref())?;
+    let name = args
+        .input
+        .file_stem()
+        .map_or_else(|| "data".to_string(), |s| s.to_string_lossy().to_string());
+
+    if !args.output.exists() {
+        std::fs::create_dir_all(&args.output).map_err(|e| e.to_string())?;
+    }
+
+    let kind = if format.is_vectors() {
+        let metric = manifest::vector_metric(&args.metric)?;
+        let data = readers::read_vectors(&args.input, format, args.input_args.has_headers)?;
+        let criteria = criteria(args);
+        let cakes = commands::build(
+            name,
+            data,
+            metric,
+            false,
+            &criteria,
+            args.seed,
+            args.shard_size,
+        );
+        cakes.save(&args.output)?;
+        Kind::Vectors
+    } else {
+        let metric = manifest::sequence_metric(&args.metric)?;
+        let data = readers::read_sequences(&args.input)?;
+        let criteria = criteria(args);
+        let cakes = commands::build(
+            name,
+            data,
+            metric,
+            true,
+            &criteria,
+            args.seed,
+            args.shard_size,
+        );
+        cakes.save(&args.output)?;
+        Kind::Sequences
+    };
+
+    let manifest = Manifest {
+        kind,
+        metric: args.metric.clone(),
+        source: Some(args.input.display().to_string()),
+    };
+    manifest.save(&args.output)
+}
+
+/// Returns the partition criteria from the arguments of `build`.
+fn criteria<U: Number>(args: &BuildArgs) -> PartitionCriteria<U> {
+    let mut criteria =
+        PartitionCriteria::new(true).with_min_cardinality(args.min_cardinality.unwrap_or(1));
+    if let Some(max_depth) = args.max_depth {
+        criteria = criteria.with_max_depth(max_depth);
+    }
+    criteria
+}
+
+/// Runs `search`.
+fn search(args: &SearchArgs) -> Result<(), String> {
+    let manifest = args.index.manifest()?;
+    let search = Search::new(args.k, args.radius)?;
+    let algorithm = args.algorithm.as_deref();
+
+    match manifest.kind {
+        Kind::Vectors => {
+            let cakes = load_vectors(&args.index.path, &manifest)?;
+            let queries = read_vector_queries(&args.queries, &args.input_args)?;
+            let results = commands::search(&cakes, &queries, search, algorithm)?;
+            write_results(&results, args.output.as_deref(), args.json)
+        }
+        Kind::Sequences => {
+            let cakes = load_sequences(&args.index.path, &manifest)?;
+            let queries = readers::read_sequences(&args.queries)?;
+            let results = commands::search(&cakes, &queries, search, algorithm)?;
+            write_results(&results, args.output.as_deref(), args.json)
+        }
+    }
+}
+
+/// Runs `tune`.
+fn tune(args: &TuneArgs) -> Result<(), String> {
+    let manifest = args.index.manifest()?;
+    let search = Search::new(args.k, args.radius)?;
+
+    let report = match manifest.kind {
+        Kind::Vectors => {
+            let mut cakes = load_vectors(&args.index.path, &manifest)?;
+            let queries = args
+                .queries
+                .as_ref()
+                .map(|path| read_vector_queries(path, &args.input_args))
+                .transpose()?;
+            commands::tune(
+                &mut cakes,
+                &args.index.path,
+                queries.as_deref(),
+                search,
+                args.min_recall,
+                args.depth,
+            )?
+        }
+        Kind::Sequences => {
+            let mut cakes = load_sequences(&args.index.path, &manifest)?;
+            let queries = args
+                .queries
+                .as_deref()
+                .map(readers::read_sequences)
+                .transpose()?;
+            commands::tune(
+                &mut cakes,
+                &args.index.path,
+                queries.as_deref(),
+                search,
+                args.min_recall,
+                args.depth,
+            )?
+        }
+    };
+    print_json(&report)
+}
+
+/// Runs `validate`.
+fn validate(args: &ValidateArgs) -> Result<(), String> {
+    let manifest = args.index.manifest()?;
+
+    let (ok, report) = match manifest.kind {
+        Kind::Vectors => {
+            let cakes = load_vectors(&args.index.path, &manifest)?;
+            let queries = args
+                .queries
+                .as_ref()
+                .map(|path| read_vector_queries(path, &args.input_args))
+                .transpose()?;
+            commands::validate(
+                &cakes,
+                queries.as_deref(),
+                args.k,
+                args.num_queries,
+                args.seed,
+            )
+        }
+        Kind::Sequences => {
+            let cakes = load_sequences(&args.index.path, &manifest)?;
+            let queries = args
+                .queries
+                .as_deref()
+                .map(readers::read_sequences)
+                .transpose()?;
+            commands::validate(
+                &cakes,
+                queries.as_deref(),
+                args.k,
+                args.num_queries,
+                args.seed,
+            )
+        }
+    };
+    print_json(&report)?;
+
+    if ok {
+        Ok(())
+    } else {
+        Err(format!(
+            "Validation of {} failed.",
+            args.index.path.display()
+        ))
+    }
+}
+
+/// Loads an index of vectors.
+fn load_vectors(path: &Path, manifest: &Manifest) -> Result<Index<Vec<f32>, f32>, String> {
+    Cakes::load(path, manifest::vector_metric(&manifest.metric)?, false)
+}
+
+/// Loads an index of sequences.
+fn load_sequences(path: &Path, manifest: &Manifest) -> Result<Index<String, u32>, String> {
+    Cakes::load(path, manifest::sequence_metric(&manifest.metric)?, true)
+}
+
+/// Reads vector queries from a `.npy` or CSV file.
+fn read_vector_queries(path: &Path, args: &InputArgs) -> Result<Vec<Vec<f32>>, String> {
+    let format = Format::new(path, args.format.as_deref())?;
+    readers::read_vectors(path, format, args.has_headers)
+}
+
+/// Writes search results to a file, or to standard output.
+fn write_results<U: Number>(
+    results: &[Vec<(usize, U)>],
+    path: Option<&Path>,
+    as_json: bool,
+) -> Result<(), String> {
+    match path {
+        Some(path) => {
+            let file = std::fs::File::create(path).map_err(|e| e.to_string())?;
+            commands::write_results(results, std::io::BufWriter::new(file), as_json)
+        }
+        None => commands::write_results(results, std::io::stdout().lock(), as_json),
+    }
+}
+
+/// Prints a value as pretty JSON to standard output.
+fn print_json(value: &serde_json::Value) -> Result<(), String> {
+    let mut out = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut out, value)
+        .and_then(|()| writeln!(out).map_err(serde_json::Error::io))
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,133 @@
+//! The description of an index, saved alongside it so that it can be loaded
+//! without repeating how it was built.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// The name of the file in which the manifest is saved.
+const FILE_NAME: &str = "clam.json";
+
+/// The type of a vector metric.
+pub type VectorMetric = fn(&Vec<f32>, &Vec<f32>) -> f32;
+
+/// The type of a sequence metric.
+pub type SequenceMetric = fn(&String, &String) -> u32;
+
+/// The kind of instances in an index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Vectors of `f32` with `f32` distances.
+    Vectors,
+    /// Sequences with `u32` distances.
+    Sequences,
+}
+
+/// How an index was built.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The kind of instances.
+    pub kind: Kind,
+    /// The name of the metric.
+    pub metric: String,
+    /// The file the index was built from, if it was built by the CLI.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl Manifest {
+    /// Saves the manifest in the index directory.
+    ///
+    /// # Errors
+    ///
+    /// * If the file cannot be written.
+    pub fn save(&self, dir: &Path) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(dir.join(FILE_NAME), contents).map_err(|e| e.to_string())
+    }
+
+    /// Loads the manifest from the index directory.
+    ///
+    /// # Errors
+    ///
+    /// * If the file does not exist or cannot be parsed.
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(FILE_NAME);
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}. {e}", path.display()))?;
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    }
+
+    /// Describes an index from the given kind and metric, falling back to the
+    /// manifest in the index directory for whichever is not given.
+    ///
+    /// This lets the CLI use any directory written by `Cakes::save`, including
+    /// those without a manifest.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The index directory.
+    /// * `kind` - The kind of instances, overriding the manifest.
+    /// * `metric` - The name of the metric, overriding the manifest.
+    ///
+    /// # Errors
+    ///
+    /// * If there is no manifest and `kind` or `metric` is not given.
+    /// * If the manifest exists but cannot be read or parsed.
+    pub fn resolve(dir: &Path, kind: Option<Kind>, metric: Option<&str>) -> Result<Self, String> {
+        if let (Some(kind), Some(metric)) = (kind, metric) {
+            let source = Self::load(dir).ok().and_then(|m| m.source);
+            return Ok(Self {
+                kind,
+                metric: metric.to_string(),
+                source,
+            });
+        }
+        if !dir.join(FILE_NAME).exists() {
+            return Err(format!(
+                "{} has no {FILE_NAME}, so both --kind and --metric must be given.",
+                dir.display()
+            ));
+        }
+        let mut manifest = Self::load(dir)?;
+        if let Some(kind) = kind {
+            manifest.kind = kind;
+        }
+        if let Some(metric) = metric {
+            manifest.metric = metric.to_string();
+        }
+        Ok(manifest)
+    }
+}
+
+/// Returns the vector metric with the given name.
+///
+/// # Errors
+///
+/// * If the name is not one of `euclidean`, `euclidean_sq`, `cosine`,
+///   `manhattan` or `chebyshev`.
+pub fn vector_metric(name: &str) -> Result<VectorMetric, String> {
+    match name {
+        "euclidean" => Ok(|x, y| distances::vectors::euclidean(x, y)),
+        "euclidean_sq" => Ok(|x, y| distances::vectors::euclidean_sq(x, y)),
+        "cosine" => Ok(|x, y| distances::vectors::cosine(x, y)),
+        "manhattan" => Ok(|x, y| distances::vectors::manhattan(x, y)),
+        "chebyshev" => Ok(|x, y| distances::vectors::chebyshev(x, y)),
+        _ => Err(format!("Unknown vector metric: {name}")),
+    }
+}
+
+/// Returns the sequence metric with the given name.
+///
+/// # Errors
+///
+/// * If the name is not one of `levenshtein`, `needleman_wunsch` or `hamming`.
+pub fn sequence_metric(name: &str) -> Result<SequenceMetric, String> {
+    match name {
+        "levenshtein" => Ok(|x, y| distances::strings::levenshtein(x, y)),
+        "needleman_wunsch" => Ok(|x, y| distances::strings::needleman_wunsch::nw_distance(x, y)),
+        "hamming" => Ok(|x, y| distances::strings::hamming(x, y)),
+        _ => Err(format!("Unknown sequence metric: {name}")),
+    }
+}
@@ -0,0 +1,101 @@
+//! Reading instances from `.npy`, TEXMEX, CSV, FASTA and FASTQ files.
+
+use std::path::Path;
+
+/// The format of an input file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// A 2-d numpy array of `f32`, one instance per row.
+    Npy,
+    /// TEXMEX `.fvecs` of `f32`, one instance per row.
+    Fvecs,
+    /// TEXMEX `.bvecs` of `u8`, converted to `f32`, one instance per row.
+    Bvecs,
+    /// Comma-separated `f32` values, one instance per row.
+    Csv,
+    /// Sequences in FASTA or FASTQ format, one instance per record.
+    Fasta,
+}
+
+impl Format {
+    /// Returns the format with the given name, or guesses it from the file
+    /// extension if no name is given.
+    ///
+    /// # Errors
+    ///
+    /// * If the name or extension is not recognized.
+    pub fn new(path: &Path, name: Option<&str>) -> Result<Self, String> {
+        let name = name.map_or_else(
+            || {
+                path.extension()
+                    .and_then(|e| e.to_str())
+                    .unwrap_or_default()
+                    .to_lowercase()
+            },
+            str::to_lowercase,
+        );
+        match name.as_str() {
+            "npy" => Ok(Self::Npy),
+            "fvecs" => Ok(Self::Fvecs),
+            "bvecs" => Ok(Self::Bvecs),
+            "csv" => Ok(Self::Csv),
+            "fasta" | "fa" | "fna" | "faa" | "fastq" | "fq" => Ok(Self::Fasta),
+            _ => Err(format!(
+                "Could not tell the format of {}. Use one of `npy`, `fvecs`, `bvecs`, `csv` or `fasta`.",
+                path.display()
+            )),
+        }
+    }
+
+    /// Whether the format holds vectors rather than sequences.
+    pub const fn is_vectors(self) -> bool {
+        !matches!(self, Self::Fasta)
+    }
+}
+
+/// Reads vectors from a `.npy`, TEXMEX or CSV file.
+///
+/// # Arguments
+///
+/// * `path` - The file to read.
+/// * `format` - The format of the file.
+/// * `has_headers` - Whether the first row of a CSV file is a header.
+///
+/// # Errors
+///
+/// * If the file cannot be read or parsed.
+/// * If `format` is not a vector format.
+pub fn read_vectors(
+    path: &Path,
+    format: Format,
+    has_headers: bool,
+) -> Result<Vec<Vec<f32>>, String> {
+    match format {
+        Format::Npy => abd_clam::readers::read_npy(path, None),
+        Format::Fvecs => abd_clam::readers::read_fvecs(path, None),
+        Format::Bvecs => abd_clam::readers::read_bvecs(path, None).map(|rows| {
+            rows.into_iter()
+                .map(|row| row.into_iter().map(f32::from).collect())
+                .collect()
+        }),
+        Format::Csv => abd_clam::readers::TabularReader::csv()
+            .with_headers(has_headers)
+            .read(path),
+        Format::Fasta => Err(format!("{} holds sequences, not vectors.", path.display())),
+    }
+}
+
+/// Reads the sequences from a FASTA or FASTQ file.
+///
+/// # Errors
+///
+/// * If the file cannot be read or parsed.
+pub fn read_sequences(path: &Path) -> Result<Vec<String>, String> {
+    abd_clam::readers::SequenceReader::open(path)?
+        .map(|record| {
+            record
+                .map(|r| r.sequence)
+                .map_err(|e| format!("Failed to read {}. {e}", path.display()))
+        })
+        .collect()
+}
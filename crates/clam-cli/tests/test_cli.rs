@@ -0,0 +1,227 @@
+use std::{path::Path, process::Command};
+
+use rand::prelude::*;
+use serde_json::Value;
+
+fn clam(args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_clam"))
+        .args(args)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(output.status.success() || !stderr.is_empty());
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+fn gen_data(cardinality: usize, dimensionality: usize, seed: u64) -> ndarray::Array2<f32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    ndarray::Array2::from_shape_fn((cardinality, dimensionality), |_| rng.gen_range(-1.0..1.0))
+}
+
+fn path(p: &Path) -> &str {
+    p.to_str().unwrap()
+}
+
+#[test]
+fn vectors() {
+    let tmp_dir = tempdir::TempDir::new("clam-cli-test").unwrap();
+    let data_path = tmp_dir.path().join("data.npy");
+    let queries_path = tmp_dir.path().join("queries.csv");
+    let index_dir = tmp_dir.path().join("index");
+
+    ndarray_npy::write_npy(&data_path, &gen_data(1000, 8, 42)).unwrap();
+    let queries = gen_data(5, 8, 43);
+    let csv = queries
+        .outer_iter()
+        .map(|row| {
+            row.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    std::fs::write(&queries_path, format!("{csv}\n")).unwrap();
+
+    let (ok, _) = clam(&[
+        "build",
+        path(&data_path),
+        "--metric",
+        "euclidean",
+        "--output",
+        path(&index_dir),
+        "--shard-size",
+        "400",
+        "--seed",
+        "42",
+    ]);
+    assert!(ok);
+
+    let (ok, out) = clam(&["inspect", path(&index_dir)]);
+    assert!(ok);
+    let summary: Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(summary["manifest"]["kind"], "vectors");
+    assert_eq!(summary["index"]["cardinality"], 1000);
+    assert_eq!(summary["index"]["num_shards"], 3);
+    assert_eq!(summary["index"]["trees"].as_array().unwrap().len(), 3);
+
+    let (ok, out) = clam(&[
+        "search",
+        path(&index_dir),
+        "--queries",
+        path(&queries_path),
+        "--k",
+        "3",
+    ]);
+    assert!(ok);
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "query,rank,index,distance");
+    assert_eq!(lines.len(), 1 + 5 * 3);
+
+    let results_path = tmp_dir.path().join("results.json");
+    let (ok, _) = clam(&[
+        "search",
+        path(&index_dir),
+        "--queries",
+        path(&queries_path),
+        "--radius",
+        "0.8",
+        "--json",
+        "--output",
+        path(&results_path),
+    ]);
+    assert!(ok);
+    let results: Value =
+        serde_json::from_str(&std::fs::read_to_string(&results_path).unwrap()).unwrap();
+    let results = results.as_array().unwrap();
+    assert_eq!(results.len(), 5);
+    for result in results {
+        for hit in result["hits"].as_array().unwrap() {
+            assert!(hit["distance"].as_f64().unwrap() <= 0.8);
+            assert!(hit["index"].as_u64().unwrap() < 1000);
+        }
+    }
+
+    let (ok, _) = clam(&["search", path(&index_dir), "--queries", path(&queries_path)]);
+    assert!(!ok);
+
+    let (ok, out) = clam(&[
+        "tune",
+        path(&index_dir),
+        "--queries",
+        path(&queries_path),
+        "--k",
+        "10",
+    ]);
+    assert!(ok);
+    let report: Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(report["num_queries"], 5);
+    assert!(index_dir
+        .join("sample_shard")
+        .join("knn-tuning.json")
+        .exists());
+
+    let (ok, out) = clam(&[
+        "validate",
+        path(&index_dir),
+        "--num-queries",
+        "20",
+        "--seed",
+        "42",
+    ]);
+    assert!(ok);
+    let report: Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(report["ok"], true);
+
+    // An index saved without a manifest, e.g. by `Cakes::save`, is described
+    // with flags instead.
+    std::fs::remove_file(index_dir.join("clam.json")).unwrap();
+    let (ok, _) = clam(&["inspect", path(&index_dir)]);
+    assert!(!ok);
+    let (ok, _) = clam(&["inspect", path(&index_dir), "--kind", "vectors"]);
+    assert!(!ok);
+    let flags = ["--kind", "vectors", "--metric", "euclidean"];
+    let (ok, out) = clam(&[&["inspect", path(&index_dir)], &flags[..]].concat());
+    assert!(ok);
+    let summary: Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(summary["manifest"]["metric"], "euclidean");
+    assert_eq!(summary["index"]["cardinality"], 1000);
+    let search = [
+        "search",
+        path(&index_dir),
+        "--queries",
+        path(&queries_path),
+        "--k",
+        "3",
+    ];
+    let (ok, out) = clam(&[&search[..], &flags[..]].concat());
+    assert!(ok);
+    assert_eq!(out.lines().count(), 1 + 5 * 3);
+    let (ok, _) = clam(
+        &[
+            &["validate", path(&index_dir), "--num-queries", "5"],
+            &flags[..],
+        ]
+        .concat(),
+    );
+    assert!(ok);
+}
+
+#[test]
+fn sequences() {
+    let tmp_dir = tempdir::TempDir::new("clam-cli-test").unwrap();
+    let fasta_path = tmp_dir.path().join("seqs.fasta");
+    let index_dir = tmp_dir.path().join("index");
+
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut fasta = String::new();
+    for i in 0..100 {
+        let seq = (0..30)
+            .map(|_| ['A', 'C', 'G', 'T'][rng.gen_range(0..4)])
+            .collect::<String>();
+        // Split each sequence over two lines.
+        fasta.push_str(&format!(">seq{i}\n{}\n{}\n", &seq[..15], &seq[15..]));
+    }
+    std::fs::write(&fasta_path, fasta).unwrap();
+
+    let (ok, _) = clam(&[
+        "build",
+        path(&fasta_path),
+        "--metric",
+        "levenshtein",
+        "--output",
+        path(&index_dir),
+    ]);
+    assert!(ok);
+
+    let (ok, out) = clam(&["inspect", path(&index_dir)]);
+    assert!(ok);
+    let summary: Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(summary["manifest"]["kind"], "sequences");
+    assert_eq!(summary["index"]["cardinality"], 100);
+
+    let (ok, out) = clam(&[
+        "search",
+        path(&index_dir),
+        "--queries",
+        path(&fasta_path),
+        "--k",
+        "1",
+        "--json",
+    ]);
+    assert!(ok);
+    let results: Value = serde_json::from_str(&out).unwrap();
+    for (i, result) in results.as_array().unwrap().iter().enumerate() {
+        let hit = &result["hits"][0];
+        assert_eq!(hit["distance"], 0.0);
+        assert_eq!(hit["index"], i);
+    }
+
+    let (ok, out) = clam(&["validate", path(&index_dir), "--k", "5"]);
+    assert!(ok);
+    let report: Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(report["ok"], true);
+}
@@ -0,0 +1,156 @@
+//! Distance functions for bit-packed binary vectors.
+//!
+//! A binary vector is packed into a slice of `u64` words, with bit `i` of the
+//! vector stored in bit `i % 64` of word `i / 64`. Each function counts bits a
+//! whole word at a time with `count_ones`, which compiles to a single
+//! `popcount` instruction on most targets.
+//!
+//! # Potentially unexpected behaviors
+//!
+//! When one slice is shorter than the other, words in the longer slice past
+//! the end of the shorter slice will be ignored. Any padding bits past the end
+//! of a vector must be zero.
+
+use crate::number::{Float, UInt};
+
+/// Hamming distance between two bit-packed binary vectors.
+///
+/// The Hamming distance is the number of bits that differ between the two
+/// vectors.
+///
+/// # Arguments
+///
+/// * `x`: The words of the first vector.
+/// * `y`: The words of the second vector.
+///
+/// # Examples
+///
+/// ```
+/// use distances::bits::hamming;
+///
+/// let x: Vec<u64> = vec![0b1011, 1];
+/// let y: Vec<u64> = vec![0b0110, 1];
+///
+/// let distance: u32 = hamming(&x, &y);
+///
+/// assert_eq!(distance, 3);
+/// ```
+#[must_use]
+pub fn hamming<U: UInt>(x: &[u64], y: &[u64]) -> U {
+    U::from(
+        x.iter()
+            .zip(y.iter())
+            .map(|(&a, &b)| u64::from((a ^ b).count_ones()))
+            .sum::<u64>(),
+    )
+}
+
+/// Jaccard distance between two bit-packed binary vectors.
+///
+/// The Jaccard distance is one minus the number of bits set in both vectors
+/// divided by the number of bits set in either vector. If either vector has no
+/// bits set, the distance is one. This matches `sets::jaccard` and
+/// `sparse::jaccard` for empty sets.
+///
+/// # Arguments
+///
+/// * `x`: The words of the first vector.
+/// * `y`: The words of the second vector.
+///
+/// # Examples
+///
+/// ```
+/// use distances::bits::jaccard;
+///
+/// let x: Vec<u64> = vec![0b1110];
+/// let y: Vec<u64> = vec![0b0111];
+///
+/// let distance: f32 = jaccard(&x, &y);
+///
+/// assert!((distance - 0.5).abs() <= f32::EPSILON);
+/// ```
+#[must_use]
+pub fn jaccard<U: Float>(x: &[u64], y: &[u64]) -> U {
+    let [intersection, union] = x.iter().zip(y.iter()).fold([0_u64; 2], |[i, u], (&a, &b)| {
+        [
+            i + u64::from((a & b).count_ones()),
+            u + u64::from((a | b).count_ones()),
+        ]
+    });
+
+    if union == 0 {
+        U::one()
+    } else {
+        U::one() - U::from(intersection) / U::from(union)
+    }
+}
+
+/// Tanimoto distance between two bit-packed binary vectors.
+///
+/// For binary vectors, the Tanimoto distance is the same as the Jaccard
+/// distance. This name is common for chemical fingerprints.
+///
+/// # Arguments
+///
+/// * `x`: The words of the first vector.
+/// * `y`: The words of the second vector.
+///
+/// # Examples
+///
+/// ```
+/// use distances::bits::tanimoto;
+///
+/// let x: Vec<u64> = vec![0b1110];
+/// let y: Vec<u64> = vec![0b0111];
+///
+/// let distance: f32 = tanimoto(&x, &y);
+///
+/// assert!((distance - 0.5).abs() <= f32::EPSILON);
+/// ```
+#[must_use]
+pub fn tanimoto<U: Float>(x: &[u64], y: &[u64]) -> U {
+    jaccard(x, y)
+}
+
+/// Dice distance between two bit-packed binary vectors.
+///
+/// The Dice distance is one minus twice the number of bits set in both
+/// vectors divided by the sum of the numbers of bits set in each vector. If
+/// either vector has no bits set, the distance is one, as for `jaccard`.
+///
+/// The Dice distance does not satisfy the triangle inequality, so it is not a
+/// metric. Search algorithms that rely on the triangle inequality, such as
+/// those in CAKES, may miss some neighbors when using it.
+///
+/// # Arguments
+///
+/// * `x`: The words of the first vector.
+/// * `y`: The words of the second vector.
+///
+/// # Examples
+///
+/// ```
+/// use distances::bits::dice;
+///
+/// let x: Vec<u64> = vec![0b1110];
+/// let y: Vec<u64> = vec![0b0111];
+///
+/// let distance: f64 = dice(&x, &y);
+///
+/// assert!((distance - 1.0 / 3.0).abs() <= f64::EPSILON);
+/// ```
+#[must_use]
+pub fn dice<U: Float>(x: &[u64], y: &[u64]) -> U {
+    let [intersection, total] = x.iter().zip(y.iter()).fold([0_u64; 2], |[i, t], (&a, &b)| {
+        [
+            i + u64::from((a & b).count_ones()),
+            t + u64::from(a.count_ones() + b.count_ones()),
+        ]
+    });
+
+    if total == 0 {
+        U::one()
+    } else {
+        U::one() - U::from(2 * intersection) / U::from(total)
+    }
+}
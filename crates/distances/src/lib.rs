@@ -23,8 +23,10 @@ pub mod number;
 
 pub use number::Number;
 
+pub mod bits;
 pub mod sets;
 pub mod simd;
+pub mod sparse;
 pub mod strings;
 pub mod vectors;
 
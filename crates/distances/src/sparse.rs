@@ -0,0 +1,267 @@
+//! Distance functions for sparse vectors.
+//!
+//! A sparse vector is represented by two slices of the same length: the
+//! indices of its non-zero elements, in strictly increasing order, and the
+//! values of those elements. All elements whose indices are absent are zero.
+//!
+//! Each function walks the two index slices together, as in the merge step of
+//! merge-sort, so that the cost is linear in the number of non-zero elements
+//! and does not depend on the dimensionality.
+//!
+//! # Potentially unexpected behaviors
+//!
+//! If the indices are not sorted in strictly increasing order, the results
+//! will be incorrect. If an index slice is longer than its value slice, or vice
+//! versa, the extra elements will be ignored.
+
+use core::cmp::Ordering;
+
+use crate::{number::Float, Number};
+
+/// Computes the Cosine distance between two sparse vectors.
+///
+/// The cosine distance is defined as `1.0 - c` where `c` is the cosine
+/// similarity. Only the elements that are non-zero in both vectors contribute
+/// to the dot product.
+///
+/// See the [`crate::sparse`] module documentation for the representation of
+/// sparse vectors.
+///
+/// # Arguments
+///
+/// * `x_indices`: The indices of the non-zero elements of the first vector.
+/// * `x_values`: The values of the non-zero elements of the first vector.
+/// * `y_indices`: The indices of the non-zero elements of the second vector.
+/// * `y_values`: The values of the non-zero elements of the second vector.
+///
+/// # Examples
+///
+/// ```
+/// use distances::sparse::cosine;
+///
+/// let (x_indices, x_values) = (vec![0_usize, 5], vec![1.0_f32, 1.0]);
+/// let (y_indices, y_values) = (vec![5_usize, 9], vec![1.0_f32, 1.0]);
+///
+/// let distance: f32 = cosine(&x_indices, &x_values, &y_indices, &y_values);
+///
+/// assert!((distance - 0.5).abs() <= f32::EPSILON);
+/// ```
+pub fn cosine<I: Ord, T: Number, U: Float>(
+    x_indices: &[I],
+    x_values: &[T],
+    y_indices: &[I],
+    y_values: &[T],
+) -> U {
+    let xx = x_values.iter().fold(T::zero(), |acc, &a| a.mul_add(a, acc));
+    let yy = y_values.iter().fold(T::zero(), |acc, &b| b.mul_add(b, acc));
+    let xy = merge_walk(x_indices, x_values, y_indices, y_values)
+        .filter_map(|(a, b)| a.zip(b))
+        .fold(T::zero(), |acc, (a, b)| a.mul_add(b, acc));
+    let [xx, yy, xy] = [U::from(xx), U::from(yy), U::from(xy)];
+
+    if xx < U::epsilon() || yy < U::epsilon() || xy < U::epsilon() {
+        U::one()
+    } else {
+        let d = U::one() - xy * (xx * yy).inv_sqrt();
+        if d < U::epsilon() {
+            U::zero()
+        } else {
+            d
+        }
+    }
+}
+
+/// Euclidean distance between two sparse vectors.
+///
+/// See the [`crate::sparse`] module documentation for the representation of
+/// sparse vectors.
+///
+/// # Arguments
+///
+/// * `x_indices`: The indices of the non-zero elements of the first vector.
+/// * `x_values`: The values of the non-zero elements of the first vector.
+/// * `y_indices`: The indices of the non-zero elements of the second vector.
+/// * `y_values`: The values of the non-zero elements of the second vector.
+///
+/// # Examples
+///
+/// ```
+/// use distances::sparse::euclidean;
+///
+/// let (x_indices, x_values) = (vec![0_usize, 5], vec![3.0_f64, 1.0]);
+/// let (y_indices, y_values) = (vec![5_usize, 9], vec![1.0_f64, 4.0]);
+///
+/// let distance: f64 = euclidean(&x_indices, &x_values, &y_indices, &y_values);
+///
+/// assert!((distance - 5.0).abs() <= f64::EPSILON);
+/// ```
+pub fn euclidean<I: Ord, T: Number, U: Float>(
+    x_indices: &[I],
+    x_values: &[T],
+    y_indices: &[I],
+    y_values: &[T],
+) -> U {
+    euclidean_sq::<I, T, U>(x_indices, x_values, y_indices, y_values).sqrt()
+}
+
+/// Squared Euclidean distance between two sparse vectors.
+///
+/// See the [`crate::sparse`] module documentation for the representation of
+/// sparse vectors.
+///
+/// # Arguments
+///
+/// * `x_indices`: The indices of the non-zero elements of the first vector.
+/// * `x_values`: The values of the non-zero elements of the first vector.
+/// * `y_indices`: The indices of the non-zero elements of the second vector.
+/// * `y_values`: The values of the non-zero elements of the second vector.
+///
+/// # Examples
+///
+/// ```
+/// use distances::sparse::euclidean_sq;
+///
+/// let (x_indices, x_values) = (vec![0_usize, 5], vec![3.0_f64, 1.0]);
+/// let (y_indices, y_values) = (vec![5_usize, 9], vec![1.0_f64, 4.0]);
+///
+/// let distance: f64 = euclidean_sq(&x_indices, &x_values, &y_indices, &y_values);
+///
+/// assert!((distance - 25.0).abs() <= f64::EPSILON);
+/// ```
+pub fn euclidean_sq<I: Ord, T: Number, U: Number>(
+    x_indices: &[I],
+    x_values: &[T],
+    y_indices: &[I],
+    y_values: &[T],
+) -> U {
+    U::from(
+        abs_diff_walk(x_indices, x_values, y_indices, y_values)
+            .fold(T::zero(), |acc, d| d.mul_add(d, acc)),
+    )
+}
+
+/// Manhattan distance between two sparse vectors.
+///
+/// See the [`crate::sparse`] module documentation for the representation of
+/// sparse vectors.
+///
+/// # Arguments
+///
+/// * `x_indices`: The indices of the non-zero elements of the first vector.
+/// * `x_values`: The values of the non-zero elements of the first vector.
+/// * `y_indices`: The indices of the non-zero elements of the second vector.
+/// * `y_values`: The values of the non-zero elements of the second vector.
+///
+/// # Examples
+///
+/// ```
+/// use distances::sparse::manhattan;
+///
+/// let (x_indices, x_values) = (vec![0_u32, 5], vec![3_i32, 1]);
+/// let (y_indices, y_values) = (vec![5_u32, 9], vec![-1_i32, 4]);
+///
+/// let distance: i32 = manhattan(&x_indices, &x_values, &y_indices, &y_values);
+///
+/// assert_eq!(distance, 9);
+/// ```
+pub fn manhattan<I: Ord, T: Number, U: Number>(
+    x_indices: &[I],
+    x_values: &[T],
+    y_indices: &[I],
+    y_values: &[T],
+) -> U {
+    U::from(abs_diff_walk(x_indices, x_values, y_indices, y_values).sum::<T>())
+}
+
+/// Jaccard distance between the supports of two sparse vectors.
+///
+/// The support of a sparse vector is the set of indices of its non-zero
+/// elements, so the values are ignored. This suits binary data such as sets of
+/// items or words.
+///
+/// The Jaccard distance is defined as one minus the cardinality of the
+/// intersection of the supports divided by the cardinality of their union.
+///
+/// # Arguments
+///
+/// * `x_indices`: The indices of the non-zero elements of the first vector.
+/// * `y_indices`: The indices of the non-zero elements of the second vector.
+///
+/// # Examples
+///
+/// ```
+/// use distances::sparse::jaccard;
+///
+/// let x_indices = vec![1_usize, 2, 3];
+/// let y_indices = vec![2_usize, 3, 4];
+///
+/// let distance: f32 = jaccard(&x_indices, &y_indices);
+///
+/// assert!((distance - 0.5).abs() < f32::EPSILON);
+/// ```
+pub fn jaccard<I: Ord, U: Float>(x_indices: &[I], y_indices: &[I]) -> U {
+    if x_indices.is_empty() || y_indices.is_empty() {
+        return U::one();
+    }
+
+    let (mut i, mut j, mut intersection) = (0, 0, 0);
+    while i < x_indices.len() && j < y_indices.len() {
+        match x_indices[i].cmp(&y_indices[j]) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                intersection += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+
+    let union = x_indices.len() + y_indices.len() - intersection;
+    if intersection == union {
+        U::zero()
+    } else {
+        U::one() - U::from(intersection) / U::from(union)
+    }
+}
+
+/// An iterator over the pairs of elements of two sparse vectors at each index
+/// that is non-zero in either vector, in increasing order of index. Elements
+/// that are absent from a vector are `None`.
+fn merge_walk<'a, I: Ord, T: Number>(
+    x_indices: &'a [I],
+    x_values: &'a [T],
+    y_indices: &'a [I],
+    y_values: &'a [T],
+) -> impl Iterator<Item = (Option<T>, Option<T>)> + 'a {
+    let mut x = x_indices.iter().zip(x_values.iter().copied()).peekable();
+    let mut y = y_indices.iter().zip(y_values.iter().copied()).peekable();
+
+    core::iter::from_fn(move || match (x.peek(), y.peek()) {
+        (Some((i, _)), Some((j, _))) => match i.cmp(j) {
+            Ordering::Less => x.next().map(|(_, a)| (Some(a), None)),
+            Ordering::Greater => y.next().map(|(_, b)| (None, Some(b))),
+            Ordering::Equal => x
+                .next()
+                .zip(y.next())
+                .map(|((_, a), (_, b))| (Some(a), Some(b))),
+        },
+        (Some(_), None) => x.next().map(|(_, a)| (Some(a), None)),
+        (None, Some(_)) => y.next().map(|(_, b)| (None, Some(b))),
+        (None, None) => None,
+    })
+}
+
+/// An iterator over the absolute differences between the elements of two
+/// sparse vectors at each index that is non-zero in either vector.
+fn abs_diff_walk<'a, I: Ord, T: Number>(
+    x_indices: &'a [I],
+    x_values: &'a [T],
+    y_indices: &'a [I],
+    y_values: &'a [T],
+) -> impl Iterator<Item = T> + 'a {
+    merge_walk(x_indices, x_values, y_indices, y_values).map(|(a, b)| {
+        a.unwrap_or_else(T::zero)
+            .abs_diff(b.unwrap_or_else(T::zero))
+    })
+}
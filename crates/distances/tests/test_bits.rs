@@ -0,0 +1,61 @@
+use rand::prelude::*;
+
+use distances::bits;
+
+/// Packs a binary vector into `u64` words.
+fn pack(x: &[bool]) -> Vec<u64> {
+    x.chunks(64)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |w, (i, &b)| w | (u64::from(b) << i))
+        })
+        .collect()
+}
+
+#[test]
+fn packed_vs_unpacked() {
+    let mut rng = StdRng::seed_from_u64(42);
+    for _ in 0..100 {
+        let dim = rng.gen_range(1..500);
+        let x = (0..dim).map(|_| rng.gen_bool(0.3)).collect::<Vec<_>>();
+        let y = (0..dim).map(|_| rng.gen_bool(0.3)).collect::<Vec<_>>();
+        let (px, py) = (pack(&x), pack(&y));
+
+        let both = x.iter().zip(&y).filter(|(&a, &b)| a && b).count();
+        let either = x.iter().zip(&y).filter(|(&a, &b)| a || b).count();
+        let differ = x.iter().zip(&y).filter(|(&a, &b)| a != b).count();
+        let total = x.iter().chain(&y).filter(|&&a| a).count();
+
+        let h: usize = bits::hamming(&px, &py);
+        assert_eq!(h, differ);
+
+        let j: f64 = bits::jaccard(&px, &py);
+        let expected = if either == 0 {
+            1.
+        } else {
+            1. - both as f64 / either as f64
+        };
+        assert!((j - expected).abs() <= f64::EPSILON);
+        let t: f64 = bits::tanimoto(&px, &py);
+        assert!((t - j).abs() <= f64::EPSILON);
+
+        let d: f64 = bits::dice(&px, &py);
+        let expected = if total == 0 {
+            1.
+        } else {
+            1. - 2. * both as f64 / total as f64
+        };
+        assert!((d - expected).abs() <= f64::EPSILON);
+    }
+
+    // Vectors with no bits set are treated like empty sets.
+    let zeros = vec![0_u64; 3];
+    let d: f32 = bits::jaccard(&zeros, &zeros);
+    assert!((d - 1.).abs() <= f32::EPSILON);
+    let set: f32 = distances::sets::jaccard::<u32, f32>(&[], &[]);
+    assert!((d - set).abs() <= f32::EPSILON);
+    let d: f32 = bits::dice(&zeros, &zeros);
+    assert!((d - 1.).abs() <= f32::EPSILON);
+}
@@ -0,0 +1,67 @@
+use rand::prelude::*;
+
+use distances::{sparse, vectors};
+
+/// Generates a random sparse vector with about `density` of its `dim`
+/// elements being non-zero, along with its dense form.
+fn gen_sparse(dim: usize, density: f64, rng: &mut StdRng) -> (Vec<usize>, Vec<f64>, Vec<f64>) {
+    let mut dense = vec![0.; dim];
+    let (mut indices, mut values) = (Vec::new(), Vec::new());
+    for (i, d) in dense.iter_mut().enumerate() {
+        if rng.gen_bool(density) {
+            *d = rng.gen_range(-10.0..10.0);
+            indices.push(i);
+            values.push(*d);
+        }
+    }
+    (indices, values, dense)
+}
+
+#[test]
+fn sparse_vs_dense() {
+    let mut rng = StdRng::seed_from_u64(42);
+    for _ in 0..100 {
+        let (xi, xv, x) = gen_sparse(200, 0.1, &mut rng);
+        let (yi, yv, y) = gen_sparse(200, 0.1, &mut rng);
+
+        let pairs: [(f64, f64); 4] = [
+            (
+                sparse::euclidean(&xi, &xv, &yi, &yv),
+                vectors::euclidean(&x, &y),
+            ),
+            (
+                sparse::euclidean_sq(&xi, &xv, &yi, &yv),
+                vectors::euclidean_sq(&x, &y),
+            ),
+            (
+                sparse::manhattan(&xi, &xv, &yi, &yv),
+                vectors::manhattan(&x, &y),
+            ),
+            (sparse::cosine(&xi, &xv, &yi, &yv), vectors::cosine(&x, &y)),
+        ];
+        for (s, d) in pairs {
+            assert!((s - d).abs() <= 1e-9 * d.abs().max(1.), "{s} vs {d}");
+        }
+
+        let s: f64 = sparse::jaccard(&xi, &yi);
+        let d: f64 = distances::sets::jaccard(&xi, &yi);
+        assert!((s - d).abs() <= f64::EPSILON, "{s} vs {d}");
+    }
+}
+
+#[test]
+fn edge_cases() {
+    let empty: (Vec<usize>, Vec<f32>) = (vec![], vec![]);
+    let x = (vec![2_usize, 7], vec![3_f32, 4.]);
+
+    let d: f32 = sparse::euclidean(&empty.0, &empty.1, &x.0, &x.1);
+    assert!((d - 5.).abs() <= f32::EPSILON);
+    let d: f32 = sparse::euclidean(&x.0, &x.1, &x.0, &x.1);
+    assert!(d <= f32::EPSILON);
+    let d: f32 = sparse::cosine(&empty.0, &empty.1, &x.0, &x.1);
+    assert!((d - 1.).abs() <= f32::EPSILON);
+    let d: f32 = sparse::jaccard(&x.0, &x.0);
+    assert!(d <= f32::EPSILON);
+    let d: f32 = sparse::jaccard(&empty.0, &x.0);
+    assert!((d - 1.).abs() <= f32::EPSILON);
+}